@@ -1,12 +1,49 @@
+use crate::config::{ClientConfig, ServerEntry};
+use crate::file_transfer::{self, IncomingFile};
+use crate::theme::{self, Theme};
+use ratatui::crossterm::event::KeyCode;
+use ratatui::style::Color;
 use rodio::{Decoder, OutputStream, Sink};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::fs::File;
-use std::io::BufReader;
-use std::path::PathBuf;
-use std::time::Instant;
+use std::io::{BufReader, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use unicode_segmentation::UnicodeSegmentation;
 use url::Url;
 
+// Chat channel every user starts in, matching the server's
+pub const DEFAULT_CHANNEL: &str = "general";
+
+// How many sent inputs are remembered for recall with the Up arrow
+pub const INPUT_HISTORY_SIZE: usize = 50;
+
+// Longest chat message that can be sent, in characters
+pub const MAX_MESSAGE_LEN: usize = 2000;
+
+// Names shown per page of /list, so a busy server's list doesn't run off the screen
+pub const USER_LIST_PAGE_SIZE: usize = 60;
+
+// How long to wait for the server to acknowledge a chat message before marking it failed
+pub const ACK_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Messages kept to send once a dropped connection is back; the oldest go first when it's full
+pub const OUTBOX_SIZE: usize = 50;
+
+// Redirects followed in a row before giving up, so two servers pointing at each other can't
+// bounce us back and forth forever
+pub const MAX_REDIRECTS: u8 = 3;
+
+// How long the chat header stays highlighted after a mention or direct message
+pub const FLASH_DURATION: Duration = Duration::from_millis(1500);
+
+// Frames of the spinner shown while connecting or logging in, and how long each is shown for
+pub const SPINNER: [&str; 10] = ["⠋", "⠙", "⠹", "⠸", "⠼", "⠴", "⠦", "⠧", "⠇", "⠏"];
+pub const SPINNER_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Clone, Debug, PartialEq)]
 pub enum CurrentScreen {
     Main,
     SetUser,
@@ -18,50 +55,537 @@ pub enum CurrentScreen {
     ExitingLoggingIn,
     ServerSelection,
     AddServer,
+    AwayList,
+    UserList,           // table of the users online, in reply to /list
+    DirectChat(String), // 1:1 view of the conversation with the named user
+    Search,             // typing a query to find in the transcript
+    Connecting,         // waiting for a connection attempt, which Esc cancels
+    Welcome,            // first-run setup, shown while there is no config file
+}
+
+// Where the first-run wizard is up to
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum WelcomeStep {
+    Username,
+    Server,
+}
+
+// What a key press does to the current screen, as decided by `App::on_key`
+#[derive(Debug, PartialEq)]
+pub enum Transition {
+    Stay,              // the screen handles the key itself, if at all
+    Go(CurrentScreen), // move to another screen
+    Quit,              // leave the app
+}
+
+// A slash command understood by `parse_command`
+pub struct CommandSpec {
+    pub name: &'static str,  // what the user types, including the leading '/'
+    pub usage: &'static str, // arguments, shown in the help menu
+    pub description: &'static str, // one-line summary for the help menu
+}
+
+// Registry of slash commands, used for Tab completion and the help menu.
+// Keep it in sync with `parse_command`.
+pub const COMMANDS: &[CommandSpec] = &[
+    CommandSpec {
+        name: "/name",
+        usage: "<name>",
+        description: "change your display name",
+    },
+    CommandSpec {
+        name: "/list",
+        usage: "[page]",
+        description: "list connected users",
+    },
+    CommandSpec {
+        name: "/names",
+        usage: "[page]",
+        description: "same as /list",
+    },
+    CommandSpec {
+        name: "/afk",
+        usage: "[message]",
+        description: "mark yourself away; DMs get an auto-reply until you next send",
+    },
+    CommandSpec {
+        name: "/afk-list",
+        usage: "",
+        description: "show who is away",
+    },
+    CommandSpec {
+        name: "/seen",
+        usage: "<user>",
+        description: "show when a user was last active",
+    },
+    CommandSpec {
+        name: "/reply",
+        usage: "<id> <message>",
+        description: "answer a message; select it with v to see its id",
+    },
+    CommandSpec {
+        name: "/dm",
+        usage: "<user> <message>",
+        description: "send a direct message",
+    },
+    CommandSpec {
+        name: "/open",
+        usage: "<user>",
+        description: "open a 1:1 chat with a user",
+    },
+    CommandSpec {
+        name: "/block",
+        usage: "<user>",
+        description: "have the server stop sending you a user's messages",
+    },
+    CommandSpec {
+        name: "/unblock",
+        usage: "<user>",
+        description: "receive a blocked user's messages again",
+    },
+    CommandSpec {
+        name: "/retry",
+        usage: "",
+        description: "resend messages that were not delivered",
+    },
+    CommandSpec {
+        name: "/topic",
+        usage: "<text>",
+        description: "set the channel topic",
+    },
+    CommandSpec {
+        name: "/history",
+        usage: "",
+        description: "reload the channel's history, replacing the transcript",
+    },
+    CommandSpec {
+        name: "/invite",
+        usage: "",
+        description: "copy a link to this server to share",
+    },
+    CommandSpec {
+        name: "/status",
+        usage: "",
+        description: "show your name, color and activity as the server sees them",
+    },
+    CommandSpec {
+        name: "/export",
+        usage: "[path]",
+        description: "save the transcript as text, or JSON for a .json path",
+    },
+    CommandSpec {
+        name: "/send",
+        usage: "<path>",
+        description: "send a small file to everyone",
+    },
+    CommandSpec {
+        name: "/stats",
+        usage: "",
+        description: "show server statistics (admins only)",
+    },
+    CommandSpec {
+        name: "/clearhistory",
+        usage: "",
+        description: "wipe the channel history new arrivals are shown (admins only)",
+    },
+    CommandSpec {
+        name: "/mute",
+        usage: "<user> [seconds]",
+        description: "silence a user, for 5 minutes by default (admins only)",
+    },
+    CommandSpec {
+        name: "/unmute",
+        usage: "<user>",
+        description: "lift a mute early (admins only)",
+    },
+    CommandSpec {
+        name: "/sound",
+        usage: "<path>",
+        description: "use a different notification sound, playing it to try",
+    },
+    CommandSpec {
+        name: "/theme",
+        usage: "<name>",
+        description: "switch the color theme (dark or light)",
+    },
+    CommandSpec {
+        name: "/clear",
+        usage: "",
+        description: "clear your local transcript",
+    },
+    CommandSpec {
+        name: "/join",
+        usage: "<channel>",
+        description: "switch to a chat channel",
+    },
+    CommandSpec {
+        name: "/part",
+        usage: "",
+        description: "leave the current channel for #general",
+    },
+    CommandSpec {
+        name: "/reconnect",
+        usage: "",
+        description: "reconnect to the current server",
+    },
+    CommandSpec {
+        name: "/quit",
+        usage: "",
+        description: "quit the messenger",
+    },
+    CommandSpec {
+        name: "/help",
+        usage: "",
+        description: "show the help menu",
+    },
+];
+
+// Registered command names starting with `prefix`, in registry order
+pub fn command_candidates(prefix: &str) -> Vec<&'static str> {
+    COMMANDS
+        .iter()
+        .map(|command| command.name)
+        .filter(|name| name.starts_with(prefix))
+        .collect()
+}
+
+// Longest prefix shared by all the given names
+fn longest_common_prefix(names: &[&str]) -> String {
+    let Some((first, rest)) = names.split_first() else {
+        return String::new();
+    };
+    let mut prefix_len = first.len();
+    for name in rest {
+        prefix_len = first
+            .bytes()
+            .zip(name.bytes())
+            .take(prefix_len)
+            .take_while(|(a, b)| a == b)
+            .count();
+    }
+    first[..prefix_len].to_string()
 }
 
 pub enum Command {
     SetName(String),
-    ListUsers,
+    ListUsers(usize), // page of the list to show, starting at 1
+    Afk(String),      // optional away message, empty for the default
+    AfkList,
+    Seen(String),                  // user to ask the server about
     DirectMessage(String, String), // recipient, message
+    OpenDirectChat(String),        // switch to the 1:1 view with a user
+    Retry,                         // resend chat messages that were never acknowledged
+    Clear,                         // wipe the local transcript
+    Theme(String),                 // color theme to switch to
+    Sound(String),                 // path of a sound file to notify with
+    Stats,                         // ask the server for its statistics (admins only)
+    ClearHistory,                  // wipe the channel's history on the server (admins only)
+    Mute(String, Option<String>),  // user and how many seconds to mute them for (admins only)
+    Unmute(String),                // user whose mute to lift (admins only)
+    Block(String),                 // user whose messages the server should stop sending us
+    Unblock(String),               // user to receive messages from again
+    SendFile(String),              // path of a file to send to everyone
+    Export(Option<String>),        // where to save the transcript, or a timestamped file in home
+    History,                       // ask the server to send the channel's history again
+    Invite,                        // share a link to the server we're connected to
+    Status,                        // ask the server what it knows about us
+    Reply(String, String),         // id (or its start) of the message to answer, and the answer
+    Topic(String),                 // new channel topic
+    Reconnect,                     // drop and re-open the connection to the current server
+    Join(String),                  // switch to a chat channel
+    Part,                          // leave the current channel for the default one
+    Quit,                          // ask to quit, same as pressing q
     Help,
     Unknown(String),
 }
 
+// A connection change requested from somewhere that can't reach the websocket streams
+#[derive(Debug, PartialEq)]
+pub enum PendingConnect {
+    Reconnect,    // re-open the connection to the current server
+    SwitchServer, // connect to `selected_server`, e.g. after a redirect
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
 pub enum LoginField {
     Username,
     Password,
 }
 
+// The fields of a form with more than one, so every such screen moves between them the same
+// way: Tab to the next field and Shift+Tab (BackTab) to the previous, wrapping at either end
+pub trait FieldFocus: Copy + PartialEq + 'static {
+    const ORDER: &'static [Self]; // the fields in the order Tab visits them
+
+    fn next(self) -> Self {
+        let index = Self::ORDER.iter().position(|f| *f == self).unwrap_or(0);
+        Self::ORDER[(index + 1) % Self::ORDER.len()]
+    }
+
+    fn previous(self) -> Self {
+        let index = Self::ORDER.iter().position(|f| *f == self).unwrap_or(0);
+        Self::ORDER[(index + Self::ORDER.len() - 1) % Self::ORDER.len()]
+    }
+
+    // The field a key moves focus to, if it's one that moves focus
+    fn after_key(self, key: KeyCode) -> Option<Self> {
+        match key {
+            KeyCode::Tab => Some(self.next()),
+            KeyCode::BackTab => Some(self.previous()),
+            _ => None,
+        }
+    }
+}
+
+impl FieldFocus for LoginField {
+    const ORDER: &'static [LoginField] = &[LoginField::Username, LoginField::Password];
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum MessageType {
-    ChatMessage { sender: String, content: String },
-    Command { name: String, args: Vec<String> },
+    ChatMessage {
+        sender: String,
+        content: String,
+        #[serde(default)]
+        sender_id: String, // Stable account id of the sender, unaffected by renames
+        #[serde(default)]
+        client_msg_id: Option<String>, // Id we attach to our own messages, echoed in the Ack
+        #[serde(default)]
+        message_id: Option<String>, // id the server stored the message under, for reactions
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        reply_to: Option<u64>, // seq of the message this one answers
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        seq: Option<u64>, // order the server broadcast it in, which only ever goes up
+    },
+    Command {
+        name: String,
+        args: Vec<String>,
+    },
     SystemMessage(String),
+    PrivateMessage {
+        sender: String,
+        recipient: String,
+        content: String,
+    },
+    AwayList(Vec<(String, String)>), // (username, away message)
+    Spectate,                        // join as a read-only spectator instead of logging in
+    Ack {
+        id: String, // client_msg_id of one of our chat messages the server accepted
+        #[serde(default)]
+        message_id: Option<String>, // the message_id the server gave it
+        #[serde(default)]
+        seq: Option<u64>, // the sequence number the server gave it
+    },
+    UserCount(usize), // number of users online, sent whenever someone joins or leaves
+    Roster(Vec<String>), // names of the users online, sent with UserCount
+    UserList(Vec<String>), // names of the users online, sorted, in reply to /list
+    UserDetails(Vec<UserSummary>), // the users online, sorted, in reply to /list from newer servers
+    History(Vec<MessageType>), // the channel's history and reactions, in reply to /history
+    Topic(String),    // channel topic, sent on connect, on joining and whenever it changes
+    Redirect(String), // server asking us to move to another server, e.g. because it's full
+    Join(String),     // switch to a chat channel; the server echoes it once we're in
+    Leave(String),    // leave a chat channel, going back to the default one
+    Notice {
+        level: Severity, // how to present it; plain SystemMessages count as Info
+        text: String,
+    },
+    Auth {
+        username: String, // login credentials, sent once from the login screen
+        password: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        token: Option<String>, // pre-shared token for bots; people log in with a password
+    },
+    FileChunk {
+        id: String,   // transfer id shared by all chunks of one file
+        name: String, // file name chosen by the sender
+        seq: u32,     // position of this chunk, starting at 0
+        total: u32,   // number of chunks in the transfer
+        data: String, // base64-encoded chunk bytes
+        #[serde(default)]
+        sender: String, // filled in by the server when relaying
+    },
+    Welcome {
+        your_name: String,  // our name on the server; a guest name until we log in
+        your_color: String, // color the server assigned us, e.g. "lightblue"
+    },
+    Reaction {
+        message_id: String, // chat message reacted to
+        #[serde(default)]
+        user: String, // account that reacted, filled in by the server
+        emoji: String,      // a toggle: the same user and emoji again takes it back
+    },
+    CommandResult {
+        command: String, // command this answers, e.g. "name"
+        ok: bool,        // whether it took effect
+        detail: String,  // the outcome (for "name", our new name) or why it failed
+    },
+    Renamed {
+        account: String, // account whose display name changed
+        from: String,    // the name they had
+        to: String,      // the name they have now
+    },
+}
+
+// One row of the user table
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct UserSummary {
+    pub username: String,
+    pub status: String,   // "online", "away" or "muted"
+    pub online_secs: u64, // how long they have been connected
+    pub messages: usize,  // chat messages sent since connecting
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+    Info,
+    Success,
+    Warn,
+    Error,
+}
+
+impl MessageType {
+    // A system notice with a severity level
+    pub fn notice(level: Severity, text: impl Into<String>) -> MessageType {
+        MessageType::Notice {
+            level,
+            text: text.into(),
+        }
+    }
+}
+
+// How new messages get our attention, picked with TUI_NOTIFY
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Notify {
+    Sound, // play the notification sound for chat messages, mentions and DMs
+    Bell,  // ring the terminal bell on mentions and DMs
+    Flash, // highlight the chat header on mentions and DMs
+    None,
+}
+
+impl Notify {
+    // TUI_NOTIFY=sound|bell|flash|none, sound when unset
+    pub fn from_env() -> Notify {
+        match std::env::var("TUI_NOTIFY") {
+            Ok(value) => Notify::parse(&value).unwrap_or_else(|| {
+                log::warn!("Unknown TUI_NOTIFY '{}', using sound", value);
+                Notify::Sound
+            }),
+            Err(_) => Notify::Sound,
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Notify> {
+        match value.trim().to_lowercase().as_str() {
+            "sound" => Some(Notify::Sound),
+            "bell" => Some(Notify::Bell),
+            "flash" => Some(Notify::Flash),
+            "none" => Some(Notify::None),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum DeliveryStatus {
+    Pending, // sent, waiting for the server's Ack
+    Sent,    // acknowledged by the server
+    Failed,  // no Ack within ACK_TIMEOUT, can be retried
+    Unsent,  // the send itself failed, so the message never left; can be retried
+}
+
+pub struct PendingMessage {
+    pub message: MessageType, // kept so the message can be resent
+    pub sent_at: Instant,
+    pub status: DeliveryStatus,
 }
 
 pub struct App {
     pub username: Option<String>, // Keep track of username
+    pub account: Option<String>,  // Account we authenticated as, used to recognise our messages
     pub staging_username: Option<String>,
     pub password: Option<String>,      // Password field for login
     pub message_input: String,         // the currently being edited message value.
     pub current_screen: CurrentScreen, // the current screen the user is looking at, and will later determine what is rendered.
-    pub messages: Vec<MessageType>,
-    pub scroll_offset: usize,
+    pub messages: Vec<MessageType>,    // transcript of the server we are connected to
+    pub transcripts: HashMap<String, Vec<MessageType>>, // archived transcripts of other servers
+    pub drafts: HashMap<String, String>, // unsent compose text of each server, kept while switching
+    pub unread_counts: HashMap<String, usize>, // chat messages per server not yet looked at
+    pub active_server: Option<String>, // server whose transcript is in `messages`
+    pub scroll_offset: usize,          // lines scrolled up from the bottom of the view
+    // While scrolled up, how many messages the view is pinned to the bottom of; ones arriving
+    // after those wait below without moving it. None when following the newest message.
+    pub scroll_anchor: Option<usize>,
+    pub selected_message: Option<usize>, // highlighted message while in selection mode
+    pub search_query: Option<String>,    // active transcript search, if any
+    pub search_matches: Vec<usize>,      // indices into `messages` matching the search
+    pub search_current: Option<usize>,   // position in `search_matches` being shown
     pub compose_scroll_offset: usize,
     pub failed_login_attempts: u8,       // keep track of failed logins
     pub current_login_field: LoginField, // track current input on login
     pub is_typing: bool,                 // track if user is typing
-    pub servers: HashMap<String, Url>,   // storing servers
+    pub spectating: bool,                // read-only session started with --spectate
+    pub remember_password: bool, // keep the password for reconnects, opted into with --remember-password
+    pub saved_password: Option<String>, // only set when remember_password is on
+    pub disconnect_reason: Option<String>, // why the last connection ended, shown on Disconnected
+    pub pending_connect: Option<PendingConnect>, // connection change for the main loop to make
+    pub redirects: u8,           // redirects followed since we last logged in or picked a server
+    pub servers: HashMap<String, Url>, // storing servers
+    pub default_username: Option<String>, // from the config, filled into the login form
+    pub welcome_step: WelcomeStep, // step of the first-run wizard
+    pub welcome_error: Option<String>, // why the wizard didn't accept the last entry
+    pub set_user_error: Option<String>, // why the last character typed as a new name was refused
     pub selected_server: Option<String>, // Track the selected server
-    pub selected_server_index: usize,
-    sound_sink: Sink,
+    pub away_users: Vec<(String, String)>, // last away list received from the server
+    pub user_table: Vec<UserSummary>, // last user table received from the server
+    pub user_table_scroll: u16,  // rows the user table is scrolled down
+    pub online_count: usize,     // users online on the current server
+    pub roster: Vec<String>,     // names of the users online on the current server
+    pub show_roster: bool,       // user list sidebar toggled with 'u'
+    pub topic: Option<String>,   // channel topic of the current server
+    pub channel: String,         // chat channel we're talking in
+    pub user_color: Option<Color>, // color the server assigned to our messages
+    pub theme: Theme,            // colors the screens are drawn with
+    pub hyperlinks: bool, // make URLs clickable with OSC 8 escapes, opted into with TUI_HYPERLINKS=1
+    pub reactions: HashMap<String, Vec<(String, String)>>, // (user, emoji) per message_id
+    pub reacting: bool,   // 'r' was pressed on a selected message, waiting for the emoji
+    pub deleting_server: Option<String>, // server 'd' was pressed on, waiting for y/n
+    pub dm_buffers: HashMap<String, Vec<MessageType>>, // per-peer direct message conversations
+    pub unread_dms: HashSet<String>, // peers with direct messages not yet viewed
+    pub pending_messages: HashMap<String, PendingMessage>, // our chat messages keyed by client_msg_id
+    pub outbox: VecDeque<MessageType>, // messages that couldn't be sent, waiting for a reconnect
+    pub logged_in: bool,               // authenticated on the current connection
+    pub numbering_unchecked: bool, // reconnected, and the server may have restarted its numbering
+    pub incoming_files: HashMap<String, IncomingFile>, // file transfers in progress, by transfer id
+    pub input_history: VecDeque<String>, // recently sent inputs, newest last
+    pub history_cursor: Option<usize>, // entry of input_history being shown, if browsing
+    pub user_list_page: usize,     // page asked for with the last /list, shown when the reply comes
+    pub help_scroll: u16,          // lines the help menu is scrolled down
+    pub completion_candidates: Vec<&'static str>, // commands being cycled through with Tab
+    pub completion_index: Option<usize>, // candidate currently shown while cycling
+    pub notify: Notify,            // how new messages are announced
+    pub flash_until: Option<Instant>, // chat header highlighted until then, after a mention or DM
+    pub spinner_frame: usize,      // index into SPINNER of the frame being shown
+    sound_sink: Option<Sink>,      // where notification sounds play; None without an audio device
+    _sound_stream: Option<OutputStream>, // the device's stream, which the sink needs kept open
     sound_path: PathBuf,
     last_notification_time: Option<Instant>,
 }
 
 impl App {
     pub fn new() -> App {
+        // Open the audio device once; without one notifications are silent. Tests never touch
+        // the sound system, so they run the same with or without one.
+        let sound_output = if cfg!(test) {
+            None
+        } else {
+            open_sound_output()
+        };
+        App::with_sound_output(sound_output)
+    }
+
+    // An app playing notification sounds on `sound_output`, or silent without one
+    fn with_sound_output(sound_output: Option<(OutputStream, Sink)>) -> App {
+        let (sound_stream, sound_sink) = sound_output.unzip();
         let mut servers = HashMap::new();
         servers.insert(
             "local".to_string(),
@@ -72,10 +596,6 @@ impl App {
             Url::parse("ws://autorack.proxy.rlwy.net:55901").unwrap(),
         );
         let selected_server = Some("default".to_string());
-        let selected_server_index = 1;
-        // Initialize rodio components
-        let (_stream, stream_handle) = OutputStream::try_default().unwrap();
-        let sink = Sink::try_new(&stream_handle).unwrap();
 
         // Assume sound file is stored in `assets/sounds/`
 
@@ -84,90 +604,430 @@ impl App {
 
         App {
             username: None, // Start without a username
+            account: None,
             staging_username: None,
             password: None, // Start without a password
             message_input: String::new(),
             current_screen: CurrentScreen::Main,
             messages: Vec::<MessageType>::new(),
+            transcripts: HashMap::new(),
+            drafts: HashMap::new(),
+            unread_counts: HashMap::new(),
+            active_server: None,
             scroll_offset: 0,
+            scroll_anchor: None,
+            selected_message: None,
+            search_query: None,
+            search_matches: Vec::new(),
+            search_current: None,
             compose_scroll_offset: 0,
             failed_login_attempts: 0,
             current_login_field: LoginField::Username, // Default value
             is_typing: false,
+            spectating: false,
+            remember_password: false,
+            saved_password: None,
+            disconnect_reason: None,
+            pending_connect: None,
+            redirects: 0,
             servers,
+            default_username: None,
+            welcome_step: WelcomeStep::Username,
+            welcome_error: None,
+            set_user_error: None,
             selected_server,
-            selected_server_index,
-            sound_sink: sink,
+            away_users: Vec::new(),
+            user_table: Vec::new(),
+            user_table_scroll: 0,
+            online_count: 0,
+            roster: Vec::new(),
+            show_roster: false,
+            topic: None,
+            channel: DEFAULT_CHANNEL.to_string(),
+            user_color: None,
+            theme: Theme::from_env(),
+            hyperlinks: std::env::var("TUI_HYPERLINKS").is_ok_and(|value| value == "1"),
+            reactions: HashMap::new(),
+            reacting: false,
+            deleting_server: None,
+            dm_buffers: HashMap::new(),
+            unread_dms: HashSet::new(),
+            pending_messages: HashMap::new(),
+            outbox: VecDeque::new(),
+            logged_in: false,
+            numbering_unchecked: false,
+            incoming_files: HashMap::new(),
+            input_history: VecDeque::with_capacity(INPUT_HISTORY_SIZE),
+            history_cursor: None,
+            user_list_page: 1,
+            help_scroll: 0,
+            completion_candidates: Vec::new(),
+            completion_index: None,
+            notify: Notify::from_env(),
+            flash_until: None,
+            spinner_frame: 0,
+            sound_sink,
+            _sound_stream: sound_stream,
             sound_path: assets_path,
             last_notification_time: None,
         }
     }
 
-    // Play sound asynchronously when a new message arrives
+    // Play sound asynchronously when a new message arrives. Does nothing without an audio device.
     pub fn play_notification_sound(&self) {
-        let sound_path = self.sound_path.clone(); // Clone the path for the closure
-
-        // Spawn a new blocking task to play sound
-        tokio::task::spawn_blocking(move || {
-            // Create a new output stream and sink for playing the sound
-            let (_stream, stream_handle) = OutputStream::try_default().unwrap();
-            let sink = Sink::try_new(&stream_handle).unwrap();
-
-            // Open and decode the sound file
-            let file = File::open(sound_path).expect("Failed to open sound file");
-            let reader = BufReader::new(file);
-            let source = Decoder::new(reader).unwrap();
-
-            // Play the sound
-            sink.append(source);
-            sink.play();
-            sink.sleep_until_end(); // Wait until the sound finishes playing
-                                    //println!("Notification sound played.");
-        });
+        let Some(sink) = &self.sound_sink else {
+            return;
+        };
+        // The sink plays what is appended in the background, after anything still playing
+        match decode_sound(&self.sound_path) {
+            Ok(source) => sink.append(source),
+            Err(e) => log::warn!("Could not play notification sound: {}", e),
+        }
+    }
+
+    // Switch the notification sound, playing it so it can be heard straight away. Files that
+    // can't be played are turned down with the reason.
+    pub fn set_sound(&mut self, path: PathBuf) -> Result<(), String> {
+        check_sound(&path)?;
+        self.sound_path = path;
+        self.play_notification_sound();
+        Ok(())
+    }
+
+    // Announce a new message the configured way. Only `notable` ones (mentions and direct
+    // messages) ring the bell or flash; the sound plays for every chat message.
+    fn notify(&mut self, notable: bool) {
+        match self.notify {
+            Notify::Sound => self.play_notification_sound(),
+            Notify::Bell if notable => {
+                let mut stderr = std::io::stderr();
+                let _ = stderr.write_all(b"\x07").and_then(|_| stderr.flush());
+            }
+            Notify::Flash if notable => self.flash_until = Some(Instant::now() + FLASH_DURATION),
+            _ => {}
+        }
+    }
+
+    // Whether the chat header should be highlighted at `now`
+    pub fn flashing(&self, now: Instant) -> bool {
+        self.flash_until.is_some_and(|until| now < until)
+    }
+
+    // End a flash that has run its course. Returns true if it just ended, so the header needs
+    // redrawing.
+    pub fn expire_flash(&mut self, now: Instant) -> bool {
+        if self.flash_until.is_some() && !self.flashing(now) {
+            self.flash_until = None;
+            return true;
+        }
+        false
+    }
+
+    // Whether we're waiting on the server with nothing else to show: connecting, or logging in
+    // once the form is submitted, until the welcome arrives
+    pub fn loading(&self) -> bool {
+        match self.current_screen {
+            CurrentScreen::Connecting => true,
+            CurrentScreen::LoggingIn => !self.is_typing,
+            _ => false,
+        }
+    }
+
+    // Move the spinner on a frame, going round again after the last
+    pub fn advance_spinner(&mut self) {
+        self.spinner_frame = (self.spinner_frame + 1) % SPINNER.len();
+    }
+
+    pub fn spinner(&self) -> &'static str {
+        SPINNER[self.spinner_frame % SPINNER.len()]
+    }
+
+    // Use the saved settings: the servers join the list, the first of them selected, and the
+    // username waits for the login form
+    pub fn apply_config(&mut self, config: &ClientConfig) {
+        for entry in config.servers.iter().rev() {
+            match Url::parse(&entry.url) {
+                Ok(url) => {
+                    self.servers.insert(entry.name.clone(), url);
+                    self.selected_server = Some(entry.name.clone());
+                }
+                Err(e) => log::warn!("Skipping server {} from the config: {}", entry.name, e),
+            }
+        }
+        for name in &config.removed_servers {
+            self.servers.remove(name);
+        }
+        if !self.can_connect_to_selected() {
+            self.selected_server = self.servers.keys().next().cloned();
+        }
+        self.default_username = config.default_username.clone();
+        if let Some(sound) = &config.sound {
+            self.sound_path = sound.clone();
+        }
+    }
+
+    // Move the server list's highlight for Up, Down or Tab (which wraps around). A selection
+    // that's no longer in the list starts over at the top, and an empty list has nothing to move.
+    pub fn move_server_selection(&mut self, key: KeyCode) {
+        let names: Vec<&String> = self.servers.keys().collect();
+        if names.is_empty() {
+            return;
+        }
+        let current = self
+            .selected_server
+            .as_ref()
+            .and_then(|selected| names.iter().position(|name| *name == selected));
+        let next = match (current, key) {
+            (None, _) => 0,
+            (Some(i), KeyCode::Up) => i.saturating_sub(1),
+            (Some(i), KeyCode::Down) => (i + 1).min(names.len() - 1),
+            (Some(i), KeyCode::Tab) => (i + 1) % names.len(),
+            (Some(i), _) => i,
+        };
+        self.selected_server = Some(names[next].clone());
+    }
+
+    // Take a server off the list, selecting the one that took its place (or the new last one)
+    pub fn delete_server(&mut self, name: &str) {
+        let Some(index) = self.servers.keys().position(|n| n == name) else {
+            return;
+        };
+        self.servers.remove(name);
+        if self.selected_server.as_deref() == Some(name) || !self.can_connect_to_selected() {
+            let index = index.min(self.servers.len().saturating_sub(1));
+            self.selected_server = self.servers.keys().nth(index).cloned();
+        }
+    }
+
+    // Whether Enter on the server list has a server to connect to
+    pub fn can_connect_to_selected(&self) -> bool {
+        self.selected_server
+            .as_ref()
+            .is_some_and(|name| self.servers.contains_key(name))
+    }
+
+    // Start the first-run wizard
+    pub fn start_welcome(&mut self) {
+        self.current_screen = CurrentScreen::Welcome;
+        self.welcome_step = WelcomeStep::Username;
+        self.welcome_error = None;
+        self.message_input.clear();
+    }
+
+    // Take what was typed for the current wizard step. Returns the config to save once the last
+    // step is done, having already applied it and moved on to the server list.
+    pub fn submit_welcome_step(&mut self) -> Option<ClientConfig> {
+        let input = self.message_input.trim().to_string();
+        match self.welcome_step {
+            WelcomeStep::Username => {
+                if input.is_empty() {
+                    self.default_username = None;
+                } else {
+                    match validate_username(&input) {
+                        Ok(name) => self.default_username = Some(name),
+                        Err(reason) => {
+                            self.welcome_error = Some(reason);
+                            return None;
+                        }
+                    }
+                }
+                self.welcome_step = WelcomeStep::Server;
+            }
+            WelcomeStep::Server => {
+                let (name, url) = match server_entry_from_arg(&input) {
+                    Ok(entry) => entry,
+                    Err(reason) => {
+                        self.welcome_error = Some(reason);
+                        return None;
+                    }
+                };
+                let config = ClientConfig {
+                    default_username: self.default_username.clone(),
+                    servers: vec![ServerEntry {
+                        name,
+                        url: url.to_string(),
+                    }],
+                    ..Default::default()
+                };
+                self.apply_config(&config);
+                self.current_screen = CurrentScreen::ServerSelection;
+                self.message_input.clear();
+                self.welcome_error = None;
+                return Some(config);
+            }
+        }
+        self.welcome_error = None;
+        self.message_input.clear();
+        None
+    }
+
+    // Count a chat message as unread if it arrived while picking a server, so the server list
+    // can show activity on the server we're still connected to
+    fn count_unread(&mut self) {
+        let away = matches!(
+            self.current_screen,
+            CurrentScreen::ServerSelection | CurrentScreen::AddServer
+        );
+        if let (true, Some(server)) = (away, &self.active_server) {
+            *self.unread_counts.entry(server.clone()).or_default() += 1;
+        }
+    }
+
+    // Back on the current server's transcript, so its messages count as read
+    pub fn mark_active_server_read(&mut self) {
+        if let Some(server) = &self.active_server {
+            self.unread_counts.remove(server);
+        }
+    }
+
+    // The screen change a key causes, worked out from the app state alone so it can be tested
+    // without a connection. Keys that edit, send or connect are left to the screen's handler.
+    pub fn on_key(&self, key: KeyCode) -> Transition {
+        use CurrentScreen::*;
+        use Transition::{Go, Quit, Stay};
+        match (&self.current_screen, key) {
+            // The key after 'r' picks a reaction
+            (Main, _) if self.reacting => Stay,
+            (Main, KeyCode::Enter) if !self.spectating => Go(ComposingMessage),
+            (Main, KeyCode::Char('/')) => Go(Search),
+            (Main, KeyCode::Char('h')) => Go(HelpMenu),
+            (Main, KeyCode::Char('q')) => Go(Exiting),
+            // While a search is active 'n' steps through its matches instead
+            (Main, KeyCode::Char('n')) if self.search_query.is_none() && !self.spectating => {
+                Go(SetUser)
+            }
+            (Main, KeyCode::Char('s')) => Go(ServerSelection),
+            (ComposingMessage, KeyCode::Esc) => Go(Main),
+            (SetUser, KeyCode::Enter | KeyCode::Esc) => Go(Main),
+            // Any key but the arrows, which scroll the help and user table, closes those popups
+            (HelpMenu | UserList, KeyCode::Up | KeyCode::Down) => Stay,
+            (HelpMenu | AwayList | UserList, _) => Go(Main),
+            (Search, KeyCode::Enter | KeyCode::Esc) => Go(Main),
+            (DirectChat(_), KeyCode::Esc) => Go(Main),
+            (Exiting | ExitingLoggingIn, KeyCode::Char('y')) => Quit,
+            (Exiting, KeyCode::Char('n' | 'q')) => Go(Main),
+            (ExitingLoggingIn, KeyCode::Char('n' | 'q') | KeyCode::Esc) => Go(LoggingIn),
+            (ExitingLoggingIn, KeyCode::Char('s')) => Go(ServerSelection),
+            (LoggingIn, KeyCode::Esc) => Go(ExitingLoggingIn),
+            (ServerSelection, KeyCode::Char('n')) => Go(AddServer),
+            (AddServer, KeyCode::Esc) => Go(ServerSelection),
+            (Disconnected, KeyCode::Char('q')) => Quit,
+            (Connecting, KeyCode::Esc) => Go(ServerSelection),
+            // Setup can be skipped, in which case it is offered again next time
+            (Welcome, KeyCode::Esc) => Go(ServerSelection),
+            _ => Stay,
+        }
+    }
+
+    // Whether someone else's chat message mentions our name
+    pub fn mentions_me(&self, message: &MessageType) -> bool {
+        match (message, &self.username) {
+            (
+                MessageType::ChatMessage {
+                    content, sender_id, ..
+                },
+                Some(name),
+            ) => {
+                Some(sender_id.as_str()) != self.account.as_deref()
+                    && !find_mentions(content, name).is_empty()
+            }
+            _ => false,
+        }
     }
 
     // Handling incoming WebSocket messages from the server
     pub fn handle_websocket_message(&mut self, message: &str) {
-        if let Ok(message_type) = serde_json::from_str::<MessageType>(&message) {
+        if let Ok(message_type) = serde_json::from_str::<MessageType>(message) {
             match message_type {
-                MessageType::ChatMessage { sender, content } => {
-                    // Push the chat message into `self.messages`
-                    self.messages
-                        .push(MessageType::ChatMessage { sender, content });
-                    // Only play sound if there hasn't been a notification within the last 1 seconds
-                    if self
-                        .last_notification_time
-                        .map(|t| t.elapsed().as_secs() > 1)
-                        .unwrap_or(true)
+                chat @ MessageType::ChatMessage { .. } => {
+                    // Keep chat in the server's order, skipping messages we already have, such as
+                    // the history replayed after a reconnect
+                    if let MessageType::ChatMessage {
+                        message_id: Some(id),
+                        ..
+                    } = &chat
+                    {
+                        if self.message_by_id(id).is_some() {
+                            return;
+                        }
+                    }
+                    let slot = match &chat {
+                        MessageType::ChatMessage { seq: Some(seq), .. } => {
+                            // The first new message after a reconnect numbered no higher than
+                            // ours means the server restarted and is numbering afresh
+                            if std::mem::take(&mut self.numbering_unchecked)
+                                && self.newest_seq().is_some_and(|newest| *seq <= newest)
+                            {
+                                self.forget_seqs();
+                            }
+                            match self.chat_slot(*seq) {
+                                Some(slot) => slot,
+                                None => return,
+                            }
+                        }
+                        _ => self.messages.len(),
+                    };
+                    let mentioned = self.mentions_me(&chat);
+                    self.messages.insert(slot, chat);
+                    // A late message landing among the ones pinned in view stays with them
+                    if let Some(anchor) = &mut self.scroll_anchor {
+                        if slot < *anchor {
+                            *anchor += 1;
+                        }
+                    }
+                    self.count_unread();
+                    // Only play sound if there hasn't been a notification within the last 1 seconds,
+                    // unless we were mentioned: those should never go unnoticed
+                    if mentioned
+                        || self
+                            .last_notification_time
+                            .map(|t| t.elapsed().as_secs() > 1)
+                            .unwrap_or(true)
                     {
-                        self.play_notification_sound(); // Play sound on new chat message
+                        self.notify(mentioned); // Play sound on new chat message
                         self.last_notification_time = Some(Instant::now()); // Update time of last notification
                     }
                 }
                 MessageType::SystemMessage(system_message) => {
-                    if system_message.contains("Authentication successful") {
+                    // Only the replies to our own login count, not chat-borne text that merely
+                    // looks like them once we're in
+                    let logging_in = !self.logged_in && !self.spectating;
+                    if logging_in && system_message.starts_with("Authentication successful") {
                         // Push authentication success message
-                        self.messages.push(MessageType::SystemMessage(
-                            "You are authenticated!".to_string(),
+                        self.messages.push(MessageType::notice(
+                            Severity::Success,
+                            "You are authenticated!",
                         ));
                         self.current_screen = CurrentScreen::Main;
+                        self.restore_draft();
                         self.failed_login_attempts = 0; // Reset failed attempts on success
+                        self.redirects = 0;
+                        self.logged_in = true;
                         self.username = self.staging_username.clone();
-                    } else if system_message.contains("Authentication failed") {
+                        self.account = self.staging_username.clone();
+                    } else if logging_in && system_message.starts_with("Authentication failed") {
+                        // A remembered password that was turned down is no use for next time
+                        self.saved_password = None;
                         self.failed_login_attempts += 1; // Increment failed attempts
                         let remaining_attempts = 5 - self.failed_login_attempts;
                         // Push authentication failure message
-                        self.messages.push(MessageType::SystemMessage(format!(
-                            "Authentication failed. {} attempts remaining.",
-                            remaining_attempts
-                        )));
+                        self.messages.push(MessageType::notice(
+                            Severity::Error,
+                            format!(
+                                "Authentication failed. {} attempts remaining. (Esc, then s to switch servers)",
+                                remaining_attempts
+                            ),
+                        ));
                         if self.failed_login_attempts >= 5 {
-                            self.current_screen = CurrentScreen::Disconnected; // Disconnect after max attempts
-                            self.messages.push(MessageType::SystemMessage(
-                                "Max login attempts reached. Connection closed.".to_string(),
+                            // Disconnect after max attempts
+                            self.set_disconnected("Max login attempts reached.".to_string());
+                            self.messages.push(MessageType::notice(
+                                Severity::Error,
+                                "Max login attempts reached. Connection closed.",
                             ));
                         } else {
-                            self.current_screen = CurrentScreen::LoggingIn; // Retry login
+                            self.reset_login_form(); // Retry login with a clean form
+                            self.current_screen = CurrentScreen::LoggingIn;
                         }
                     } else {
                         // Push any other system message received
@@ -175,6 +1035,95 @@ impl App {
                             .push(MessageType::SystemMessage(system_message));
                     }
                 }
+                MessageType::PrivateMessage {
+                    sender,
+                    recipient,
+                    content,
+                } => {
+                    let from_me = Some(&sender) == self.username.as_ref();
+                    self.route_private_message(MessageType::PrivateMessage {
+                        sender,
+                        recipient,
+                        content,
+                    });
+                    if !from_me {
+                        self.notify(true);
+                    }
+                }
+                MessageType::Ack {
+                    id,
+                    message_id,
+                    seq,
+                } => {
+                    self.acknowledge(&id, message_id, seq);
+                }
+                MessageType::Reaction {
+                    message_id,
+                    user,
+                    emoji,
+                } => self.apply_reaction(message_id, user, emoji),
+                MessageType::UserCount(count) => {
+                    self.online_count = count;
+                }
+                MessageType::Roster(names) => {
+                    self.roster = names;
+                }
+                MessageType::UserList(names) => self.show_user_list(names),
+                MessageType::CommandResult {
+                    command,
+                    ok,
+                    detail,
+                } => self.apply_command_result(&command, ok, detail),
+                MessageType::Renamed { account, from, to } => self.apply_rename(&account, from, to),
+                MessageType::History(history) => self.replace_history(history),
+                MessageType::Topic(topic) => {
+                    self.topic = Some(topic);
+                }
+                MessageType::Redirect(url) => self.follow_redirect(&url),
+                MessageType::Join(channel) => {
+                    // The server replays the channel's history right after this, reactions
+                    // included, and then its topic if it has one
+                    self.reactions.clear();
+                    self.topic = None;
+                    self.messages
+                        .push(MessageType::SystemMessage(format!("Now in #{}", channel)));
+                    self.channel = channel;
+                }
+                // The server welcomes us on connect and again after login. While the login form
+                // is up its username field is ours, so the guest name is only taken by spectators.
+                MessageType::Welcome { .. } if !self.logged_in && !self.spectating => {}
+                MessageType::Welcome {
+                    your_name,
+                    your_color,
+                } => {
+                    self.username = Some(your_name.clone());
+                    self.account = Some(your_name);
+                    self.user_color = your_color.parse().ok();
+                }
+                notice @ MessageType::Notice { .. } => {
+                    self.messages.push(notice);
+                }
+                MessageType::FileChunk {
+                    id,
+                    name,
+                    seq,
+                    total,
+                    data,
+                    sender,
+                } => {
+                    self.receive_file_chunk(id, name, seq, total, &data, sender);
+                }
+                MessageType::AwayList(away_users) => {
+                    // Show the away list in its own popup
+                    self.away_users = away_users;
+                    self.current_screen = CurrentScreen::AwayList;
+                }
+                MessageType::UserDetails(users) => {
+                    // Likewise the user table, starting from the top
+                    self.user_table = users;
+                    self.user_table_scroll = 0;
+                    self.current_screen = CurrentScreen::UserList;
+                }
                 _ => {}
             }
         } else {
@@ -182,47 +1131,2464 @@ impl App {
             self.messages
                 .push(MessageType::SystemMessage(message.to_string()));
         }
+    }
+    // Add a chunk to its transfer, saving the file and reporting it once all chunks are in
+    pub fn receive_file_chunk(
+        &mut self,
+        id: String,
+        name: String,
+        seq: u32,
+        total: u32,
+        data: &str,
+        sender: String,
+    ) {
+        if total == 0 || total > file_transfer::MAX_CHUNKS {
+            return;
+        }
+        let incoming = self
+            .incoming_files
+            .entry(id.clone())
+            .or_insert_with(|| IncomingFile::new(name, sender, total));
+        if let Err(e) = incoming.add_chunk(seq, data) {
+            self.incoming_files.remove(&id);
+            self.messages.push(MessageType::notice(Severity::Error, e));
+            return;
+        }
+        if !incoming.is_complete() {
+            return;
+        }
 
-        self.scroll_offset = 0;
+        let incoming = self.incoming_files.remove(&id).unwrap();
+        let bytes = incoming.assemble().unwrap_or_default();
+        let notice = match file_transfer::save_file(
+            &file_transfer::download_dir(),
+            &incoming.name,
+            &bytes,
+        ) {
+            Ok(path) => MessageType::notice(
+                Severity::Success,
+                format!(
+                    "Received file {} from {}, saved to {}",
+                    incoming.name,
+                    incoming.sender,
+                    path.display()
+                ),
+            ),
+            Err(e) => MessageType::notice(
+                Severity::Error,
+                format!("Could not save file {}: {}", incoming.name, e),
+            ),
+        };
+        self.messages.push(notice);
     }
-    // Methods for scrolling up and down in main chat
-    pub fn scroll_up(&mut self) {
-        self.scroll_offset = self.scroll_offset.saturating_add(1);
+
+    // Remember one of our chat messages until the server acknowledges it
+    pub fn track_pending(&mut self, id: String, message: MessageType) {
+        self.pending_messages.insert(
+            id,
+            PendingMessage {
+                message,
+                sent_at: Instant::now(),
+                status: DeliveryStatus::Pending,
+            },
+        );
     }
 
-    pub fn scroll_down(&mut self) {
-        self.scroll_offset = self.scroll_offset.saturating_sub(1);
+    // Stop tracking a message once its Ack arrives, taking on the id the server gave it
+    pub fn acknowledge(&mut self, id: &str, server_id: Option<String>, server_seq: Option<u64>) {
+        self.pending_messages.remove(id);
+        let own = self
+            .messages
+            .iter_mut()
+            .rev()
+            .find_map(|message| match message {
+                MessageType::ChatMessage {
+                    client_msg_id: Some(client_msg_id),
+                    message_id,
+                    seq,
+                    ..
+                } if client_msg_id == id => Some((message_id, seq)),
+                _ => None,
+            });
+        if let Some((message_id, seq)) = own {
+            *message_id = server_id;
+            *seq = server_seq;
+        }
     }
 
-    // Methods for scrolling up and down in compose area
-    pub fn compose_scroll_up(&mut self) {
-        self.compose_scroll_offset = self.scroll_offset.saturating_add(1);
+    // Highest number the server gave any chat message we hold
+    fn newest_seq(&self) -> Option<u64> {
+        self.messages
+            .iter()
+            .filter_map(|message| match message {
+                MessageType::ChatMessage { seq, .. } => *seq,
+                _ => None,
+            })
+            .max()
     }
 
-    pub fn compose_scroll_down(&mut self) {
-        self.compose_scroll_offset = self.scroll_offset.saturating_sub(1);
+    // Drop the numbers of the chat we hold once the server starts numbering again, so new
+    // messages follow it rather than being taken for ones we already have. Replies among them
+    // lose their quote, as the number they name now belongs to a different message.
+    fn forget_seqs(&mut self) {
+        for message in &mut self.messages {
+            if let MessageType::ChatMessage { seq, reply_to, .. } = message {
+                *seq = None;
+                *reply_to = None;
+            }
+        }
     }
 
-    // Method for setting username
-    pub fn set_username(&mut self, name: String) {
-        self.username = Some(name);
+    // Where a chat message the server numbered `seq` goes in the transcript: before the first
+    // message numbered after it, or None if we already have it
+    fn chat_slot(&self, seq: u64) -> Option<usize> {
+        let mut slot = self.messages.len();
+        for (index, message) in self.messages.iter().enumerate().rev() {
+            match message {
+                MessageType::ChatMessage {
+                    seq: Some(other), ..
+                } if *other == seq => return None,
+                MessageType::ChatMessage {
+                    seq: Some(other), ..
+                } if *other > seq => slot = index,
+                // Everything before an earlier message is earlier still
+                MessageType::ChatMessage { seq: Some(_), .. } => break,
+                _ => {}
+            }
+        }
+        Some(slot)
     }
-    pub fn parse_command(&self, input: &str) -> Command {
-        let input = input.trim();
 
-        if input.starts_with("/") {
-            let parts: Vec<&str> = input.splitn(3, ' ').collect();
-            match parts.as_slice() {
-                ["/name", name] if !name.is_empty() => Command::SetName(name.to_string()),
-                ["/list"] => Command::ListUsers,
-                ["/dm", recipient, message] if !message.is_empty() => {
-                    Command::DirectMessage(recipient.to_string(), message.to_string())
+    // Put the page of the user list asked for into the transcript, under a header that says
+    // how to see the rest
+    pub fn show_user_list(&mut self, mut names: Vec<String>) {
+        let total = names.len();
+        let pages = total.div_ceil(USER_LIST_PAGE_SIZE).max(1);
+        let page = self.user_list_page.clamp(1, pages);
+        let header = if pages > 1 {
+            format!(
+                "Connected users ({}), page {}/{}; /names <page> for the others:",
+                total, page, pages
+            )
+        } else {
+            format!("Connected users ({}):", total)
+        };
+        names.drain(..(page - 1) * USER_LIST_PAGE_SIZE);
+        names.truncate(USER_LIST_PAGE_SIZE);
+        self.messages.push(MessageType::SystemMessage(header));
+        self.messages.push(MessageType::UserList(names));
+    }
+
+    // Act on the server's answer to a command. Our name only changes once the server accepts it.
+    pub fn apply_command_result(&mut self, command: &str, ok: bool, detail: String) {
+        match (command, ok) {
+            ("name", true) => {
+                self.messages.push(MessageType::SystemMessage(format!(
+                    "Your name is now set to '{}'",
+                    detail
+                )));
+                self.set_username(detail);
+            }
+            (_, true) => self.messages.push(MessageType::SystemMessage(detail)),
+            (_, false) => self
+                .messages
+                .push(MessageType::notice(Severity::Warn, detail)),
+        }
+    }
+
+    // Someone changed their name: say so, and show their earlier messages and direct chat under
+    // the new name. A rename of our own account, from another session, renames us here too.
+    pub fn apply_rename(&mut self, account: &str, from: String, to: String) {
+        if Some(account) == self.account.as_deref() {
+            self.messages.push(MessageType::SystemMessage(format!(
+                "You are now known as '{}'",
+                to
+            )));
+            self.set_username(to.clone());
+        } else {
+            self.messages.push(MessageType::SystemMessage(format!(
+                "{} is now known as {}",
+                from, to
+            )));
+        }
+        for message in &mut self.messages {
+            if let MessageType::ChatMessage {
+                sender, sender_id, ..
+            } = message
+            {
+                if sender_id == account {
+                    *sender = to.clone();
                 }
-                ["/help"] => Command::Help,
-                _ => Command::Unknown(input.to_string()),
             }
+        }
+
+        // Direct messages are filed by name, so the conversation moves to the new one
+        if let Some(mut buffer) = self.dm_buffers.remove(&from) {
+            for message in &mut buffer {
+                if let MessageType::PrivateMessage {
+                    sender, recipient, ..
+                } = message
+                {
+                    for name in [sender, recipient] {
+                        if *name == from {
+                            *name = to.clone();
+                        }
+                    }
+                }
+            }
+            self.dm_buffers
+                .entry(to.clone())
+                .or_default()
+                .splice(0..0, buffer);
+        }
+        if self.unread_dms.remove(&from) {
+            self.unread_dms.insert(to.clone());
+        }
+        if self.current_screen == CurrentScreen::DirectChat(from) {
+            self.current_screen = CurrentScreen::DirectChat(to);
+        }
+    }
+
+    // Add or take back a reaction, as told by the server
+    pub fn apply_reaction(&mut self, message_id: String, user: String, emoji: String) {
+        let reactions = self.reactions.entry(message_id.clone()).or_default();
+        match reactions
+            .iter()
+            .position(|reaction| reaction.0 == user && reaction.1 == emoji)
+        {
+            Some(index) => {
+                reactions.remove(index);
+            }
+            None => reactions.push((user, emoji)),
+        }
+        if reactions.is_empty() {
+            self.reactions.remove(&message_id);
+        }
+    }
+
+    // The chat message the server stored under `id`, if it's still in the transcript
+    pub fn message_by_id(&self, id: &str) -> Option<&MessageType> {
+        self.messages.iter().rev().find(|message| {
+            matches!(message, MessageType::ChatMessage { message_id: Some(message_id), .. } if message_id == id)
+        })
+    }
+
+    // The chat message the server numbered `seq`, if it's still in the transcript
+    pub fn message_by_seq(&self, seq: u64) -> Option<&MessageType> {
+        self.messages.iter().rev().find(|message| {
+            matches!(message, MessageType::ChatMessage { seq: Some(other), .. } if *other == seq)
+        })
+    }
+
+    // Number of the one message whose id is or starts with `id`, which is how a reply names
+    // the message it answers
+    pub fn reply_target(&self, id: &str) -> Option<u64> {
+        match self.message_by_id(&self.resolve_message_id(id)?)? {
+            MessageType::ChatMessage { seq, .. } => *seq,
+            _ => None,
+        }
+    }
+
+    // Full id of the one message whose id is or starts with `id`, so short ids can be typed
+    pub fn resolve_message_id(&self, id: &str) -> Option<String> {
+        let mut matches = self.messages.iter().filter_map(|message| match message {
+            MessageType::ChatMessage {
+                message_id: Some(message_id),
+                ..
+            } if message_id.starts_with(id) => Some(message_id),
+            _ => None,
+        });
+        let found = matches.next()?;
+        match matches.next() {
+            Some(_) => None, // ambiguous
+            None => Some(found.clone()),
+        }
+    }
+
+    // Server id of the highlighted message, if it's a chat message that can be reacted to
+    pub fn selected_message_id(&self) -> Option<String> {
+        match self.messages.get(self.selected_message?)? {
+            MessageType::ChatMessage { message_id, .. } => message_id.clone(),
+            _ => None,
+        }
+    }
+
+    // Wait for the emoji to react to the highlighted message with
+    pub fn start_reaction(&mut self) {
+        if self.selected_message_id().is_some() {
+            self.reacting = true;
         } else {
-            Command::Unknown(input.to_string()) // Treat as unknown if it's not a valid command
+            self.messages.push(MessageType::notice(
+                Severity::Warn,
+                "Only chat messages from the server can be reacted to.",
+            ));
+        }
+    }
+
+    // Mark messages still waiting past the timeout as failed. Returns true if any changed.
+    pub fn expire_pending(&mut self, now: Instant) -> bool {
+        let mut changed = false;
+        for pending in self.pending_messages.values_mut() {
+            if pending.status == DeliveryStatus::Pending
+                && now.duration_since(pending.sent_at) >= ACK_TIMEOUT
+            {
+                pending.status = DeliveryStatus::Failed;
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    // Flag a message whose send failed outright, so it isn't shown as on its way
+    pub fn mark_unsent(&mut self, id: &str) {
+        if let Some(pending) = self.pending_messages.get_mut(id) {
+            pending.status = DeliveryStatus::Unsent;
+        }
+    }
+
+    // A chat message the socket refused: flag it as unsent rather than on its way, queue it for
+    // after a reconnect, and report the connection as dead
+    pub fn send_failed(&mut self, id: &str, message: MessageType, error: String) {
+        self.mark_unsent(id);
+        self.queue_outgoing(message);
+        self.set_disconnected(format!("Failed to send message: {}", error));
+    }
+
+    // Take the failed and unsent messages for resending, putting them back into the pending state
+    pub fn take_failed_for_retry(&mut self) -> Vec<MessageType> {
+        let mut retries = Vec::new();
+        for pending in self.pending_messages.values_mut() {
+            if matches!(
+                pending.status,
+                DeliveryStatus::Failed | DeliveryStatus::Unsent
+            ) {
+                pending.status = DeliveryStatus::Pending;
+                pending.sent_at = Instant::now();
+                retries.push(pending.message.clone());
+            }
+        }
+        retries
+    }
+
+    // Keep a message the connection dropped, to send once we're logged back in. A full outbox
+    // makes room by giving up on its oldest message.
+    pub fn queue_outgoing(&mut self, message: MessageType) {
+        if self.outbox.len() >= OUTBOX_SIZE {
+            self.outbox.pop_front();
+            self.messages.push(MessageType::notice(
+                Severity::Warn,
+                format!(
+                    "Too many messages waiting to be sent; dropped the oldest (at most {} are kept).",
+                    OUTBOX_SIZE
+                ),
+            ));
+        }
+        self.outbox.push_back(message);
+    }
+
+    // The oldest queued message, once there is a logged-in connection to send it on
+    pub fn queued_message(&self) -> Option<&MessageType> {
+        self.outbox.front().filter(|_| self.logged_in)
+    }
+
+    // Drop the message `queued_message` gave out now that it has been sent, waiting for its Ack
+    // again if it is one of our chat messages
+    pub fn dequeue_sent(&mut self) {
+        if let Some(MessageType::ChatMessage {
+            client_msg_id: Some(id),
+            ..
+        }) = self.outbox.front()
+        {
+            if let Some(pending) = self.pending_messages.get_mut(id) {
+                pending.status = DeliveryStatus::Pending;
+                pending.sent_at = Instant::now();
+            }
+        }
+        self.outbox.pop_front();
+    }
+
+    // How far one of our chat messages got. Acknowledged messages are no longer tracked, so
+    // they count as sent.
+    pub fn delivery_status(&self, id: &str) -> DeliveryStatus {
+        self.pending_messages
+            .get(id)
+            .map_or(DeliveryStatus::Sent, |pending| pending.status)
+    }
+
+    // Move to the Disconnected screen, recording why the connection ended
+    pub fn set_disconnected(&mut self, reason: String) {
+        self.logged_in = false;
+        self.disconnect_reason = Some(reason);
+        self.current_screen = CurrentScreen::Disconnected;
+    }
+
+    // Link to the server we're connected to, for others to join it with
+    pub fn invite_link(&self) -> Option<String> {
+        let url = self.servers.get(self.active_server.as_ref()?)?;
+        Some(invite_link(url))
+    }
+
+    // Move to the server a Redirect points at, adding it to the server list. The URL comes from
+    // the server, so anything that isn't a valid ws:// or wss:// URL is ignored, as is a redirect
+    // past MAX_REDIRECTS in a row.
+    pub fn follow_redirect(&mut self, url: &str) {
+        if self.redirects >= MAX_REDIRECTS {
+            self.messages.push(MessageType::notice(
+                Severity::Error,
+                format!("Ignoring redirect after {} in a row", MAX_REDIRECTS),
+            ));
+            return;
+        }
+        match server_entry_from_arg(url) {
+            Ok((name, url)) => {
+                self.redirects += 1;
+                self.messages.push(MessageType::notice(
+                    Severity::Warn,
+                    format!("Redirected to {}", name),
+                ));
+                self.servers.insert(name.clone(), url);
+                self.selected_server = Some(name);
+                self.pending_connect = Some(PendingConnect::SwitchServer);
+            }
+            Err(e) => self.messages.push(MessageType::notice(
+                Severity::Error,
+                format!("Ignoring redirect: {}", e),
+            )),
+        }
+    }
+
+    // Get ready to log back in after reconnecting. With a remembered password this returns the
+    // Auth message to send; otherwise the login form is prefilled with the cached username.
+    pub fn begin_reauth(&mut self) -> Option<MessageType> {
+        let account = self.account.clone();
+        self.reset_login_form();
+        self.logged_in = false; // Until the server accepts us on the new connection
+        self.numbering_unchecked = true;
+        self.failed_login_attempts = 0;
+        self.current_screen = CurrentScreen::LoggingIn;
+        match (account, self.saved_password.clone()) {
+            (Some(username), Some(password)) => {
+                self.staging_username = Some(username.clone());
+                self.is_typing = false;
+                self.messages.push(MessageType::SystemMessage(format!(
+                    "Signing in again as {}...",
+                    username
+                )));
+                Some(MessageType::Auth {
+                    username,
+                    password,
+                    token: None,
+                })
+            }
+            (Some(username), None) => {
+                self.username = Some(username);
+                self.current_login_field = LoginField::Password;
+                self.messages.push(MessageType::SystemMessage(
+                    "Enter your password:".to_string(),
+                ));
+                None
+            }
+            (None, _) => None,
+        }
+    }
+
+    // Keep what was typed into the focused login field
+    fn store_login_field(&mut self) {
+        let value = std::mem::take(&mut self.message_input);
+        match self.current_login_field {
+            LoginField::Username => self.username = Some(value),
+            LoginField::Password => self.password = Some(value),
+        }
+    }
+
+    // Focus a login field, bringing its value into the input for editing
+    fn load_login_field(&mut self, field: LoginField) {
+        self.message_input = match field {
+            LoginField::Username => self.username.take(),
+            LoginField::Password => self.password.take(),
+        }
+        .unwrap_or_default();
+        self.current_login_field = field;
+    }
+
+    // Tab or Shift+Tab between the login fields, keeping what was typed in each. Returns
+    // whether the key moved focus.
+    pub fn switch_login_field(&mut self, key: KeyCode) -> bool {
+        match self.current_login_field.after_key(key) {
+            Some(field) => {
+                self.store_login_field();
+                self.load_login_field(field);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Submit the whole login form. Returns the Auth message to send, or moves focus to the
+    // first empty field if the form isn't complete.
+    pub fn submit_login(&mut self) -> Option<MessageType> {
+        self.store_login_field();
+        let username = self.username.clone().unwrap_or_default();
+        let password = self.password.take().unwrap_or_default();
+        if username.is_empty() {
+            self.password = Some(password);
+            self.load_login_field(LoginField::Username);
+            self.messages.push(MessageType::SystemMessage(
+                "Enter your username:".to_string(),
+            ));
+            return None;
+        }
+        if password.is_empty() {
+            self.load_login_field(LoginField::Password);
+            self.messages.push(MessageType::SystemMessage(
+                "Enter your password:".to_string(),
+            ));
+            return None;
+        }
+        self.staging_username = Some(username.clone());
+        if self.remember_password {
+            self.saved_password = Some(password.clone());
+        }
+        // Wait for the server's answer; the password isn't kept on screen
+        self.current_login_field = LoginField::Password;
+        self.is_typing = false;
+        Some(MessageType::Auth {
+            username,
+            password,
+            token: None,
+        })
+    }
+
+    // Clear both login fields and return focus to the username input
+    pub fn reset_login_form(&mut self) {
+        self.username = None;
+        self.staging_username = None;
+        self.password = None;
+        self.message_input.clear();
+        self.current_login_field = LoginField::Username;
+        self.is_typing = true;
+    }
+
+    // File a direct message into the buffer for the other participant, flagging it unread
+    // unless that conversation is currently open
+    pub fn route_private_message(&mut self, message: MessageType) {
+        if let MessageType::PrivateMessage {
+            sender, recipient, ..
+        } = &message
+        {
+            let peer = if Some(sender) == self.username.as_ref() {
+                recipient.clone()
+            } else {
+                sender.clone()
+            };
+            if self.current_screen != CurrentScreen::DirectChat(peer.clone()) {
+                self.unread_dms.insert(peer.clone());
+            }
+            self.dm_buffers.entry(peer).or_default().push(message);
+        }
+    }
+
+    // Switch to the 1:1 view with a user, marking their conversation as read
+    pub fn open_direct_chat(&mut self, peer: String) {
+        self.unread_dms.remove(&peer);
+        self.dm_buffers.entry(peer.clone()).or_default();
+        self.message_input.clear();
+        self.current_screen = CurrentScreen::DirectChat(peer);
+    }
+
+    // Esc on the main screen backs out one step: out of selection and search first, then it
+    // discards the draft kept when composing was left with Esc
+    pub fn escape_main(&mut self) {
+        if self.selected_message.is_some() || self.search_query.is_some() {
+            self.selected_message = None;
+            self.clear_search();
+        } else if !self.message_input.is_empty() {
+            self.message_input.clear();
+            self.messages
+                .push(MessageType::notice(Severity::Info, "Draft discarded."));
+        }
+    }
+
+    // Put a half-typed message aside under the current server, so leaving it doesn't lose it
+    pub fn stash_draft(&mut self) {
+        if let Some(server) = &self.active_server {
+            if !self.message_input.is_empty() {
+                self.drafts
+                    .insert(server.clone(), std::mem::take(&mut self.message_input));
+            }
+        }
+    }
+
+    // Bring back the message that was being typed when we last left the current server
+    pub fn restore_draft(&mut self) {
+        if let Some(draft) = self
+            .active_server
+            .as_ref()
+            .and_then(|server| self.drafts.remove(server))
+        {
+            self.message_input = draft;
+        }
+    }
+
+    // Archive the current transcript under its server and bring up the one kept for `server`,
+    // so switching servers never mixes their scrollback
+    pub fn switch_transcript(&mut self, server: &str) {
+        let previous = std::mem::take(&mut self.messages);
+        if let Some(active) = self.active_server.take() {
+            self.transcripts.insert(active, previous);
+        }
+        self.messages = self.transcripts.remove(server).unwrap_or_default();
+        if !self.messages.is_empty() {
+            // The server replays its history on connect; mark where that starts
+            self.messages.push(MessageType::SystemMessage(format!(
+                "Reconnected to {}",
+                server
+            )));
+        }
+        self.active_server = Some(server.to_string());
+        self.mark_active_server_read();
+        self.outbox.clear(); // Queued messages were meant for the server we left
+        self.logged_in = false;
+        self.numbering_unchecked = true;
+        self.topic = None; // The new server sends its own topic
+        self.channel = DEFAULT_CHANNEL.to_string();
+        self.roster.clear();
+        self.user_color = None;
+        self.scroll_to_bottom();
+        self.selected_message = None;
+        self.clear_search();
+    }
+
+    // Remember a sent input for later recall, skipping immediate repeats
+    pub fn record_input(&mut self, input: &str) {
+        self.history_cursor = None;
+        if input.trim().is_empty() || self.input_history.back().map(String::as_str) == Some(input) {
+            return;
+        }
+        if self.input_history.len() == INPUT_HISTORY_SIZE {
+            self.input_history.pop_front();
+        }
+        self.input_history.push_back(input.to_string());
+    }
+
+    // Step back to an older sent input, stopping at the oldest one
+    pub fn recall_previous_input(&mut self) {
+        if self.input_history.is_empty() {
+            return;
+        }
+        let index = match self.history_cursor {
+            Some(index) => index.saturating_sub(1),
+            None => self.input_history.len() - 1,
+        };
+        self.history_cursor = Some(index);
+        self.message_input = self.input_history[index].clone();
+    }
+
+    // Step forward to a newer sent input; past the newest the input is cleared again
+    pub fn recall_next_input(&mut self) {
+        match self.history_cursor {
+            Some(index) if index + 1 < self.input_history.len() => {
+                self.history_cursor = Some(index + 1);
+                self.message_input = self.input_history[index + 1].clone();
+            }
+            Some(_) => {
+                self.history_cursor = None;
+                self.message_input.clear();
+            }
+            None => {}
+        }
+    }
+
+    // Tab completion for slash commands: the first Tab completes to the longest common prefix
+    // of the matching commands, further Tabs cycle through the candidates
+    pub fn complete_command(&mut self) {
+        if !self.completion_candidates.is_empty() {
+            let next = self
+                .completion_index
+                .map_or(0, |index| (index + 1) % self.completion_candidates.len());
+            self.completion_index = Some(next);
+            self.message_input = self.completion_candidates[next].to_string();
+            return;
+        }
+
+        // Only the command name itself is completed, not its arguments
+        if !self.message_input.starts_with('/') || self.message_input.contains(' ') {
+            return;
+        }
+        let candidates = command_candidates(&self.message_input);
+        match candidates.len() {
+            0 => {}
+            1 => self.message_input = candidates[0].to_string(),
+            _ => {
+                self.message_input = longest_common_prefix(&candidates);
+                self.completion_candidates = candidates;
+            }
+        }
+    }
+
+    // Forget the candidates being cycled through, e.g. once the user types something else
+    pub fn reset_completion(&mut self) {
+        self.completion_candidates.clear();
+        self.completion_index = None;
+    }
+
+    // The rest of the first command matching the input, shown ghosted after the cursor
+    pub fn completion_hint(&self) -> Option<&'static str> {
+        if !self.message_input.starts_with('/') || self.message_input.contains(' ') {
+            return None;
+        }
+        command_candidates(&self.message_input)
+            .first()
+            .map(|name| &name[self.message_input.len()..])
+            .filter(|rest| !rest.is_empty())
+    }
+
+    // Empty the local transcript; the server and other clients are unaffected
+    pub fn clear_transcript(&mut self) {
+        self.messages.clear();
+        self.scroll_to_bottom();
+        self.selected_message = None;
+        self.clear_search();
+        self.compose_scroll_offset = 0;
+        self.messages
+            .push(MessageType::SystemMessage("Transcript cleared".to_string()));
+    }
+
+    // Swap the transcript for the history the server sent again, rebuilding the reactions
+    pub fn replace_history(&mut self, history: Vec<MessageType>) {
+        self.messages.clear();
+        self.reactions.clear();
+        self.scroll_to_bottom();
+        self.selected_message = None;
+        self.clear_search();
+        let mut count = 0;
+        for message in history {
+            match message {
+                MessageType::Reaction {
+                    message_id,
+                    user,
+                    emoji,
+                } => self.apply_reaction(message_id, user, emoji),
+                message => {
+                    self.messages.push(message);
+                    count += 1;
+                }
+            }
+        }
+        self.messages.push(MessageType::SystemMessage(format!(
+            "History reloaded: {} messages",
+            count
+        )));
+    }
+
+    // Switch to a preset theme by name, reporting the choices if it doesn't exist
+    pub fn set_theme(&mut self, name: &str) {
+        let notice = match Theme::by_name(name) {
+            Some(theme) => {
+                self.theme = theme;
+                MessageType::notice(Severity::Success, format!("Theme set to {}", theme.name))
+            }
+            None => {
+                let names: Vec<&str> = theme::PRESETS.iter().map(|theme| theme.name).collect();
+                MessageType::notice(
+                    Severity::Error,
+                    format!("Unknown theme {} (try {})", name, names.join(", ")),
+                )
+            }
+        };
+        self.messages.push(notice);
+    }
+
+    // Enter selection mode on the newest message, or leave it
+    pub fn toggle_selection(&mut self) {
+        self.selected_message = match self.selected_message {
+            Some(_) => None,
+            None => self.messages.len().checked_sub(1),
+        };
+    }
+
+    // Move the highlight to an older message, stopping at the first one
+    pub fn select_previous_message(&mut self) {
+        if let Some(index) = self.selected_message {
+            self.selected_message = Some(index.saturating_sub(1));
+        }
+    }
+
+    // Move the highlight to a newer message, stopping at the last one
+    pub fn select_next_message(&mut self) {
+        if let Some(index) = self.selected_message {
+            let last = self.messages.len().saturating_sub(1);
+            self.selected_message = Some((index + 1).min(last));
+        }
+    }
+
+    // Plain text of the highlighted message, as it would be copied to the clipboard
+    pub fn selected_message_text(&self) -> Option<String> {
+        message_text(self.messages.get(self.selected_message?)?)
+    }
+
+    // Start typing a new transcript search
+    pub fn start_search(&mut self) {
+        self.search_query = Some(String::new());
+        self.search_matches.clear();
+        self.search_current = None;
+        self.current_screen = CurrentScreen::Search;
+    }
+
+    pub fn clear_search(&mut self) {
+        self.search_query = None;
+        self.search_matches.clear();
+        self.search_current = None;
+    }
+
+    // Re-run the search, e.g. after the query changed or new messages arrived, and show the
+    // newest match when the query changed
+    pub fn update_search(&mut self) {
+        let Some(query) = &self.search_query else {
+            return;
+        };
+        self.search_matches = find_matches(&self.messages, query);
+        self.search_current = self.search_matches.len().checked_sub(1);
+    }
+
+    // Step to the next older match (n), wrapping around to the newest
+    pub fn search_previous(&mut self) {
+        self.refresh_search_matches();
+        let count = self.search_matches.len();
+        if count > 0 {
+            self.search_current = Some(match self.search_current {
+                Some(0) | None => count - 1,
+                Some(current) => current - 1,
+            });
+        }
+    }
+
+    // Step to the next newer match (N), wrapping around to the oldest
+    pub fn search_next(&mut self) {
+        self.refresh_search_matches();
+        let count = self.search_matches.len();
+        if count > 0 {
+            self.search_current = Some(match self.search_current {
+                Some(current) if current + 1 < count => current + 1,
+                _ => 0,
+            });
+        }
+    }
+
+    // Pick up messages that arrived since the search ran, keeping the current match
+    fn refresh_search_matches(&mut self) {
+        if let Some(query) = &self.search_query {
+            self.search_matches = find_matches(&self.messages, query);
+        }
+    }
+
+    // Index into `messages` of the match being shown
+    pub fn current_search_match(&self) -> Option<usize> {
+        self.search_matches.get(self.search_current?).copied()
+    }
+
+    // Methods for scrolling up and down in main chat. Scrolling up pins the view to the
+    // messages already there, so someone reading history keeps their place as new ones arrive
+    // and gets an indicator instead.
+    pub fn scroll_up(&mut self) {
+        self.scroll_anchor.get_or_insert(self.messages.len());
+        self.scroll_offset = self.scroll_offset.saturating_add(1);
+    }
+
+    pub fn scroll_down(&mut self) {
+        match self.scroll_anchor {
+            _ if self.scroll_offset > 0 => self.scroll_offset -= 1,
+            // Below the pinned messages, bring in the ones that arrived since, one at a time
+            Some(anchor) => self.scroll_anchor = Some(anchor + 1),
+            None => {}
+        }
+        if self.scroll_offset == 0
+            && self
+                .scroll_anchor
+                .is_some_and(|anchor| anchor >= self.messages.len())
+        {
+            self.scroll_anchor = None;
+        }
+    }
+
+    pub fn scroll_to_bottom(&mut self) {
+        self.scroll_offset = 0;
+        self.scroll_anchor = None;
+    }
+
+    // Messages that arrived below the view while scrolled up, for the indicator
+    pub fn new_messages_below(&self) -> usize {
+        self.scroll_anchor
+            .map_or(0, |anchor| self.messages.len().saturating_sub(anchor))
+    }
+
+    // Methods for scrolling up and down in compose area
+    pub fn compose_scroll_up(&mut self) {
+        self.compose_scroll_offset = self.scroll_offset.saturating_add(1);
+    }
+
+    pub fn compose_scroll_down(&mut self) {
+        self.compose_scroll_offset = self.scroll_offset.saturating_sub(1);
+    }
+
+    // Method for setting username
+    pub fn set_username(&mut self, name: String) {
+        self.username = Some(name);
+    }
+    pub fn parse_command(&self, input: &str) -> Command {
+        let input = input.trim();
+
+        if input.starts_with("/") {
+            let parts: Vec<&str> = input.splitn(3, ' ').collect();
+            match parts.as_slice() {
+                ["/name", name] if !name.is_empty() => Command::SetName(name.to_string()),
+                ["/list" | "/names"] => Command::ListUsers(1),
+                ["/list" | "/names", page] => match page.parse() {
+                    Ok(page) if page > 0 => Command::ListUsers(page),
+                    _ => Command::Unknown(input.to_string()),
+                },
+                ["/afk", rest @ ..] => Command::Afk(rest.join(" ")),
+                ["/afk-list"] => Command::AfkList,
+                ["/seen", user] if !user.is_empty() => Command::Seen(user.to_string()),
+                ["/dm", recipient, message] if !message.is_empty() => {
+                    Command::DirectMessage(recipient.to_string(), message.to_string())
+                }
+                ["/open", user] if !user.is_empty() => Command::OpenDirectChat(user.to_string()),
+                ["/retry"] => Command::Retry,
+                ["/clear"] => Command::Clear,
+                ["/theme", name] if !name.is_empty() => Command::Theme(name.to_string()),
+                ["/sound", rest @ ..] if !rest.is_empty() => Command::Sound(rest.join(" ")),
+                ["/stats"] => Command::Stats,
+                ["/clearhistory"] => Command::ClearHistory,
+                ["/mute", user, rest @ ..] if !user.is_empty() => {
+                    Command::Mute(user.to_string(), rest.first().map(|secs| secs.to_string()))
+                }
+                ["/unmute", user] if !user.is_empty() => Command::Unmute(user.to_string()),
+                ["/block", user] if !user.is_empty() => Command::Block(user.to_string()),
+                ["/unblock", user] if !user.is_empty() => Command::Unblock(user.to_string()),
+                ["/send", path] if !path.is_empty() => Command::SendFile(path.to_string()),
+                ["/history"] => Command::History,
+                ["/invite"] => Command::Invite,
+                ["/status"] => Command::Status,
+                ["/export"] => Command::Export(None),
+                ["/export", path] if !path.is_empty() => Command::Export(Some(path.to_string())),
+                ["/reply", id, text] if !text.is_empty() => {
+                    Command::Reply(id.to_string(), text.to_string())
+                }
+                ["/topic", rest @ ..] if !rest.is_empty() => Command::Topic(rest.join(" ")),
+                ["/reconnect"] => Command::Reconnect,
+                ["/join", channel] if !channel.is_empty() => Command::Join(channel.to_string()),
+                ["/part"] => Command::Part,
+                ["/quit"] => Command::Quit,
+                ["/help"] => Command::Help,
+                _ => Command::Unknown(input.to_string()),
+            }
+        } else {
+            Command::Unknown(input.to_string()) // Treat as unknown if it's not a valid command
+        }
+    }
+}
+
+// Open the default audio device with a sink to play notification sounds on, or None if there
+// isn't one (headless, SSH, CI)
+fn open_sound_output() -> Option<(OutputStream, Sink)> {
+    let opened = OutputStream::try_default()
+        .map_err(|e| e.to_string())
+        .and_then(|(stream, handle)| {
+            let sink = Sink::try_new(&handle).map_err(|e| e.to_string())?;
+            Ok((stream, sink))
+        });
+    match opened {
+        Ok(output) => Some(output),
+        Err(e) => {
+            log::warn!("No audio device, notification sounds disabled: {}", e);
+            None
+        }
+    }
+}
+
+// Open and decode a sound file, ready to play
+fn decode_sound(path: &Path) -> Result<Decoder<BufReader<File>>, Box<dyn std::error::Error>> {
+    let file = File::open(path)?;
+    Ok(Decoder::new(BufReader::new(file))?)
+}
+
+// Whether a file will do as the notification sound: it has to exist and be audio rodio can
+// decode. Checking doesn't need an audio device.
+pub fn check_sound(path: &Path) -> Result<(), String> {
+    let file = File::open(path).map_err(|e| format!("Can't open {}: {}", path.display(), e))?;
+    Decoder::new(BufReader::new(file))
+        .map(|_| ())
+        .map_err(|e| format!("Can't play {}: {}", path.display(), e))
+}
+
+// Plain text of a message as shown in the transcript, used for copying and searching
+pub fn message_text(message: &MessageType) -> Option<String> {
+    match message {
+        MessageType::ChatMessage {
+            sender, content, ..
+        }
+        | MessageType::PrivateMessage {
+            sender, content, ..
+        } => Some(format!("{}: {}", sender, content)),
+        MessageType::SystemMessage(text) | MessageType::Notice { text, .. } => Some(text.clone()),
+        MessageType::UserList(names) => Some(names.join(", ")),
+        _ => None,
+    }
+}
+
+// The transcript as plain text, one message per line; messages that aren't conversation, such as
+// acks and file chunks, are left out
+pub fn transcript_text(messages: &[MessageType]) -> String {
+    let mut text = String::new();
+    for message in messages {
+        let line = match message {
+            MessageType::ChatMessage {
+                sender, content, ..
+            } => format!("{}: {}", sender, content),
+            MessageType::PrivateMessage {
+                sender,
+                recipient,
+                content,
+            } => format!("[DM {} -> {}] {}", sender, recipient, content),
+            MessageType::SystemMessage(text) => format!("* {}", text),
+            MessageType::Notice { level, text } => match level {
+                Severity::Info | Severity::Success => format!("* {}", text),
+                Severity::Warn => format!("* warning: {}", text),
+                Severity::Error => format!("* error: {}", text),
+            },
+            MessageType::UserList(names) => format!("* {}", names.join(", ")),
+            _ => continue,
+        };
+        text.push_str(&line);
+        text.push('\n');
+    }
+    text
+}
+
+// The same messages as `transcript_text`, as a pretty-printed JSON array
+pub fn transcript_json(messages: &[MessageType]) -> String {
+    let messages: Vec<&MessageType> = messages
+        .iter()
+        .filter(|message| {
+            matches!(
+                message,
+                MessageType::ChatMessage { .. }
+                    | MessageType::PrivateMessage { .. }
+                    | MessageType::SystemMessage(_)
+                    | MessageType::Notice { .. }
+                    | MessageType::UserList(_)
+            )
+        })
+        .collect();
+    serde_json::to_string_pretty(&messages).unwrap()
+}
+
+// Reactions grouped by emoji in the order each was first used, e.g. "👍 2  😂 1"
+pub fn reaction_summary(reactions: &[(String, String)]) -> String {
+    let mut counts: Vec<(&str, usize)> = Vec::new();
+    for (_, emoji) in reactions {
+        match counts.iter_mut().find(|(existing, _)| existing == emoji) {
+            Some((_, count)) => *count += 1,
+            None => counts.push((emoji, 1)),
+        }
+    }
+    counts
+        .iter()
+        .map(|(emoji, count)| format!("{} {}", emoji, count))
+        .collect::<Vec<String>>()
+        .join("  ")
+}
+
+// Indices of the messages containing `query`, ignoring case. An empty query matches nothing.
+pub fn find_matches(messages: &[MessageType], query: &str) -> Vec<usize> {
+    if query.is_empty() {
+        return Vec::new();
+    }
+    let query = query.to_lowercase();
+    messages
+        .iter()
+        .enumerate()
+        .filter(|(_, message)| {
+            message_text(message).is_some_and(|text| text.to_lowercase().contains(&query))
+        })
+        .map(|(index, _)| index)
+        .collect()
+}
+
+// Byte ranges where `name` is mentioned in `text`, ignoring case, with any leading '@'. The name
+// has to stand on its own, so "alice" isn't found in "alicex" or "malice".
+pub fn find_mentions(text: &str, name: &str) -> Vec<Range<usize>> {
+    let is_name_char = |c: char| c.is_alphanumeric() || matches!(c, '_' | '-' | '.');
+    let name: Vec<char> = name.chars().collect();
+    let chars: Vec<(usize, char)> = text.char_indices().collect();
+    let mut mentions = Vec::new();
+    if name.is_empty() {
+        return mentions;
+    }
+    let mut i = 0;
+    while i + name.len() <= chars.len() {
+        let end = i + name.len();
+        let same = chars[i..end]
+            .iter()
+            .zip(&name)
+            .all(|(&(_, a), &b)| a.to_lowercase().eq(b.to_lowercase()));
+        let starts_word = i == 0 || !is_name_char(chars[i - 1].1);
+        // A full stop straight after the name ends the sentence unless a word follows it
+        let ends_word = match chars.get(end).map(|&(_, c)| c) {
+            None => true,
+            Some('.') => !chars
+                .get(end + 1)
+                .is_some_and(|&(_, c)| c.is_alphanumeric()),
+            Some(c) => !is_name_char(c),
+        };
+        if same && starts_word && ends_word {
+            let start = match i.checked_sub(1).map(|before| chars[before]) {
+                Some((offset, '@')) => offset,
+                _ => chars[i].0,
+            };
+            mentions.push(start..chars.get(end).map_or(text.len(), |&(offset, _)| offset));
+            i = end;
+        } else {
+            i += 1;
+        }
+    }
+    mentions
+}
+
+// Backspace: remove the last character as the user sees it, so an emoji made of several code
+// points, or a letter with a combining accent, goes in one keypress rather than leaving part of it
+pub fn pop_grapheme(text: &mut String) {
+    if let Some((start, _)) = text.grapheme_indices(true).next_back() {
+        text.truncate(start);
+    }
+}
+
+// Longest display name accepted, in characters; the server applies the same rules
+pub const MAX_USERNAME_LEN: usize = 32;
+
+// Trim a requested display name and check it is usable: non-empty, not too long, and made only of
+// letters, digits, '_', '-' and '.', so it can't carry control characters or break the
+// "name: message" layout. Returns the trimmed name or the reason it was rejected.
+// The server checks names the same way in its own validate_username, so names are turned down
+// before they are sent; the two crates share no code, so change both together.
+pub fn validate_username(name: &str) -> Result<String, String> {
+    let name = name.trim();
+    if name.is_empty() {
+        return Err("Username cannot be empty.".to_string());
+    }
+    if name.chars().count() > MAX_USERNAME_LEN {
+        return Err(format!(
+            "Username is too long (at most {} characters).",
+            MAX_USERNAME_LEN
+        ));
+    }
+    if let Some(c) = name.chars().find(|c| !username_char_allowed(*c)) {
+        return Err(bad_username_char(c));
+    }
+    Ok(name.to_string())
+}
+
+// Check a character about to be typed into a username field against `validate_username`'s rules,
+// so a paste or a slip of the keyboard can't build up a name the server would refuse. Returns why
+// the character was turned away.
+pub fn check_username_char(input: &str, c: char) -> Result<(), String> {
+    if !username_char_allowed(c) {
+        return Err(bad_username_char(c));
+    }
+    if input.chars().count() >= MAX_USERNAME_LEN {
+        return Err(format!(
+            "Usernames are at most {} characters.",
+            MAX_USERNAME_LEN
+        ));
+    }
+    Ok(())
+}
+
+fn username_char_allowed(c: char) -> bool {
+    c.is_alphanumeric() || matches!(c, '_' | '-' | '.')
+}
+
+fn bad_username_char(c: char) -> String {
+    format!(
+        "Username cannot contain {:?}; use letters, digits, '_', '-' or '.'.",
+        c
+    )
+}
+
+// Turn a server URL given on the command line into a (name, url) entry for `App::servers`
+pub fn server_entry_from_arg(arg: &str) -> Result<(String, Url), String> {
+    let url = Url::parse(arg).map_err(|e| format!("Invalid server URL '{}': {}", arg, e))?;
+    if url.scheme() != "ws" && url.scheme() != "wss" {
+        return Err(format!(
+            "Unsupported scheme '{}' in '{}', expected ws:// or wss://",
+            url.scheme(),
+            arg
+        ));
+    }
+    let host = url
+        .host_str()
+        .ok_or_else(|| format!("Server URL '{}' has no host", arg))?;
+    // Name the entry after the host (and port) so it is recognisable in the server list
+    let name = match url.port() {
+        Some(port) => format!("{}:{}", host, port),
+        None => host.to_string(),
+    };
+    Ok((name, url))
+}
+
+// A server URL in the form to hand out: scheme, host and any port, with the bare "/" path
+// that `Url` adds left off so it reads like what people type
+pub fn invite_link(url: &Url) -> String {
+    let link = url.as_str();
+    if url.path() == "/" && url.query().is_none() && url.fragment().is_none() {
+        link.trim_end_matches('/').to_string()
+    } else {
+        link.to_string()
+    }
+}
+
+// What was typed on the Add Server screen: a pasted ws:// or wss:// link, named after its host,
+// or a "name:url" pair
+pub fn parse_server_input(input: &str) -> Result<(String, Url), String> {
+    let input = input.trim();
+    if input.starts_with("ws://") || input.starts_with("wss://") {
+        return server_entry_from_arg(input);
+    }
+    let (name, url) = input
+        .split_once(':')
+        .ok_or_else(|| "Enter name:url or a ws:// link".to_string())?;
+    let url = Url::parse(url.trim()).map_err(|e| format!("Invalid server URL '{}': {}", url, e))?;
+    Ok((name.trim().to_string(), url))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use CurrentScreen::*;
+    use Transition::{Go, Quit, Stay};
+
+    fn app_on(screen: CurrentScreen) -> App {
+        let mut app = App::new();
+        app.current_screen = screen;
+        app
+    }
+
+    #[test]
+    fn server_selection_keys_are_safe_without_servers() {
+        let mut app = app_on(ServerSelection);
+        app.servers.clear();
+        for key in [KeyCode::Up, KeyCode::Down, KeyCode::Tab, KeyCode::Enter] {
+            app.move_server_selection(key);
+            assert_eq!(app.on_key(key), Stay);
+        }
+        assert!(!app.can_connect_to_selected());
+
+        // Adding a server afterwards makes it selectable again
+        app.servers.insert(
+            "local".to_string(),
+            Url::parse("ws://127.0.0.1:8080").unwrap(),
+        );
+        app.move_server_selection(KeyCode::Down);
+        assert_eq!(app.selected_server.as_deref(), Some("local"));
+        assert!(app.can_connect_to_selected());
+    }
+
+    #[test]
+    fn deleting_a_server_selects_its_neighbour() {
+        let mut app = app_on(ServerSelection);
+        for name in ["a", "b", "c"] {
+            app.servers
+                .insert(name.to_string(), Url::parse("ws://127.0.0.1:8080").unwrap());
+        }
+        let names: Vec<String> = app.servers.keys().cloned().collect();
+
+        // The selected server goes, and whichever moved into its place is selected
+        app.selected_server = Some(names[1].clone());
+        app.delete_server(&names[1]);
+        assert!(!app.servers.contains_key(&names[1]));
+        let remaining: Vec<String> = app.servers.keys().cloned().collect();
+        assert_eq!(app.selected_server.as_ref(), Some(&remaining[1]));
+
+        // Deleting another server keeps the selection where it is
+        let selected = app.selected_server.clone().unwrap();
+        let other = remaining.iter().find(|n| **n != selected).unwrap().clone();
+        app.delete_server(&other);
+        assert_eq!(app.selected_server.as_ref(), Some(&selected));
+        assert!(app.servers.contains_key(&selected));
+
+        // Deleting the last one leaves nothing selected
+        for name in app.servers.keys().cloned().collect::<Vec<_>>() {
+            app.delete_server(&name);
+        }
+        assert_eq!(app.selected_server, None);
+        assert!(!app.can_connect_to_selected());
+    }
+
+    #[test]
+    fn removed_built_in_servers_stay_removed() {
+        let mut app = App::new();
+        app.apply_config(&ClientConfig {
+            removed_servers: vec!["default".to_string()],
+            ..Default::default()
+        });
+        assert!(!app.servers.contains_key("default"));
+        assert_eq!(app.selected_server.as_deref(), Some("local"));
+    }
+
+    #[test]
+    fn on_key_follows_the_transition_table() {
+        let table = [
+            (Main, KeyCode::Enter, Go(ComposingMessage)),
+            (Main, KeyCode::Char('/'), Go(Search)),
+            (Main, KeyCode::Char('h'), Go(HelpMenu)),
+            (Main, KeyCode::Char('q'), Go(Exiting)),
+            (Main, KeyCode::Char('n'), Go(SetUser)),
+            (Main, KeyCode::Char('s'), Go(ServerSelection)),
+            (Main, KeyCode::Char('v'), Stay),
+            (Main, KeyCode::Up, Stay),
+            (ComposingMessage, KeyCode::Esc, Go(Main)),
+            (ComposingMessage, KeyCode::Enter, Stay),
+            (ComposingMessage, KeyCode::Char('q'), Stay),
+            (SetUser, KeyCode::Enter, Go(Main)),
+            (SetUser, KeyCode::Esc, Go(Main)),
+            (SetUser, KeyCode::Char('q'), Stay),
+            (HelpMenu, KeyCode::Char('x'), Go(Main)),
+            (HelpMenu, KeyCode::Down, Stay),
+            (AwayList, KeyCode::Esc, Go(Main)),
+            (UserList, KeyCode::Down, Stay),
+            (UserList, KeyCode::Esc, Go(Main)),
+            (Search, KeyCode::Enter, Go(Main)),
+            (Search, KeyCode::Esc, Go(Main)),
+            (Search, KeyCode::Char('q'), Stay),
+            (DirectChat("bob".to_string()), KeyCode::Esc, Go(Main)),
+            (DirectChat("bob".to_string()), KeyCode::Char('q'), Stay),
+            (Exiting, KeyCode::Char('y'), Quit),
+            (Exiting, KeyCode::Char('n'), Go(Main)),
+            (Exiting, KeyCode::Char('q'), Go(Main)),
+            (ExitingLoggingIn, KeyCode::Char('y'), Quit),
+            (ExitingLoggingIn, KeyCode::Esc, Go(LoggingIn)),
+            (ExitingLoggingIn, KeyCode::Char('s'), Go(ServerSelection)),
+            (LoggingIn, KeyCode::Esc, Go(ExitingLoggingIn)),
+            (LoggingIn, KeyCode::Char('q'), Stay),
+            (ServerSelection, KeyCode::Char('n'), Go(AddServer)),
+            (ServerSelection, KeyCode::Enter, Stay),
+            (AddServer, KeyCode::Esc, Go(ServerSelection)),
+            (Disconnected, KeyCode::Char('q'), Quit),
+            (Disconnected, KeyCode::Char('r'), Stay),
+            (Connecting, KeyCode::Esc, Go(ServerSelection)),
+            (Welcome, KeyCode::Esc, Go(ServerSelection)),
+            (Welcome, KeyCode::Enter, Stay),
+            (Welcome, KeyCode::Char('q'), Stay),
+            (Connecting, KeyCode::Char('q'), Stay),
+        ];
+        for (screen, key, expected) in table {
+            let app = app_on(screen.clone());
+            assert_eq!(app.on_key(key), expected, "{:?} on {:?}", key, screen);
+        }
+    }
+
+    #[test]
+    fn arrow_keys_recall_sent_inputs() {
+        let mut app = App::new();
+        for input in ["first", "second", "second", "  ", "third"] {
+            app.record_input(input);
+        }
+        // Repeats and blank inputs aren't kept
+        assert_eq!(app.input_history, ["first", "second", "third"]);
+
+        let mut recalled = Vec::new();
+        for _ in 0..4 {
+            app.recall_previous_input();
+            recalled.push(app.message_input.clone());
+        }
+        assert_eq!(recalled, ["third", "second", "first", "first"]);
+        app.recall_next_input();
+        assert_eq!(app.message_input, "second");
+        app.recall_next_input();
+        app.recall_next_input();
+        assert!(app.message_input.is_empty());
+        // Down with nothing recalled leaves the input alone
+        app.message_input = "typing".to_string();
+        app.recall_next_input();
+        assert_eq!(app.message_input, "typing");
+    }
+
+    #[test]
+    fn tab_completes_commands_then_cycles_candidates() {
+        let mut app = App::new();
+        app.message_input = "/qu".to_string();
+        app.complete_command();
+        assert_eq!(app.message_input, "/quit");
+
+        // Several matches complete to what they share, then Tab steps through them
+        app.message_input = "/na".to_string();
+        app.complete_command();
+        assert_eq!(app.message_input, "/name");
+        app.complete_command();
+        assert_eq!(app.message_input, "/name");
+        app.complete_command();
+        assert_eq!(app.message_input, "/names");
+        app.complete_command();
+        assert_eq!(app.message_input, "/name");
+        app.reset_completion();
+
+        // Unknown commands and arguments are left alone
+        for input in ["/zzz", "/name bo", "hello"] {
+            app.message_input = input.to_string();
+            app.complete_command();
+            assert_eq!(app.message_input, input);
+            assert_eq!(app.completion_hint(), None);
+        }
+        app.message_input = "/he".to_string();
+        assert_eq!(app.completion_hint(), Some("lp"));
+    }
+
+    #[test]
+    fn clear_empties_the_transcript_and_leaves_a_note() {
+        let mut app = App::new();
+        app.messages = (0..5)
+            .map(|i| MessageType::SystemMessage(format!("line {}", i)))
+            .collect();
+        app.scroll_offset = 3;
+        app.selected_message = Some(2);
+        assert!(matches!(app.parse_command("/clear"), Command::Clear));
+
+        app.clear_transcript();
+        assert!(matches!(
+            &app.messages[..],
+            [MessageType::SystemMessage(text)] if text == "Transcript cleared"
+        ));
+        assert_eq!(app.scroll_offset, 0);
+        assert_eq!(app.selected_message, None);
+    }
+
+    #[test]
+    fn find_matches_ignores_case_and_messages_without_text() {
+        let messages = vec![
+            MessageType::SystemMessage("Welcome to the server".to_string()),
+            MessageType::notice(Severity::Info, "WELCOME back"),
+            MessageType::Ack {
+                id: "welcome".to_string(),
+                message_id: None,
+                seq: None,
+            },
+            MessageType::ChatMessage {
+                sender: "Bob".to_string(),
+                content: "Café at noon?".to_string(),
+                sender_id: "bob".to_string(),
+                client_msg_id: None,
+                message_id: None,
+                reply_to: None,
+                seq: None,
+            },
+        ];
+        assert_eq!(find_matches(&messages, "welcome"), vec![0, 1]);
+        assert_eq!(find_matches(&messages, "CAFÉ"), vec![3]);
+        // The sender's name is searched along with what they said
+        assert_eq!(find_matches(&messages, "bob: caf"), vec![3]);
+        assert!(find_matches(&messages, "tea").is_empty());
+        assert!(find_matches(&messages, "").is_empty());
+
+        // n and N step through the matches from the newest, wrapping at either end
+        let mut app = App::new();
+        app.messages = messages;
+        app.search_query = Some("welcome".to_string());
+        app.update_search();
+        assert_eq!(app.current_search_match(), Some(1));
+        app.search_previous();
+        assert_eq!(app.current_search_match(), Some(0));
+        app.search_previous();
+        assert_eq!(app.current_search_match(), Some(1));
+        app.search_next();
+        assert_eq!(app.current_search_match(), Some(0));
+    }
+
+    #[test]
+    fn parse_command_accepts_names_as_an_alias_for_list() {
+        let app = App::new();
+        for input in ["/list", "/names", " /names "] {
+            assert!(
+                matches!(app.parse_command(input), Command::ListUsers(1)),
+                "{}",
+                input
+            );
+        }
+        assert!(matches!(
+            app.parse_command("/names 3"),
+            Command::ListUsers(3)
+        ));
+        assert!(matches!(app.parse_command("/names 0"), Command::Unknown(_)));
+        assert!(matches!(
+            app.parse_command("/namesake"),
+            Command::Unknown(_)
+        ));
+    }
+
+    #[test]
+    fn show_user_list_pages_long_lists() {
+        let mut app = App::new();
+        let names: Vec<String> = (0..USER_LIST_PAGE_SIZE + 5)
+            .map(|i| format!("user{:03}", i))
+            .collect();
+        app.user_list_page = 2;
+        app.show_user_list(names);
+        let shown = app.messages.len();
+        assert!(matches!(
+            &app.messages[shown - 2],
+            MessageType::SystemMessage(header) if header.contains("page 2/2")
+        ));
+        assert!(matches!(
+            &app.messages[shown - 1],
+            MessageType::UserList(page) if page.len() == 5 && page[0] == format!("user{:03}", USER_LIST_PAGE_SIZE)
+        ));
+    }
+
+    #[test]
+    fn esc_from_composing_keeps_the_draft_and_a_second_esc_discards_it() {
+        let mut app = app_on(ComposingMessage);
+        app.message_input = "half typed".to_string();
+        assert_eq!(app.on_key(KeyCode::Esc), Go(Main));
+        app.current_screen = Main;
+        assert_eq!(app.message_input, "half typed");
+
+        // On Main, Esc first leaves selection mode and only then discards the draft
+        app.selected_message = Some(0);
+        app.escape_main();
+        assert_eq!(app.selected_message, None);
+        assert_eq!(app.message_input, "half typed");
+        app.escape_main();
+        assert!(app.message_input.is_empty());
+    }
+
+    #[test]
+    fn selection_stays_within_the_transcript() {
+        let mut app = App::new();
+        // Nothing to select in an empty transcript
+        app.toggle_selection();
+        assert_eq!(app.selected_message, None);
+
+        app.messages = ["one", "two", "three"]
+            .map(|text| MessageType::SystemMessage(text.to_string()))
+            .to_vec();
+        app.toggle_selection();
+        assert_eq!(app.selected_message, Some(2));
+        app.select_next_message();
+        assert_eq!(app.selected_message, Some(2));
+        for _ in 0..5 {
+            app.select_previous_message();
+        }
+        assert_eq!(app.selected_message, Some(0));
+        assert_eq!(app.selected_message_text().as_deref(), Some("one"));
+        app.toggle_selection();
+        assert_eq!(app.selected_message, None);
+    }
+
+    #[test]
+    fn on_key_keeps_spectators_from_composing_or_renaming() {
+        let mut app = app_on(Main);
+        app.spectating = true;
+        assert_eq!(app.on_key(KeyCode::Enter), Stay);
+        assert_eq!(app.on_key(KeyCode::Char('n')), Stay);
+        assert_eq!(app.on_key(KeyCode::Char('h')), Go(HelpMenu));
+    }
+
+    #[test]
+    fn on_key_leaves_keys_to_searches_and_reactions() {
+        // With a search active, 'n' steps to the previous match rather than renaming
+        let mut app = app_on(Main);
+        app.search_query = Some("hello".to_string());
+        assert_eq!(app.on_key(KeyCode::Char('n')), Stay);
+
+        // After 'r' the next key picks the reaction, even one that would leave Main
+        let mut app = app_on(Main);
+        app.reacting = true;
+        assert_eq!(app.on_key(KeyCode::Char('q')), Stay);
+    }
+
+    #[test]
+    fn find_mentions_matches_whole_names_ignoring_case() {
+        let found = |text: &str| find_mentions(text, "alice");
+        assert_eq!(found("hi @alice!"), vec![3..9]);
+        assert_eq!(found("ALICE, lunch?"), vec![0..5]);
+        assert_eq!(found("thanks alice."), vec![7..12]);
+        assert_eq!(found("alice and @Alice"), vec![0..5, 10..16]);
+        for miss in [
+            "alicex",
+            "malice",
+            "@alice_b",
+            "alice-bot",
+            "alice.smith",
+            "ali ce",
+        ] {
+            assert!(found(miss).is_empty(), "{}", miss);
+        }
+        assert!(find_mentions("anything", "").is_empty());
+    }
+
+    #[test]
+    fn chat_is_kept_in_server_order_without_repeats() {
+        let mut app = App::new();
+        let chat = |seq: u64| {
+            format!(
+                r#"{{"ChatMessage":{{"sender":"bob","content":"m{}","sender_id":"bob","seq":{}}}}}"#,
+                seq, seq
+            )
+        };
+        for seq in [1, 2, 4] {
+            app.handle_websocket_message(&chat(seq));
+        }
+        app.handle_websocket_message(r#"{"SystemMessage":"Reconnected"}"#);
+        // A late message slots in by number, and a replayed one isn't shown twice
+        app.handle_websocket_message(&chat(3));
+        app.handle_websocket_message(&chat(2));
+        app.handle_websocket_message(&chat(5));
+        let seqs: Vec<u64> = app
+            .messages
+            .iter()
+            .filter_map(|message| match message {
+                MessageType::ChatMessage { seq, .. } => *seq,
+                _ => None,
+            })
+            .collect();
+        assert_eq!(seqs, [1, 2, 3, 4, 5]);
+
+        // Our own messages take their number from the Ack
+        app.messages.push(MessageType::ChatMessage {
+            sender: "alice".to_string(),
+            content: "mine".to_string(),
+            sender_id: "alice".to_string(),
+            client_msg_id: Some("c1".to_string()),
+            message_id: None,
+            reply_to: None,
+            seq: None,
+        });
+        app.handle_websocket_message(r#"{"Ack":{"id":"c1","message_id":"m6","seq":6}}"#);
+        app.handle_websocket_message(&chat(6));
+        assert!(matches!(
+            app.messages.last(),
+            Some(MessageType::ChatMessage { seq: Some(6), content, .. }) if content == "mine"
+        ));
+    }
+
+    #[test]
+    fn a_server_restarting_its_numbering_is_not_taken_for_repeats() {
+        let chat = |id: &str, seq: u64| {
+            format!(
+                r#"{{"ChatMessage":{{"sender":"bob","content":"{}","sender_id":"bob","message_id":"{}","seq":{}}}}}"#,
+                id, id, seq
+            )
+        };
+        let contents = |app: &App| -> Vec<String> {
+            app.messages
+                .iter()
+                .filter_map(|message| match message {
+                    MessageType::ChatMessage { content, .. } => Some(content.clone()),
+                    _ => None,
+                })
+                .collect()
+        };
+        let mut app = App::new();
+        for (id, seq) in [("a", 1), ("b", 2), ("c", 4)] {
+            app.handle_websocket_message(&chat(id, seq));
+        }
+
+        // Reconnecting to the same server: the replay is skipped and what we missed follows on
+        app.begin_reauth();
+        app.handle_websocket_message(&chat("c", 4));
+        app.handle_websocket_message(&chat("d", 5));
+        app.handle_websocket_message(&chat("e", 3)); // a late one still slots in by number
+        assert_eq!(contents(&app), ["a", "b", "e", "c", "d"]);
+
+        // Reconnecting after a restart that lost the count: numbering starts again from 1
+        app.begin_reauth();
+        app.handle_websocket_message(&chat("f", 1));
+        app.handle_websocket_message(&chat("g", 3));
+        app.handle_websocket_message(&chat("h", 2));
+        app.handle_websocket_message(&chat("g", 3));
+        assert_eq!(contents(&app), ["a", "b", "e", "c", "d", "f", "h", "g"]);
+    }
+
+    #[test]
+    fn server_entry_from_arg_names_the_entry_after_the_host() {
+        let (name, url) = server_entry_from_arg("ws://chat.example.com:9000").unwrap();
+        assert_eq!(name, "chat.example.com:9000");
+        assert_eq!(url.as_str(), "ws://chat.example.com:9000/");
+        let (name, _) = server_entry_from_arg("wss://chat.example.com/room").unwrap();
+        assert_eq!(name, "chat.example.com");
+
+        assert!(server_entry_from_arg("http://chat.example.com")
+            .unwrap_err()
+            .contains("Unsupported scheme"));
+        assert!(server_entry_from_arg("not a url")
+            .unwrap_err()
+            .contains("Invalid server URL"));
+    }
+
+    #[test]
+    fn each_server_keeps_its_own_transcript_without_replayed_repeats() {
+        let chat = |id: &str, seq: Option<u64>| {
+            let seq = seq.map_or(String::new(), |seq| format!(r#","seq":{}"#, seq));
+            format!(
+                r#"{{"ChatMessage":{{"sender":"bob","content":"{}","sender_id":"bob","message_id":"{}"{}}}}}"#,
+                id, id, seq
+            )
+        };
+        let contents = |app: &App| -> Vec<String> {
+            app.messages
+                .iter()
+                .filter_map(|message| match message {
+                    MessageType::ChatMessage { content, .. } => Some(content.clone()),
+                    _ => None,
+                })
+                .collect()
+        };
+        let mut app = App::new();
+        app.switch_transcript("a");
+        app.handle_websocket_message(&chat("a1", Some(1)));
+        app.handle_websocket_message(&chat("a2", None));
+
+        app.switch_transcript("b");
+        assert!(app.messages.is_empty());
+        app.handle_websocket_message(&chat("b1", Some(1)));
+
+        // Back on the first server, its history is replayed over the transcript kept for it
+        app.switch_transcript("a");
+        app.handle_websocket_message(&chat("a1", Some(1)));
+        app.handle_websocket_message(&chat("a2", None));
+        assert_eq!(contents(&app), ["a1", "a2"]);
+        app.switch_transcript("b");
+        assert_eq!(contents(&app), ["b1"]);
+    }
+
+    #[test]
+    fn invite_links_round_trip_through_add_server() {
+        for (url, link, name) in [
+            (
+                "ws://chat.example.com",
+                "ws://chat.example.com",
+                "chat.example.com",
+            ),
+            (
+                "wss://chat.example.com:8443/",
+                "wss://chat.example.com:8443",
+                "chat.example.com:8443",
+            ),
+            (
+                "ws://10.0.0.5:9000/rooms",
+                "ws://10.0.0.5:9000/rooms",
+                "10.0.0.5:9000",
+            ),
+        ] {
+            let url = Url::parse(url).unwrap();
+            assert_eq!(invite_link(&url), link);
+            assert_eq!(parse_server_input(link), Ok((name.to_string(), url)));
+        }
+
+        let mut app = App::new();
+        assert_eq!(app.invite_link(), None);
+        app.active_server = Some("local".to_string());
+        assert_eq!(app.invite_link().as_deref(), Some("ws://0.0.0.0:8080"));
+    }
+
+    #[test]
+    fn add_server_input_takes_a_name_or_a_link() {
+        assert_eq!(
+            parse_server_input(" home:ws://home.example:8080 "),
+            Ok((
+                "home".to_string(),
+                Url::parse("ws://home.example:8080").unwrap()
+            ))
+        );
+        assert!(parse_server_input("wss://").is_err());
+        assert!(parse_server_input("http://example.com").is_err());
+        assert!(parse_server_input("no separator").is_err());
+    }
+
+    #[test]
+    fn queued_messages_are_sent_in_order_after_logging_back_in() {
+        let mut app = App::new();
+        app.logged_in = true;
+        app.set_disconnected("Connection reset".to_string());
+        for text in ["one", "two", "three"] {
+            let msg = MessageType::ChatMessage {
+                sender: "alice".to_string(),
+                content: text.to_string(),
+                sender_id: "alice".to_string(),
+                client_msg_id: Some(text.to_string()),
+                message_id: None,
+                reply_to: None,
+                seq: None,
+            };
+            app.track_pending(text.to_string(), msg.clone());
+            app.mark_unsent(text);
+            app.queue_outgoing(msg);
+        }
+        // Nothing goes out until the reconnect has logged us in
+        assert!(app.queued_message().is_none());
+        app.handle_websocket_message(r#"{"SystemMessage":"Authentication successful"}"#);
+
+        let mut sent = Vec::new();
+        while let Some(MessageType::ChatMessage { content, .. }) = app.queued_message() {
+            sent.push(content.clone());
+            app.dequeue_sent();
+        }
+        assert_eq!(sent, ["one", "two", "three"]);
+        assert_eq!(app.delivery_status("two"), DeliveryStatus::Pending);
+        assert!(app.take_failed_for_retry().is_empty());
+    }
+
+    #[test]
+    fn acks_stop_tracking_and_silence_fails_a_message() {
+        let mut app = App::new();
+        for id in ["acked", "lost"] {
+            app.track_pending(id.to_string(), MessageType::SystemMessage(id.to_string()));
+        }
+        app.acknowledge("acked", Some("m1".to_string()), Some(1));
+        assert!(!app.pending_messages.contains_key("acked"));
+        assert_eq!(app.delivery_status("acked"), DeliveryStatus::Sent);
+
+        // Nothing changes until the timeout has passed without an Ack
+        let sent_at = app.pending_messages["lost"].sent_at;
+        assert!(!app.expire_pending(sent_at + ACK_TIMEOUT / 2));
+        assert_eq!(app.delivery_status("lost"), DeliveryStatus::Pending);
+        assert!(app.expire_pending(sent_at + ACK_TIMEOUT));
+        assert_eq!(app.delivery_status("lost"), DeliveryStatus::Failed);
+
+        // A retry waits again, and a late Ack still clears it
+        assert_eq!(app.take_failed_for_retry().len(), 1);
+        assert_eq!(app.delivery_status("lost"), DeliveryStatus::Pending);
+        app.acknowledge("lost", None, None);
+        assert!(app.pending_messages.is_empty());
+    }
+
+    #[test]
+    fn full_outbox_drops_the_oldest_message() {
+        let mut app = App::new();
+        for n in 0..OUTBOX_SIZE + 2 {
+            app.queue_outgoing(MessageType::SystemMessage(n.to_string()));
+        }
+        assert_eq!(app.outbox.len(), OUTBOX_SIZE);
+        assert!(matches!(app.outbox.front(), Some(MessageType::SystemMessage(n)) if n == "2"));
+        assert!(matches!(
+            app.messages.last(),
+            Some(MessageType::Notice {
+                level: Severity::Warn,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn username_input_is_checked_as_it_is_typed() {
+        assert_eq!(check_username_char("ali", 'c'), Ok(()));
+        assert_eq!(check_username_char("", '.'), Ok(()));
+        assert!(check_username_char("alice", ':').is_err());
+        assert!(check_username_char("alice", ' ').is_err());
+        assert!(check_username_char("alice", '\u{1b}').is_err());
+
+        // Typing stops at the length limit, so whatever got in still validates
+        let full = "a".repeat(MAX_USERNAME_LEN);
+        assert!(check_username_char(&full[1..], 'a').is_ok());
+        assert!(check_username_char(&full, 'a').is_err());
+        assert_eq!(validate_username(&full), Ok(full.clone()));
+    }
+
+    #[test]
+    fn validate_username_rejects_empty_long_and_odd_names() {
+        assert_eq!(
+            validate_username("  al.ice_2-b "),
+            Ok("al.ice_2-b".to_string())
+        );
+        assert_eq!(validate_username("Zoë"), Ok("Zoë".to_string()));
+        assert_eq!(
+            validate_username("   "),
+            Err("Username cannot be empty.".to_string())
+        );
+        let long = "é".repeat(MAX_USERNAME_LEN + 1);
+        assert!(validate_username(&long).unwrap_err().contains("too long"));
+        for name in ["al ice", "alice:", "a\u{1b}[31m", "b\nob"] {
+            assert!(validate_username(name)
+                .unwrap_err()
+                .contains("cannot contain"));
+        }
+    }
+
+    #[test]
+    fn tab_cycles_fields_and_shift_tab_goes_back() {
+        #[derive(Clone, Copy, PartialEq, Debug)]
+        enum Field {
+            Host,
+            Port,
+            Name,
+        }
+        impl FieldFocus for Field {
+            const ORDER: &'static [Field] = &[Field::Host, Field::Port, Field::Name];
+        }
+
+        let mut field = Field::Host;
+        let mut visited = Vec::new();
+        for _ in 0..3 {
+            field = field.after_key(KeyCode::Tab).unwrap();
+            visited.push(field);
+        }
+        assert_eq!(visited, [Field::Port, Field::Name, Field::Host]);
+        visited.clear();
+        for _ in 0..3 {
+            field = field.after_key(KeyCode::BackTab).unwrap();
+            visited.push(field);
+        }
+        assert_eq!(visited, [Field::Name, Field::Port, Field::Host]);
+        assert_eq!(field.after_key(KeyCode::Enter), None);
+
+        // The login form keeps what was typed in a field when focus leaves it
+        let mut app = App::new();
+        app.message_input = "alice".to_string();
+        assert!(app.switch_login_field(KeyCode::Tab));
+        assert_eq!(app.current_login_field, LoginField::Password);
+        app.message_input = "secret".to_string();
+        assert!(app.switch_login_field(KeyCode::BackTab));
+        assert_eq!(app.current_login_field, LoginField::Username);
+        assert_eq!(app.message_input, "alice");
+        assert!(!app.switch_login_field(KeyCode::Char('x')));
+        assert!(app.switch_login_field(KeyCode::BackTab));
+        assert_eq!(app.message_input, "secret");
+    }
+
+    #[test]
+    fn submitting_the_login_form_focuses_the_first_empty_field() {
+        // Nothing typed: back to the username
+        let mut app = App::new();
+        app.reset_login_form();
+        app.current_login_field = LoginField::Password;
+        app.message_input = "secret".to_string();
+        assert!(app.submit_login().is_none());
+        assert_eq!(app.current_login_field, LoginField::Username);
+        assert!(app.message_input.is_empty());
+        assert_eq!(app.password.as_deref(), Some("secret"));
+
+        // A username but no password: on to the password
+        app.message_input = "alice".to_string();
+        app.password = None;
+        assert!(app.submit_login().is_none());
+        assert_eq!(app.current_login_field, LoginField::Password);
+        assert!(app.message_input.is_empty());
+        assert!(app.is_typing);
+
+        // Both filled in: the form is sent and typing waits for the answer
+        app.message_input = "secret".to_string();
+        assert!(matches!(
+            app.submit_login(),
+            Some(MessageType::Auth { username, password, token: None })
+                if username == "alice" && password == "secret"
+        ));
+        assert_eq!(app.staging_username.as_deref(), Some("alice"));
+        assert!(!app.is_typing);
+    }
+
+    #[test]
+    fn guest_welcome_leaves_the_login_form_alone() {
+        let welcome = |name: &str| {
+            serde_json::to_string(&MessageType::Welcome {
+                your_name: name.to_string(),
+                your_color: "green".to_string(),
+            })
+            .unwrap()
+        };
+        let mut app = App::new();
+        app.reset_login_form();
+        app.handle_websocket_message(&welcome("Guest-1a2b3c4d"));
+        assert_eq!(app.username, None);
+
+        app.logged_in = true;
+        app.handle_websocket_message(&welcome("alice"));
+        assert_eq!(app.username.as_deref(), Some("alice"));
+        assert_eq!(app.user_color, Some(Color::Green));
+
+        // Spectators never log in, so they go by the guest name
+        let mut spectator = App::new();
+        spectator.spectating = true;
+        spectator.handle_websocket_message(&welcome("Guest-1a2b3c4d"));
+        assert_eq!(spectator.username.as_deref(), Some("Guest-1a2b3c4d"));
+    }
+
+    #[test]
+    fn reset_login_form_clears_both_fields() {
+        let mut app = App::new();
+        app.username = Some("alice".to_string());
+        app.staging_username = Some("alice".to_string());
+        app.password = Some("secret".to_string());
+        app.message_input = "secr".to_string();
+        app.current_login_field = LoginField::Password;
+        app.is_typing = false;
+
+        app.reset_login_form();
+        assert_eq!(app.username, None);
+        assert_eq!(app.staging_username, None);
+        assert_eq!(app.password, None);
+        assert!(app.message_input.is_empty());
+        assert_eq!(app.current_login_field, LoginField::Username);
+        assert!(app.is_typing);
+    }
+
+    #[test]
+    fn login_replies_in_chat_leave_a_logged_in_client_alone() {
+        let mut app = App::new();
+        app.remember_password = true;
+        app.message_input = "alice".to_string();
+        app.switch_login_field(KeyCode::Tab);
+        app.message_input = "secret".to_string();
+        app.submit_login();
+        app.handle_websocket_message(r#"{"SystemMessage":"Authentication successful"}"#);
+        assert_eq!(app.current_screen, CurrentScreen::Main);
+
+        // Someone sets the topic to text the login replies start with, or says it in chat
+        for text in ["Authentication failed", "Authentication successful"] {
+            let topic = MessageType::Topic(text.to_string());
+            let echoed = MessageType::SystemMessage(text.to_string());
+            for _ in 0..5 {
+                app.handle_websocket_message(&serde_json::to_string(&topic).unwrap());
+                app.handle_websocket_message(&serde_json::to_string(&echoed).unwrap());
+            }
+        }
+        assert_eq!(app.current_screen, CurrentScreen::Main);
+        assert!(app.logged_in);
+        assert_eq!(app.saved_password.as_deref(), Some("secret"));
+        assert_eq!(app.failed_login_attempts, 0);
+    }
+
+    #[test]
+    fn reauth_uses_the_remembered_password_until_it_is_refused() {
+        let mut app = App::new();
+        app.remember_password = true;
+        app.message_input = "alice".to_string();
+        app.switch_login_field(KeyCode::Tab);
+        app.message_input = "secret".to_string();
+        app.submit_login();
+        app.handle_websocket_message(r#"{"SystemMessage":"Authentication successful"}"#);
+
+        // After a reconnect we sign straight back in
+        assert!(matches!(
+            app.begin_reauth(),
+            Some(MessageType::Auth { username, password, .. })
+                if username == "alice" && password == "secret"
+        ));
+        // Once the server turns it down, the password has to be typed again
+        app.handle_websocket_message(
+            r#"{"SystemMessage":"Authentication failed. 4 attempts remaining."}"#,
+        );
+        assert_eq!(app.saved_password, None);
+        assert!(app.begin_reauth().is_none());
+        assert_eq!(app.current_login_field, LoginField::Password);
+        assert_eq!(app.username.as_deref(), Some("alice"));
+    }
+
+    #[test]
+    fn app_runs_silently_without_an_audio_device() {
+        let mut app = App::with_sound_output(None);
+        app.notify = Notify::Sound;
+        app.handle_websocket_message(
+            r#"{"ChatMessage":{"sender":"bob","content":"hi","sender_id":"bob"}}"#,
+        );
+        assert_eq!(app.messages.len(), 1);
+
+        // Picking a sound still checks it, even though there is nothing to play it on
+        let manifest = Path::new(env!("CARGO_MANIFEST_DIR"));
+        assert!(app
+            .set_sound(manifest.join("sounds/system-notification-199277.mp3"))
+            .is_ok());
+        app.play_notification_sound();
+    }
+
+    #[test]
+    fn redirects_are_validated_and_limited_until_a_login_succeeds() {
+        let mut app = App::new();
+        app.follow_redirect("http://example.com");
+        assert_eq!(app.pending_connect, None);
+        assert_eq!(app.redirects, 0);
+
+        for hop in 1..=MAX_REDIRECTS {
+            app.follow_redirect(&format!("ws://host{}.example:9000", hop));
+            assert_eq!(
+                app.pending_connect.take(),
+                Some(PendingConnect::SwitchServer)
+            );
+            assert_eq!(
+                app.selected_server,
+                Some(format!("host{}.example:9000", hop))
+            );
+        }
+        // One more in a row is refused, so servers can't bounce us between them forever
+        app.follow_redirect("ws://elsewhere.example");
+        assert_eq!(app.pending_connect, None);
+        assert!(!app.servers.contains_key("elsewhere.example"));
+
+        // Getting logged in somewhere starts the count again
+        app.handle_websocket_message(r#"{"SystemMessage":"Authentication successful"}"#);
+        app.follow_redirect("ws://elsewhere.example");
+        assert_eq!(app.pending_connect, Some(PendingConnect::SwitchServer));
+    }
+
+    #[test]
+    fn check_sound_accepts_only_playable_files() {
+        let manifest = Path::new(env!("CARGO_MANIFEST_DIR"));
+        assert_eq!(
+            check_sound(&manifest.join("sounds/system-notification-199277.mp3")),
+            Ok(())
+        );
+        let missing = check_sound(&manifest.join("sounds/missing.mp3")).unwrap_err();
+        assert!(missing.starts_with("Can't open"), "{}", missing);
+        let not_audio = check_sound(&manifest.join("Cargo.toml")).unwrap_err();
+        assert!(not_audio.starts_with("Can't play"), "{}", not_audio);
+
+        let mut app = App::new();
+        assert!(app.set_sound(manifest.join("Cargo.toml")).is_err());
+        assert!(matches!(
+            app.parse_command("/sound /home/me/My Sounds/ding.wav"),
+            Command::Sound(path) if path == "/home/me/My Sounds/ding.wav"
+        ));
+    }
+
+    #[test]
+    fn name_changes_only_when_the_server_accepts_it() {
+        let mut app = App::new();
+        app.username = Some("alice".to_string());
+        app.handle_websocket_message(
+            r#"{"CommandResult":{"command":"name","ok":false,"detail":"Name is taken"}}"#,
+        );
+        assert_eq!(app.username.as_deref(), Some("alice"));
+        assert!(matches!(
+            app.messages.last(),
+            Some(MessageType::Notice { text, .. }) if text == "Name is taken"
+        ));
+
+        app.handle_websocket_message(
+            r#"{"CommandResult":{"command":"name","ok":true,"detail":"alicia"}}"#,
+        );
+        assert_eq!(app.username.as_deref(), Some("alicia"));
+    }
+
+    #[test]
+    fn private_messages_are_filed_by_the_other_person() {
+        let mut app = App::new();
+        app.username = Some("alice".to_string());
+        let dm = |sender: &str, recipient: &str, content: &str| MessageType::PrivateMessage {
+            sender: sender.to_string(),
+            recipient: recipient.to_string(),
+            content: content.to_string(),
+        };
+
+        // Both directions of a conversation land in the same buffer
+        app.route_private_message(dm("bob", "alice", "psst"));
+        app.route_private_message(dm("alice", "bob", "what?"));
+        assert_eq!(app.dm_buffers["bob"].len(), 2);
+        assert!(app.unread_dms.contains("bob"));
+
+        // Nothing is unread in the conversation being looked at
+        app.open_direct_chat("carol".to_string());
+        app.route_private_message(dm("carol", "alice", "hi"));
+        assert_eq!(app.dm_buffers["carol"].len(), 1);
+        assert!(!app.unread_dms.contains("carol"));
+
+        // A note to ourselves is filed under our own name
+        app.route_private_message(dm("alice", "alice", "remember milk"));
+        assert_eq!(app.dm_buffers["alice"].len(), 1);
+    }
+
+    #[test]
+    fn chat_arriving_while_picking_a_server_counts_as_unread() {
+        let mut app = App::new();
+        app.active_server = Some("home".to_string());
+        let receive = |app: &mut App, content: &str| {
+            let chat = MessageType::ChatMessage {
+                sender: "bob".to_string(),
+                content: content.to_string(),
+                sender_id: "bob".to_string(),
+                client_msg_id: None,
+                message_id: None,
+                reply_to: None,
+                seq: None,
+            };
+            app.handle_websocket_message(&serde_json::to_string(&chat).unwrap());
+        };
+
+        // Messages read as they arrive aren't counted
+        app.current_screen = CurrentScreen::Main;
+        receive(&mut app, "seen");
+        assert!(app.unread_counts.is_empty());
+
+        app.current_screen = CurrentScreen::ServerSelection;
+        receive(&mut app, "one");
+        receive(&mut app, "two");
+        app.current_screen = CurrentScreen::AddServer;
+        receive(&mut app, "three");
+        assert_eq!(app.unread_counts.get("home"), Some(&3));
+
+        app.mark_active_server_read();
+        assert!(app.unread_counts.is_empty());
+    }
+
+    #[test]
+    fn renames_relabel_the_transcript_and_direct_chat() {
+        let mut app = App::new();
+        app.username = Some("alice".to_string());
+        app.account = Some("alice".to_string());
+        app.handle_websocket_message(
+            r#"{"ChatMessage":{"sender":"bob","content":"hi","sender_id":"bob"}}"#,
+        );
+        app.handle_websocket_message(
+            r#"{"PrivateMessage":{"sender":"bob","recipient":"alice","content":"psst"}}"#,
+        );
+        app.current_screen = CurrentScreen::DirectChat("bob".to_string());
+
+        app.handle_websocket_message(r#"{"Renamed":{"account":"bob","from":"bob","to":"robert"}}"#);
+        assert!(matches!(
+            app.messages.last(),
+            Some(MessageType::SystemMessage(text)) if text == "bob is now known as robert"
+        ));
+        assert!(matches!(
+            &app.messages[0],
+            MessageType::ChatMessage { sender, .. } if sender == "robert"
+        ));
+        assert!(!app.dm_buffers.contains_key("bob"));
+        assert!(matches!(
+            &app.dm_buffers["robert"][..],
+            [MessageType::PrivateMessage { sender, .. }] if sender == "robert"
+        ));
+        assert_eq!(
+            app.current_screen,
+            CurrentScreen::DirectChat("robert".to_string())
+        );
+
+        // Our own account renamed from another session renames us too
+        app.handle_websocket_message(
+            r#"{"Renamed":{"account":"alice","from":"alice","to":"alicia"}}"#,
+        );
+        assert_eq!(app.username.as_deref(), Some("alicia"));
+    }
+
+    #[test]
+    fn spinner_advances_and_wraps_while_loading() {
+        let mut app = App::new();
+        assert!(!app.loading());
+        app.current_screen = CurrentScreen::Connecting;
+        assert!(app.loading());
+
+        let frames: Vec<&str> = (0..SPINNER.len() + 1)
+            .map(|_| {
+                let frame = app.spinner();
+                app.advance_spinner();
+                frame
+            })
+            .collect();
+        assert_eq!(frames[..SPINNER.len()], SPINNER);
+        assert_eq!(frames[SPINNER.len()], SPINNER[0]);
+
+        // The login form only spins once it has been sent
+        app.current_screen = CurrentScreen::LoggingIn;
+        app.reset_login_form();
+        assert!(!app.loading());
+        app.username = Some("alice".to_string());
+        app.message_input = "secret".to_string();
+        app.current_login_field = LoginField::Password;
+        assert!(app.submit_login().is_some());
+        assert!(app.loading());
+    }
+
+    #[test]
+    fn flash_expires_after_its_duration() {
+        let mut app = App::new();
+        app.notify = Notify::Flash;
+        let now = Instant::now();
+        assert!(!app.flashing(now));
+
+        // Ordinary chat doesn't flash, a mention or DM does
+        app.notify(false);
+        assert!(app.flash_until.is_none());
+        app.notify(true);
+        assert!(app.flashing(now));
+        assert!(!app.expire_flash(now));
+
+        let later = app.flash_until.unwrap() + Duration::from_millis(1);
+        assert!(!app.flashing(later));
+        assert!(app.expire_flash(later));
+        assert!(app.flash_until.is_none());
+        assert!(!app.expire_flash(later));
+
+        assert_eq!(Notify::parse(" Bell "), Some(Notify::Bell));
+        assert_eq!(Notify::parse("loud"), None);
+    }
+
+    #[test]
+    fn mentions_me_ignores_our_own_messages() {
+        let mut app = App::new();
+        app.username = Some("alice".to_string());
+        app.account = Some("alice".to_string());
+        let message = |sender_id: &str, content: &str| MessageType::ChatMessage {
+            sender: sender_id.to_string(),
+            content: content.to_string(),
+            sender_id: sender_id.to_string(),
+            client_msg_id: None,
+            message_id: None,
+            reply_to: None,
+            seq: None,
+        };
+        assert!(app.mentions_me(&message("bob", "ping @alice")));
+        assert!(!app.mentions_me(&message("bob", "ping alicex")));
+        assert!(!app.mentions_me(&message("alice", "I'm alice")));
+    }
+
+    #[test]
+    fn transcript_text_writes_one_readable_line_per_message() {
+        let messages = vec![
+            MessageType::SystemMessage("Welcome".to_string()),
+            MessageType::ChatMessage {
+                sender: "bob".to_string(),
+                content: "hi all".to_string(),
+                sender_id: "bob".to_string(),
+                client_msg_id: None,
+                message_id: Some("m1".to_string()),
+                reply_to: None,
+                seq: None,
+            },
+            MessageType::Ack {
+                id: "c1".to_string(),
+                message_id: None,
+                seq: None,
+            },
+            MessageType::PrivateMessage {
+                sender: "alice".to_string(),
+                recipient: "bob".to_string(),
+                content: "psst".to_string(),
+            },
+            MessageType::notice(Severity::Error, "Not sent"),
+            MessageType::UserList(vec!["alice".to_string(), "bob".to_string()]),
+        ];
+        assert_eq!(
+            transcript_text(&messages),
+            "* Welcome\nbob: hi all\n[DM alice -> bob] psst\n* error: Not sent\n* alice, bob\n"
+        );
+        let json: Vec<MessageType> = serde_json::from_str(&transcript_json(&messages)).unwrap();
+        assert_eq!(json.len(), 5);
+        assert!(transcript_text(&[]).is_empty());
+    }
+
+    #[test]
+    fn welcome_wizard_produces_a_config_and_applies_it() {
+        let mut app = App::new();
+        app.start_welcome();
+
+        app.message_input = "bad name!".to_string();
+        assert_eq!(app.submit_welcome_step(), None);
+        assert_eq!(app.welcome_step, WelcomeStep::Username);
+        assert!(app.welcome_error.is_some());
+
+        app.message_input = " alice ".to_string();
+        assert_eq!(app.submit_welcome_step(), None);
+        assert_eq!(app.welcome_step, WelcomeStep::Server);
+        assert!(app.message_input.is_empty());
+        assert_eq!(app.welcome_error, None);
+
+        app.message_input = "http://chat.example.com".to_string();
+        assert_eq!(app.submit_welcome_step(), None);
+        assert!(app.welcome_error.is_some());
+
+        app.message_input = "ws://chat.example.com:9000".to_string();
+        let config = app.submit_welcome_step().unwrap();
+        assert_eq!(
+            config,
+            ClientConfig {
+                default_username: Some("alice".to_string()),
+                servers: vec![ServerEntry {
+                    name: "chat.example.com:9000".to_string(),
+                    url: "ws://chat.example.com:9000/".to_string(),
+                }],
+                ..Default::default()
+            }
+        );
+        assert_eq!(app.current_screen, ServerSelection);
+        assert_eq!(
+            app.selected_server.as_deref(),
+            Some("chat.example.com:9000")
+        );
+        assert_eq!(app.default_username.as_deref(), Some("alice"));
+    }
+
+    #[test]
+    fn history_reply_replaces_the_transcript() {
+        let mut app = App::new();
+        app.messages = vec![MessageType::SystemMessage("garbled".to_string())];
+        app.apply_reaction("old".to_string(), "bob".to_string(), "👍".to_string());
+        let history = vec![
+            MessageType::ChatMessage {
+                sender: "bob".to_string(),
+                content: "first".to_string(),
+                sender_id: "bob".to_string(),
+                client_msg_id: None,
+                message_id: Some("m1".to_string()),
+                reply_to: None,
+                seq: None,
+            },
+            MessageType::Reaction {
+                message_id: "m1".to_string(),
+                user: "carol".to_string(),
+                emoji: "🎉".to_string(),
+            },
+        ];
+        let reply = serde_json::to_string(&MessageType::History(history)).unwrap();
+        app.handle_websocket_message(&reply);
+
+        assert_eq!(app.messages.len(), 2);
+        assert!(
+            matches!(&app.messages[0], MessageType::ChatMessage { content, .. } if content == "first")
+        );
+        assert!(
+            matches!(&app.messages[1], MessageType::SystemMessage(text) if text == "History reloaded: 1 messages")
+        );
+        assert!(!app.reactions.contains_key("old"));
+        assert_eq!(
+            app.reactions["m1"],
+            vec![("carol".to_string(), "🎉".to_string())]
+        );
+        assert!(matches!(app.parse_command("/history"), Command::History));
+        assert!(matches!(app.parse_command("/status"), Command::Status));
+    }
+
+    #[test]
+    fn reply_resolves_a_unique_id_prefix() {
+        let mut app = App::new();
+        for id in ["abc123", "abd456"] {
+            app.messages.push(MessageType::ChatMessage {
+                sender: "bob".to_string(),
+                content: id.to_string(),
+                sender_id: "bob".to_string(),
+                client_msg_id: None,
+                message_id: Some(id.to_string()),
+                reply_to: None,
+                seq: None,
+            });
+        }
+        assert!(matches!(
+            app.parse_command("/reply abc sounds good"),
+            Command::Reply(id, text) if id == "abc" && text == "sounds good"
+        ));
+        assert!(matches!(
+            app.parse_command("/reply abc"),
+            Command::Unknown(_)
+        ));
+        assert_eq!(app.resolve_message_id("abc"), Some("abc123".to_string()));
+        assert_eq!(app.resolve_message_id("ab"), None);
+        assert_eq!(app.resolve_message_id("zzz"), None);
+
+        // A reply names its parent by the number the server gave it
+        assert_eq!(app.reply_target("abc"), None);
+        if let MessageType::ChatMessage { seq, .. } = &mut app.messages[0] {
+            *seq = Some(7);
         }
+        assert_eq!(app.reply_target("abc"), Some(7));
+        assert!(matches!(
+            app.message_by_seq(7),
+            Some(MessageType::ChatMessage { content, .. }) if content == "abc123"
+        ));
     }
 }
@@ -0,0 +1,122 @@
+//  This file contains `ClientConfig`, the settings the client keeps between runs: the name to
+//  log in with, the user's own servers and their notification sound. It is saved as JSON, and
+//  written by the first-run wizard when it doesn't exist yet.
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct ClientConfig {
+    #[serde(default)]
+    pub default_username: Option<String>, // filled into the login form
+    #[serde(default)]
+    pub servers: Vec<ServerEntry>, // shown in the server list next to the built-in ones
+    #[serde(default)]
+    pub removed_servers: Vec<String>, // built-in servers the user deleted from the list
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sound: Option<PathBuf>, // notification sound picked with /sound, instead of the bundled one
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ServerEntry {
+    pub name: String,
+    pub url: String,
+}
+
+// Where the config lives: CLIENT_CONFIG, or terminal_messenger/config.json in the user's config
+// directory. None when there is no home directory to put it in.
+pub fn config_path() -> Option<PathBuf> {
+    if let Some(path) = std::env::var_os("CLIENT_CONFIG") {
+        return Some(PathBuf::from(path));
+    }
+    let config_dir = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+        .or_else(|| std::env::var_os("APPDATA").map(PathBuf::from))?;
+    Some(config_dir.join("terminal_messenger").join("config.json"))
+}
+
+// Read the config, or None when it hasn't been written yet, i.e. on the first run
+pub fn load(path: &Path) -> io::Result<Option<ClientConfig>> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    serde_json::from_str(&text)
+        .map(Some)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+impl ClientConfig {
+    // Forget a server. Built-in ones aren't in the file, so they are remembered as removed.
+    pub fn remove_server(&mut self, name: &str) {
+        let saved = self.servers.len();
+        self.servers.retain(|entry| entry.name != name);
+        if self.servers.len() == saved && !self.removed_servers.iter().any(|n| n == name) {
+            self.removed_servers.push(name.to_string());
+        }
+    }
+}
+
+pub fn save(path: &Path, config: &ClientConfig) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(path, serde_json::to_string_pretty(config).unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path() -> PathBuf {
+        std::env::temp_dir()
+            .join(format!("terminal_messenger-{}", uuid::Uuid::new_v4()))
+            .join("config.json")
+    }
+
+    #[test]
+    fn missing_config_means_first_run() {
+        let path = temp_path();
+        assert_eq!(load(&path).unwrap(), None);
+
+        let config = ClientConfig {
+            default_username: Some("alice".to_string()),
+            servers: vec![ServerEntry {
+                name: "home".to_string(),
+                url: "ws://home.example:8080/".to_string(),
+            }],
+            ..Default::default()
+        };
+        save(&path, &config).unwrap();
+        assert_eq!(load(&path).unwrap(), Some(config));
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn removing_a_built_in_server_is_remembered() {
+        let mut config = ClientConfig {
+            servers: vec![ServerEntry {
+                name: "home".to_string(),
+                url: "ws://home.example:8080/".to_string(),
+            }],
+            ..Default::default()
+        };
+        config.remove_server("home");
+        config.remove_server("local");
+        config.remove_server("local");
+        assert!(config.servers.is_empty());
+        assert_eq!(config.removed_servers, vec!["local".to_string()]);
+    }
+
+    #[test]
+    fn broken_config_is_an_error_not_a_first_run() {
+        let path = temp_path();
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, "{ not json").unwrap();
+        assert!(load(&path).is_err());
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
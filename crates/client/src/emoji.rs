@@ -0,0 +1,117 @@
+// Expanding emoji shortcodes such as `:smile:` in outgoing messages.
+
+// Shortcodes understood by `expand_emoji`, without the surrounding colons
+const SHORTCODES: &[(&str, &str)] = &[
+    ("smile", "😄"),
+    ("grin", "😁"),
+    ("joy", "😂"),
+    ("wink", "😉"),
+    ("blush", "😊"),
+    ("heart_eyes", "😍"),
+    ("thinking", "🤔"),
+    ("neutral_face", "😐"),
+    ("sweat_smile", "😅"),
+    ("cry", "😢"),
+    ("sob", "😭"),
+    ("angry", "😠"),
+    ("sunglasses", "😎"),
+    ("scream", "😱"),
+    ("wave", "👋"),
+    ("clap", "👏"),
+    ("pray", "🙏"),
+    ("ok_hand", "👌"),
+    ("+1", "👍"),
+    ("thumbsup", "👍"),
+    ("-1", "👎"),
+    ("thumbsdown", "👎"),
+    ("eyes", "👀"),
+    ("heart", "❤️"),
+    ("fire", "🔥"),
+    ("star", "⭐"),
+    ("sparkles", "✨"),
+    ("tada", "🎉"),
+    ("rocket", "🚀"),
+    ("coffee", "☕"),
+    ("pizza", "🍕"),
+    ("beer", "🍺"),
+    ("100", "💯"),
+    ("check", "✅"),
+    ("x", "❌"),
+    ("warning", "⚠️"),
+    ("bug", "🐛"),
+    ("crab", "🦀"),
+];
+
+// Reactions picked with the number keys 1-6 after pressing 'r' on a selected message
+pub const REACTIONS: [&str; 6] = ["👍", "❤️", "😂", "😮", "😢", "🎉"];
+
+// Replace every known `:shortcode:` with its emoji. Unknown codes, and colons that don't start a
+// code at all (times, "note: ..."), are left exactly as typed.
+pub fn expand_emoji(text: &str) -> String {
+    let mut expanded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find(':') {
+        expanded.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let emoji = after
+            .find(':')
+            .and_then(|end| lookup(&after[..end]).map(|emoji| (emoji, end)));
+        match emoji {
+            Some((emoji, end)) => {
+                expanded.push_str(emoji);
+                rest = &after[end + 1..];
+            }
+            // Keep the colon; it may still close a code that starts later
+            None => {
+                expanded.push(':');
+                rest = after;
+            }
+        }
+    }
+    expanded.push_str(rest);
+    expanded
+}
+
+fn lookup(code: &str) -> Option<&'static str> {
+    SHORTCODES
+        .iter()
+        .find(|(shortcode, _)| *shortcode == code)
+        .map(|(_, emoji)| *emoji)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn known_codes_are_replaced() {
+        assert_eq!(expand_emoji(":smile:"), "😄");
+        assert_eq!(expand_emoji("ship it :rocket: :+1:"), "ship it 🚀 👍");
+        assert_eq!(expand_emoji(":crab::crab:"), "🦀🦀");
+    }
+
+    #[test]
+    fn unknown_codes_are_left_as_typed() {
+        assert_eq!(expand_emoji(":nope:"), ":nope:");
+        assert_eq!(expand_emoji(":Smile:"), ":Smile:");
+        assert_eq!(expand_emoji("a : b"), "a : b");
+        assert_eq!(expand_emoji("trailing:"), "trailing:");
+    }
+
+    #[test]
+    fn codes_next_to_punctuation_are_replaced() {
+        assert_eq!(expand_emoji("(:tada:)"), "(🎉)");
+        assert_eq!(expand_emoji("done:check:, thanks!"), "done✅, thanks!");
+        assert_eq!(expand_emoji("note: :smile:!"), "note: 😄!");
+    }
+
+    #[test]
+    fn a_colon_that_starts_no_code_may_still_close_one() {
+        // Each unmatched colon is kept and scanning resumes from it
+        assert_eq!(expand_emoji("::smile::"), ":😄:");
+        assert_eq!(expand_emoji("10:30 :fire:"), "10:30 🔥");
+        // Digits between colons are codes too, so times can run into them
+        assert_eq!(expand_emoji("10:30:x:"), "10:30❌");
+        assert_eq!(expand_emoji("1:100:1"), "1💯1");
+    }
+}
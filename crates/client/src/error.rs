@@ -0,0 +1,29 @@
+//  This file contains `ClientError`, the error type of the client's connect and run paths, so
+//  callers can tell a server that can't be reached from a broken terminal.
+use std::io;
+use thiserror::Error;
+use tokio_tungstenite::tungstenite;
+
+#[derive(Debug, Error)]
+pub enum ClientError {
+    // The server couldn't be reached, or the websocket handshake failed or timed out
+    #[error("Could not connect: {0}")]
+    Connect(String),
+    // The websocket broke after connecting; boxed, as tungstenite's error is large
+    #[error("Connection error: {0}")]
+    Protocol(Box<tungstenite::Error>),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    // The server turned our login down for good
+    #[error("{0}")]
+    Auth(String),
+    // The terminal couldn't be set up, drawn on or restored
+    #[error("Terminal error: {0}")]
+    Terminal(io::Error),
+}
+
+impl From<tungstenite::Error> for ClientError {
+    fn from(err: tungstenite::Error) -> ClientError {
+        ClientError::Protocol(Box::new(err))
+    }
+}
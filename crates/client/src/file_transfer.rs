@@ -0,0 +1,171 @@
+// Splitting files into base64 `FileChunk` messages for sending, and putting received chunks
+// back together.
+use crate::app::MessageType;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+// Raw bytes carried by each chunk, before base64 encoding
+pub const CHUNK_SIZE: usize = 16 * 1024;
+// Largest file that can be sent. The server refuses chunks beyond these limits with its own copy
+// of both numbers (in its websocket.rs), so they have to change together.
+pub const MAX_FILE_SIZE: usize = 1024 * 1024;
+// Most chunks a single file may be split into
+pub const MAX_CHUNKS: u32 = (MAX_FILE_SIZE / CHUNK_SIZE) as u32;
+
+// Split a file's bytes into the chunk messages that make up one transfer
+pub fn chunk_file(id: &str, name: &str, bytes: &[u8]) -> Result<Vec<MessageType>, String> {
+    if bytes.len() > MAX_FILE_SIZE {
+        return Err(format!(
+            "{} is too large to send ({} bytes, limit {})",
+            name,
+            bytes.len(),
+            MAX_FILE_SIZE
+        ));
+    }
+    // Empty files still need one (empty) chunk so the receiver sees them
+    let chunks: Vec<&[u8]> = if bytes.is_empty() {
+        vec![&[]]
+    } else {
+        bytes.chunks(CHUNK_SIZE).collect()
+    };
+    let total = chunks.len() as u32;
+    Ok(chunks
+        .into_iter()
+        .enumerate()
+        .map(|(seq, chunk)| MessageType::FileChunk {
+            id: id.to_string(),
+            name: name.to_string(),
+            seq: seq as u32,
+            total,
+            data: STANDARD.encode(chunk),
+            sender: String::new(),
+        })
+        .collect())
+}
+
+// A file being received, filled in as its chunks arrive
+pub struct IncomingFile {
+    pub name: String,
+    pub sender: String,
+    chunks: Vec<Option<Vec<u8>>>,
+}
+
+impl IncomingFile {
+    pub fn new(name: String, sender: String, total: u32) -> IncomingFile {
+        IncomingFile {
+            name,
+            sender,
+            chunks: vec![None; total as usize],
+        }
+    }
+
+    // Store one chunk; out-of-range or undecodable chunks are rejected
+    pub fn add_chunk(&mut self, seq: u32, data: &str) -> Result<(), String> {
+        let bytes = STANDARD
+            .decode(data)
+            .map_err(|e| format!("Invalid data in {}: {}", self.name, e))?;
+        match self.chunks.get_mut(seq as usize) {
+            Some(slot) => {
+                *slot = Some(bytes);
+                Ok(())
+            }
+            None => Err(format!("Unexpected chunk {} for {}", seq, self.name)),
+        }
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.chunks.iter().all(Option::is_some)
+    }
+
+    // The whole file, once every chunk has arrived
+    pub fn assemble(&self) -> Option<Vec<u8>> {
+        if !self.is_complete() {
+            return None;
+        }
+        Some(self.chunks.iter().flatten().flatten().copied().collect())
+    }
+}
+
+// Directory received files are saved to (DOWNLOAD_DIR, or ./downloads)
+pub fn download_dir() -> PathBuf {
+    std::env::var("DOWNLOAD_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| PathBuf::from("downloads"))
+}
+
+// Save a received file under the download directory and return where it went.
+// Only the final path component of the sender's name is used, so a file can't escape the directory,
+// and a file already there is kept by saving the new one as "name (1).ext", "name (2).ext"...
+pub fn save_file(dir: &Path, name: &str, bytes: &[u8]) -> io::Result<PathBuf> {
+    let file_name = Path::new(name)
+        .file_name()
+        .map(Path::new)
+        .unwrap_or(Path::new("download"));
+    fs::create_dir_all(dir)?;
+    for copy in 0.. {
+        let path = match copy {
+            0 => dir.join(file_name),
+            _ => dir.join(numbered_name(file_name, copy)),
+        };
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(mut file) => {
+                file.write_all(bytes)?;
+                return Ok(path);
+            }
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    unreachable!("ran out of file names")
+}
+
+// "notes (2).txt" for the second copy of "notes.txt"
+fn numbered_name(file_name: &Path, copy: u32) -> String {
+    let stem = file_name.file_stem().unwrap_or_default().to_string_lossy();
+    match file_name.extension() {
+        Some(extension) => format!("{} ({}).{}", stem, copy, extension.to_string_lossy()),
+        None => format!("{} ({})", stem, copy),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunks_reassemble_into_the_original_file() {
+        let bytes: Vec<u8> = (0..CHUNK_SIZE * 2 + 10).map(|i| i as u8).collect();
+        let chunks = chunk_file("t1", "data.bin", &bytes).unwrap();
+        assert_eq!(chunks.len(), 3);
+
+        let mut incoming = IncomingFile::new("data.bin".to_string(), "bob".to_string(), 3);
+        // Chunks may arrive in any order
+        for chunk in chunks.iter().rev() {
+            let MessageType::FileChunk { seq, data, .. } = chunk else {
+                panic!("not a chunk");
+            };
+            assert!(!incoming.is_complete());
+            incoming.add_chunk(*seq, data).unwrap();
+        }
+        assert_eq!(incoming.assemble(), Some(bytes));
+        assert!(incoming.add_chunk(3, "").is_err());
+
+        let too_big = vec![0; MAX_FILE_SIZE + 1];
+        assert!(chunk_file("t2", "big.bin", &too_big).is_err());
+    }
+
+    #[test]
+    fn saving_keeps_files_already_downloaded() {
+        let dir = std::env::temp_dir().join(format!("file-transfer-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let first = save_file(&dir, "../notes.txt", b"one").unwrap();
+        let second = save_file(&dir, "notes.txt", b"two").unwrap();
+        assert_eq!(first, dir.join("notes.txt"));
+        assert_eq!(second, dir.join("notes (1).txt"));
+        assert_eq!(fs::read(&first).unwrap(), b"one");
+        assert_eq!(fs::read(&second).unwrap(), b"two");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -10,48 +10,100 @@ use ratatui::{
     Terminal,
 };
 use std::io as err_io;
+use std::time::{Duration, Instant};
 use tokio::io::{self};
 use tokio::select;
 use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
 use tokio_tungstenite::tungstenite::Message;
 use url::Url;
+use uuid::Uuid;
 
 mod app;
+mod config;
+mod emoji;
+mod error;
+mod file_transfer;
+mod theme;
 mod ui;
 mod websocket;
-use crate::app::{App, Command, CurrentScreen, LoginField, MessageType};
-use crate::event::MouseEvent;
-use crate::event::MouseEventKind;
+use crate::app::{
+    check_username_char, parse_server_input, pop_grapheme, server_entry_from_arg,
+    validate_username, App, Command, CurrentScreen, MessageType, PendingConnect, Severity,
+    Transition, MAX_MESSAGE_LEN, SPINNER_INTERVAL,
+};
+use crate::error::ClientError;
 use crate::ui::ui;
-use websocket::{connect_to_server, handle_websocket};
+use websocket::{connect_to_server, handle_websocket, KeepAlive};
+
+// A connection attempt running in the background, so the screen keeps updating and Esc can
+// cancel it
+struct Connecting {
+    kind: PendingConnect,
+    task: JoinHandle<Result<websocket::WsStream, ClientError>>,
+}
 #[tokio::main]
 async fn main() {
     env_logger::init();
 
-    if let Err(e) = launch_tui().await {
-        eprintln!("Error launching TUI: {:?}", e);
+    // An optional positional server URL skips the server selection screen
+    let server_arg = std::env::args().skip(1).find(|arg| !arg.starts_with("--"));
+    let server_entry = match server_arg.as_deref().map(server_entry_from_arg) {
+        Some(Ok(entry)) => Some(entry),
+        Some(Err(e)) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+        None => None,
+    };
+
+    if let Err(e) = launch_tui(server_entry).await {
+        eprintln!("Error launching TUI: {}", e);
     }
-    return;
 }
 
-async fn launch_tui() -> Result<(), Box<dyn std::error::Error>> {
+async fn launch_tui(server_entry: Option<(String, Url)>) -> Result<(), ClientError> {
     // setup terminal
-    enable_raw_mode().map_err(Box::new)?;
+    enable_raw_mode().map_err(ClientError::Terminal)?;
     let mut stdout = err_io::stderr();
-    execute!(stdout, EnterAlternateScreen, EnableMouseCapture)?;
+    execute!(stdout, EnterAlternateScreen, EnableMouseCapture).map_err(ClientError::Terminal)?;
     let backend = CrosstermBackend::new(stdout);
-    let mut terminal = Terminal::new(backend)?;
+    let mut terminal = Terminal::new(backend).map_err(ClientError::Terminal)?;
 
     let mut app = App::new();
+    app.spectating = std::env::args().any(|arg| arg == "--spectate");
+    app.remember_password = std::env::args().any(|arg| arg == "--remember-password");
+    let auto_connect = server_entry.is_some();
+
+    // Saved settings, or the setup wizard on the first run unless a server was given to join
+    let config_path = config::config_path();
+    match config_path.as_deref().map(config::load) {
+        Some(Ok(Some(config))) => app.apply_config(&config),
+        Some(Ok(None)) if !auto_connect => app.start_welcome(),
+        Some(Ok(None)) | None => {}
+        Some(Err(e)) => log::warn!("Could not read the client config: {}", e),
+    }
+
+    if let Some((name, url)) = server_entry {
+        app.servers.insert(name.clone(), url);
+        app.selected_server = Some(name);
+    }
 
     // Create a channel for handling input events asynchronously
     let (tx, mut rx) = mpsc::channel(100);
 
-    // Spawn a task to read input events asynchronously
+    // Spawn a task to read input events asynchronously. It stops if the terminal can't be read
+    // any more, which closes the channel and shuts the app down.
     tokio::spawn(async move {
         loop {
-            if let Ok(event) = event::read() {
-                if tx.send(event).await.is_err() {
+            match event::read() {
+                Ok(event) => {
+                    if tx.send(event).await.is_err() {
+                        break;
+                    }
+                }
+                Err(e) => {
+                    log::error!("Failed to read terminal input: {:?}", e);
                     break;
                 }
             }
@@ -59,22 +111,29 @@ async fn launch_tui() -> Result<(), Box<dyn std::error::Error>> {
     });
 
     // Start running the app
-    match run_app(&mut terminal, &mut app, &mut rx).await {
-        Ok(result) => result,
-        Err(err) => {
-            log::error!("Error running app: {:?}", err);
-            std::process::exit(1);
-        }
-    };
-
-    // Restore terminal state
-    disable_raw_mode()?;
+    let result = run_app(
+        &mut terminal,
+        &mut app,
+        &mut rx,
+        auto_connect,
+        config_path.as_deref(),
+    )
+    .await;
+
+    // Restore terminal state, however the app stopped
+    disable_raw_mode().map_err(ClientError::Terminal)?;
     execute!(
         terminal.backend_mut(),
         LeaveAlternateScreen,
         DisableMouseCapture
-    )?;
-    terminal.show_cursor()?;
+    )
+    .map_err(ClientError::Terminal)?;
+    terminal.show_cursor().map_err(ClientError::Terminal)?;
+
+    if let Err(err) = result {
+        log::error!("Error running app: {}", err);
+        std::process::exit(1);
+    }
 
     Ok(())
 }
@@ -83,38 +142,100 @@ async fn run_app<B: Backend>(
     terminal: &mut Terminal<B>,
     app: &mut App,
     rx: &mut mpsc::Receiver<Event>,
-) -> io::Result<bool> {
-    // Set the initial state to ServerSelection
-    app.current_screen = CurrentScreen::ServerSelection;
+    auto_connect: bool,
+    config_path: Option<&std::path::Path>,
+) -> Result<bool, ClientError> {
+    // Set the initial state to ServerSelection, unless the first-run wizard comes first
+    if app.current_screen != CurrentScreen::Welcome {
+        app.current_screen = CurrentScreen::ServerSelection;
+    }
     terminal
         .draw(|f| ui(f, app))
-        .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+        .map_err(ClientError::Terminal)?;
 
     // Define `write` and `read` as Options, initially set to `None`
     let mut write: Option<futures_util::stream::SplitSink<websocket::WsStream, Message>> = None;
     let mut read: Option<futures_util::stream::SplitStream<websocket::WsStream>> = None;
 
+    let mut connecting: Option<Connecting> = None;
+    let mut keep_alive = KeepAlive::from_env();
+
+    // Connect straight away when a server was given on the command line
+    if auto_connect {
+        app.pending_connect = Some(PendingConnect::SwitchServer);
+    }
+
+    // Periodic tick for time-based state such as message acknowledgement timeouts, and a
+    // faster one that animates the spinner while connecting or logging in
+    let mut tick = tokio::time::interval(Duration::from_secs(1));
+    let mut spinner_tick = tokio::time::interval(SPINNER_INTERVAL);
+
     loop {
         select! {
+            _ = tick.tick() => {
+                let now = Instant::now();
+                let expired = app.expire_pending(now);
+                if app.expire_flash(now) || expired {
+                    terminal.draw(|f| ui(f, app)).map_err(ClientError::Terminal)?;
+                }
+            }
+
+            _ = spinner_tick.tick(), if app.loading() => {
+                app.advance_spinner();
+                terminal.draw(|f| ui(f, app)).map_err(ClientError::Terminal)?;
+            }
+
             // Handle WebSocket messages if connection exists
             ws_res = async {
                 if let (Some(write_ref), Some(read_ref)) = (write.as_mut(), read.as_mut()) {
-                    handle_websocket(app, terminal, write_ref, read_ref).await
+                    handle_websocket(app, terminal, write_ref, read_ref, &mut keep_alive).await
                 } else {
                     Ok(())  // Skip handling if no WebSocket connection exists
                 }
             }, if write.is_some() && read.is_some() => {
                 if let Err(ws_err) = ws_res {
                     log::error!("WebSocket error: {:?}", ws_err);
-                    app.current_screen = CurrentScreen::Disconnected;
-                    write = None;  // Set streams to None on disconnection
+                    app.set_disconnected(ws_err.to_string());
+                }
+                // However the connection ended, stop polling its streams
+                if app.current_screen == CurrentScreen::Disconnected {
+                    write = None;
                     read = None;
-                    terminal.draw(|f| ui(f, app))?;
+                    terminal.draw(|f| ui(f, app)).map_err(ClientError::Terminal)?;
                 }
             }
 
+            // Pick up the connection once the attempt in the background finishes
+            result = async {
+                match connecting.as_mut() {
+                    Some(attempt) => (&mut attempt.task).await,
+                    None => std::future::pending().await,
+                }
+            }, if connecting.is_some() => {
+                let kind = connecting.take().map_or(PendingConnect::SwitchServer, |attempt| attempt.kind);
+                let connected = match result {
+                    Ok(Ok(ws_stream)) => finish_connect(app, kind, ws_stream).await,
+                    Ok(Err(e)) => Err(e),
+                    Err(e) => Err(ClientError::Connect(e.to_string())),
+                };
+                match connected {
+                    Ok((new_write, new_read)) => {
+                        write = Some(new_write);
+                        read = Some(new_read);
+                        keep_alive.reset(tokio::time::Instant::now());
+                    }
+                    Err(e) => app.set_disconnected(e.to_string()),
+                }
+                terminal.draw(|f| ui(f, app)).map_err(ClientError::Terminal)?;
+            }
+
             // Handle user input events
-            Some(event) = rx.recv() => {
+            event = rx.recv() => {
+                // Without an input reader nothing can be typed, so quit rather than hang
+                let Some(event) = event else {
+                    log::error!("Input reader stopped, shutting down");
+                    break Ok(false);
+                };
                 if let Event::Key(key) = event {
                     if key.kind == KeyEventKind::Release {
                         continue;
@@ -123,7 +244,7 @@ async fn run_app<B: Backend>(
                     match app.current_screen {
                         CurrentScreen::ServerSelection => {
                             // Handle server selection input, and connect to the selected server afterward
-                           if handle_server_selection_input(key.code, app, &mut write, &mut read, terminal).await? {
+                           if handle_server_selection_input(key.code, app, &mut write, &mut read, terminal, config_path).await? {
                                 // After the user selects a server, attempt to connect
 
                             }
@@ -131,6 +252,7 @@ async fn run_app<B: Backend>(
                         CurrentScreen::AddServer => {
                            handle_add_server_input(key.code, app).await?;
                         }
+                        CurrentScreen::Welcome => handle_welcome_input(key.code, app, config_path),
 
                         // Handle other screens only if WebSocket streams are initialized
                         CurrentScreen::LoggingIn => {
@@ -139,10 +261,10 @@ async fn run_app<B: Backend>(
                                 handle_login_input(key.code, app, write_stream).await?;
                             }
                         }
-                        CurrentScreen::Main => handle_main_input(key.code, app).await,
+                        CurrentScreen::Main => handle_main_input(key.code, app, write.as_mut()).await?,
                         CurrentScreen::ComposingMessage => {
                             if let Some(ref mut write_stream) = write {
-                                handle_composing_message_input(key.code, app, write_stream).await?;
+                                handle_composing_message_input(key.code, app, write_stream, config_path).await?;
                             }
                         }
                         CurrentScreen::SetUser => {
@@ -151,6 +273,15 @@ async fn run_app<B: Backend>(
                             }
                         }
                         CurrentScreen::HelpMenu => handle_help_menu_input(key.code, app).await?,
+                        CurrentScreen::AwayList => handle_away_list_input(key.code, app).await?,
+                        CurrentScreen::UserList => handle_user_list_input(key.code, app),
+                        CurrentScreen::Search => handle_search_input(key.code, app),
+                        CurrentScreen::DirectChat(ref peer) => {
+                            let peer = peer.clone();
+                            if let Some(ref mut write_stream) = write {
+                                handle_direct_chat_input(key.code, app, &peer, write_stream).await?;
+                            }
+                        }
                         CurrentScreen::Exiting => {
                             if handle_exiting_input(key.code, app).await? {
                                 break Ok(false);
@@ -162,138 +293,247 @@ async fn run_app<B: Backend>(
                             }
                         }
                         CurrentScreen::Disconnected => {
-                                handle_disconnected_input(key.code, app, terminal, &mut write, &mut read).await?;
+                            if handle_disconnected_input(key.code, app) {
+                                break Ok(false);
+                            }
+                        }
+                        CurrentScreen::Connecting => {
+                            // Esc gives up on the attempt and goes back to choosing a server
+                            if let Transition::Go(screen) = app.on_key(key.code) {
+                                if let Some(attempt) = connecting.take() {
+                                    attempt.task.abort();
+                                }
+                                app.current_screen = screen;
+                            }
                         }
                     }
 
-                    terminal.draw(|f| ui(f, app)).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+                    // Drop the streams of a connection that died while handling input
+                    if app.current_screen == CurrentScreen::Disconnected {
+                        write = None;
+                        read = None;
+                    }
+
+                    terminal.draw(|f| ui(f, app)).map_err(ClientError::Terminal)?;
                 } else if let Event::Resize(_, _) = event {
-                    terminal.draw(|f| ui(f, app)).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+                    terminal.draw(|f| ui(f, app)).map_err(ClientError::Terminal)?;
                 }
             }
         }
+
+        // Start connection changes asked for by a key, a command or the server, replacing any
+        // attempt still running
+        if let Some(kind) = app.pending_connect.take() {
+            if let Some(attempt) = connecting.take() {
+                attempt.task.abort();
+            }
+            write = None;
+            read = None;
+            match start_connect(app, kind) {
+                Ok(attempt) => connecting = Some(attempt),
+                Err(e) => app.set_disconnected(e.to_string()),
+            }
+            terminal
+                .draw(|f| ui(f, app))
+                .map_err(ClientError::Terminal)?;
+        }
     }
 }
 
 async fn handle_add_server_input(key: KeyCode, app: &mut App) -> io::Result<bool> {
+    // Esc cancels and goes back
+    if let Transition::Go(screen) = app.on_key(key) {
+        app.current_screen = screen;
+        app.message_input.clear(); // so it isn't mistaken for a draft
+        return Ok(false);
+    }
+
     match key {
         KeyCode::Enter => {
-            if app.message_input.contains(':') {
-                // Add a new server if the input contains "name:url"
-                let parts: Vec<&str> = app.message_input.splitn(2, ':').collect();
-                if let Ok(url) = Url::parse(parts[1]) {
-                    app.servers.insert(parts[0].to_string(), url);
+            // Add a new server from "name:url", or from a pasted invite link
+            match parse_server_input(&app.message_input) {
+                Ok((name, url)) => {
+                    app.servers.insert(name.clone(), url);
+                    app.selected_server = Some(name);
                 }
-                app.message_input.clear();
+                Err(reason) => log::warn!("Not adding server: {}", reason),
             }
+            app.message_input.clear();
         }
         KeyCode::Backspace => {
-            app.message_input.pop(); // Handle backspace to delete characters
+            pop_grapheme(&mut app.message_input); // Handle backspace to delete characters
         }
         KeyCode::Char(c) => {
             app.message_input.push(c); // Add character to input
         }
-        KeyCode::Esc => {
-            app.current_screen = CurrentScreen::ServerSelection; // Cancel add_server input and go back
-        }
-
         _ => {}
     }
 
     Ok(false) // Return false if no valid server is selected
 }
 
+// Take a deleted server out of the saved config too, so it doesn't come back next time
+fn forget_server(path: &std::path::Path, name: &str) {
+    let result = config::load(path).and_then(|config| {
+        let mut config = config.unwrap_or_default();
+        config.remove_server(name);
+        config::save(path, &config)
+    });
+    if let Err(e) = result {
+        log::warn!("Could not save the client config: {}", e);
+    }
+}
+
+// Save the notification sound picked with /sound, so it is used next time too
+fn remember_sound(path: &std::path::Path, sound: &str) {
+    let result = config::load(path).and_then(|config| {
+        let mut config = config.unwrap_or_default();
+        config.sound = Some(std::path::PathBuf::from(sound));
+        config::save(path, &config)
+    });
+    if let Err(e) = result {
+        log::warn!("Could not save the client config: {}", e);
+    }
+}
+
+fn handle_welcome_input(key: KeyCode, app: &mut App, config_path: Option<&std::path::Path>) {
+    // Esc skips setup; nothing is saved, so it comes back next time
+    if let Transition::Go(screen) = app.on_key(key) {
+        app.current_screen = screen;
+        app.message_input.clear();
+        return;
+    }
+
+    match key {
+        KeyCode::Enter => {
+            let (Some(config), Some(path)) = (app.submit_welcome_step(), config_path) else {
+                return;
+            };
+            if let Err(e) = config::save(path, &config) {
+                log::warn!("Could not save the client config: {}", e);
+                app.messages.push(MessageType::notice(
+                    Severity::Error,
+                    format!("Could not save settings to {}: {}", path.display(), e),
+                ));
+            }
+        }
+        KeyCode::Backspace => {
+            pop_grapheme(&mut app.message_input);
+        }
+        KeyCode::Char(c) => {
+            app.message_input.push(c);
+        }
+        _ => {}
+    }
+}
+
+// Start connecting to the selected server in the background and show the Connecting screen
+fn start_connect(app: &mut App, kind: PendingConnect) -> Result<Connecting, ClientError> {
+    let url = app
+        .selected_server
+        .as_ref()
+        .and_then(|name| app.servers.get(name))
+        .cloned()
+        .ok_or_else(|| ClientError::Connect("No server selected".to_string()))?;
+
+    // The login form reuses the input box, so set aside anything still being typed
+    app.stash_draft();
+
+    // Keep each server's scrollback separate
+    if kind == PendingConnect::SwitchServer {
+        if let Some(server) = app.selected_server.clone() {
+            app.switch_transcript(&server);
+        }
+    }
+    app.current_screen = CurrentScreen::Connecting;
+    Ok(Connecting {
+        kind,
+        task: tokio::spawn(connect_to_server(url)),
+    })
+}
+
+// Set up a new connection: spectators go straight to chat, a reconnect logs back in the same
+// way as before, and a new server gets an empty login form
+async fn finish_connect(
+    app: &mut App,
+    kind: PendingConnect,
+    ws_stream: websocket::WsStream,
+) -> Result<
+    (
+        SplitSink<websocket::WsStream, Message>,
+        futures_util::stream::SplitStream<websocket::WsStream>,
+    ),
+    ClientError,
+> {
+    let (mut new_write, new_read) = ws_stream.split();
+    app.disconnect_reason = None;
+    // The history replayed on connect is followed by the reactions to it
+    app.reactions.clear();
+
+    if app.spectating {
+        // Spectators skip the login screen entirely
+        send_spectate(&mut new_write).await?;
+        app.current_screen = CurrentScreen::Main;
+    } else if kind == PendingConnect::Reconnect {
+        // Log back in; the server's reply moves us on to Main or back to the form
+        if let Some(auth_message) = app.begin_reauth() {
+            new_write
+                .send(Message::Text(serde_json::to_string(&auth_message).unwrap()))
+                .await?;
+        }
+    } else {
+        app.current_screen = CurrentScreen::LoggingIn;
+        app.failed_login_attempts = 0;
+        app.reset_login_form();
+        if let Some(name) = &app.default_username {
+            app.message_input = name.clone();
+        }
+    }
+
+    Ok((new_write, new_read))
+}
+
 async fn handle_server_selection_input(
     key: KeyCode,
     app: &mut App,
     write: &mut Option<futures_util::stream::SplitSink<websocket::WsStream, Message>>,
     read: &mut Option<futures_util::stream::SplitStream<websocket::WsStream>>,
     terminal: &mut Terminal<impl Backend>,
+    config_path: Option<&std::path::Path>,
 ) -> io::Result<bool> {
-    match key {
-        KeyCode::Enter => {
-            if let Some(selected_server) = app.servers.get(app.selected_server.as_ref().unwrap()) {
-                // Disconnect the current WebSocket streams
-                *write = None;
-                *read = None;
-
-                // Establish a new WebSocket connection with the selected server
-                let ws_stream = connect_to_server(app)
-                    .await
-                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
-
-                // Split the new WebSocket stream into `write` and `read`
-                let (new_write, new_read) = ws_stream.split();
-                *write = Some(new_write);
-                *read = Some(new_read);
-
-                // Transition to the login screen after connection
-                app.current_screen = CurrentScreen::LoggingIn;
-                app.message_input.clear();
-
-                // Reset login input fields
-                app.username = None; // Clear any existing username
-                app.password = None; // Clear any existing password
-                app.current_login_field = LoginField::Username; // Start with the username field
-                app.is_typing = true;
-
-                terminal
-                    .draw(|f| ui(f, app))
-                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
-
-                return Ok(true);
+    // The key after 'd' confirms or cancels the delete
+    if let Some(name) = app.deleting_server.take() {
+        if key == KeyCode::Char('y') {
+            app.delete_server(&name);
+            if let Some(path) = config_path {
+                forget_server(path, &name);
             }
         }
+        return Ok(false);
+    }
 
-        KeyCode::Up => {
-            if let Some(selected_server) = &app.selected_server {
-                let server_names: Vec<&String> = app.servers.keys().collect();
-
-                for (i, name) in server_names.iter().enumerate() {
-                    if *name == selected_server {
-                        if i > 0 {
-                            let new_selected_server_name =
-                                server_names.get(i - 1).expect("Failed to get server name");
-                            app.selected_server = Some(new_selected_server_name.to_string());
-                            break;
-                        }
-                    }
-                }
-            } else {
-                if let Some(first_server_name) = app.servers.keys().next() {
-                    app.selected_server = Some(first_server_name.to_string());
-                }
-            }
+    match key {
+        KeyCode::Enter if app.can_connect_to_selected() => {
+            app.redirects = 0;
+            app.pending_connect = Some(PendingConnect::SwitchServer);
+            return Ok(true);
         }
 
-        KeyCode::Down => {
-            if let Some(selected_server) = &app.selected_server {
-                let server_names: Vec<&String> = app.servers.keys().collect();
+        KeyCode::Up | KeyCode::Down | KeyCode::Tab => app.move_server_selection(key),
 
-                for (i, name) in server_names.iter().enumerate() {
-                    if *name == selected_server {
-                        if i < app.servers.len() - 1 {
-                            let new_selected_server_name =
-                                server_names.get(i + 1).expect("Failed to get server name");
-                            app.selected_server = Some(new_selected_server_name.to_string());
-                            break;
-                        }
-                    }
-                }
-            } else {
-                if let Some(first_server_name) = app.servers.keys().next() {
-                    app.selected_server = Some(first_server_name.to_string());
-                }
-            }
+        KeyCode::Char('d') if app.can_connect_to_selected() => {
+            app.deleting_server = app.selected_server.clone();
         }
 
         KeyCode::Char('n') => {
-            app.current_screen = CurrentScreen::AddServer; // Transition to add server screen
+            if let Transition::Go(screen) = app.on_key(key) {
+                app.current_screen = screen; // Transition to add server screen
+            }
             app.message_input.clear(); // Clear any input
 
             terminal
                 .draw(|f| ui(f, app))
-                .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+                .map_err(|e| io::Error::other(e.to_string()))?;
 
             //return Ok(true);
         }
@@ -302,10 +542,12 @@ async fn handle_server_selection_input(
                 // Check if there is an active server connection
                 app.current_screen = CurrentScreen::Main; // Transition to the main screen
                 app.message_input.clear(); // Clear any input
+                app.restore_draft();
+                app.mark_active_server_read();
 
                 terminal
                     .draw(|f| ui(f, app))
-                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+                    .map_err(|e| io::Error::other(e.to_string()))?;
 
                 return Ok(true);
             } else {
@@ -313,191 +555,304 @@ async fn handle_server_selection_input(
                 app.message_input.clear();
             }
         }
-        KeyCode::Tab => {
-            if let Some(selected_server) = &app.selected_server {
-                let server_names: Vec<&String> = app.servers.keys().collect();
-                let current_index = server_names
-                    .iter()
-                    .position(|name| *name == selected_server);
-
-                if let Some(index) = current_index {
-                    let next_index = (index + 1) % app.servers.len();
-                    let new_selected_server_name = server_names
-                        .get(next_index)
-                        .expect("Failed to get server name");
-                    app.selected_server = Some(new_selected_server_name.to_string());
-                } else if let Some(first_server_name) = app.servers.keys().next() {
-                    app.selected_server = Some(first_server_name.to_string());
-                }
-            } else if let Some(first_server_name) = app.servers.keys().next() {
-                app.selected_server = Some(first_server_name.to_string());
-            }
-        }
-
         _ => {}
     }
 
     Ok(false) // Return false if no valid server is selected
 }
 
+// Announce a read-only spectator session in place of credentials
+async fn send_spectate(write: &mut SplitSink<websocket::WsStream, Message>) -> io::Result<()> {
+    write
+        .send(Message::Text(
+            serde_json::to_string(&MessageType::Spectate).unwrap(),
+        ))
+        .await
+        .map_err(io::Error::other)
+}
+
 async fn handle_login_input(
     key: KeyCode,
     app: &mut App,
     write: &mut SplitSink<websocket::WsStream, Message>,
 ) -> io::Result<()> {
-    // Handle input based on whether the user is typing
-    if app.is_typing {
-        match key {
-            // Submit the field after typing
-            KeyCode::Enter => {
-                match app.current_login_field {
-                    LoginField::Username => {
-                        if !app.message_input.is_empty() {
-                            app.username = Some(app.message_input.clone());
-                            app.message_input.clear(); // Clear for password input
-                            app.current_login_field = LoginField::Password; // Move to password field
-                            app.messages.push(MessageType::SystemMessage(
-                                "Enter your password:".to_string(),
-                            ));
-                        }
-                    }
-                    LoginField::Password => {
-                        if !app.message_input.is_empty() {
-                            app.password = Some(app.message_input.clone());
-                            app.message_input.clear();
-
-                            // If both fields are filled, submit the login request
-                            if let (Some(username), Some(password)) = (&app.username, &app.password)
-                            {
-                                let auth_message = MessageType::SystemMessage(format!(
-                                    "{}:{}",
-                                    username, password
-                                ));
-                                write
-                                    .send(Message::Text(
-                                        serde_json::to_string(&auth_message).unwrap(),
-                                    ))
-                                    .await
-                                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
-
-                                // Store username as staging and reset for a retry if needed
-                                app.staging_username = Some(username.clone());
-                            }
-
-                            // Reset after submission
-                            app.username = None;
-                            app.password = None;
-                            app.current_login_field = LoginField::Username; // Reset to username field
-                            app.is_typing = false; // Stop typing
-                        }
-                    }
-                }
-            }
+    // Esc leaves the form to quit or pick another server
+    if let Transition::Go(screen) = app.on_key(key) {
+        app.current_screen = screen;
+        return Ok(());
+    }
 
-            // Handle Backspace key press only when typing
-            KeyCode::Backspace => {
-                if !app.message_input.is_empty() {
-                    app.message_input.pop();
-                }
-            }
+    match key {
+        // Ignore typing while waiting for the server to answer a submitted login
+        _ if !app.is_typing => {}
 
-            // Handle character input while typing
-            KeyCode::Char(c) => {
-                app.message_input.push(c);
-            }
+        // Tab and Shift+Tab move between fields
+        _ if app.switch_login_field(key) => {}
 
-            // Handle Esc key press to stop typing
-            KeyCode::Esc => {
-                // Stop typing when the user presses Esc
-                app.is_typing = false;
+        // Enter submits both fields at once
+        KeyCode::Enter => {
+            if let Some(auth_message) = app.submit_login() {
+                write
+                    .send(Message::Text(serde_json::to_string(&auth_message).unwrap()))
+                    .await
+                    .map_err(io::Error::other)?;
             }
+        }
 
-            _ => {}
+        // Typing edits the focused field
+        KeyCode::Backspace => {
+            pop_grapheme(&mut app.message_input);
+        }
+        KeyCode::Char(c) => {
+            app.message_input.push(c);
         }
-    } else {
-        // When not typing, handle navigation and quitting
-        match key {
-            // Start typing when Enter is pressed
-            KeyCode::Enter => {
-                app.is_typing = true;
-            }
 
-            // Switch between fields using Tab
-            KeyCode::Tab => {
-                app.current_login_field = match app.current_login_field {
-                    LoginField::Username => LoginField::Password,
-                    LoginField::Password => LoginField::Username,
-                };
-            }
+        _ => {}
+    }
 
-            // Quit the application with 'q' when not typing
-            KeyCode::Char('q') => {
-                // Set screen to Exiting
-                app.current_screen = CurrentScreen::ExitingLoggingIn;
-            }
+    Ok(())
+}
 
+async fn handle_main_input(
+    key: KeyCode,
+    app: &mut App,
+    write: Option<&mut SplitSink<websocket::WsStream, Message>>,
+) -> io::Result<()> {
+    if app.reacting {
+        return handle_reaction_input(key, app, write).await;
+    }
+
+    // Spectators can look around but not compose or rename, which `on_key` accounts for
+    if let Transition::Go(screen) = app.on_key(key) {
+        // Composing picks up the draft where it was left; screens that reuse the input box
+        // set it aside until we're back
+        match screen {
+            CurrentScreen::Search => app.start_search(),
+            CurrentScreen::ServerSelection => {
+                app.selected_message = None;
+                app.stash_draft();
+            }
+            CurrentScreen::SetUser => app.stash_draft(),
+            CurrentScreen::HelpMenu => app.help_scroll = 0,
             _ => {}
         }
+        app.current_screen = screen;
+        return Ok(());
     }
 
+    match key {
+        // While a search is active, n/N step through its matches
+        KeyCode::Char('n') if app.search_query.is_some() => app.search_previous(),
+        KeyCode::Char('N') if app.search_query.is_some() => app.search_next(),
+        KeyCode::Char('v') => app.toggle_selection(),
+        KeyCode::Char('u') => app.show_roster = !app.show_roster,
+        KeyCode::Esc => app.escape_main(),
+        KeyCode::Char('y') => copy_selected_message(app),
+        KeyCode::Char('r') if app.selected_message.is_some() && !app.spectating => {
+            app.start_reaction()
+        }
+        // In selection mode the arrows move the highlight instead of scrolling
+        KeyCode::Up if app.selected_message.is_some() => app.select_previous_message(),
+        KeyCode::Down if app.selected_message.is_some() => app.select_next_message(),
+        KeyCode::Up => app.scroll_up(),
+        KeyCode::Down => app.scroll_down(),
+        _ => {}
+    }
     Ok(())
 }
 
-async fn handle_main_input(key: KeyCode, app: &mut App) {
-    match key {
-        KeyCode::Enter => {
-            app.current_screen = CurrentScreen::ComposingMessage;
-            app.message_input.clear();
-        }
-        KeyCode::Char('h') => {
-            app.current_screen = CurrentScreen::HelpMenu;
-        }
-        KeyCode::Char('q') => {
-            app.current_screen = CurrentScreen::Exiting;
+// The key after 'r': 1-6 pick a common reaction, any other character reacts with itself
+async fn handle_reaction_input(
+    key: KeyCode,
+    app: &mut App,
+    write: Option<&mut SplitSink<websocket::WsStream, Message>>,
+) -> io::Result<()> {
+    app.reacting = false;
+    let emoji = match key {
+        KeyCode::Char(c @ '1'..='6') => emoji::REACTIONS[c as usize - '1' as usize].to_string(),
+        KeyCode::Char(c) => c.to_string(),
+        _ => return Ok(()),
+    };
+    let (Some(message_id), Some(write)) = (app.selected_message_id(), write) else {
+        return Ok(());
+    };
+    // The server echoes the reaction back, which is when it shows up
+    let reaction = MessageType::Reaction {
+        message_id,
+        user: String::new(),
+        emoji,
+    };
+    write
+        .send(Message::Text(serde_json::to_string(&reaction).unwrap()))
+        .await
+        .map_err(io::Error::other)
+}
+
+// Typing a search query; matches update as you type
+fn handle_search_input(key: KeyCode, app: &mut App) {
+    if let Transition::Go(screen) = app.on_key(key) {
+        // Enter keeps the search to browse its matches with n/N; Esc drops it
+        if key == KeyCode::Esc {
+            app.clear_search();
         }
-        KeyCode::Char('n') => {
-            app.current_screen = CurrentScreen::SetUser;
+        app.current_screen = screen;
+        return;
+    }
+    match key {
+        KeyCode::Backspace => {
+            if let Some(query) = &mut app.search_query {
+                pop_grapheme(query);
+            }
+            app.update_search();
         }
-        KeyCode::Char('s') => {
-            // Transition to server selection screen
-            app.current_screen = CurrentScreen::ServerSelection;
-            app.message_input.clear();
+        KeyCode::Char(c) => {
+            if let Some(query) = &mut app.search_query {
+                query.push(c);
+            }
+            app.update_search();
         }
-        KeyCode::Up => app.scroll_up(),
-        KeyCode::Down => app.scroll_down(),
         _ => {}
     }
 }
+
+// Copy the highlighted message to the system clipboard, reporting failures (e.g. no display)
+fn copy_selected_message(app: &mut App) {
+    let Some(text) = app.selected_message_text() else {
+        return;
+    };
+    let notice = match copy_to_clipboard(text) {
+        Ok(()) => MessageType::notice(Severity::Success, "Message copied to clipboard."),
+        Err(e) => MessageType::notice(
+            Severity::Error,
+            format!("Could not copy to clipboard: {}", e),
+        ),
+    };
+    app.messages.push(notice);
+}
+
+// Show the link to the current server, and copy it too where there is a clipboard
+fn share_invite_link(app: &mut App) {
+    let Some(link) = app.invite_link() else {
+        app.messages.push(MessageType::notice(
+            Severity::Warn,
+            "Not connected to a server to invite anyone to.",
+        ));
+        return;
+    };
+    let notice = match copy_to_clipboard(link.clone()) {
+        Ok(()) => MessageType::notice(
+            Severity::Success,
+            format!("Invite link copied to clipboard: {}", link),
+        ),
+        Err(_) => MessageType::notice(
+            Severity::Info,
+            format!("Invite link (paste it on the Add Server screen): {}", link),
+        ),
+    };
+    app.messages.push(notice);
+}
+
+fn copy_to_clipboard(text: String) -> Result<(), arboard::Error> {
+    arboard::Clipboard::new().and_then(|mut clipboard| clipboard.set_text(text))
+}
+
 async fn handle_composing_message_input(
     key: KeyCode,
     app: &mut App,
     write: &mut futures_util::stream::SplitSink<websocket::WsStream, Message>,
+    config_path: Option<&std::path::Path>,
 ) -> io::Result<()> {
+    if key != KeyCode::Tab {
+        app.reset_completion();
+    }
+    if let Transition::Go(screen) = app.on_key(key) {
+        app.history_cursor = None;
+        app.current_screen = screen;
+        return Ok(());
+    }
     match key {
+        KeyCode::Tab => {
+            app.complete_command();
+            return Ok(());
+        }
         KeyCode::Enter => {
+            // Keep an over-long message in the box so it can be shortened
+            let length = app.message_input.chars().count();
+            if length > MAX_MESSAGE_LEN {
+                app.messages.push(MessageType::notice(
+                    Severity::Error,
+                    format!(
+                        "Message is too long ({}/{} characters)",
+                        length, MAX_MESSAGE_LEN
+                    ),
+                ));
+                return Ok(());
+            }
             let user_input = app.message_input.clone();
+            app.message_input.clear();
+            app.record_input(&user_input);
+            // Return to the main screen unless the command navigates somewhere else
+            app.current_screen = CurrentScreen::Main;
             match app.parse_command(&user_input) {
-                Command::SetName(name) => {
+                Command::SetName(name) => match validate_username(&name) {
+                    Ok(name) => {
+                        let cmd = MessageType::Command {
+                            name: "name".to_string(),
+                            args: vec![name.clone()],
+                        };
+                        write
+                            .send(Message::Text(serde_json::to_string(&cmd).unwrap()))
+                            .await
+                            .map_err(io::Error::other)?;
+                    }
+                    Err(reason) => app.messages.push(MessageType::SystemMessage(reason)),
+                },
+                Command::ListUsers(page) => {
+                    // Servers that can send the user table reply with it; older ones send the
+                    // names, which go into the transcript a page at a time
+                    app.user_list_page = page;
                     let cmd = MessageType::Command {
-                        name: "name".to_string(),
-                        args: vec![name.clone()],
+                        name: "list".to_string(),
+                        args: vec!["details".to_string()],
                     };
                     write
                         .send(Message::Text(serde_json::to_string(&cmd).unwrap()))
                         .await
-                        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
-
-                    app.set_username(name);
+                        .map_err(io::Error::other)?;
                 }
-                Command::ListUsers => {
+                Command::Afk(message) => {
                     let cmd = MessageType::Command {
-                        name: "list".to_string(),
+                        name: "afk".to_string(),
+                        args: if message.is_empty() {
+                            vec![]
+                        } else {
+                            vec![message]
+                        },
+                    };
+                    write
+                        .send(Message::Text(serde_json::to_string(&cmd).unwrap()))
+                        .await
+                        .map_err(io::Error::other)?;
+                }
+                Command::Seen(user) => {
+                    let cmd = MessageType::Command {
+                        name: "seen".to_string(),
+                        args: vec![user],
+                    };
+                    write
+                        .send(Message::Text(serde_json::to_string(&cmd).unwrap()))
+                        .await
+                        .map_err(io::Error::other)?;
+                }
+                Command::AfkList => {
+                    let cmd = MessageType::Command {
+                        name: "afk-list".to_string(),
                         args: vec![],
                     };
                     write
                         .send(Message::Text(serde_json::to_string(&cmd).unwrap()))
                         .await
-                        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+                        .map_err(io::Error::other)?;
                 }
                 Command::DirectMessage(recipient, message) => {
                     let cmd = MessageType::Command {
@@ -507,26 +862,182 @@ async fn handle_composing_message_input(
                     write
                         .send(Message::Text(serde_json::to_string(&cmd).unwrap()))
                         .await
-                        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+                        .map_err(io::Error::other)?;
+                }
+                Command::OpenDirectChat(user) => {
+                    app.open_direct_chat(user);
                 }
                 Command::Help => {
                     app.current_screen = CurrentScreen::HelpMenu;
                 }
-                Command::Unknown(input) => {
-                    let msg = MessageType::ChatMessage {
-                        sender: app.username.clone().unwrap_or_else(|| "You".to_string()),
-                        content: input.clone(),
+                Command::Invite => share_invite_link(app),
+                Command::Join(channel) => {
+                    let join = MessageType::Join(channel);
+                    write
+                        .send(Message::Text(serde_json::to_string(&join).unwrap()))
+                        .await
+                        .map_err(io::Error::other)?;
+                }
+                Command::Part => {
+                    let leave = MessageType::Leave(app.channel.clone());
+                    write
+                        .send(Message::Text(serde_json::to_string(&leave).unwrap()))
+                        .await
+                        .map_err(io::Error::other)?;
+                }
+                Command::Reconnect => {
+                    app.pending_connect = Some(PendingConnect::Reconnect);
+                }
+                Command::Quit => {
+                    // Same confirmation popup as the q key
+                    app.current_screen = CurrentScreen::Exiting;
+                }
+                Command::Clear => {
+                    app.clear_transcript();
+                }
+                Command::Theme(name) => {
+                    app.set_theme(&name);
+                }
+                Command::Sound(path) => match app.set_sound(std::path::PathBuf::from(&path)) {
+                    Ok(()) => {
+                        app.messages.push(MessageType::SystemMessage(format!(
+                            "Notification sound set to {}",
+                            path
+                        )));
+                        if let Some(config_path) = config_path {
+                            remember_sound(config_path, &path);
+                        }
+                    }
+                    Err(reason) => app.messages.push(MessageType::SystemMessage(reason)),
+                },
+                Command::Topic(topic) => {
+                    let cmd = MessageType::Command {
+                        name: "topic".to_string(),
+                        args: vec![topic],
+                    };
+                    write
+                        .send(Message::Text(serde_json::to_string(&cmd).unwrap()))
+                        .await
+                        .map_err(io::Error::other)?;
+                }
+                Command::SendFile(path) => {
+                    send_file(app, write, &path).await;
+                }
+                Command::Export(path) => {
+                    export_transcript(app, path);
+                }
+                Command::Stats => {
+                    let cmd = MessageType::Command {
+                        name: "stats".to_string(),
+                        args: vec![],
+                    };
+                    write
+                        .send(Message::Text(serde_json::to_string(&cmd).unwrap()))
+                        .await
+                        .map_err(io::Error::other)?;
+                }
+                Command::Status => {
+                    let cmd = MessageType::Command {
+                        name: "status".to_string(),
+                        args: vec![],
+                    };
+                    write
+                        .send(Message::Text(serde_json::to_string(&cmd).unwrap()))
+                        .await
+                        .map_err(io::Error::other)?;
+                }
+                Command::History => {
+                    let cmd = MessageType::Command {
+                        name: "history".to_string(),
+                        args: vec![],
+                    };
+                    write
+                        .send(Message::Text(serde_json::to_string(&cmd).unwrap()))
+                        .await
+                        .map_err(io::Error::other)?;
+                }
+                Command::ClearHistory => {
+                    let cmd = MessageType::Command {
+                        name: "clearhistory".to_string(),
+                        args: vec![],
                     };
-                    app.messages.push(msg.clone());
                     write
-                        .send(Message::Text(serde_json::to_string(&msg).unwrap()))
+                        .send(Message::Text(serde_json::to_string(&cmd).unwrap()))
                         .await
-                        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+                        .map_err(io::Error::other)?;
+                }
+                Command::Mute(user, seconds) => {
+                    let cmd = MessageType::Command {
+                        name: "mute".to_string(),
+                        args: std::iter::once(user).chain(seconds).collect(),
+                    };
+                    write
+                        .send(Message::Text(serde_json::to_string(&cmd).unwrap()))
+                        .await
+                        .map_err(io::Error::other)?;
+                }
+                Command::Unmute(user) => {
+                    let cmd = MessageType::Command {
+                        name: "unmute".to_string(),
+                        args: vec![user],
+                    };
+                    write
+                        .send(Message::Text(serde_json::to_string(&cmd).unwrap()))
+                        .await
+                        .map_err(io::Error::other)?;
+                }
+                Command::Block(user) => {
+                    let cmd = MessageType::Command {
+                        name: "block".to_string(),
+                        args: vec![user],
+                    };
+                    write
+                        .send(Message::Text(serde_json::to_string(&cmd).unwrap()))
+                        .await
+                        .map_err(io::Error::other)?;
+                }
+                Command::Unblock(user) => {
+                    let cmd = MessageType::Command {
+                        name: "unblock".to_string(),
+                        args: vec![user],
+                    };
+                    write
+                        .send(Message::Text(serde_json::to_string(&cmd).unwrap()))
+                        .await
+                        .map_err(io::Error::other)?;
+                }
+                Command::Retry => {
+                    for msg in app.take_failed_for_retry() {
+                        let client_msg_id = match &msg {
+                            MessageType::ChatMessage { client_msg_id, .. } => {
+                                client_msg_id.clone().unwrap_or_default()
+                            }
+                            _ => String::new(),
+                        };
+                        send_chat_message(app, write, &client_msg_id, &msg).await;
+                    }
+                }
+                Command::Reply(id, text) => match app.reply_target(&id) {
+                    Some(parent) => send_new_chat_message(app, write, &text, Some(parent)).await,
+                    None => app.messages.push(MessageType::notice(
+                        Severity::Error,
+                        format!("No single message has an id starting with {}", id),
+                    )),
+                },
+                Command::Unknown(input) => {
+                    send_new_chat_message(app, write, &input, None).await;
                 }
             }
 
-            app.message_input.clear();
-            app.current_screen = CurrentScreen::Main;
+            return Ok(());
+        }
+        // Up/Down recall sent inputs when the box is empty or already showing one
+        KeyCode::Up if app.message_input.is_empty() || app.history_cursor.is_some() => {
+            app.recall_previous_input();
+            return Ok(());
+        }
+        KeyCode::Down if app.history_cursor.is_some() => {
+            app.recall_next_input();
             return Ok(());
         }
         KeyCode::Up | KeyCode::PageUp => {
@@ -538,57 +1049,176 @@ async fn handle_composing_message_input(
             return Ok(());
         }
         KeyCode::Backspace => {
-            app.message_input.pop();
+            app.history_cursor = None;
+            pop_grapheme(&mut app.message_input);
             return Ok(());
         }
-        KeyCode::Esc => {
-            app.current_screen = CurrentScreen::Main;
-            return Ok(());
+        KeyCode::Char(c) => {
+            app.history_cursor = None;
+            app.message_input.push(c);
         }
-        KeyCode::Char(c) => app.message_input.push(c),
         _ => {}
     }
 
     Ok(())
 }
 
-async fn handle_disconnected_input(
+// Read a file from disk and send it to everyone as a series of chunks
+async fn send_file(
+    app: &mut App,
+    write: &mut futures_util::stream::SplitSink<websocket::WsStream, Message>,
+    path: &str,
+) {
+    let name = std::path::Path::new(path)
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_else(|| path.to_string());
+    let chunks = std::fs::read(path)
+        .map_err(|e| format!("Could not read {}: {}", path, e))
+        .and_then(|bytes| {
+            file_transfer::chunk_file(&Uuid::new_v4().to_string(), &name, &bytes)
+                .map(|chunks| (bytes.len(), chunks))
+        });
+    let (size, chunks) = match chunks {
+        Ok(result) => result,
+        Err(e) => {
+            app.messages.push(MessageType::notice(Severity::Error, e));
+            return;
+        }
+    };
+
+    for chunk in chunks {
+        let serialized = serde_json::to_string(&chunk).unwrap();
+        if let Err(e) = write.send(Message::Text(serialized)).await {
+            app.set_disconnected(format!("Failed to send file: {}", e));
+            return;
+        }
+    }
+    app.messages.push(MessageType::SystemMessage(format!(
+        "Sent file {} ({} bytes)",
+        name, size
+    )));
+}
+
+// Save the transcript to `path`, or to a timestamped file in the home directory, as JSON when
+// the file name ends in .json and as text otherwise
+fn export_transcript(app: &mut App, path: Option<String>) {
+    let path = path.map(std::path::PathBuf::from).unwrap_or_else(|| {
+        let home = std::env::var_os("HOME")
+            .or_else(|| std::env::var_os("USERPROFILE"))
+            .map(std::path::PathBuf::from)
+            .unwrap_or_default();
+        let stamp = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        home.join(format!("terminal_messenger-{}.txt", stamp))
+    });
+    let contents = if path.extension().is_some_and(|ext| ext == "json") {
+        app::transcript_json(&app.messages)
+    } else {
+        app::transcript_text(&app.messages)
+    };
+    let notice = match std::fs::write(&path, contents) {
+        Ok(()) => MessageType::SystemMessage(format!("Transcript saved to {}", path.display())),
+        Err(e) => MessageType::notice(
+            Severity::Error,
+            format!("Could not save the transcript to {}: {}", path.display(), e),
+        ),
+    };
+    app.messages.push(notice);
+}
+
+// Show a chat message we're sending in the transcript, track it until it's acknowledged and
+// send it
+async fn send_new_chat_message(
+    app: &mut App,
+    write: &mut futures_util::stream::SplitSink<websocket::WsStream, Message>,
+    input: &str,
+    reply_to: Option<u64>,
+) {
+    let client_msg_id = Uuid::new_v4().to_string();
+    let msg = MessageType::ChatMessage {
+        sender: app.username.clone().unwrap_or_else(|| "You".to_string()),
+        content: emoji::expand_emoji(input),
+        sender_id: app.account.clone().unwrap_or_default(),
+        client_msg_id: Some(client_msg_id.clone()),
+        message_id: None,
+        reply_to,
+        seq: None,
+    };
+    // Jump back down so the message just sent is in view
+    app.scroll_to_bottom();
+    app.messages.push(msg.clone());
+    app.track_pending(client_msg_id.clone(), msg.clone());
+    send_chat_message(app, write, &client_msg_id, &msg).await;
+}
+
+// Send one of our tracked chat messages. If the socket refuses it the message is flagged as
+// unsent instead of left looking delivered, queued to go out after a reconnect, and the dead
+// connection is reported.
+async fn send_chat_message(
+    app: &mut App,
+    write: &mut futures_util::stream::SplitSink<websocket::WsStream, Message>,
+    client_msg_id: &str,
+    msg: &MessageType,
+) {
+    let serialized = serde_json::to_string(msg).unwrap();
+    if let Err(e) = write.send(Message::Text(serialized)).await {
+        log::error!("Failed to send message: {:?}", e);
+        app.send_failed(client_msg_id, msg.clone(), e.to_string());
+    }
+}
+
+async fn handle_direct_chat_input(
     key: KeyCode,
     app: &mut App,
-    terminal: &mut Terminal<impl Backend>,
-    write: &mut Option<futures_util::stream::SplitSink<websocket::WsStream, Message>>,
-    read: &mut Option<futures_util::stream::SplitStream<websocket::WsStream>>,
+    peer: &str,
+    write: &mut futures_util::stream::SplitSink<websocket::WsStream, Message>,
 ) -> io::Result<()> {
+    if let Transition::Go(screen) = app.on_key(key) {
+        app.message_input.clear();
+        app.current_screen = screen;
+        return Ok(());
+    }
     match key {
-        KeyCode::Char('r') => {
-            // Attempt to reconnect to the selected server
-            if let Ok(ws_stream) = websocket::connect_to_server(app).await {
-                let (new_write, new_read) = ws_stream.split();
-                *write = Some(new_write);
-                *read = Some(new_read);
-
-                // Clear the terminal and force a full redraw
-                terminal.clear()?;
-                app.current_screen = CurrentScreen::Main; // Back to main screen after reconnection
-                terminal.draw(|f| crate::ui::ui(f, app))?;
-            } else {
-                // Handle reconnection failure, maybe push a system message to the app
-                app.messages.push(MessageType::SystemMessage(
-                    "Reconnection failed. Please check the server.".to_string(),
-                ));
-                terminal.draw(|f| crate::ui::ui(f, app))?;
+        KeyCode::Enter => {
+            // Everything typed in the 1:1 view goes to the open peer
+            if !app.message_input.trim().is_empty() {
+                let cmd = MessageType::Command {
+                    name: "DirectMessage".to_string(),
+                    args: vec![peer.to_string(), app.message_input.clone()],
+                };
+                write
+                    .send(Message::Text(serde_json::to_string(&cmd).unwrap()))
+                    .await
+                    .map_err(io::Error::other)?;
             }
+            app.message_input.clear();
         }
-        KeyCode::Char('q') => {
-            // Quit the app gracefully
-            std::process::exit(0);
+        KeyCode::Backspace => {
+            pop_grapheme(&mut app.message_input);
         }
+        KeyCode::Char(c) => app.message_input.push(c),
         _ => {}
     }
 
     Ok(())
 }
 
+// Returns true when the user chose to quit
+fn handle_disconnected_input(key: KeyCode, app: &mut App) -> bool {
+    if app.on_key(key) == Transition::Quit {
+        return true;
+    }
+    if key == KeyCode::Char('r') {
+        // Attempt to reconnect to the selected server; a failure comes back here with
+        // the reason
+        app.pending_connect = Some(PendingConnect::Reconnect);
+    }
+    false
+}
+
 async fn handle_set_user_input(
     key: KeyCode,
     app: &mut App,
@@ -597,66 +1227,316 @@ async fn handle_set_user_input(
     match key {
         KeyCode::Enter => {
             // Set the username and switch back to the main screen
-            let username = app.message_input.clone();
-            app.set_username(username.clone());
-
-            let cmd = MessageType::Command {
-                name: "name".to_string(),
-                args: vec![username.clone()],
-            };
-            if let Err(e) = write
-                .send(Message::Text(serde_json::to_string(&cmd).unwrap()))
-                .await
-            {
-                log::error!("Failed to send command: {:?}", e);
+            match validate_username(&app.message_input) {
+                // The name is used once the server confirms it
+                Ok(username) => {
+                    let cmd = MessageType::Command {
+                        name: "name".to_string(),
+                        args: vec![username],
+                    };
+                    if let Err(e) = write
+                        .send(Message::Text(serde_json::to_string(&cmd).unwrap()))
+                        .await
+                    {
+                        log::error!("Failed to send command: {:?}", e);
+                    }
+                }
+                Err(reason) => app.messages.push(MessageType::SystemMessage(reason)),
             }
-
-            app.current_screen = CurrentScreen::Main; // Go back to the main screen
-            app.message_input.clear(); // Clear input after setting username
         }
         KeyCode::Backspace => {
-            app.message_input.pop(); // Handle backspace to delete last character
-        }
-        KeyCode::Esc => {
-            app.current_screen = CurrentScreen::Main; // Cancel username input and go back
-        }
-        KeyCode::Char(c) => {
-            app.message_input.push(c); // Add typed character to input
+            pop_grapheme(&mut app.message_input); // Handle backspace to delete last character
+            app.set_user_error = None;
         }
+        // Characters a name can't have, or past its length limit, are refused as they're typed
+        KeyCode::Char(c) => match check_username_char(&app.message_input, c) {
+            Ok(()) => {
+                app.message_input.push(c);
+                app.set_user_error = None;
+            }
+            Err(reason) => app.set_user_error = Some(reason),
+        },
         _ => {}
     }
+    // Enter sets the name and Esc cancels; both go back to the main screen and its draft
+    if let Transition::Go(screen) = app.on_key(key) {
+        app.current_screen = screen;
+        app.message_input.clear();
+        app.set_user_error = None;
+        app.restore_draft();
+    }
     Ok(())
 }
 
-async fn handle_help_menu_input(_key: KeyCode, app: &mut App) -> io::Result<()> {
-    // pressing any key will exit help menu and go back to main screen
-    app.current_screen = CurrentScreen::Main;
+async fn handle_help_menu_input(key: KeyCode, app: &mut App) -> io::Result<()> {
+    // pressing any key but the arrows will exit help menu and go back to main screen
+    match (app.on_key(key), key) {
+        (Transition::Go(screen), _) => app.current_screen = screen,
+        (_, KeyCode::Up) => app.help_scroll = app.help_scroll.saturating_sub(1),
+        (_, KeyCode::Down) => app.help_scroll = app.help_scroll.saturating_add(1),
+        _ => {}
+    }
 
     Ok(())
 }
 
-async fn handle_exiting_input(key: KeyCode, app: &mut App) -> io::Result<bool> {
-    match key {
-        KeyCode::Char('y') => {
-            return Ok(true); // Exit the app
-        }
-        KeyCode::Char('n') | KeyCode::Char('q') => {
-            app.current_screen = CurrentScreen::Main;
-        }
+fn handle_user_list_input(key: KeyCode, app: &mut App) {
+    // The arrows scroll the table and any other key closes it
+    match (app.on_key(key), key) {
+        (Transition::Go(screen), _) => app.current_screen = screen,
+        (_, KeyCode::Up) => app.user_table_scroll = app.user_table_scroll.saturating_sub(1),
+        (_, KeyCode::Down) => app.user_table_scroll = app.user_table_scroll.saturating_add(1),
         _ => {}
     }
+}
+
+async fn handle_away_list_input(key: KeyCode, app: &mut App) -> io::Result<()> {
+    // pressing any key will close the away list and go back to main screen
+    if let Transition::Go(screen) = app.on_key(key) {
+        app.current_screen = screen;
+    }
+
+    Ok(())
+}
+
+async fn handle_exiting_input(key: KeyCode, app: &mut App) -> io::Result<bool> {
+    match app.on_key(key) {
+        Transition::Quit => return Ok(true), // Exit the app
+        Transition::Go(screen) => app.current_screen = screen,
+        Transition::Stay => {}
+    }
     Ok(false)
 }
 
 async fn handle_exiting_logging_in_input(key: KeyCode, app: &mut App) -> io::Result<bool> {
-    match key {
-        KeyCode::Char('y') => {
-            return Ok(true); // Exit the app
-        }
-        KeyCode::Char('n') | KeyCode::Char('q') => {
-            app.current_screen = CurrentScreen::LoggingIn;
+    match app.on_key(key) {
+        Transition::Quit => return Ok(true), // Exit the app
+        Transition::Go(screen) => {
+            // Picking a different server, e.g. after a failed login, starts the form afresh
+            if screen == CurrentScreen::ServerSelection {
+                app.reset_login_form();
+            }
+            app.current_screen = screen;
         }
-        _ => {}
+        Transition::Stay => {}
     }
     Ok(false)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio_tungstenite::WebSocketStream;
+
+    // A connected socket: the half the input handlers write to, and the server's end of it
+    async fn socket() -> (
+        SplitSink<websocket::WsStream, Message>,
+        WebSocketStream<tokio::net::TcpStream>,
+    ) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        let (client, server) = tokio::join!(tokio_tungstenite::connect_async(url), async {
+            let (stream, _) = listener.accept().await.unwrap();
+            tokio_tungstenite::accept_async(stream).await.unwrap()
+        });
+        (client.unwrap().0.split().0, server)
+    }
+
+    // Type `input` into the compose box and press Enter
+    async fn submit(
+        app: &mut App,
+        write: &mut SplitSink<websocket::WsStream, Message>,
+        input: &str,
+    ) {
+        for c in input.chars() {
+            handle_composing_message_input(KeyCode::Char(c), app, write, None)
+                .await
+                .unwrap();
+        }
+        handle_composing_message_input(KeyCode::Enter, app, write, None)
+            .await
+            .unwrap();
+    }
+
+    // Text frames the server got, up to the client going away
+    async fn received(mut server: WebSocketStream<tokio::net::TcpStream>) -> Vec<String> {
+        let mut texts = Vec::new();
+        while let Ok(Some(Ok(message))) =
+            tokio::time::timeout(Duration::from_secs(1), server.next()).await
+        {
+            if let Message::Text(text) = message {
+                texts.push(text);
+            }
+        }
+        texts
+    }
+
+    // A server that accepts one connection and hands back the first message sent on it
+    async fn listen() -> (Url, JoinHandle<Option<String>>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("ws://{}", listener.local_addr().unwrap())).unwrap();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+            received(ws).await.into_iter().next()
+        });
+        (url, server)
+    }
+
+    // Make the connection change the main loop would, up to the new connection being set up
+    async fn connect_pending(
+        app: &mut App,
+    ) -> (
+        SplitSink<websocket::WsStream, Message>,
+        futures_util::stream::SplitStream<websocket::WsStream>,
+    ) {
+        let kind = app
+            .pending_connect
+            .take()
+            .expect("no connection change asked for");
+        let attempt = start_connect(app, kind).unwrap();
+        assert_eq!(app.current_screen, CurrentScreen::Connecting);
+        let ws_stream = attempt.task.await.unwrap().unwrap();
+        finish_connect(app, attempt.kind, ws_stream).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn reconnect_command_logs_back_in_to_the_same_server() {
+        let (mut write, old_server) = socket().await;
+        let (url, new_server) = listen().await;
+        let mut app = App::new();
+        app.servers.insert("home".to_string(), url);
+        app.selected_server = Some("home".to_string());
+        app.account = Some("alice".to_string());
+        app.saved_password = Some("secret".to_string());
+        app.current_screen = CurrentScreen::ComposingMessage;
+
+        submit(&mut app, &mut write, "/reconnect").await;
+        assert_eq!(app.pending_connect, Some(PendingConnect::Reconnect));
+        let (new_write, new_read) = connect_pending(&mut app).await;
+        assert_eq!(app.selected_server.as_deref(), Some("home"));
+        assert_eq!(app.current_screen, CurrentScreen::LoggingIn);
+
+        // The new connection logs straight back in; the old one was sent nothing
+        drop((write, new_write, new_read));
+        let auth = new_server
+            .await
+            .unwrap()
+            .expect("nothing sent on reconnect");
+        assert!(matches!(
+            serde_json::from_str(&auth),
+            Ok(MessageType::Auth { username, password, .. })
+                if username == "alice" && password == "secret"
+        ));
+        assert!(received(old_server).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn redirect_moves_to_the_new_server_and_its_login_form() {
+        let (url, server) = listen().await;
+        let mut app = App::new();
+        app.current_screen = CurrentScreen::Main;
+        let redirect = MessageType::Redirect(url.to_string());
+        app.handle_websocket_message(&serde_json::to_string(&redirect).unwrap());
+
+        let name = format!("127.0.0.1:{}", url.port().unwrap());
+        assert_eq!(app.servers.get(&name), Some(&url));
+        assert_eq!(app.selected_server.as_ref(), Some(&name));
+        assert_eq!(app.pending_connect, Some(PendingConnect::SwitchServer));
+
+        let (write, read) = connect_pending(&mut app).await;
+        assert_eq!(app.current_screen, CurrentScreen::LoggingIn);
+        assert!(app.message_input.is_empty());
+        drop((write, read));
+        assert_eq!(server.await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn quit_command_asks_to_confirm_like_the_q_key() {
+        let (mut write, server) = socket().await;
+        let mut app = App::new();
+        app.current_screen = CurrentScreen::Main;
+
+        handle_main_input(KeyCode::Enter, &mut app, None)
+            .await
+            .unwrap();
+        assert_eq!(app.current_screen, CurrentScreen::ComposingMessage);
+        submit(&mut app, &mut write, "/quit").await;
+        assert_eq!(app.current_screen, CurrentScreen::Exiting);
+        assert!(app.message_input.is_empty());
+        assert!(handle_exiting_input(KeyCode::Char('y'), &mut app)
+            .await
+            .unwrap());
+
+        // It is handled locally, never sent as a message
+        drop(write);
+        assert!(received(server).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn app_stops_when_the_input_reader_does() {
+        let mut terminal = Terminal::new(ratatui::backend::TestBackend::new(80, 24)).unwrap();
+        let mut app = App::new();
+        let (tx, mut rx) = mpsc::channel(1);
+        drop(tx);
+
+        let result = tokio::time::timeout(
+            Duration::from_secs(5),
+            run_app(&mut terminal, &mut app, &mut rx, false, None),
+        )
+        .await
+        .expect("the app kept running without input");
+        assert!(matches!(result, Ok(false)));
+    }
+
+    #[tokio::test]
+    async fn drafts_stay_with_their_server_across_a_switch() {
+        let (home_url, home) = listen().await;
+        let (work_url, work) = listen().await;
+        let mut app = App::new();
+        app.servers.insert("home".to_string(), home_url);
+        app.servers.insert("work".to_string(), work_url);
+        app.active_server = Some("home".to_string());
+        app.current_screen = CurrentScreen::Main;
+        app.message_input = "half typed".to_string();
+        let log_in = |app: &mut App| {
+            let welcome = MessageType::SystemMessage("Authentication successful".to_string());
+            app.handle_websocket_message(&serde_json::to_string(&welcome).unwrap());
+            assert_eq!(app.current_screen, CurrentScreen::Main);
+        };
+
+        // Switching away sets the draft aside, and the other server doesn't get it
+        handle_main_input(KeyCode::Char('s'), &mut app, None)
+            .await
+            .unwrap();
+        assert!(app.message_input.is_empty());
+        app.selected_server = Some("work".to_string());
+        app.pending_connect = Some(PendingConnect::SwitchServer);
+        let work_connection = connect_pending(&mut app).await;
+        log_in(&mut app);
+        assert!(app.message_input.is_empty());
+
+        // Back on the first server the draft is waiting, and shown as one
+        handle_main_input(KeyCode::Char('s'), &mut app, None)
+            .await
+            .unwrap();
+        app.selected_server = Some("home".to_string());
+        app.pending_connect = Some(PendingConnect::SwitchServer);
+        let home_connection = connect_pending(&mut app).await;
+        log_in(&mut app);
+        assert_eq!(app.message_input, "half typed");
+        let mut terminal = Terminal::new(ratatui::backend::TestBackend::new(80, 24)).unwrap();
+        terminal.draw(|f| ui(f, &mut app)).unwrap();
+        let screen: String = terminal
+            .backend()
+            .buffer()
+            .content()
+            .iter()
+            .map(|cell| cell.symbol())
+            .collect();
+        assert!(screen.contains("draft preserved"));
+
+        drop((work_connection, home_connection));
+        let _ = tokio::join!(home, work);
+    }
+}
@@ -0,0 +1,106 @@
+// Color themes for the TUI, so the screens don't hardcode their colors and can be switched at
+// runtime with /theme.
+use crate::app::Severity;
+use ratatui::style::{Color, Style};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Theme {
+    pub name: &'static str,
+    pub header: Color,        // server name in the chat header
+    pub my_message: Color,    // our own messages, unless the server gave us a color
+    pub other_message: Color, // direct messages from other people
+    pub system: Color,        // system messages and informational notices
+    pub error: Color,         // errors and key hints
+    pub highlight: Color,     // focused fields, selections and search matches
+    pub link: Color,          // URLs in messages
+}
+
+impl Theme {
+    // The original look, made for dark terminal backgrounds
+    pub const DARK: Theme = Theme {
+        name: "dark",
+        header: Color::Green,
+        my_message: Color::Cyan,
+        other_message: Color::Magenta,
+        system: Color::Yellow,
+        error: Color::Red,
+        highlight: Color::Yellow,
+        link: Color::Blue,
+    };
+
+    // Darker colors that stay readable on light terminal backgrounds
+    pub const LIGHT: Theme = Theme {
+        name: "light",
+        header: Color::Green,
+        my_message: Color::Blue,
+        other_message: Color::Magenta,
+        system: Color::DarkGray,
+        error: Color::Red,
+        highlight: Color::Blue,
+        link: Color::Blue,
+    };
+
+    pub fn by_name(name: &str) -> Option<Theme> {
+        PRESETS
+            .iter()
+            .find(|theme| theme.name.eq_ignore_ascii_case(name))
+            .copied()
+    }
+
+    // Theme named by TUI_THEME, or the dark one
+    pub fn from_env() -> Theme {
+        std::env::var("TUI_THEME")
+            .ok()
+            .and_then(|name| Theme::by_name(&name))
+            .unwrap_or_default()
+    }
+
+    pub fn severity_style(&self, level: Severity) -> Style {
+        let color = match level {
+            Severity::Info => self.system,
+            Severity::Success => Color::Green,
+            Severity::Warn => Color::LightYellow,
+            Severity::Error => self.error,
+        };
+        Style::default().fg(color)
+    }
+}
+
+impl Default for Theme {
+    fn default() -> Theme {
+        Theme::DARK
+    }
+}
+
+// Themes that can be picked with TUI_THEME or /theme
+pub const PRESETS: [Theme; 2] = [Theme::DARK, Theme::LIGHT];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn severity_styles_follow_the_theme() {
+        assert_eq!(
+            Theme::DARK.severity_style(Severity::Info).fg,
+            Some(Color::Yellow)
+        );
+        assert_eq!(
+            Theme::LIGHT.severity_style(Severity::Info).fg,
+            Some(Color::DarkGray)
+        );
+        // Success and warnings look the same in every theme
+        for theme in PRESETS {
+            assert_eq!(
+                theme.severity_style(Severity::Success).fg,
+                Some(Color::Green)
+            );
+            assert_eq!(
+                theme.severity_style(Severity::Warn).fg,
+                Some(Color::LightYellow)
+            );
+            assert_eq!(theme.severity_style(Severity::Error).fg, Some(theme.error));
+        }
+        assert_eq!(Theme::by_name("LIGHT"), Some(Theme::LIGHT));
+    }
+}
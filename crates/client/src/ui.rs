@@ -1,25 +1,909 @@
 use crate::app::{App, CurrentScreen};
-use ratatui::Frame;
+use ratatui::{
+    widgets::{Paragraph, Wrap},
+    Frame,
+};
 
 mod add_server;
+mod away_list;
 mod chat;
+mod connecting;
+mod direct_chat;
 mod disconnected;
 mod exiting;
 mod help;
 mod login;
 mod server_selection;
 mod set_user;
+mod user_list;
 mod utils;
+mod welcome;
+
+// Smallest terminal the screens are laid out for
+const MIN_WIDTH: u16 = 20;
+const MIN_HEIGHT: u16 = 5;
 
 pub fn ui(frame: &mut Frame, app: &mut App) {
+    let area = frame.area();
+    if area.width < MIN_WIDTH || area.height < MIN_HEIGHT {
+        let message = Paragraph::new("Terminal too small").wrap(Wrap { trim: true });
+        frame.render_widget(message, area);
+        return;
+    }
+
     match app.current_screen {
         CurrentScreen::LoggingIn => login::render_login(frame, app),
-        CurrentScreen::Main | CurrentScreen::ComposingMessage => chat::render_chat(frame, app),
-        CurrentScreen::HelpMenu => help::render_help(frame),
-        CurrentScreen::Exiting | CurrentScreen::ExitingLoggingIn => exiting::render_exiting(frame),
-        CurrentScreen::Disconnected => disconnected::render_disconnected(frame),
+        CurrentScreen::Main | CurrentScreen::ComposingMessage | CurrentScreen::Search => {
+            chat::render_chat(frame, app)
+        }
+        CurrentScreen::HelpMenu => help::render_help(frame, app),
+        CurrentScreen::Exiting => exiting::render_exiting(frame),
+        CurrentScreen::ExitingLoggingIn => exiting::render_exiting_logging_in(frame),
+        CurrentScreen::Disconnected => disconnected::render_disconnected(frame, app),
+        CurrentScreen::Connecting => connecting::render_connecting(frame, app),
         CurrentScreen::SetUser => set_user::render_set_user(frame, app),
         CurrentScreen::ServerSelection => server_selection::render_server_selection(frame, app), // Route for the server selection screen
         CurrentScreen::AddServer => add_server::render_add_server(frame, app), // _ => {} // Handle other screens if needed
+        CurrentScreen::AwayList => away_list::render_away_list(frame, app),
+        CurrentScreen::UserList => user_list::render_user_list(frame, app),
+        CurrentScreen::Welcome => welcome::render_welcome(frame, app),
+        CurrentScreen::DirectChat(ref peer) => {
+            let peer = peer.clone();
+            direct_chat::render_direct_chat(frame, app, &peer)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::{pop_grapheme, MessageType, Severity, UserSummary, MAX_MESSAGE_LEN};
+    use ratatui::{
+        backend::TestBackend,
+        buffer::Buffer,
+        crossterm::event::KeyCode,
+        layout::Rect,
+        style::{Color, Modifier},
+        Terminal,
+    };
+
+    const SIZES: [(u16, u16); 2] = [(10, 5), (80, 24)];
+
+    // Render with the given draw function into a fixed-size buffer
+    fn render(width: u16, height: u16, draw: impl FnOnce(&mut Frame)) -> Buffer {
+        let mut terminal = Terminal::new(TestBackend::new(width, height)).unwrap();
+        terminal.draw(draw).unwrap();
+        terminal.backend().buffer().clone()
+    }
+
+    // Flatten a buffer into one line of text per row
+    fn buffer_text(buffer: &Buffer) -> String {
+        let area = buffer.area;
+        (area.top()..area.bottom())
+            .map(|y| {
+                (area.left()..area.right())
+                    .map(|x| buffer[(x, y)].symbol())
+                    .collect::<String>()
+            })
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+
+    fn app_with_messages() -> App {
+        let mut app = App::new();
+        app.username = Some("alice".to_string());
+        app.account = Some("alice".to_string());
+        app.messages = vec![
+            MessageType::SystemMessage("Welcome to the server".to_string()),
+            MessageType::ChatMessage {
+                sender: "bob".to_string(),
+                content: "hello there".to_string(),
+                sender_id: "bob".to_string(),
+                client_msg_id: None,
+                message_id: None,
+                reply_to: None,
+                seq: None,
+            },
+            MessageType::ChatMessage {
+                sender: "alice".to_string(),
+                content: "hi bob".to_string(),
+                sender_id: "alice".to_string(),
+                client_msg_id: None,
+                message_id: None,
+                reply_to: None,
+                seq: None,
+            },
+        ];
+        app
+    }
+
+    #[test]
+    fn render_chat_shows_header_and_messages() {
+        for (width, height) in SIZES {
+            let mut app = app_with_messages();
+            let buffer = render(width, height, |f| chat::render_chat(f, &mut app));
+            if width == 80 {
+                let text = buffer_text(&buffer);
+                assert!(text.contains("Server: default"));
+                assert!(text.contains("(h) help"));
+                assert!(text.contains("bob: hello there"));
+                assert!(text.contains("hi bob"));
+                assert!(text.contains("Press Enter to compose"));
+            }
+        }
+    }
+
+    #[test]
+    fn render_chat_compose_title_follows_screen() {
+        let mut app = app_with_messages();
+        let text = buffer_text(&render(80, 24, |f| chat::render_chat(f, &mut app)));
+        assert!(text.contains("Press Enter to compose"));
+        assert!(!text.contains("Compose Message"));
+
+        app.current_screen = CurrentScreen::ComposingMessage;
+        let text = buffer_text(&render(80, 24, |f| chat::render_chat(f, &mut app)));
+        assert!(text.contains("Compose Message"));
+        assert!(!text.contains("Press Enter to compose"));
+    }
+
+    #[test]
+    fn render_chat_compose_counter_counts_characters() {
+        let mut app = app_with_messages();
+        app.current_screen = CurrentScreen::ComposingMessage;
+        let counter_color = |app: &mut App, counter: &str| {
+            let buffer = render(80, 24, |f| chat::render_chat(f, app));
+            let text = buffer_text(&buffer);
+            let (row, line) = text
+                .lines()
+                .enumerate()
+                .find(|(_, line)| line.contains(counter))
+                .unwrap_or_else(|| panic!("counter {} not shown", counter));
+            let column = line[..line.find(counter).unwrap()].chars().count();
+            buffer[(column as u16, row as u16)].fg
+        };
+
+        let limit = MAX_MESSAGE_LEN;
+        assert_ne!(counter_color(&mut app, &format!("0/{}", limit)), Color::Red);
+        // Emoji count as one character each
+        app.message_input = "hi 👋".to_string();
+        assert_ne!(counter_color(&mut app, &format!("4/{}", limit)), Color::Red);
+        app.message_input = "x".repeat(limit + 1);
+        assert_eq!(
+            counter_color(&mut app, &format!("{}/{}", limit + 1, limit)),
+            Color::Red
+        );
+
+        // No counter while just reading
+        app.current_screen = CurrentScreen::Main;
+        let text = buffer_text(&render(80, 24, |f| chat::render_chat(f, &mut app)));
+        assert!(!text.contains(&format!("/{}", limit)));
+    }
+
+    #[test]
+    fn render_chat_user_list_needs_room() {
+        let mut app = app_with_messages();
+        app.show_roster = true;
+        app.roster = vec!["alice".to_string(), "bob".to_string()];
+        let text = buffer_text(&render(80, 24, |f| chat::render_chat(f, &mut app)));
+        assert!(text.contains("Users (2)"));
+
+        // Too narrow for the sidebar, so only the chat is shown
+        let text = buffer_text(&render(40, 24, |f| chat::render_chat(f, &mut app)));
+        assert!(!text.contains("Users"));
+        assert!(text.contains("hello there"));
+    }
+
+    #[test]
+    fn render_chat_strips_escape_sequences() {
+        let mut app = app_with_messages();
+        app.messages.push(MessageType::ChatMessage {
+            sender: "mallory\u{1b}[2J".to_string(),
+            content: "evil\u{1b}[31m red\u{1b}]0;title\u{7} \u{9b}1Adone\u{8}".to_string(),
+            sender_id: "mallory".to_string(),
+            client_msg_id: None,
+            message_id: None,
+            reply_to: None,
+            seq: None,
+        });
+        let buffer = render(80, 24, |f| chat::render_chat(f, &mut app));
+        let text = buffer_text(&buffer);
+        assert!(text.contains("mallory: evil red done"));
+        assert!(!text.contains('\u{1b}'));
+        assert!(!text.contains("title"));
+    }
+
+    #[test]
+    fn render_chat_shows_sender_only_on_first_wrapped_line() {
+        let mut app = app_with_messages();
+        app.messages.push(MessageType::ChatMessage {
+            sender: "bob".to_string(),
+            content: "word ".repeat(30).trim_end().to_string(),
+            sender_id: "bob".to_string(),
+            client_msg_id: None,
+            message_id: None,
+            reply_to: None,
+            seq: None,
+        });
+        app.messages.push(MessageType::ChatMessage {
+            sender: "alice".to_string(),
+            content: "mine ".repeat(30).trim_end().to_string(),
+            sender_id: "alice".to_string(),
+            client_msg_id: None,
+            message_id: None,
+            reply_to: None,
+            seq: None,
+        });
+        let buffer = render(80, 24, |f| chat::render_chat(f, &mut app));
+        let text = buffer_text(&buffer);
+        let rows: Vec<&str> = text.lines().collect();
+
+        // Only the first line names the sender; the rest start under the content
+        let bob: Vec<&str> = rows
+            .iter()
+            .copied()
+            .filter(|row| row.contains("word"))
+            .collect();
+        assert!(bob.len() > 1);
+        assert!(bob[0].starts_with("│bob: word"));
+        assert!(bob[1..].iter().all(|row| row.starts_with("│     word")));
+
+        // Our own wrapped message stays right-aligned with no prefix
+        let own: Vec<&str> = rows
+            .iter()
+            .copied()
+            .filter(|row| row.contains("mine"))
+            .collect();
+        assert!(own.len() > 1);
+        assert!(own
+            .iter()
+            .all(|row| row.trim_end_matches(['│', ' ']).ends_with("mine")));
+        assert!(!own.iter().any(|row| row.contains("alice:")));
+    }
+
+    #[test]
+    fn render_chat_flags_messages_that_failed_to_send() {
+        let mut app = app_with_messages();
+        let message = MessageType::ChatMessage {
+            sender: "alice".to_string(),
+            content: "are you there".to_string(),
+            sender_id: "alice".to_string(),
+            client_msg_id: Some("c1".to_string()),
+            message_id: None,
+            reply_to: None,
+            seq: None,
+        };
+        app.messages.push(message.clone());
+        app.track_pending("c1".to_string(), message.clone());
+        app.send_failed("c1", message, "Broken pipe".to_string());
+
+        assert_eq!(app.current_screen, CurrentScreen::Disconnected);
+        assert_eq!(
+            app.disconnect_reason.as_deref(),
+            Some("Failed to send message: Broken pipe")
+        );
+        let text = buffer_text(&render(80, 24, |f| chat::render_chat(f, &mut app)));
+        assert!(text.contains("⚠ are you there (unsent, /retry)"));
+
+        // It goes out again once we're logged back in
+        app.logged_in = true;
+        assert!(matches!(
+            app.queued_message(),
+            Some(MessageType::ChatMessage { content, .. }) if content == "are you there"
+        ));
+    }
+
+    #[test]
+    fn render_chat_sums_reactions_under_the_message() {
+        let mut app = app_with_messages();
+        app.messages.push(MessageType::ChatMessage {
+            sender: "bob".to_string(),
+            content: "lunch?".to_string(),
+            sender_id: "bob".to_string(),
+            client_msg_id: None,
+            message_id: Some("m1".to_string()),
+            reply_to: None,
+            seq: None,
+        });
+        let react = |app: &mut App, user: &str, emoji: &str| {
+            app.apply_reaction("m1".to_string(), user.to_string(), emoji.to_string())
+        };
+        react(&mut app, "alice", "👍");
+        react(&mut app, "carol", "😂");
+        react(&mut app, "bob", "👍");
+        react(&mut app, "carol", "👍");
+        // Sending the same reaction again takes it back
+        react(&mut app, "carol", "😂");
+        react(&mut app, "alice", "🎉");
+
+        let buffer = render(80, 24, |f| chat::render_chat(f, &mut app));
+        let text = buffer_text(&buffer);
+        let rows: Vec<&str> = text.lines().collect();
+        let message = rows.iter().position(|row| row.contains("lunch?")).unwrap();
+        let summary = rows[message + 1].replace(' ', "");
+        assert!(summary.contains("👍3🎉1"), "{}", rows[message + 1]);
+        assert!(!text.contains("😂"));
+
+        // Taking back the last reaction removes the summary line
+        for (user, emoji) in [
+            ("alice", "👍"),
+            ("bob", "👍"),
+            ("carol", "👍"),
+            ("alice", "🎉"),
+        ] {
+            react(&mut app, user, emoji);
+        }
+        assert!(app.reactions.is_empty());
+        let buffer = render(80, 24, |f| chat::render_chat(f, &mut app));
+        assert!(!buffer_text(&buffer).contains('👍'));
+    }
+
+    #[test]
+    fn render_chat_quotes_the_message_a_reply_answers() {
+        let mut app = app_with_messages();
+        if let MessageType::ChatMessage { seq, .. } = &mut app.messages[1] {
+            *seq = Some(1);
+        }
+        for (content, parent) in [("agreed", 1), ("what?", 2)] {
+            app.messages.push(MessageType::ChatMessage {
+                sender: "carol".to_string(),
+                content: content.to_string(),
+                sender_id: "carol".to_string(),
+                client_msg_id: None,
+                message_id: None,
+                reply_to: Some(parent),
+                seq: None,
+            });
+        }
+
+        let buffer = render(80, 24, |f| chat::render_chat(f, &mut app));
+        let text = buffer_text(&buffer);
+        let rows: Vec<&str> = text.lines().collect();
+        let reply = rows.iter().position(|row| row.contains("agreed")).unwrap();
+        assert!(
+            rows[reply - 1].contains("> bob: hello there"),
+            "{}",
+            rows[reply - 1]
+        );
+        let orphan = rows.iter().position(|row| row.contains("what?")).unwrap();
+        assert!(rows[orphan - 1].contains("> (message unavailable)"));
+    }
+
+    #[test]
+    fn fit_columns_fills_the_width_column_by_column() {
+        let names: Vec<String> = ["amy", "bob", "carol", "dave", "eve"]
+            .iter()
+            .map(|name| name.to_string())
+            .collect();
+        // Columns are 7 wide ("carol" plus a gap), so 16 columns of text fit two of them
+        assert_eq!(
+            utils::fit_columns(&names, 16),
+            vec!["amy    dave", "bob    eve", "carol"]
+        );
+        assert_eq!(
+            utils::fit_columns(&names, 40),
+            vec!["amy    bob    carol  dave   eve"]
+        );
+        // Too narrow for even one column still gives one name per row
+        assert_eq!(utils::fit_columns(&names, 2).len(), 5);
+        assert!(utils::fit_columns(&[], 40).is_empty());
+    }
+
+    // A chat message from bob
+    fn from_bob(content: &str) -> MessageType {
+        MessageType::ChatMessage {
+            sender: "bob".to_string(),
+            content: content.to_string(),
+            sender_id: "bob".to_string(),
+            client_msg_id: None,
+            message_id: None,
+            reply_to: None,
+            seq: None,
+        }
+    }
+
+    #[test]
+    fn render_chat_collapses_repeated_messages() {
+        let mut app = app_with_messages();
+        app.messages.extend(["spam", "spam", "spam"].map(from_bob));
+        let text = buffer_text(&render(80, 24, |f| chat::render_chat(f, &mut app)));
+        assert!(text.contains("bob: spam ×3"));
+        assert_eq!(text.matches("spam").count(), 1);
+    }
+
+    #[test]
+    fn notices_are_colored_and_marked_by_level() {
+        let app = App::new();
+        let cases = [
+            (Severity::Info, "done", Color::Yellow),
+            (Severity::Success, "✓ done", Color::Green),
+            (Severity::Warn, "⚠ done", Color::LightYellow),
+            (Severity::Error, "✗ done", Color::Red),
+        ];
+        for (level, text, color) in cases {
+            let lines = utils::wrap_text(&[MessageType::notice(level, "done")], 40, &app);
+            assert_eq!(lines.len(), 1);
+            assert_eq!(lines[0].to_string(), text);
+            assert_eq!(lines[0].style.fg, Some(color), "{:?}", level);
+        }
+
+        // A plain system message looks like an Info notice
+        let lines = utils::wrap_text(&[MessageType::SystemMessage("done".to_string())], 40, &app);
+        assert_eq!(lines[0].to_string(), "done");
+        assert_eq!(lines[0].spans[0].style.fg, Some(Color::Yellow));
+    }
+
+    #[test]
+    fn a_different_message_breaks_a_run_of_repeats() {
+        let app = App::new();
+        let messages = ["spam", "spam", "other", "spam"].map(from_bob);
+        assert_eq!(utils::repeat_counts(&messages, &app), vec![0, 2, 1, 1]);
+        let text: Vec<String> = utils::wrap_text(&messages, 40, &app)
+            .iter()
+            .map(|line| line.to_string())
+            .collect();
+        assert_eq!(text, vec!["bob: spam ×2", "bob: other", "bob: spam"]);
+
+        // Someone else sending the same words isn't a repeat either
+        let mut messages = messages.to_vec();
+        messages.push(MessageType::ChatMessage {
+            sender: "carol".to_string(),
+            content: "spam".to_string(),
+            sender_id: "carol".to_string(),
+            client_msg_id: None,
+            message_id: None,
+            reply_to: None,
+            seq: None,
+        });
+        assert_eq!(utils::repeat_counts(&messages, &app), vec![0, 2, 1, 1, 1]);
+    }
+
+    #[test]
+    fn render_chat_underlines_urls_without_trailing_punctuation() {
+        let mut app = app_with_messages();
+        app.messages.push(MessageType::ChatMessage {
+            sender: "bob".to_string(),
+            content: "see https://example.com/a_(b). or (http://x.org/y), ok".to_string(),
+            sender_id: "bob".to_string(),
+            client_msg_id: None,
+            message_id: None,
+            reply_to: None,
+            seq: None,
+        });
+        let buffer = render(80, 24, |f| chat::render_chat(f, &mut app));
+        let text = buffer_text(&buffer);
+        let row = text
+            .lines()
+            .position(|line| line.contains("see https"))
+            .unwrap();
+        let line = text.lines().nth(row).unwrap();
+        let underlined = |column: usize| {
+            buffer[(column as u16, row as u16)]
+                .modifier
+                .contains(Modifier::UNDERLINED)
+        };
+        // Columns, not bytes, since the border characters are wider than one byte
+        let column = |needle: &str| line[..line.find(needle).unwrap()].chars().count();
+        let start = column("https");
+        let end = start + "https://example.com/a_(b)".len();
+        assert!((start..end).all(underlined));
+        assert!(!underlined(start - 1));
+        assert!(!underlined(end), "trailing '.' is not part of the link");
+        let start = column("http://");
+        let end = start + "http://x.org/y".len();
+        assert!((start..end).all(underlined));
+        assert!(!underlined(end), "unopened ')' is not part of the link");
+
+        // With hyperlinks on, the link cells carry the OSC 8 escape for the whole URL
+        app.hyperlinks = true;
+        let buffer = render(80, 24, |f| chat::render_chat(f, &mut app));
+        let cell = &buffer[(start as u16, row as u16)];
+        assert!(cell.symbol().starts_with("\u{1b}]8;;http://x.org/y\u{7}ht"));
+    }
+
+    #[test]
+    fn render_chat_scrollbar_tracks_scroll_offset() {
+        // Row of the scrollbar thumb on the messages block's right border
+        let thumb_row = |app: &mut App| {
+            let buffer = render(80, 24, |f| chat::render_chat(f, app));
+            (0..24u16)
+                .find(|&y| buffer[(79, y)].symbol() == "█")
+                .expect("no scrollbar thumb")
+        };
+        let mut app = app_with_messages();
+        app.messages
+            .extend((0..60).map(|i| from_bob(&format!("message {}", i))));
+
+        let bottom = thumb_row(&mut app);
+        app.scroll_offset = 30;
+        let middle = thumb_row(&mut app);
+        app.scroll_offset = 1000;
+        let top = thumb_row(&mut app);
+        assert!(
+            top < middle && middle < bottom,
+            "{} {} {}",
+            top,
+            middle,
+            bottom
+        );
+        // The thumb sits just under the up arrow once scrolled all the way
+        assert_eq!(top, 4);
+    }
+
+    #[test]
+    fn scrolled_up_view_stays_put_as_messages_arrive() {
+        // Rows without the scrollbar column, whose thumb moves as the transcript grows
+        let rows = |app: &mut App| -> Vec<String> {
+            let buffer = render(80, 24, |f| chat::render_chat(f, app));
+            let text = buffer_text(&buffer);
+            text.lines()
+                .map(|row| row.chars().take(79).collect())
+                .collect()
+        };
+        let receive = |app: &mut App, content: &str| {
+            app.handle_websocket_message(&serde_json::to_string(&from_bob(content)).unwrap());
+        };
+        let mut app = app_with_messages();
+        app.messages
+            .extend((0..60).map(|i| from_bob(&format!("message {}", i))));
+        for _ in 0..5 {
+            app.scroll_up();
+        }
+        let before = rows(&mut app);
+
+        // A repeat of the last message folds into it, and a longer one wraps
+        receive(&mut app, "message 59");
+        receive(&mut app, &"long ".repeat(40));
+        receive(&mut app, "after");
+        assert_eq!(app.new_messages_below(), 3);
+        let after = rows(&mut app);
+        let changed: Vec<&String> = before
+            .iter()
+            .zip(&after)
+            .filter(|(before, after)| before != after)
+            .map(|(_, after)| after)
+            .collect();
+        assert_eq!(changed.len(), 1, "{:?}", changed);
+        assert!(changed[0].contains(" 3 new messages ↓ "));
+
+        // Scrolling back down reaches the new messages and then follows them again
+        for _ in 0..5 {
+            app.scroll_down();
+        }
+        assert_eq!(app.new_messages_below(), 3);
+        for _ in 0..3 {
+            app.scroll_down();
+        }
+        assert_eq!(app.new_messages_below(), 0);
+        assert_eq!(app.scroll_anchor, None);
+        let text = rows(&mut app).join("\n");
+        assert!(text.contains("bob: after"));
+        assert!(!text.contains("new messages"));
+    }
+
+    #[test]
+    fn following_the_newest_message_has_no_indicator() {
+        let mut app = app_with_messages();
+        app.handle_websocket_message(&serde_json::to_string(&from_bob("news")).unwrap());
+        assert_eq!(app.new_messages_below(), 0);
+        let text = buffer_text(&render(80, 24, |f| chat::render_chat(f, &mut app)));
+        assert!(text.contains("bob: news"));
+        assert!(!text.contains("new messages"));
+
+        // Jumping to the bottom drops the pinned view
+        app.scroll_up();
+        app.handle_websocket_message(&serde_json::to_string(&from_bob("more")).unwrap());
+        assert_eq!(app.new_messages_below(), 1);
+        app.scroll_to_bottom();
+        assert_eq!(app.new_messages_below(), 0);
+    }
+
+    #[test]
+    fn render_login_shows_fields_and_last_system_message() {
+        for (width, height) in SIZES {
+            let mut app = App::new();
+            app.messages.push(MessageType::SystemMessage(
+                "Enter your password:".to_string(),
+            ));
+            let buffer = render(width, height, |f| login::render_login(f, &mut app));
+            if width == 80 {
+                let text = buffer_text(&buffer);
+                assert!(text.contains("Username"));
+                assert!(text.contains("Password"));
+                assert!(text.contains("Enter your password:"));
+            }
+        }
+    }
+
+    #[test]
+    fn render_login_masks_password() {
+        let mut app = App::new();
+        app.password = Some("hunter2".to_string());
+        let buffer = render(80, 24, |f| login::render_login(f, &mut app));
+        let text = buffer_text(&buffer);
+        assert!(text.contains("*******"));
+        assert!(!text.contains("hunter2"));
+    }
+
+    #[test]
+    fn render_login_shows_focused_field_live() {
+        let mut app = App::new();
+        app.message_input = "ali".to_string();
+        let text = buffer_text(&render(80, 24, |f| login::render_login(f, &mut app)));
+        assert!(text.contains("ali"));
+
+        // Typing into the password field stays masked
+        app.switch_login_field(KeyCode::Tab);
+        app.message_input = "hunter2".to_string();
+        let text = buffer_text(&render(80, 24, |f| login::render_login(f, &mut app)));
+        assert!(text.contains("ali"));
+        assert!(text.contains("*******"));
+        assert!(!text.contains("hunter2"));
+    }
+
+    #[test]
+    fn render_server_selection_lists_servers() {
+        for (width, height) in SIZES {
+            let mut app = App::new();
+            let buffer = render(width, height, |f| {
+                server_selection::render_server_selection(f, &mut app)
+            });
+            if width == 80 {
+                let text = buffer_text(&buffer);
+                assert!(text.contains("Select a Server"));
+                assert!(text.contains("local"));
+                assert!(text.contains("default"));
+            }
+        }
+    }
+
+    #[test]
+    fn render_server_selection_without_servers_offers_to_add_one() {
+        for (width, height) in SIZES {
+            let mut app = App::new();
+            app.servers.clear();
+            let buffer = render(width, height, |f| {
+                server_selection::render_server_selection(f, &mut app)
+            });
+            if width == 80 {
+                assert!(buffer_text(&buffer).contains("(n) to add your first server"));
+            }
+        }
+    }
+
+    #[test]
+    fn render_disconnected_shows_reason_and_reconnect_hint() {
+        for (width, height) in SIZES {
+            let mut app = App::new();
+            app.set_disconnected("Server closed the connection.".to_string());
+            let buffer = render(width, height, |f| {
+                disconnected::render_disconnected(f, &mut app)
+            });
+            if width == 80 {
+                let text = buffer_text(&buffer);
+                assert!(text.contains("Disconnected"));
+                assert!(text.contains("Server closed the connection."));
+                assert!(text.contains("reconnect"));
+            }
+        }
+    }
+
+    #[test]
+    fn ui_reports_terminal_too_small() {
+        let mut app = app_with_messages();
+        app.current_screen = CurrentScreen::Main;
+        let text = buffer_text(&render(10, 3, |f| ui(f, &mut app)));
+        assert!(text.contains("Terminal"));
+        assert!(!text.contains("bob"));
+    }
+
+    // Drive one App through a session the way the event loop would, drawing each state with
+    // `ui` at a range of sizes, and return what the 80x24 draw showed
+    fn draw_state(app: &mut App) -> String {
+        for (width, height) in [(20, 5), (33, 9), (200, 60)] {
+            render(width, height, |f| ui(f, app));
+        }
+        buffer_text(&render(80, 24, |f| ui(f, app)))
+    }
+
+    #[test]
+    fn ui_draws_a_session_from_login_to_disconnect() {
+        let mut app = App::new();
+        app.current_screen = CurrentScreen::LoggingIn;
+        app.messages.push(MessageType::SystemMessage(
+            "Enter your username:".to_string(),
+        ));
+        let text = draw_state(&mut app);
+        assert!(text.contains("Username"));
+        assert!(text.contains("Enter your username:"));
+
+        app.staging_username = Some("alice".to_string());
+        app.handle_websocket_message(r#"{"SystemMessage":"Authentication successful"}"#);
+        app.handle_websocket_message(
+            r#"{"ChatMessage":{"sender":"bob","content":"hello there","sender_id":"bob"}}"#,
+        );
+        assert_eq!(app.current_screen, CurrentScreen::Main);
+        let text = draw_state(&mut app);
+        assert!(text.contains("Server: default #general"));
+        assert!(text.contains("You are authenticated!"));
+        assert!(text.contains("bob: hello there"));
+
+        app.current_screen = CurrentScreen::SetUser;
+        app.message_input = "alicia".to_string();
+        let text = draw_state(&mut app);
+        assert!(text.contains("Set Username"));
+        assert!(text.contains("alicia"));
+
+        app.message_input.clear();
+        app.set_disconnected("Server closed the connection.".to_string());
+        let text = draw_state(&mut app);
+        assert!(text.contains("Disconnected"));
+        assert!(text.contains("Server closed the connection."));
+    }
+
+    #[test]
+    fn centered_rect_is_never_degenerate() {
+        for (width, height) in [(1, 1), (3, 2), (10, 5), (19, 4), (30, 8), (80, 24)] {
+            let area = Rect::new(0, 0, width, height);
+            let popup = utils::centered_rect(60, 25, area);
+            assert!(popup.width >= 20.min(width) && popup.height >= 5.min(height));
+            assert_eq!(popup.intersection(area), popup, "{}x{}", width, height);
+        }
+        // Big enough terminals still get the requested share
+        let popup = utils::centered_rect(60, 25, Rect::new(0, 0, 100, 40));
+        assert_eq!((popup.width, popup.height), (60, 10));
+    }
+
+    #[test]
+    fn render_popups_do_not_panic() {
+        for (width, height) in SIZES {
+            let mut app = App::new();
+            app.message_input = "typed".to_string();
+            render(width, height, |f| help::render_help(f, &mut app));
+            render(width, height, exiting::render_exiting);
+            render(width, height, exiting::render_exiting_logging_in);
+            render(width, height, |f| set_user::render_set_user(f, &mut app));
+            render(width, height, |f| {
+                add_server::render_add_server(f, &mut app)
+            });
+            render(width, height, |f| away_list::render_away_list(f, &mut app));
+            render(width, height, |f| user_list::render_user_list(f, &mut app));
+            render(width, height, |f| {
+                connecting::render_connecting(f, &mut app)
+            });
+            render(width, height, |f| welcome::render_welcome(f, &mut app));
+        }
+    }
+
+    #[test]
+    fn render_help_lists_core_commands() {
+        let mut app = App::new();
+        let text = buffer_text(&render(80, 40, |f| help::render_help(f, &mut app)));
+        for command in ["/name", "/list", "/dm", "/help"] {
+            assert!(text.contains(command), "help is missing {}", command);
+        }
+        assert!(text.contains("switch server"));
+    }
+
+    #[test]
+    fn render_away_list_handles_empty_and_populated_lists() {
+        let mut app = App::new();
+        let text = buffer_text(&render(80, 24, |f| {
+            away_list::render_away_list(f, &mut app)
+        }));
+        assert!(text.contains("Nobody is away."));
+
+        app.away_users = vec![("bob".to_string(), "lunch".to_string())];
+        let text = buffer_text(&render(80, 24, |f| {
+            away_list::render_away_list(f, &mut app)
+        }));
+        assert!(text.contains("bob: lunch"));
+    }
+
+    #[test]
+    fn backspace_removes_whole_emoji_and_moves_the_cursor_by_its_width() {
+        let cursor = |app: &mut App| {
+            let mut terminal = Terminal::new(TestBackend::new(80, 24)).unwrap();
+            terminal
+                .draw(|f| set_user::render_set_user(f, app))
+                .unwrap();
+            terminal.get_cursor_position().unwrap().x
+        };
+        let mut app = App::new();
+        app.message_input = "hi".to_string();
+        let start = cursor(&mut app);
+
+        // A wide emoji takes two cells, and a family joined from several code points goes at once
+        app.message_input.push('😀');
+        assert_eq!(cursor(&mut app), start + 2);
+        app.message_input.push_str("👨\u{200d}👩\u{200d}👧");
+        pop_grapheme(&mut app.message_input);
+        assert_eq!(app.message_input, "hi😀");
+        pop_grapheme(&mut app.message_input);
+        assert_eq!(app.message_input, "hi");
+        assert_eq!(cursor(&mut app), start);
+
+        // So does a letter with a combining accent, and an empty input stays empty
+        app.message_input = "e\u{301}".to_string();
+        pop_grapheme(&mut app.message_input);
+        assert_eq!(app.message_input, "");
+        pop_grapheme(&mut app.message_input);
+        assert_eq!(app.message_input, "");
+    }
+
+    #[test]
+    fn input_cursor_follows_display_width_and_the_last_line() {
+        let cursor = |app: &mut App| {
+            let mut terminal = Terminal::new(TestBackend::new(40, 24)).unwrap();
+            terminal.draw(|f| chat::render_chat(f, app)).unwrap();
+            let position = terminal.get_cursor_position().unwrap();
+            (position.x, position.y)
+        };
+
+        // "é" is two bytes but one cell, "☕" one character but two cells
+        let mut app = App::new();
+        app.start_search();
+        app.search_query = Some("café ☕".to_string());
+        assert_eq!(cursor(&mut app), (1 + 7, 22));
+
+        // A message that wraps puts the cursor at the end of its last line, not past the box
+        app.current_screen = CurrentScreen::ComposingMessage;
+        app.message_input = "word ".repeat(8) + "end";
+        assert_eq!(cursor(&mut app), (1 + "word end".len() as u16, 22));
+    }
+
+    #[test]
+    fn render_user_list_shows_a_row_per_user() {
+        let mut app = App::new();
+        app.user_table = [("alice", "online", 42, 3), ("bob", "away", 192, 0)]
+            .into_iter()
+            .chain((0..20).map(|_| ("zed", "muted", 7500, 12)))
+            .map(|(username, status, online_secs, messages)| UserSummary {
+                username: username.to_string(),
+                status: status.to_string(),
+                online_secs,
+                messages,
+            })
+            .collect();
+        let text = buffer_text(&render(80, 24, |f| {
+            user_list::render_user_list(f, &mut app)
+        }));
+        for column in ["Name", "Status", "Online", "Messages"] {
+            assert!(text.contains(column), "missing column {}", column);
+        }
+        assert!(text.contains("Users Online (22)"));
+        let alice = text.lines().find(|line| line.contains("alice")).unwrap();
+        assert!(alice.contains("online") && alice.contains("42s") && alice.contains('3'));
+        let bob = text.lines().find(|line| line.contains("bob")).unwrap();
+        assert!(bob.contains("away") && bob.contains("3m 12s"));
+
+        // Scrolling stops once the last row is in view
+        app.user_table_scroll = 100;
+        let text = buffer_text(&render(80, 24, |f| {
+            user_list::render_user_list(f, &mut app)
+        }));
+        assert!(!text.contains("alice"));
+        assert!(text.contains("2h 5m"));
+        assert!(app.user_table_scroll < 22);
+    }
+
+    #[test]
+    fn render_direct_chat_shows_only_peer_buffer() {
+        for (width, height) in SIZES {
+            let mut app = app_with_messages();
+            app.route_private_message(MessageType::PrivateMessage {
+                sender: "carol".to_string(),
+                recipient: "alice".to_string(),
+                content: "psst".to_string(),
+            });
+            let buffer = render(width, height, |f| {
+                direct_chat::render_direct_chat(f, &mut app, "carol")
+            });
+            if width == 80 {
+                let text = buffer_text(&buffer);
+                assert!(text.contains("Direct chat with carol"));
+                assert!(text.contains("carol: psst"));
+                assert!(!text.contains("hello there"));
+            }
+        }
     }
 }
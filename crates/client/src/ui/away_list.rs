@@ -0,0 +1,36 @@
+// ui/away_list.rs
+use crate::app::App;
+use crate::ui::utils::{centered_rect, sanitize};
+use ratatui::{
+    style::{Color, Style},
+    text::Text,
+    widgets::{Block, Borders, Clear, Paragraph, Wrap},
+    Frame,
+};
+
+pub fn render_away_list(frame: &mut Frame, app: &mut App) {
+    frame.render_widget(Clear, frame.area());
+    let block = Block::default()
+        .title("Away Users (any key to close)")
+        .borders(Borders::ALL)
+        .style(Style::default().bg(Color::DarkGray));
+
+    // One line per away user, or a placeholder when everyone is around
+    let body = if app.away_users.is_empty() {
+        "Nobody is away.".to_string()
+    } else {
+        app.away_users
+            .iter()
+            .map(|(username, away_message)| {
+                sanitize(&format!("{}: {}", username, away_message)).replace('\n', " ")
+            })
+            .collect::<Vec<String>>()
+            .join("\n")
+    };
+
+    let paragraph = Paragraph::new(Text::styled(body, Style::default().fg(app.theme.system)))
+        .block(block)
+        .wrap(Wrap { trim: true });
+    let area = centered_rect(60, 25, frame.area());
+    frame.render_widget(paragraph, area);
+}
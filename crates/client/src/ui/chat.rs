@@ -1,17 +1,43 @@
 // ui/chat.rs
-use crate::app::{App, CurrentScreen};
-use crate::ui::utils::{wrap_single_line, wrap_text};
+use crate::app::{App, CurrentScreen, MAX_MESSAGE_LEN};
+use crate::emoji::REACTIONS;
+use crate::ui::utils::{
+    add_hyperlinks, box_cursor, repeat_counts, sanitize, wrap_message, wrap_single_line,
+};
 use ratatui::{
-    layout::{Constraint, Direction, Layout, Position},
-    style::{Color, Style},
-    text::{Line, Span},
-    widgets::{Block, Borders, List, ListItem, Paragraph, Wrap},
+    layout::{Constraint, Direction, Layout},
+    style::{Color, Modifier, Style},
+    text::{Line, Span, Text},
+    widgets::{
+        Block, Borders, List, ListItem, Paragraph, Scrollbar, ScrollbarOrientation, ScrollbarState,
+        Wrap,
+    },
     Frame,
 };
+use std::time::Instant;
+use unicode_width::UnicodeWidthStr;
+
+// Characters typed before the compose counter turns red
+const COUNTER_WARN_LEN: usize = MAX_MESSAGE_LEN * 9 / 10;
+
+// Characters of a message id shown while it's selected, enough to tell messages apart
+const REPLY_ID_LEN: usize = 8;
+
+// Width of the user list sidebar, and the narrowest terminal it is shown on
+const ROSTER_WIDTH: u16 = 20;
+const ROSTER_MIN_WIDTH: u16 = 60;
 
 pub fn render_chat(frame: &mut Frame, app: &mut App) {
+    // The bottom box holds the query while searching, otherwise the message being composed
+    let searching = app.current_screen == CurrentScreen::Search;
+    let input = if searching {
+        app.search_query.clone().unwrap_or_default()
+    } else {
+        app.message_input.clone()
+    };
+
     // Compose message scrolling management
-    let input_lines = wrap_single_line(&app.message_input, frame.area().width as usize - 4); // Subtracting borders
+    let input_lines = wrap_single_line(&input, frame.area().width.saturating_sub(4) as usize); // Subtracting borders
 
     let available_height = frame.area().height as usize; // u16 to usize value
     let max_input_height = std::cmp::min(available_height.saturating_sub(4), 5); // Prevent overflow
@@ -37,65 +63,271 @@ pub fn render_chat(frame: &mut Frame, app: &mut App) {
         .split(frame.area());
 
     // Header block (Title and Help)
-    let server_title = match &app.selected_server {
-        Some(server) => format!("Server: {}", server),
+    let mut server_title = match &app.selected_server {
+        Some(server) => format!("Server: {} #{}", server, sanitize(&app.channel)),
         None => "TUI Messenger".to_string(),
     };
+    if let Some(topic) = &app.topic {
+        server_title.push_str(&format!(" - {}", sanitize(topic).replace('\n', " ")));
+    }
+    // Flag conversations with unread direct messages
+    if !app.unread_dms.is_empty() {
+        let mut peers: Vec<&String> = app.unread_dms.iter().collect();
+        peers.sort();
+        let peers: Vec<&str> = peers.iter().map(|peer| peer.as_str()).collect();
+        server_title.push_str(&format!("  [new DM: {}]", peers.join(", ")));
+    }
+    if app.spectating {
+        server_title.push_str("  SPECTATING");
+    }
     const KEY_HINT: &str = "(h) help";
+    let online = format!("{} online  ", app.online_count);
     let total_width = frame.area().width as usize;
 
     // Ensure that we don't subtract too much and cause a crash
-    let space_padding = total_width.saturating_sub(server_title.len() + KEY_HINT.len() + 2); // Avoid negative values
+    let space_padding = total_width
+        .saturating_sub(server_title.chars().count() + online.len() + KEY_HINT.len() + 2); // Avoid negative values
 
+    // A mention or DM briefly shows the title reversed, when notifications flash
+    let mut title_style = Style::default().fg(app.theme.header);
+    if app.flashing(Instant::now()) {
+        title_style = title_style.add_modifier(Modifier::REVERSED);
+    }
     let header = Paragraph::new(Line::from(vec![
-        Span::styled(server_title, Style::default().fg(Color::Green)),
+        Span::styled(server_title, title_style),
         Span::raw(" ".repeat(space_padding)), // Safely repeat spaces
-        Span::styled(KEY_HINT, Style::default().fg(Color::Red)),
+        Span::styled(online, Style::default().fg(app.theme.my_message)),
+        Span::styled(KEY_HINT, Style::default().fg(app.theme.error)),
     ]))
     .block(Block::default().borders(Borders::ALL));
     frame.render_widget(header, chunks[0]);
 
-    // Messages area with left/right alignment for sent/received messages
-    let messages_area = chunks[1];
-    let max_width = messages_area.width.checked_sub(4).unwrap_or(0) as usize;
+    // Messages area with left/right alignment for sent/received messages, with the user list
+    // beside it when toggled on and there is room
+    let (messages_area, roster_area) = if app.show_roster && frame.area().width >= ROSTER_MIN_WIDTH
+    {
+        let columns = Layout::default()
+            .direction(Direction::Horizontal)
+            .constraints([Constraint::Min(1), Constraint::Length(ROSTER_WIDTH)])
+            .split(chunks[1]);
+        (columns[0], Some(columns[1]))
+    } else {
+        (chunks[1], None)
+    };
+    let max_width = messages_area.width.saturating_sub(4) as usize;
     let available_lines = (messages_area.height as usize).saturating_sub(2);
 
-    // Wrap messages, and calculate total lines
-    let wrapped_lines = wrap_text(&app.messages, max_width, app.username.as_deref());
+    // Wrap messages one at a time, remembering which lines belong to the selected message
+    // and to search matches
+    let current_match = app.current_search_match();
+    let mut wrapped_lines = Vec::new();
+    let mut selected_lines = None;
+    let mut current_match_lines = None;
+    let mut match_lines = Vec::new();
+    let mut anchor_line = None;
+    let repeats = repeat_counts(&app.messages, app);
+    for (index, message) in app.messages.iter().enumerate() {
+        if app.scroll_anchor == Some(index) {
+            anchor_line = Some(wrapped_lines.len());
+        }
+        let lines = wrap_message(message, repeats[index], max_width, app);
+        let range = wrapped_lines.len()..wrapped_lines.len() + lines.len();
+        if app.selected_message == Some(index) {
+            selected_lines = Some(range.clone());
+        }
+        if current_match == Some(index) {
+            current_match_lines = Some(range.clone());
+        }
+        if app.search_matches.binary_search(&index).is_ok() {
+            match_lines.push(range);
+        }
+        wrapped_lines.extend(lines);
+    }
     let total_lines = wrapped_lines.len();
 
-    // Calculate starting line based on the scroll offset and total lines
-    let start_line = total_lines
+    // The scroll offset counts up from the bottom of the pinned messages while scrolled up, so
+    // the lines being read stay put as others arrive below. A repeat that arrived takes the
+    // place of the pinned copy before it, which still counts where it was.
+    let anchor_line = match (anchor_line, app.scroll_anchor) {
+        (Some(line), Some(anchor)) => match anchor.checked_sub(1) {
+            Some(last_pinned) if repeats[last_pinned] == 0 => {
+                line + wrap_message(&app.messages[last_pinned], 1, max_width, app).len()
+            }
+            _ => line,
+        },
+        _ => total_lines,
+    };
+
+    // Calculate starting line based on the scroll offset and where the view is pinned
+    let mut start_line = anchor_line
         .saturating_sub(available_lines)
         .saturating_sub(app.scroll_offset);
+    // Keep the selected message, or else the current search match, in view
+    if let Some(selected) = selected_lines.as_ref().or(current_match_lines.as_ref()) {
+        if selected.start < start_line {
+            start_line = selected.start;
+        } else if selected.end > start_line + available_lines {
+            start_line = selected.end.saturating_sub(available_lines);
+        }
+    }
 
-    // Render the visible lines
+    // Render the visible lines, keeping a copy to find links in when they're made clickable
+    let linked_lines = if app.hyperlinks {
+        wrapped_lines
+            .iter()
+            .skip(start_line)
+            .take(available_lines)
+            .cloned()
+            .collect()
+    } else {
+        Vec::new()
+    };
     let visible_lines = wrapped_lines
         .into_iter()
+        .enumerate()
         .skip(start_line)
         .take(available_lines)
-        .map(|line| {
-            ListItem::new(line) // The line is already a Span with styling
+        .map(|(line_index, line)| {
+            let item = ListItem::new(line); // The line is already styled
+            let in_range = |range: &std::ops::Range<usize>| range.contains(&line_index);
+            if selected_lines.as_ref().is_some_and(in_range) {
+                item.style(Style::default().add_modifier(Modifier::REVERSED))
+            } else if current_match_lines.as_ref().is_some_and(in_range) {
+                item.style(Style::default().fg(Color::Black).bg(app.theme.highlight))
+            } else if match_lines.iter().any(in_range) {
+                item.style(Style::default().bg(Color::DarkGray))
+            } else {
+                item
+            }
         })
         .collect::<Vec<ListItem>>();
 
-    let list = List::new(visible_lines).block(Block::default().borders(Borders::ALL));
+    let mut messages_block = Block::default().borders(Borders::ALL);
+    if app.new_messages_below() > 0 {
+        messages_block = messages_block.title_bottom(
+            Line::styled(
+                format!(" {} new messages ↓ ", app.new_messages_below()),
+                Style::default().fg(app.theme.system),
+            )
+            .right_aligned(),
+        );
+    }
+    let list = List::new(visible_lines).block(messages_block);
     frame.render_widget(list, messages_area);
+    add_hyperlinks(frame.buffer_mut(), messages_area, &linked_lines);
 
-    // Message input block
-    let typing = Paragraph::new(visible_input_lines.join("\n"))
-        .block(
+    // Scrollbar on the right border of the messages block, tracking the first visible line
+    let mut scrollbar_state = scrollbar_state(total_lines, available_lines, start_line);
+    let scrollbar = Scrollbar::new(ScrollbarOrientation::VerticalRight)
+        .begin_symbol(Some("↑"))
+        .end_symbol(Some("↓"));
+    frame.render_stateful_widget(scrollbar, messages_area, &mut scrollbar_state);
+
+    if let Some(roster_area) = roster_area {
+        let names = app
+            .roster
+            .iter()
+            .map(|name| ListItem::new(sanitize(name)))
+            .collect::<Vec<ListItem>>();
+        let roster = List::new(names).block(
             Block::default()
                 .borders(Borders::ALL)
-                .title("Compose Message"),
-        )
+                .title(format!("Users ({})", app.roster.len())),
+        );
+        frame.render_widget(roster, roster_area);
+    }
+
+    // Message input block, with the rest of a slash command ghosted after what was typed
+    let mut input_text = Text::raw(visible_input_lines.join("\n"));
+    if app.current_screen == CurrentScreen::ComposingMessage {
+        if let (Some(hint), Some(last_line)) = (app.completion_hint(), input_text.lines.last_mut())
+        {
+            last_line.push_span(Span::styled(hint, Style::default().fg(Color::DarkGray)));
+        }
+    }
+    let title = match &app.search_query {
+        Some(_) if searching => "Search (Enter to keep, Esc to cancel)".to_string(),
+        Some(query) => format!(
+            "Search \"{}\": {}/{} (n/N, Esc to clear)",
+            sanitize(query),
+            app.search_current.map_or(0, |current| current + 1),
+            app.search_matches.len()
+        ),
+        None if app.reacting => {
+            let choices = REACTIONS
+                .iter()
+                .enumerate()
+                .map(|(i, emoji)| format!("{} {}", i + 1, emoji))
+                .collect::<Vec<String>>()
+                .join("  ");
+            format!("React: {}, or type an emoji (Esc to cancel)", choices)
+        }
+        None if app.spectating => "Read-only (spectating)".to_string(),
+        // The start of the selected message's id is enough to /reply to it
+        None if app.selected_message_id().is_some() => {
+            let id = app.selected_message_id().unwrap_or_default();
+            let short_id: String = id.chars().take(REPLY_ID_LEN).collect();
+            format!(
+                "Message {} selected (y copy, r react, /reply {} <message>)",
+                short_id, short_id
+            )
+        }
+        None if app.current_screen == CurrentScreen::Main && !app.message_input.is_empty() => {
+            "Press Enter to compose (draft preserved, Esc to discard)".to_string()
+        }
+        None if app.current_screen == CurrentScreen::Main => "Press Enter to compose".to_string(),
+        None => "Compose Message".to_string(),
+    };
+    // The box is dimmed until it takes input, so it's clear Enter has to be pressed first
+    let input_style = if app.current_screen == CurrentScreen::Main {
+        Style::default().fg(Color::DarkGray)
+    } else {
+        Style::default().fg(app.theme.highlight)
+    };
+    let mut input_block = Block::default()
+        .borders(Borders::ALL)
+        .title(title)
+        .border_style(input_style);
+    if app.current_screen == CurrentScreen::ComposingMessage {
+        input_block = input_block.title(compose_counter(&app.message_input, app).right_aligned());
+    }
+    let typing = Paragraph::new(input_text)
+        .block(input_block)
         .wrap(Wrap { trim: true });
     frame.render_widget(typing, chunks[2]);
 
-    // Set cursor position if composing a message
-    if let CurrentScreen::ComposingMessage = app.current_screen {
-        let cursor_x = chunks[2].x + app.message_input.len() as u16 + 1;
-        let cursor_y = chunks[2].y + visible_input_lines.len() as u16;
-        frame.set_cursor_position(Position::new(cursor_x, cursor_y));
+    // Set cursor position if composing a message or typing a search, after the last line shown.
+    // Columns are display cells, so wide characters move it by two.
+    if app.current_screen == CurrentScreen::ComposingMessage || searching {
+        let cursor = box_cursor(
+            chunks[2],
+            visible_input_lines.last().map_or(0, |line| line.width()),
+            visible_input_lines.len().saturating_sub(1),
+        );
+        frame.set_cursor_position(cursor);
     }
 }
+
+// "42/2000" counter for the compose box, red once the message nears the limit. Counts characters
+// rather than bytes so emoji count as one.
+fn compose_counter(input: &str, app: &App) -> Line<'static> {
+    let length = input.chars().count();
+    let style = if length >= COUNTER_WARN_LEN {
+        Style::default().fg(app.theme.error)
+    } else {
+        Style::default()
+    };
+    Line::styled(format!(" {}/{} ", length, MAX_MESSAGE_LEN), style)
+}
+
+// Build the scrollbar state for the messages list. The scrollable range is the number of
+// lines hidden above the viewport, so the thumb sits at the bottom when fully scrolled down.
+pub fn scrollbar_state(
+    total_lines: usize,
+    available_lines: usize,
+    start_line: usize,
+) -> ScrollbarState {
+    ScrollbarState::new(total_lines.saturating_sub(available_lines))
+        .viewport_content_length(available_lines)
+        .position(start_line)
+}
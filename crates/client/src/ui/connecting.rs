@@ -0,0 +1,27 @@
+// ui/connecting.rs
+use crate::app::App;
+use crate::ui::utils::centered_rect;
+use ratatui::{
+    style::{Color, Style},
+    widgets::{Block, Borders, Clear, Paragraph, Wrap},
+    Frame,
+};
+
+pub fn render_connecting(frame: &mut Frame, app: &mut App) {
+    let block = Block::default()
+        .title("Connecting")
+        .borders(Borders::ALL)
+        .style(Style::default().bg(Color::DarkGray));
+    let server = app.selected_server.as_deref().unwrap_or("server");
+    let paragraph = Paragraph::new(format!(
+        "{} Connecting to {}…\nPress Esc to cancel.",
+        app.spinner(),
+        server
+    ))
+    .block(block)
+    .wrap(Wrap { trim: true })
+    .style(Style::default().fg(app.theme.system));
+    let area = centered_rect(60, 25, frame.area());
+    frame.render_widget(Clear, frame.area());
+    frame.render_widget(paragraph, area);
+}
@@ -0,0 +1,75 @@
+// ui/direct_chat.rs
+use crate::app::App;
+use crate::ui::utils::{add_hyperlinks, box_cursor, wrap_single_line, wrap_text};
+use ratatui::{
+    layout::{Constraint, Direction, Layout},
+    style::Style,
+    text::{Line, Span},
+    widgets::{Block, Borders, List, ListItem, Paragraph, Wrap},
+    Frame,
+};
+use unicode_width::UnicodeWidthStr;
+
+pub fn render_direct_chat(frame: &mut Frame, app: &mut App, peer: &str) {
+    frame.render_widget(ratatui::widgets::Clear, frame.area());
+
+    let input_lines = wrap_single_line(
+        &app.message_input,
+        frame.area().width.saturating_sub(4) as usize,
+    );
+    let input_height = std::cmp::min(input_lines.len(), 5);
+
+    let chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([
+            Constraint::Length(3),                         // Title/Header
+            Constraint::Min(1),                            // Conversation
+            Constraint::Length((input_height + 2) as u16), // Message Input Field
+        ])
+        .split(frame.area());
+
+    let header = Paragraph::new(Line::from(vec![
+        Span::styled(
+            format!("Direct chat with {}", peer),
+            Style::default().fg(app.theme.other_message),
+        ),
+        Span::raw(" "),
+        Span::styled("(Esc) back", Style::default().fg(app.theme.error)),
+    ]))
+    .block(Block::default().borders(Borders::ALL));
+    frame.render_widget(header, chunks[0]);
+
+    // Only the buffer for this peer is shown, pinned to the newest lines
+    let messages_area = chunks[1];
+    let max_width = messages_area.width.saturating_sub(4) as usize;
+    let available_lines = (messages_area.height as usize).saturating_sub(2);
+    let buffer = app.dm_buffers.get(peer).map(Vec::as_slice).unwrap_or(&[]);
+    let wrapped_lines = wrap_text(buffer, max_width, app);
+    let start_line = wrapped_lines.len().saturating_sub(available_lines);
+    let visible_lines = wrapped_lines
+        .into_iter()
+        .skip(start_line)
+        .collect::<Vec<_>>();
+    let list = List::new(visible_lines.iter().cloned().map(ListItem::new))
+        .block(Block::default().borders(Borders::ALL));
+    frame.render_widget(list, messages_area);
+    if app.hyperlinks {
+        add_hyperlinks(frame.buffer_mut(), messages_area, &visible_lines);
+    }
+
+    let typing = Paragraph::new(input_lines.join("\n"))
+        .block(
+            Block::default()
+                .borders(Borders::ALL)
+                .title(format!("Message {}", peer)),
+        )
+        .wrap(Wrap { trim: true });
+    frame.render_widget(typing, chunks[2]);
+
+    let cursor = box_cursor(
+        chunks[2],
+        app.message_input.width(),
+        input_lines.len().saturating_sub(1),
+    );
+    frame.set_cursor_position(cursor);
+}
@@ -1,4 +1,5 @@
 // ui/disconnected.rs
+use crate::app::App;
 use crate::ui::utils::centered_rect;
 use ratatui::{
     style::{Color, Style},
@@ -6,18 +7,23 @@ use ratatui::{
     Frame,
 };
 
-pub fn render_disconnected(frame: &mut Frame) {
+pub fn render_disconnected(frame: &mut Frame, app: &mut App) {
     let block = Block::default()
         .title("Disconnected")
         .borders(Borders::ALL)
         .style(Style::default().bg(Color::DarkGray));
-    let paragraph =
-        Paragraph::new("Connection lost. Press 'r' to attempt to reconnect or press 'q' to quit.")
-            .block(block)
-            .wrap(Wrap { trim: true })
-            .style(Style::default().fg(Color::Yellow));
+    let reason = app
+        .disconnect_reason
+        .as_deref()
+        .unwrap_or("Connection lost.");
+    let paragraph = Paragraph::new(format!(
+        "{}\nPress 'r' to attempt to reconnect or press 'q' to quit.",
+        reason
+    ))
+    .block(block)
+    .wrap(Wrap { trim: true })
+    .style(Style::default().fg(app.theme.system));
     let area = centered_rect(60, 25, frame.area());
     frame.render_widget(Clear, frame.area());
     frame.render_widget(paragraph, area);
-    return;
 }
@@ -8,15 +8,26 @@ use ratatui::{
 };
 
 pub fn render_exiting(frame: &mut Frame) {
+    render_prompt(frame, "y/n", "Are you sure you want to quit?");
+}
+
+// Leaving the login form can also mean going back to pick another server
+pub fn render_exiting_logging_in(frame: &mut Frame) {
+    render_prompt(
+        frame,
+        "y/n/s",
+        "Are you sure you want to quit? (s to switch servers)",
+    );
+}
+
+// A yes/no question in a popup, also used by screens other than quitting
+pub fn render_prompt(frame: &mut Frame, title: &str, text: &str) {
     frame.render_widget(Clear, frame.area());
     let popup_block = Block::default()
-        .title("y/n")
+        .title(title)
         .borders(Borders::NONE)
         .style(Style::default().bg(Color::DarkGray));
-    let exit_text = Text::styled(
-        "Are you sure you want to quit?",
-        Style::default().fg(Color::Red),
-    );
+    let exit_text = Text::styled(text, Style::default().fg(Color::Red));
     let exit_paragraph = Paragraph::new(exit_text)
         .block(popup_block)
         .wrap(Wrap { trim: false });
@@ -1,27 +1,80 @@
 // ui/help.rs
-use crate::ui::utils::centered_rect;
+use crate::app::{App, COMMANDS};
 use ratatui::{
+    layout::Margin,
     style::{Color, Style},
-    text::Text,
+    text::{Line, Span, Text},
     widgets::{Block, Borders, Clear, Paragraph, Wrap},
     Frame,
 };
 
-pub fn render_help(frame: &mut Frame) {
-    frame.render_widget(ratatui::widgets::Clear, frame.area());
+// Key bindings on the main screen
+const KEY_BINDINGS: &[(&str, &str)] = &[
+    ("Enter", "compose a message"),
+    ("n", "set username"),
+    ("s", "switch server"),
+    ("↑↓", "scroll"),
+    (
+        "/",
+        "search the transcript (n/N for older/newer matches, Esc to clear)",
+    ),
+    (
+        "v",
+        "select messages (↑↓ to move, y to copy, r to react, Esc to stop)",
+    ),
+    ("u", "show or hide the user list"),
+    ("h", "help"),
+    ("q", "quit"),
+];
 
+pub fn render_help(frame: &mut Frame, app: &mut App) {
     frame.render_widget(Clear, frame.area());
     let help_menu_block = Block::default()
-        .title("Help Menu")
+        .title("Help Menu (↑↓ to scroll, any other key to close)")
         .borders(Borders::NONE)
         .style(Style::default().bg(Color::DarkGray));
-    let help_menu_text = Text::styled(
-        "(q) to quit\n(n) to set username\n(s) to select server \n(↑↓) to scroll",
-        Style::default().fg(Color::Red),
-    );
-    let help_menu_paragraph = Paragraph::new(help_menu_text)
-        .block(help_menu_block)
-        .wrap(Wrap { trim: false });
-    let area = centered_rect(60, 25, frame.area());
-    frame.render_widget(help_menu_paragraph, area);
+
+    let area = frame.area();
+    let inner = area.inner(Margin::new(1, 1));
+    let key_style = Style::default().fg(Color::Red);
+
+    // Keys are packed side by side, as many to a row as fit, to leave room for the commands
+    let mut lines = vec![Line::from("Keys")];
+    let mut row: Vec<Span> = Vec::new();
+    let mut row_width = 0;
+    for (key, action) in KEY_BINDINGS {
+        let key = format!("  ({}) ", key);
+        let width = key.chars().count() + action.chars().count();
+        if row_width > 0 && row_width + width > inner.width as usize {
+            lines.push(Line::from(std::mem::take(&mut row)));
+            row_width = 0;
+        }
+        row.push(Span::styled(key, key_style));
+        row.push(Span::raw(*action));
+        row_width += width;
+    }
+    lines.push(Line::from(row));
+    lines.push(Line::from(""));
+
+    // The command list comes from the registry so it never falls out of date
+    lines.push(Line::from("Commands (Tab completes)"));
+    for command in COMMANDS {
+        let usage = if command.usage.is_empty() {
+            command.name.to_string()
+        } else {
+            format!("{} {}", command.name, command.usage)
+        };
+        lines.push(Line::from(vec![
+            Span::styled(format!("  {} ", usage), key_style),
+            Span::raw(command.description),
+        ]));
+    }
+
+    // Scrolls when it doesn't fit
+    frame.render_widget(help_menu_block, area);
+    app.help_scroll = app.help_scroll.min(lines.len().saturating_sub(1) as u16);
+    let paragraph = Paragraph::new(Text::from(lines))
+        .wrap(Wrap { trim: false })
+        .scroll((app.help_scroll, 0));
+    frame.render_widget(paragraph, inner);
 }
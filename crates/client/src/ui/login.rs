@@ -1,10 +1,12 @@
 // ui/login.rs
 use crate::app::{App, LoginField, MessageType};
+use crate::ui::utils::box_cursor;
 use ratatui::{
-    layout::{Constraint, Direction, Layout, Position},
+    layout::{Constraint, Direction, Layout},
     widgets::{Block, Borders, Paragraph, Wrap},
     Frame,
 };
+use unicode_width::UnicodeWidthStr;
 
 pub fn render_login(frame: &mut Frame, app: &mut App) {
     frame.render_widget(ratatui::widgets::Clear, frame.area());
@@ -16,7 +18,7 @@ pub fn render_login(frame: &mut Frame, app: &mut App) {
             Constraint::Length(3), // Title
             Constraint::Length(3), // Username input
             Constraint::Length(3), // Password input
-            Constraint::Length(2), // System message
+            Constraint::Length(3), // System message
             Constraint::Min(0),    // Filler (remaining space)
         ])
         .split(frame.area());
@@ -26,12 +28,18 @@ pub fn render_login(frame: &mut Frame, app: &mut App) {
         .title("Username")
         .borders(Borders::ALL)
         .style(if let LoginField::Username = app.current_login_field {
-            ratatui::style::Style::default().fg(ratatui::style::Color::Yellow) // Highlight active input
+            ratatui::style::Style::default().fg(app.theme.highlight) // Highlight active input
         } else {
             ratatui::style::Style::default()
         });
 
-    let username_input = Paragraph::new(app.username.clone().unwrap_or_default())
+    // The focused field shows what is being typed; the other shows its stored value
+    let username = if app.current_login_field == LoginField::Username {
+        app.message_input.clone()
+    } else {
+        app.username.clone().unwrap_or_default()
+    };
+    let username_input = Paragraph::new(username)
         .block(username_block)
         .wrap(Wrap { trim: true });
 
@@ -42,49 +50,52 @@ pub fn render_login(frame: &mut Frame, app: &mut App) {
         .title("Password")
         .borders(Borders::ALL)
         .style(if let LoginField::Password = app.current_login_field {
-            ratatui::style::Style::default().fg(ratatui::style::Color::Yellow) // Highlight active input
+            ratatui::style::Style::default().fg(app.theme.highlight) // Highlight active input
         } else {
             ratatui::style::Style::default()
         });
 
-    let password_input = Paragraph::new(if app.password.is_some() {
-        "*".repeat(app.password.as_ref().unwrap().len()) // Mask the password input
+    let password_len = if app.current_login_field == LoginField::Password {
+        app.message_input.chars().count()
     } else {
-        String::new()
-    })
-    .block(password_block)
-    .wrap(Wrap { trim: true });
+        app.password
+            .as_deref()
+            .map_or(0, |password| password.chars().count())
+    };
+    let password_input = Paragraph::new("*".repeat(password_len)) // Mask the password input
+        .block(password_block)
+        .wrap(Wrap { trim: true });
 
     frame.render_widget(password_input, chunks[2]);
 
     // Display the most recent system message (e.g., authentication failure)
     let system_message = if let Some(last_message) = app.messages.last() {
         match last_message {
-            MessageType::SystemMessage(msg) => msg.clone(),
+            MessageType::SystemMessage(msg) | MessageType::Notice { text: msg, .. } => msg.clone(),
             _ => "".to_string(),
         }
     } else {
         "".to_string()
     };
 
-    let message_block = Block::default()
-        .borders(Borders::ALL)
-        .title("System Message");
+    // A submitted login spins until the server answers
+    let title = if app.loading() {
+        format!("System Message {}", app.spinner())
+    } else {
+        "System Message".to_string()
+    };
+    let message_block = Block::default().borders(Borders::ALL).title(title);
     let message_paragraph = Paragraph::new(system_message)
         .block(message_block)
         .wrap(Wrap { trim: true });
     frame.render_widget(message_paragraph, chunks[3]);
 
-    // Set cursor position based on the active field
-    let cursor_x = match app.current_login_field {
-        LoginField::Username => chunks[1].x + app.message_input.len() as u16 + 1,
-        LoginField::Password => chunks[2].x + app.message_input.len() as u16 + 1,
-    };
-    let cursor_y = match app.current_login_field {
-        LoginField::Username => chunks[1].y + 1,
-        LoginField::Password => chunks[2].y + 1,
+    // Set cursor position based on the active field, after the last `*` for the password
+    let (field_area, column) = match app.current_login_field {
+        LoginField::Username => (chunks[1], app.message_input.width()),
+        LoginField::Password => (chunks[2], password_len),
     };
     if app.is_typing {
-        frame.set_cursor_position(Position::new(cursor_x, cursor_y));
+        frame.set_cursor_position(box_cursor(field_area, column, 0));
     }
 }
@@ -1,8 +1,9 @@
 // ui/server_selection.rs
 use crate::app::App;
+use crate::ui::exiting;
 use ratatui::{
     layout::{Constraint, Direction, Layout},
-    style::{Color, Style},
+    style::Style,
     widgets::{Block, Borders, List, ListItem, Paragraph},
     Frame,
 };
@@ -22,24 +23,53 @@ pub fn render_server_selection(frame: &mut Frame, app: &mut App) {
     let title = Paragraph::new("Select a Server").block(Block::default().borders(Borders::ALL));
     frame.render_widget(title, chunks[0]);
 
+    // With nothing configured yet, point at how to add a server instead of an empty list
+    if app.servers.is_empty() {
+        let empty = Paragraph::new("No servers yet. Press (n) to add your first server.").block(
+            Block::default()
+                .borders(Borders::ALL)
+                .title("Available Servers"),
+        );
+        frame.render_widget(empty, chunks[1]);
+        return;
+    }
+
     // Render the server list
     let server_list: Vec<ListItem> = app
         .servers
-        .iter()
-        .map(|(name, _url)| {
+        .keys()
+        .map(|name| {
             let style = if Some(name) == app.selected_server.as_ref() {
-                Style::default().fg(Color::Yellow)
+                Style::default().fg(app.theme.highlight)
             } else {
                 Style::default()
             };
-            ListItem::new(name.clone()).style(style)
+            // Show how many messages arrived on a server since it was last looked at
+            let label = match app.unread_counts.get(name) {
+                Some(unread) if *unread > 0 => format!("{} ({})", name, unread),
+                _ => name.clone(),
+            };
+            ListItem::new(label).style(style)
         })
         .collect();
 
     let server_list_widget = List::new(server_list).block(
         Block::default()
             .borders(Borders::ALL)
-            .title("Available Servers, add with (n)"),
+            .title("Available Servers, add with (n), delete with (d)"),
     );
     frame.render_widget(server_list_widget, chunks[1]);
+
+    // Deleting asks first, and says so when it's the server we're connected to
+    if let Some(name) = &app.deleting_server {
+        let question = if app.active_server.as_ref() == Some(name) {
+            format!(
+                "You are connected to {}. Delete it from the list anyway? It stays connected until you switch servers.",
+                name
+            )
+        } else {
+            format!("Delete {} from the list?", name)
+        };
+        exiting::render_prompt(frame, "y/n", &question);
+    }
 }
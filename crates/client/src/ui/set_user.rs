@@ -1,12 +1,13 @@
 // ui/set_user.rs
 use crate::app::App;
-use crate::ui::utils::centered_rect;
+use crate::ui::utils::{box_cursor, centered_rect};
 use ratatui::{
-    layout::Position,
     style::{Color, Style},
+    text::{Line, Text},
     widgets::{Block, Borders, Clear, Paragraph, Wrap},
     Frame,
 }; // Import the utility functions
+use unicode_width::UnicodeWidthStr;
 
 pub fn render_set_user(frame: &mut Frame, app: &mut App) {
     frame.render_widget(Clear, frame.area());
@@ -14,12 +15,16 @@ pub fn render_set_user(frame: &mut Frame, app: &mut App) {
         .title("Set Username")
         .borders(Borders::ALL)
         .style(Style::default().bg(Color::DarkGray));
-    let paragraph = Paragraph::new(app.message_input.as_str())
-        .block(block)
-        .wrap(Wrap { trim: true });
+    // Say why the last key was ignored under the name being typed
+    let mut text = Text::from(app.message_input.as_str());
+    if let Some(error) = &app.set_user_error {
+        text.push_line(Line::styled(
+            error.as_str(),
+            Style::default().fg(app.theme.error),
+        ));
+    }
+    let paragraph = Paragraph::new(text).block(block).wrap(Wrap { trim: true });
     let area = centered_rect(60, 25, frame.area());
     frame.render_widget(paragraph, area);
-    let cursor_x = area.x + app.message_input.len() as u16 + 1;
-    let cursor_y = area.y + 1;
-    frame.set_cursor_position(Position::new(cursor_x, cursor_y));
+    frame.set_cursor_position(box_cursor(area, app.message_input.width(), 0));
 }
@@ -0,0 +1,63 @@
+// ui/user_list.rs
+use crate::app::App;
+use crate::ui::utils::{centered_rect, sanitize};
+use ratatui::{
+    layout::Constraint,
+    style::{Color, Modifier, Style},
+    widgets::{Block, Borders, Clear, Row, Table},
+    Frame,
+};
+
+pub fn render_user_list(frame: &mut Frame, app: &mut App) {
+    frame.render_widget(Clear, frame.area());
+    let area = centered_rect(60, 50, frame.area());
+    let block = Block::default()
+        .title(format!(
+            "Users Online ({}) (Up/Down to scroll, any other key to close)",
+            app.user_table.len()
+        ))
+        .borders(Borders::ALL)
+        .style(Style::default().bg(Color::DarkGray));
+
+    // Scroll no further than it takes to show the last row, less the borders and header
+    let visible = area.height.saturating_sub(3) as usize;
+    let max_scroll = app.user_table.len().saturating_sub(visible);
+    app.user_table_scroll = app.user_table_scroll.min(max_scroll as u16);
+
+    let rows = app
+        .user_table
+        .iter()
+        .skip(app.user_table_scroll as usize)
+        .map(|user| {
+            Row::new(vec![
+                sanitize(&user.username).replace('\n', " "),
+                user.status.clone(),
+                format_online(user.online_secs),
+                user.messages.to_string(),
+            ])
+        });
+    let header = Row::new(vec!["Name", "Status", "Online", "Messages"])
+        .style(Style::default().add_modifier(Modifier::BOLD));
+    let table = Table::new(
+        rows,
+        [
+            Constraint::Min(10),
+            Constraint::Length(7),
+            Constraint::Length(8),
+            Constraint::Length(8),
+        ],
+    )
+    .header(header)
+    .block(block)
+    .style(Style::default().fg(app.theme.system));
+    frame.render_widget(table, area);
+}
+
+// Time online for people: "42s", "3m 12s" or "2h 5m"
+fn format_online(secs: u64) -> String {
+    match secs {
+        0..=59 => format!("{}s", secs),
+        60..=3599 => format!("{}m {}s", secs / 60, secs % 60),
+        _ => format!("{}h {}m", secs / 3600, secs % 3600 / 60),
+    }
+}
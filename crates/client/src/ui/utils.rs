@@ -1,13 +1,34 @@
 // Define `centered_rect`
-use crate::app::MessageType;
+use crate::app::{find_mentions, reaction_summary, App, DeliveryStatus, MessageType, Severity};
 
 use ratatui::{
-    layout::{Constraint, Direction, Layout, Rect},
-    style::{Color, Style},
-    text::Span,
+    buffer::Buffer,
+    layout::{Constraint, Direction, Layout, Position, Rect},
+    style::{Color, Modifier, Style},
+    text::{Line, Span},
 };
+use std::ops::Range;
+use unicode_width::UnicodeWidthStr;
 
+// Smallest popup worth drawing; percentages of a tiny terminal can round down to nothing
+const POPUP_MIN_WIDTH: u16 = 20;
+const POPUP_MIN_HEIGHT: u16 = 5;
+
+// A popup `percent_x` by `percent_y` of `r`, centred in it, but never smaller than
+// POPUP_MIN_WIDTH x POPUP_MIN_HEIGHT unless `r` itself is
 pub fn centered_rect(percent_x: u16, percent_y: u16, r: Rect) -> Rect {
+    let popup = percent_rect(percent_x, percent_y, r);
+    let width = popup.width.max(POPUP_MIN_WIDTH.min(r.width));
+    let height = popup.height.max(POPUP_MIN_HEIGHT.min(r.height));
+    Rect::new(
+        r.x + (r.width - width) / 2,
+        r.y + (r.height - height) / 2,
+        width,
+        height,
+    )
+}
+
+fn percent_rect(percent_x: u16, percent_y: u16, r: Rect) -> Rect {
     let popup_layout = Layout::default()
         .direction(Direction::Vertical)
         .constraints([
@@ -27,60 +48,491 @@ pub fn centered_rect(percent_x: u16, percent_y: u16, r: Rect) -> Rect {
         .split(popup_layout[1])[1]
 }
 
+// Cursor position `column` cells into line `row` of a bordered input box, clamped so it stays
+// inside the box however small the terminal is. Columns are display widths, not characters, as
+// wide characters like emoji take two cells.
+pub fn box_cursor(area: Rect, column: usize, row: usize) -> Position {
+    let offset = |value: usize| u16::try_from(value).unwrap_or(u16::MAX);
+    let max_x = area.right().saturating_sub(2).max(area.x);
+    let max_y = area.bottom().saturating_sub(2).max(area.y);
+    Position::new(
+        area.x
+            .saturating_add(1)
+            .saturating_add(offset(column))
+            .min(max_x),
+        area.y
+            .saturating_add(1)
+            .saturating_add(offset(row))
+            .min(max_y),
+    )
+}
+
+// Columns taken by the `sender: ` prefix. Very long names would leave no room, so the content
+// gets at least half the width.
+fn sender_indent(sender: &str, max_width: usize) -> usize {
+    (sanitize(sender).chars().count() + 2).min(max_width / 2)
+}
+
+// Prefix the first wrapped line with `sender: ` and indent the rest to line up under the content
+fn push_with_sender(
+    lines: &mut Vec<Line<'static>>,
+    sender: &str,
+    content: &str,
+    max_width: usize,
+    style: Style,
+    app: &App,
+) {
+    let prefix = format!("{}: ", sanitize(sender));
+    let indent = sender_indent(sender, max_width);
+    let wrapped_lines = wrap_single_line(content, max_width.saturating_sub(indent).max(1));
+    for (i, line) in wrapped_lines.into_iter().enumerate() {
+        let lead = if i == 0 {
+            prefix.clone()
+        } else {
+            " ".repeat(indent)
+        };
+        // Mentions of our name stand out, but only in what was said, not in the sender's name
+        let mentions = match &app.username {
+            Some(name) => find_mentions(&line, name)
+                .into_iter()
+                .map(|range| range.start + lead.len()..range.end + lead.len())
+                .collect(),
+            None => Vec::new(),
+        };
+        lines.push(highlight(
+            format!("{}{}", lead, line),
+            style,
+            &mentions,
+            app,
+        ));
+    }
+}
+
+// One-line quote of the message a reply answers, cut to fit `width`
+fn reply_quote(app: &App, parent: u64, width: usize) -> String {
+    let quote = match app.message_by_seq(parent) {
+        Some(MessageType::ChatMessage {
+            sender, content, ..
+        }) => format!("> {}: {}", sanitize(sender), sanitize(content)),
+        _ => "> (message unavailable)".to_string(),
+    };
+    if quote.chars().count() <= width {
+        return quote;
+    }
+    let mut cut: String = quote.chars().take(width.saturating_sub(1)).collect();
+    cut.push('…');
+    cut
+}
+
 // Define `wrap_text` (example)
-pub fn wrap_text(
-    messages: &[MessageType],
+pub fn wrap_text(messages: &[MessageType], max_width: usize, app: &App) -> Vec<Line<'static>> {
+    let mut lines = Vec::new();
+    for (message, repeats) in messages.iter().zip(repeat_counts(messages, app)) {
+        lines.extend(wrap_message(message, repeats, max_width, app));
+    }
+    lines
+}
+
+// How many times in a row each message was sent: a run of identical chat messages from one
+// sender is shown once, as its last copy with "×N" after it, so the earlier copies count 0.
+// A copy that has been reacted to ends the run, so its reactions stay visible.
+pub fn repeat_counts(messages: &[MessageType], app: &App) -> Vec<usize> {
+    let mut counts = vec![1; messages.len()];
+    for index in 1..messages.len() {
+        if let (
+            MessageType::ChatMessage {
+                sender_id,
+                content,
+                reply_to,
+                message_id,
+                ..
+            },
+            MessageType::ChatMessage {
+                sender_id: next_sender_id,
+                content: next_content,
+                reply_to: next_reply_to,
+                ..
+            },
+        ) = (&messages[index - 1], &messages[index])
+        {
+            let reacted = message_id
+                .as_ref()
+                .is_some_and(|id| app.reactions.contains_key(id));
+            if sender_id == next_sender_id
+                && content == next_content
+                && reply_to == next_reply_to
+                && !reacted
+            {
+                counts[index] += counts[index - 1];
+                counts[index - 1] = 0;
+            }
+        }
+    }
+    counts
+}
+
+// The lines for one message, which stands for `repeats` identical ones in a row (see
+// `repeat_counts`); none at all if it is folded into a later copy
+pub fn wrap_message(
+    message: &MessageType,
+    repeats: usize,
     max_width: usize,
-    current_username: Option<&str>,
-) -> Vec<Span<'static>> {
+    app: &App,
+) -> Vec<Line<'static>> {
     let mut lines = Vec::new();
+    let current_username = app.username.as_deref();
+    if repeats == 0 {
+        return lines;
+    }
 
-    for message in messages {
-        match message {
-            MessageType::ChatMessage { sender, content } => {
-                let wrapped_lines = wrap_single_line(content, max_width);
-                if Some(sender.as_str()) == current_username {
-                    // Right-align the current user's messages with Cyan color
-                    for line in wrapped_lines {
-                        let padding = " ".repeat(max_width.saturating_sub(line.len()));
-                        lines.push(Span::styled(
-                            format!("{}{}", padding, line),
-                            Style::default().fg(Color::Cyan),
-                        ));
-                    }
-                } else {
-                    // Left-align other users' messages with Green color
-                    for line in wrapped_lines {
-                        lines.push(Span::styled(
-                            format!("{}: {}", sender, line),
-                            Style::default().fg(Color::Green),
-                        ));
+    match message {
+        MessageType::ChatMessage {
+            sender,
+            content,
+            sender_id,
+            client_msg_id,
+            message_id,
+            reply_to,
+            ..
+        } => {
+            let quote_style = Style::default()
+                .fg(Color::DarkGray)
+                .add_modifier(Modifier::ITALIC);
+            let reactions = message_id
+                .as_ref()
+                .and_then(|id| app.reactions.get(id))
+                .map(|reactions| reaction_summary(reactions));
+            // A run of the same message says how many times it was sent
+            let content = &match repeats {
+                1 => content.clone(),
+                _ => format!("{} ×{}", content, repeats),
+            };
+            // Own messages are recognised by account id so they survive renames
+            if Some(sender_id.as_str()) == app.account.as_deref() {
+                // A reply starts with a quote of what it answers
+                if let Some(parent) = reply_to {
+                    let quote = reply_quote(app, *parent, max_width);
+                    let padding = " ".repeat(max_width.saturating_sub(quote.chars().count()));
+                    lines.push(Line::styled(format!("{}{}", padding, quote), quote_style));
+                }
+                let mut wrapped_lines = wrap_single_line(content, max_width);
+                // Mark the delivery state of our own messages on their last line
+                let status = client_msg_id.as_deref().map(|id| app.delivery_status(id));
+                let marker = match status {
+                    Some(DeliveryStatus::Sent) => " ✓",
+                    Some(DeliveryStatus::Failed) => " ! (/retry)",
+                    Some(DeliveryStatus::Unsent) => " (unsent, /retry)",
+                    _ => "",
+                };
+                if let Some(last_line) = wrapped_lines.last_mut() {
+                    last_line.push_str(marker);
+                }
+                // Messages that never left get a warning sign up front
+                if status == Some(DeliveryStatus::Unsent) {
+                    if let Some(first_line) = wrapped_lines.first_mut() {
+                        first_line.insert_str(0, "⚠ ");
                     }
                 }
-            }
-            MessageType::SystemMessage(system_message) => {
-                let wrapped_lines = wrap_single_line(system_message, max_width);
+                // Right-align the current user's messages in the color the server gave us
+                let color = app.user_color.unwrap_or(app.theme.my_message);
                 for line in wrapped_lines {
-                    lines.push(Span::styled(line, Style::default().fg(Color::Yellow)));
+                    let padding = " ".repeat(max_width.saturating_sub(line.chars().count()));
+                    lines.push(linkify(
+                        format!("{}{}", padding, line),
+                        Style::default().fg(color),
+                        app,
+                    ));
+                }
+                // Reactions sit under the message, on the same side
+                if let Some(summary) = reactions {
+                    let padding = " ".repeat(max_width.saturating_sub(summary.chars().count()));
+                    lines.push(Line::styled(
+                        format!("{}{}", padding, summary),
+                        app.theme.severity_style(Severity::Info),
+                    ));
+                }
+            } else {
+                // Left-align other users' messages in a color stable for each sender
+                let style = Style::default().fg(sender_color(sender_id));
+                let indent = sender_indent(sender, max_width);
+                if let Some(parent) = reply_to {
+                    let quote = reply_quote(app, *parent, max_width - indent);
+                    lines.push(Line::styled(
+                        format!("{}{}", " ".repeat(indent), quote),
+                        quote_style,
+                    ));
+                }
+                push_with_sender(&mut lines, sender, content, max_width, style, app);
+                if let Some(summary) = reactions {
+                    lines.push(Line::styled(
+                        format!("{}{}", " ".repeat(indent), summary),
+                        app.theme.severity_style(Severity::Info),
+                    ));
+                }
+            }
+        }
+        MessageType::PrivateMessage {
+            sender, content, ..
+        } => {
+            if Some(sender.as_str()) == current_username {
+                // Right-align direct messages we sent, like our own chat messages
+                for line in wrap_single_line(content, max_width) {
+                    let padding = " ".repeat(max_width.saturating_sub(line.len()));
+                    lines.push(linkify(
+                        format!("{}{}", padding, line),
+                        Style::default().fg(app.theme.my_message),
+                        app,
+                    ));
                 }
+            } else {
+                let style = Style::default().fg(app.theme.other_message);
+                push_with_sender(&mut lines, sender, content, max_width, style, app);
+            }
+        }
+        MessageType::SystemMessage(system_message) => {
+            let wrapped_lines = wrap_single_line(system_message, max_width);
+            for line in wrapped_lines {
+                lines.push(linkify(line, app.theme.severity_style(Severity::Info), app));
+            }
+        }
+        MessageType::UserList(names) => {
+            let names: Vec<String> = names.iter().map(|name| sanitize(name)).collect();
+            for row in fit_columns(&names, max_width) {
+                lines.push(Line::styled(row, app.theme.severity_style(Severity::Info)));
             }
-            _ => {}
         }
+        MessageType::Notice { level, text } => {
+            // Lead with an icon so the level is clear without relying on color
+            let text = format!("{}{}", severity_icon(*level), text);
+            for line in wrap_single_line(&text, max_width) {
+                lines.push(Line::styled(line, app.theme.severity_style(*level)));
+            }
+        }
+
+        _ => {}
     }
 
     lines
 }
 
+// Lay names out in as many columns as fit in `width`, reading down each column like `ls`
+pub fn fit_columns(names: &[String], width: usize) -> Vec<String> {
+    const GAP: usize = 2;
+    let column_width = names
+        .iter()
+        .map(|name| name.chars().count())
+        .max()
+        .unwrap_or(0)
+        + GAP;
+    let columns = ((width + GAP) / column_width).clamp(1, names.len().max(1));
+    let rows = names.len().div_ceil(columns);
+    (0..rows)
+        .map(|row| {
+            let cells: Vec<&String> = names.iter().skip(row).step_by(rows).collect();
+            let mut line = String::new();
+            for (i, name) in cells.iter().enumerate() {
+                line.push_str(name);
+                if i + 1 < cells.len() {
+                    let padding = column_width - name.chars().count();
+                    line.push_str(&" ".repeat(padding));
+                }
+            }
+            line
+        })
+        .collect()
+}
+
+// Byte ranges of the http(s) URLs in a line. A URL runs to the next whitespace, minus any
+// trailing punctuation and closing brackets that were never opened inside it.
+pub fn find_urls(text: &str) -> Vec<Range<usize>> {
+    let mut urls = Vec::new();
+    let mut search_from = 0;
+    while let Some(found) = ["http://", "https://"]
+        .iter()
+        .filter_map(|scheme| text[search_from..].find(scheme))
+        .min()
+    {
+        let start = search_from + found;
+        let mut end = text[start..]
+            .find(char::is_whitespace)
+            .map_or(text.len(), |len| start + len);
+        while let Some(last) = text[start..end].chars().last() {
+            let url = &text[start..end];
+            let unopened = match last {
+                ')' => url.matches('(').count() < url.matches(')').count(),
+                ']' => url.matches('[').count() < url.matches(']').count(),
+                _ => false,
+            };
+            if matches!(last, '.' | ',' | ';' | ':' | '!' | '?' | '\'' | '"') || unopened {
+                end -= last.len_utf8();
+            } else {
+                break;
+            }
+        }
+        // Only a scheme with nothing after it isn't a link
+        if text[start..end].ends_with("//") {
+            search_from = end;
+            continue;
+        }
+        urls.push(start..end);
+        search_from = end;
+    }
+    urls
+}
+
+// One line of a message, with any URLs in it underlined in the link color
+fn linkify(text: String, style: Style, app: &App) -> Line<'static> {
+    highlight(text, style, &[], app)
+}
+
+// Like `linkify`, also picking out the `mentions` of our name in bold highlight. Mentions inside
+// a URL are left as part of the link.
+fn highlight(text: String, style: Style, mentions: &[Range<usize>], app: &App) -> Line<'static> {
+    let urls = find_urls(&text);
+    if urls.is_empty() && mentions.is_empty() {
+        return Line::from(Span::styled(text, style));
+    }
+    let link_style = style.fg(app.theme.link).add_modifier(Modifier::UNDERLINED);
+    let mention_style = style.fg(app.theme.highlight).add_modifier(Modifier::BOLD);
+    let mut marked: Vec<(Range<usize>, Style)> =
+        urls.iter().map(|url| (url.clone(), link_style)).collect();
+    for mention in mentions {
+        if !urls
+            .iter()
+            .any(|url| url.start < mention.end && mention.start < url.end)
+        {
+            marked.push((mention.clone(), mention_style));
+        }
+    }
+    marked.sort_by_key(|(range, _)| range.start);
+    let mut spans = Vec::new();
+    let mut last_end = 0;
+    for (range, marked_style) in marked {
+        if range.start > last_end {
+            spans.push(Span::styled(text[last_end..range.start].to_string(), style));
+        }
+        spans.push(Span::styled(text[range.clone()].to_string(), marked_style));
+        last_end = range.end;
+    }
+    if last_end < text.len() {
+        spans.push(Span::styled(text[last_end..].to_string(), style));
+    }
+    Line::from(spans)
+}
+
+// Make the URLs in lines already drawn inside a bordered `area` clickable, by wrapping them in
+// OSC 8 hyperlink escapes. Each cell holds two characters of the link, because ratatui would
+// otherwise count the escape sequence towards the width and misplace what follows.
+pub fn add_hyperlinks(buffer: &mut Buffer, area: Rect, lines: &[Line]) {
+    let inner_width = area.width.saturating_sub(2) as usize;
+    for (row, line) in lines.iter().enumerate() {
+        let y = area.y + 1 + row as u16;
+        if y >= area.bottom().saturating_sub(1) {
+            break;
+        }
+        let text: String = line
+            .spans
+            .iter()
+            .map(|span| span.content.as_ref())
+            .collect();
+        for url in find_urls(&text) {
+            let column = text[..url.start].chars().count();
+            let chars: Vec<char> = text[url.clone()].chars().collect();
+            for (i, pair) in chars.chunks(2).enumerate() {
+                let offset = column + i * 2;
+                if offset >= inner_width {
+                    break;
+                }
+                let pair: String = pair.iter().collect();
+                let link = format!("\x1b]8;;{}\x07{}\x1b]8;;\x07", &text[url.clone()], pair);
+                buffer[(area.x + 1 + offset as u16, y)].set_symbol(&link);
+            }
+        }
+    }
+}
+
+pub fn severity_icon(level: Severity) -> &'static str {
+    match level {
+        Severity::Info => "",
+        Severity::Success => "✓ ",
+        Severity::Warn => "⚠ ",
+        Severity::Error => "✗ ",
+    }
+}
+
+// Pick a color for a sender from their stable id, so it doesn't change when they rename.
+// Matches the server's `App::user_color`, which picks the color we're welcomed with.
+pub fn sender_color(sender_id: &str) -> Color {
+    const PALETTE: [Color; 6] = [
+        Color::Green,
+        Color::LightGreen,
+        Color::Blue,
+        Color::LightBlue,
+        Color::Magenta,
+        Color::LightRed,
+    ];
+    let hash = sender_id.bytes().fold(0usize, |hash, byte| {
+        hash.wrapping_mul(31).wrapping_add(byte as usize)
+    });
+    PALETTE[hash % PALETTE.len()]
+}
+
+// Strip terminal escape sequences and control characters from text sent by other people,
+// so it can't move the cursor, change colors or otherwise corrupt the screen. Newlines are kept
+// and tabs become spaces.
+pub fn sanitize(text: &str) -> String {
+    let mut sanitized = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\n' => sanitized.push('\n'),
+            '\t' => sanitized.push(' '),
+            // CSI sequences, 7-bit (ESC [) and 8-bit: skip up to and including the final byte
+            '\u{1b}' if chars.peek() == Some(&'[') => {
+                chars.next();
+                for c in chars.by_ref() {
+                    if ('\u{40}'..='\u{7e}').contains(&c) {
+                        break;
+                    }
+                }
+            }
+            '\u{9b}' => {
+                for c in chars.by_ref() {
+                    if ('\u{40}'..='\u{7e}').contains(&c) {
+                        break;
+                    }
+                }
+            }
+            // OSC sequences (titles, hyperlinks): skip up to BEL or ESC \
+            '\u{1b}' if chars.peek() == Some(&']') => {
+                while let Some(c) = chars.next() {
+                    if c == '\u{7}' || (c == '\u{1b}' && chars.next_if_eq(&'\\').is_some()) {
+                        break;
+                    }
+                }
+            }
+            // Any other escape takes the following character with it
+            '\u{1b}' => {
+                chars.next();
+            }
+            c if c.is_control() => {}
+            c => sanitized.push(c),
+        }
+    }
+    sanitized
+}
+
 pub fn wrap_single_line(line: &str, max_width: usize) -> Vec<String> {
     let max_width = std::cmp::max(max_width, 10); // Avoid subtracting below a reasonable minimum width
     let mut wrapped_lines = Vec::new();
+    let line = sanitize(line);
 
     for line in line.split('\n') {
         let words = line.split_whitespace();
         let mut new_line = String::new();
 
         for word in words {
-            if new_line.len() + word.len() > max_width {
+            if new_line.width() + word.width() > max_width {
                 wrapped_lines.push(new_line.trim().to_string());
                 new_line.clear();
             }
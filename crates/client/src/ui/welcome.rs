@@ -0,0 +1,66 @@
+// ui/welcome.rs
+use crate::app::{App, WelcomeStep};
+use crate::ui::utils::{box_cursor, centered_rect};
+use ratatui::{
+    layout::{Constraint, Direction, Layout},
+    style::Style,
+    text::{Line, Text},
+    widgets::{Block, Borders, Clear, Paragraph, Wrap},
+    Frame,
+};
+use unicode_width::UnicodeWidthStr;
+
+// First-run setup: pick a name to log in with, then add a server
+pub fn render_welcome(frame: &mut Frame, app: &mut App) {
+    frame.render_widget(Clear, frame.area());
+    let area = centered_rect(80, 60, frame.area());
+    let block = Block::default()
+        .title("Welcome to Terminal Messenger")
+        .borders(Borders::ALL);
+    frame.render_widget(block, area);
+
+    let chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .margin(1)
+        .constraints([
+            Constraint::Min(2),    // What this step is for
+            Constraint::Length(3), // Input
+            Constraint::Length(1), // Problem with the last entry, if any
+        ])
+        .split(area);
+
+    let (step, label, explanation) = match app.welcome_step {
+        WelcomeStep::Username => (
+            1,
+            "Username",
+            "Which name do you log in with? It is filled in for you on the login screen. Leave it empty to type it each time.",
+        ),
+        WelcomeStep::Server => (
+            2,
+            "Server URL",
+            "Add the first server to chat on, e.g. ws://chat.example.com:8080. You can add more later with n on the server list.",
+        ),
+    };
+    let intro = Text::from(vec![
+        Line::from(format!("Step {} of 2. {}", step, explanation)),
+        Line::styled(
+            "Enter to continue, Esc to skip setup for now",
+            Style::default().fg(app.theme.system),
+        ),
+    ]);
+    frame.render_widget(Paragraph::new(intro).wrap(Wrap { trim: true }), chunks[0]);
+
+    let input = Paragraph::new(app.message_input.as_str()).block(
+        Block::default()
+            .title(label)
+            .borders(Borders::ALL)
+            .style(Style::default().fg(app.theme.highlight)),
+    );
+    frame.render_widget(input, chunks[1]);
+    frame.set_cursor_position(box_cursor(chunks[1], app.message_input.width(), 0));
+
+    if let Some(error) = &app.welcome_error {
+        let error = Paragraph::new(error.as_str()).style(Style::default().fg(app.theme.error));
+        frame.render_widget(error, chunks[2]);
+    }
+}
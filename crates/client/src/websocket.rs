@@ -1,27 +1,156 @@
-use crate::app::App;
+use crate::app::{App, CurrentScreen};
+use crate::error::ClientError;
+use flate2::read::DeflateDecoder;
 use futures_util::{SinkExt, StreamExt};
 use ratatui::backend::Backend;
 use ratatui::Terminal;
-use tokio::io;
+use std::io::Read;
+use std::time::Duration;
 use tokio::net::TcpStream;
-use tokio_tungstenite::{connect_async, tungstenite::Message, MaybeTlsStream, WebSocketStream};
+use tokio::time::{sleep_until, timeout, Instant};
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::error::CapacityError;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::{CloseFrame, WebSocketConfig};
+use tokio_tungstenite::tungstenite::Error as WsError;
+use tokio_tungstenite::{
+    connect_async_with_config, tungstenite::Message, MaybeTlsStream, WebSocketStream,
+};
+use url::Url;
 
 pub type WsStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
 
-pub async fn connect_to_server(
-    app: &App,
-) -> Result<WsStream, Box<dyn std::error::Error + Send + Sync>> {
-    if let Some(server_name) = &app.selected_server {
-        if let Some(server_url) = app.servers.get(server_name) {
-            let url_string = server_url.to_string();
-            let (ws_stream, _) = connect_async(&url_string).await?;
-            return Ok(ws_stream);
+// How long the TCP and WebSocket handshakes may take before the attempt is given up
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Largest message or frame accepted from the server; far above anything it sends, even a
+// full user list, but small enough that a broken server can't exhaust memory
+pub const MAX_MESSAGE_SIZE: usize = 4 * 1024 * 1024;
+
+// Handshake header offering to take messages deflated in binary frames; servers with
+// compression turned on send large messages that way. Outgoing messages are always plain text.
+const COMPRESSION_HEADER: &str = "x-message-compression";
+
+// How long the server gets to answer one of our pings before the connection is given up
+pub const PONG_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Our own pings, sent so NAT routers see traffic on an idle connection and so a silently dropped
+// connection is noticed. The state lives outside `handle_websocket`, which is restarted whenever
+// the main loop handles something else.
+pub struct KeepAlive {
+    interval: Option<Duration>, // None when keep-alive pings are turned off
+    last_ping: Instant,
+    awaiting_pong: Option<Instant>, // when the unanswered ping was sent
+}
+
+#[derive(Debug, PartialEq)]
+pub enum KeepAliveAction {
+    Wait,
+    SendPing,
+    TimedOut,
+}
+
+impl KeepAlive {
+    pub fn new(interval: Option<Duration>) -> KeepAlive {
+        KeepAlive {
+            interval,
+            last_ping: Instant::now(),
+            awaiting_pong: None,
+        }
+    }
+
+    // Interval from KEEPALIVE_SECS (20 by default); 0 turns the pings off
+    pub fn from_env() -> KeepAlive {
+        let secs = match std::env::var("KEEPALIVE_SECS") {
+            Ok(value) => value.parse().unwrap_or_else(|_| {
+                log::warn!("Invalid KEEPALIVE_SECS '{}', using 20", value);
+                20
+            }),
+            Err(_) => 20,
+        };
+        KeepAlive::new((secs > 0).then(|| Duration::from_secs(secs)))
+    }
+
+    // Start counting afresh for a new connection
+    pub fn reset(&mut self, now: Instant) {
+        self.last_ping = now;
+        self.awaiting_pong = None;
+    }
+
+    // When `poll` next has something to do, or None if never
+    pub fn deadline(&self) -> Option<Instant> {
+        let interval = self.interval?;
+        Some(match self.awaiting_pong {
+            Some(sent) => sent + PONG_TIMEOUT,
+            None => self.last_ping + interval,
+        })
+    }
+
+    pub fn poll(&mut self, now: Instant) -> KeepAliveAction {
+        match (self.awaiting_pong, self.deadline()) {
+            (Some(_), Some(deadline)) if now >= deadline => KeepAliveAction::TimedOut,
+            (None, Some(deadline)) if now >= deadline => {
+                self.last_ping = now;
+                self.awaiting_pong = Some(now);
+                KeepAliveAction::SendPing
+            }
+            _ => KeepAliveAction::Wait,
         }
     }
-    Err(Box::new(io::Error::new(
-        io::ErrorKind::NotFound,
-        "No server selected",
-    )))
+
+    pub fn on_pong(&mut self) {
+        self.awaiting_pong = None;
+    }
+}
+
+pub async fn connect_to_server(url: Url) -> Result<WsStream, ClientError> {
+    connect_within(url, CONNECT_TIMEOUT).await
+}
+
+// Connect, giving up if the handshake hasn't finished within `limit`
+async fn connect_within(url: Url, limit: Duration) -> Result<WsStream, ClientError> {
+    let config = WebSocketConfig {
+        max_message_size: Some(MAX_MESSAGE_SIZE),
+        max_frame_size: Some(MAX_MESSAGE_SIZE),
+        ..Default::default()
+    };
+    let mut request = url
+        .as_str()
+        .into_client_request()
+        .map_err(|e| ClientError::Connect(e.to_string()))?;
+    request
+        .headers_mut()
+        .insert(COMPRESSION_HEADER, "deflate".parse().unwrap());
+    let connect = connect_async_with_config(request, Some(config), false);
+    match timeout(limit, connect).await {
+        Ok(Ok((ws_stream, _))) => Ok(ws_stream),
+        Ok(Err(e)) => Err(ClientError::Connect(e.to_string())),
+        Err(_) => Err(ClientError::Connect(format!(
+            "timed out after {}s",
+            limit.as_secs()
+        ))),
+    }
+}
+
+// Inflate a message the server deflated into a binary frame, holding it to the same size limit
+// as any other message. Other frames pass through untouched. Errors are boxed like in
+// `ClientError::Protocol`, as tungstenite's error is large.
+pub fn decode_frame(message: Message) -> Result<Message, Box<WsError>> {
+    let Message::Binary(data) = message else {
+        return Ok(message);
+    };
+    let mut text = String::new();
+    DeflateDecoder::new(data.as_slice())
+        .take(MAX_MESSAGE_SIZE as u64 + 1)
+        .read_to_string(&mut text)
+        .map_err(|e| Box::new(WsError::Io(e)))?;
+    if text.len() > MAX_MESSAGE_SIZE {
+        return Err(Box::new(WsError::Capacity(CapacityError::MessageTooLong {
+            size: text.len(),
+            max_size: MAX_MESSAGE_SIZE,
+        })));
+    }
+    Ok(Message::Text(text))
 }
 
 pub async fn handle_websocket<B: Backend>(
@@ -29,45 +158,98 @@ pub async fn handle_websocket<B: Backend>(
     terminal: &mut Terminal<B>,
     write: &mut futures_util::stream::SplitSink<WsStream, Message>,
     read: &mut futures_util::stream::SplitStream<WsStream>,
-) -> io::Result<()> {
+    keep_alive: &mut KeepAlive,
+) -> Result<(), ClientError> {
     loop {
         tokio::select! {
+            _ = async {
+                match keep_alive.deadline() {
+                    Some(deadline) => sleep_until(deadline).await,
+                    None => std::future::pending().await,
+                }
+            } => {
+                match keep_alive.poll(Instant::now()) {
+                    KeepAliveAction::SendPing => {
+                        write.send(Message::Ping(Vec::new())).await?;
+                    }
+                    KeepAliveAction::TimedOut => {
+                        log::warn!("No pong within {}s, dropping the connection", PONG_TIMEOUT.as_secs());
+                        app.set_disconnected("Server stopped answering pings.".to_string());
+                        terminal.draw(|f| crate::ui::ui(f, app))
+                            .map_err(ClientError::Terminal)?;
+                        break;
+                    }
+                    KeepAliveAction::Wait => {}
+                }
+            }
             ws_msg = read.next() => {
-                match ws_msg {
+                match ws_msg.map(|frame| frame.map_err(Box::new).and_then(decode_frame)) {
                     Some(Ok(Message::Text(text))) => {
                         app.handle_websocket_message(&text);
+                        // Once logged back in, send what the last connection couldn't, in order.
+                        // A message stays queued until its send goes through.
+                        while let Some(queued) = app.queued_message() {
+                            let queued = serde_json::to_string(queued).unwrap();
+                            write.send(Message::Text(queued)).await?;
+                            app.dequeue_sent();
+                        }
                         terminal.draw(|f| crate::ui::ui(f, app))
-                            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+                            .map_err(ClientError::Terminal)?;
+                        // Only a login turned down for good ends the session from a message
+                        if app.current_screen == CurrentScreen::Disconnected {
+                            let reason = app.disconnect_reason.clone().unwrap_or_default();
+                            return Err(ClientError::Auth(reason));
+                        }
                     }
                     Some(Ok(Message::Binary(_))) => {
-                        // Handle binary message if needed
+                        // Binary frames are decoded into text above
                     }
                     Some(Ok(Message::Ping(ping))) => {
                         // Respond to ping by sending a Pong message
-                      write.send(Message::Pong(ping)).await.map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+                      write.send(Message::Pong(ping)).await?;
                     }
                     Some(Ok(Message::Pong(_))) => {
-                        // Handle pong if necessary
+                        // The server answered our keep-alive ping
+                        keep_alive.on_pong();
                     }
-                    Some(Ok(Message::Close(_))) => {
-                        app.current_screen = crate::app::CurrentScreen::Disconnected;
+                    Some(Ok(Message::Close(frame))) => {
+                        // A close frame means the server ended the session on purpose
+                        let reason = match frame {
+                            Some(frame) if !frame.reason.is_empty() => {
+                                format!("Server closed the connection: {}", frame.reason)
+                            }
+                            _ => "Server closed the connection.".to_string(),
+                        };
+                        app.set_disconnected(reason);
                         terminal.draw(|f| crate::ui::ui(f, app))
-                            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+                            .map_err(ClientError::Terminal)?;
                         break;
                     }
-                    Some(Err(e)) => {
-                        // Log the WebSocket error and move to the Disconnected state
-                        app.current_screen = crate::app::CurrentScreen::Disconnected;
+                    Some(Err(e)) if matches!(*e, WsError::Capacity(_)) => {
+                        // Tell the server why we are hanging up rather than just dropping it
+                        log::error!("Oversized message from server: {}", e);
+                        let close_frame = CloseFrame {
+                            code: CloseCode::Size,
+                            reason: "Message too big".into(),
+                        };
+                        let _ = write.send(Message::Close(Some(close_frame))).await;
+                        app.set_disconnected(format!(
+                            "Server sent a message over the {} byte limit.",
+                            MAX_MESSAGE_SIZE
+                        ));
                         terminal.draw(|f| crate::ui::ui(f, app))
-                            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
-                        log::error!("WebSocket error: {:?}", e);
+                            .map_err(ClientError::Terminal)?;
                         break;
                     }
+                    Some(Err(e)) => {
+                        // The caller moves to the Disconnected state
+                        return Err(ClientError::Protocol(e));
+                    }
                     None => {
-                        // Handle the case when the stream ends
-                        app.current_screen = crate::app::CurrentScreen::Disconnected;
+                        // Handle the case when the stream ends without a close frame
+                        app.set_disconnected("Connection dropped unexpectedly.".to_string());
                         terminal.draw(|f| crate::ui::ui(f, app))
-                            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+                            .map_err(ClientError::Terminal)?;
                         break;
                     }
                     Some(Ok(Message::Frame(frame_data))) => {
@@ -80,3 +262,160 @@ pub async fn handle_websocket<B: Backend>(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keep_alive_pings_on_the_interval_and_times_out_without_a_pong() {
+        let start = Instant::now();
+        let secs = |n| start + Duration::from_secs(n);
+        let mut keep_alive = KeepAlive::new(Some(Duration::from_secs(20)));
+        keep_alive.reset(start);
+
+        assert_eq!(keep_alive.deadline(), Some(secs(20)));
+        assert_eq!(keep_alive.poll(secs(19)), KeepAliveAction::Wait);
+        assert_eq!(keep_alive.poll(secs(20)), KeepAliveAction::SendPing);
+        // A pong in time puts the next ping an interval after the last one
+        keep_alive.on_pong();
+        assert_eq!(keep_alive.deadline(), Some(secs(40)));
+        assert_eq!(keep_alive.poll(secs(40)), KeepAliveAction::SendPing);
+        // Without one the connection is given up after PONG_TIMEOUT
+        assert_eq!(keep_alive.poll(secs(49)), KeepAliveAction::Wait);
+        assert_eq!(keep_alive.poll(secs(50)), KeepAliveAction::TimedOut);
+    }
+
+    #[tokio::test]
+    async fn connect_failure_is_a_connect_error() {
+        // Nothing listens on port 1, so the connection is refused straight away
+        let url = Url::parse("ws://127.0.0.1:1").unwrap();
+        let result = connect_to_server(url).await;
+        assert!(matches!(result, Err(ClientError::Connect(_))));
+    }
+
+    #[tokio::test]
+    async fn connect_gives_up_on_a_server_that_never_answers() {
+        // The connection is accepted but the handshake is never answered
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("ws://{}", listener.local_addr().unwrap())).unwrap();
+        let started = Instant::now();
+        let result = connect_within(url, Duration::from_millis(200)).await;
+        assert!(
+            matches!(&result, Err(ClientError::Connect(reason)) if reason.starts_with("timed out")),
+            "{:?}",
+            result.err()
+        );
+        assert!(started.elapsed() < Duration::from_secs(5));
+        drop(listener);
+    }
+
+    // The handshake callback's error type is tungstenite's, which is large
+    #[allow(clippy::result_large_err)]
+    #[tokio::test]
+    async fn compressed_messages_round_trip_over_a_socket() {
+        use flate2::{write::DeflateEncoder, Compression};
+        use std::io::Write;
+        use tokio_tungstenite::tungstenite::handshake::server::{Request, Response};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("ws://{}", listener.local_addr().unwrap())).unwrap();
+        let text = serde_json::to_string(&crate::app::MessageType::SystemMessage(
+            "a long broadcast ".repeat(5000),
+        ))
+        .unwrap();
+
+        // A server that only deflates when the client offered to take it
+        let sent = text.clone();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut offered = false;
+            let negotiate = |request: &Request, response: Response| {
+                offered = request.headers().contains_key(COMPRESSION_HEADER);
+                Ok(response)
+            };
+            let mut ws = tokio_tungstenite::accept_hdr_async(stream, negotiate)
+                .await
+                .unwrap();
+            assert!(offered);
+            let mut encoder = DeflateEncoder::new(Vec::new(), Compression::fast());
+            encoder.write_all(sent.as_bytes()).unwrap();
+            let compressed = encoder.finish().unwrap();
+            assert!(compressed.len() < sent.len() / 10);
+            ws.send(Message::Binary(compressed)).await.unwrap();
+            ws.send(Message::Text("short".to_string())).await.unwrap();
+        });
+
+        let mut ws = connect_to_server(url).await.unwrap();
+        let first = decode_frame(ws.next().await.unwrap().unwrap()).unwrap();
+        assert_eq!(first, Message::Text(text));
+        let second = decode_frame(ws.next().await.unwrap().unwrap()).unwrap();
+        assert_eq!(second, Message::Text("short".to_string()));
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn disconnect_reasons_say_how_the_connection_ended() {
+        use ratatui::backend::TestBackend;
+
+        let cases = [
+            (
+                Some("Server full"),
+                Some("Server closed the connection: Server full"),
+            ),
+            (Some(""), Some("Server closed the connection.")),
+            // Hanging up without a close frame is a protocol error, left to the caller
+            (None, None),
+        ];
+        for (close_reason, expected) in cases {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let url = Url::parse(&format!("ws://{}", listener.local_addr().unwrap())).unwrap();
+            let server = tokio::spawn(async move {
+                let (stream, _) = listener.accept().await.unwrap();
+                let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+                if let Some(reason) = close_reason {
+                    let frame = CloseFrame {
+                        code: CloseCode::Again,
+                        reason: reason.into(),
+                    };
+                    ws.send(Message::Close(Some(frame))).await.unwrap();
+                }
+            });
+
+            let (mut write, mut read) = connect_to_server(url).await.unwrap().split();
+            let mut terminal = Terminal::new(TestBackend::new(80, 24)).unwrap();
+            let mut app = App::new();
+            let mut keep_alive = KeepAlive::new(None);
+            let result = handle_websocket(
+                &mut app,
+                &mut terminal,
+                &mut write,
+                &mut read,
+                &mut keep_alive,
+            )
+            .await;
+            match expected {
+                Some(reason) => {
+                    assert!(result.is_ok());
+                    assert_eq!(app.current_screen, CurrentScreen::Disconnected);
+                    assert_eq!(app.disconnect_reason.as_deref(), Some(reason));
+                }
+                None => assert!(matches!(result, Err(ClientError::Protocol(_)))),
+            }
+            server.await.unwrap();
+        }
+    }
+
+    #[test]
+    fn corrupt_compressed_frames_are_errors() {
+        assert!(decode_frame(Message::Binary(vec![1, 2, 3])).is_err());
+    }
+
+    #[test]
+    fn keep_alive_can_be_turned_off() {
+        let mut keep_alive = KeepAlive::new(None);
+        assert_eq!(keep_alive.deadline(), None);
+        let later = Instant::now() + Duration::from_secs(3600);
+        assert_eq!(keep_alive.poll(later), KeepAliveAction::Wait);
+    }
+}
@@ -0,0 +1,115 @@
+//  Load harness for the server's broadcast path. Start the server with room for everyone
+//  (e.g. MAX_CLIENTS=200), then run
+//
+//      cargo run --release --example broadcast_bench
+//
+//  It connects BENCH_CLIENTS spectators (default 100) plus one spectator that never reads,
+//  logs in one sender and sends BENCH_MESSAGES chat messages (default 200). Every reading
+//  spectator must receive every message before the timeout, otherwise the run fails, which
+//  catches deadlocks and slow clients holding up everyone else. If BENCH_SERVER_PID is set,
+//  the server's resident memory is reported before and after so growth can be checked.
+use futures_util::{SinkExt, StreamExt};
+use serde_json::json;
+use std::time::{Duration, Instant};
+use tokio::time::timeout;
+use tokio_tungstenite::{connect_async, tungstenite::protocol::Message};
+
+const RUN_TIMEOUT: Duration = Duration::from_secs(30);
+
+fn env_or<T: std::str::FromStr>(name: &str, default: T) -> T {
+    std::env::var(name)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
+
+// Resident memory of a process in kB, read from /proc (Linux only)
+fn resident_kb(pid: &str) -> Option<u64> {
+    let status = std::fs::read_to_string(format!("/proc/{}/status", pid)).ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+    line.split_whitespace().nth(1)?.parse().ok()
+}
+
+#[tokio::main]
+async fn main() {
+    let addr: String = env_or("BENCH_ADDR", "ws://127.0.0.1:8080".to_string());
+    let clients: usize = env_or("BENCH_CLIENTS", 100);
+    let messages: usize = env_or("BENCH_MESSAGES", 200);
+    let user: String = env_or("BENCH_USER", "user1".to_string());
+    let password: String = env_or("BENCH_PASSWORD", "password1".to_string());
+    let server_pid = std::env::var("BENCH_SERVER_PID").ok();
+
+    let rss_before = server_pid.as_deref().and_then(resident_kb);
+    let spectate = Message::Text(json!("Spectate").to_string());
+
+    // Spectators count the benchmark messages they receive
+    let mut receivers = Vec::new();
+    for _ in 0..clients {
+        let (mut ws, _) = connect_async(addr.as_str())
+            .await
+            .expect("Failed to connect spectator");
+        ws.send(spectate.clone()).await.unwrap();
+        // Wait until the server has registered us so no broadcast is missed
+        while let Some(Ok(message)) = ws.next().await {
+            if matches!(&message, Message::Text(text) if text.contains("Spectating")) {
+                break;
+            }
+        }
+        receivers.push(tokio::spawn(async move {
+            let mut received = 0;
+            while received < messages {
+                match ws.next().await {
+                    Some(Ok(Message::Text(text))) if text.contains("bench-") => received += 1,
+                    Some(Ok(_)) => {}
+                    _ => break,
+                }
+            }
+            received
+        }));
+    }
+
+    // A spectator that never reads, to check it can't stall everyone else
+    let (mut stalled, _) = connect_async(addr.as_str())
+        .await
+        .expect("Failed to connect stalled spectator");
+    stalled.send(spectate.clone()).await.unwrap();
+
+    let (mut sender, _) = connect_async(addr.as_str())
+        .await
+        .expect("Failed to connect sender");
+    let auth = json!({ "Auth": { "username": user, "password": password } });
+    sender.send(Message::Text(auth.to_string())).await.unwrap();
+
+    let started = Instant::now();
+    for i in 0..messages {
+        let chat = json!({ "ChatMessage": { "sender": user, "content": format!("bench-{}", i) } });
+        sender.send(Message::Text(chat.to_string())).await.unwrap();
+    }
+
+    // Every reader must see every message; hanging here means a deadlock or a stall
+    let results = timeout(RUN_TIMEOUT, futures_util::future::join_all(receivers))
+        .await
+        .expect("Timed out waiting for broadcasts (deadlock or stalled fan-out?)");
+    let elapsed = started.elapsed();
+
+    let complete = results
+        .into_iter()
+        .filter(|result| matches!(result, Ok(received) if *received == messages))
+        .count();
+    println!(
+        "{} of {} clients received all {} messages in {:?} ({:.0} deliveries/s)",
+        complete,
+        clients,
+        messages,
+        elapsed,
+        (complete * messages) as f64 / elapsed.as_secs_f64()
+    );
+    if let (Some(before), Some(after)) = (rss_before, server_pid.as_deref().and_then(resident_kb)) {
+        println!(
+            "Server resident memory: {} kB before, {} kB after",
+            before, after
+        );
+    }
+    assert_eq!(complete, clients, "some clients missed messages");
+    drop(stalled);
+}
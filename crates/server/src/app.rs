@@ -1,76 +1,262 @@
 //  This file contains the definition of the `App` struct, which represents the server state.
 //  It also defines the `UserInfo` struct and an enumeration of message types.
+use crate::chat_log::{ChatLog, ChatLogEntry};
+use crate::filter::{FilterResult, WordFilter};
+use crate::sequence::MessageSeq;
 use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, VecDeque};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
-use std::time::SystemTime;
-use tokio::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::{broadcast, mpsc, Mutex, Notify};
+
+// Messages the broadcast channel holds for slow connections before they start missing some
+pub const BROADCAST_CAPACITY: usize = 256;
+// Chat channel every user starts in
+pub const DEFAULT_CHANNEL: &str = "general";
+// How long /mute silences a user when no duration is given
+pub const DEFAULT_MUTE: Duration = Duration::from_secs(300);
+// Longest a /mute lasts; longer ones are cut to this so the end time can't overflow
+pub const MAX_MUTE: Duration = Duration::from_secs(365 * 24 * 60 * 60);
+// How often a user may ask for the history to be sent again with /history
+pub const HISTORY_COOLDOWN: Duration = Duration::from_secs(10);
+// Chat messages remembered per account, by the sender's id for them, to spot resends
+const RECENT_MESSAGE_IDS: usize = 64;
+
+// A message fanned out to every connection through the shared broadcast channel
+#[derive(Clone, Debug)]
+pub struct Broadcast {
+    pub origin: Option<String>, // Connection that caused it, which doesn't receive its own copy
+    pub channel: Option<String>, // Chat channel it belongs to; None for every connection
+    pub number: u64,            // Position among all broadcasts, counting from 0
+    pub message: MessageType,
+}
+
+// A user joining or leaving, published on the presence channel
+#[derive(Clone, Debug, PartialEq)]
+pub enum PresenceEvent {
+    Joined { user_id: String, username: String },
+    Left { user_id: String, username: String },
+}
 
 // App struct to store connected users and message history
 pub struct App {
     // Store users with their UUID as key
     connected_users: HashMap<String, Arc<Mutex<UserInfo>>>,
-    // Global message history (last 100 messages)
-    message_history: VecDeque<MessageType>,
+    // Message history of each chat channel (last `history_size` messages per channel)
+    message_history: HashMap<String, VecDeque<MessageType>>,
+    history_size: usize, // Maximum number of messages kept per channel
+    // (account, emoji) reactions to the chat messages in history, keyed by message id
+    reactions: HashMap<String, Vec<(String, String)>>,
+    topics: HashMap<String, String>, // Topic of each chat channel, set with /topic
+    // Where changed topics are sent to be saved so they outlive a restart, if they are kept
+    topic_saver: Option<mpsc::UnboundedSender<HashMap<String, String>>>,
     user_credentials: HashMap<String, UserCredentials>, // Add this for storing credentials
+    word_filter: Option<WordFilter>, // Optional blocked-word filter for chat messages
+    // Thread writing the optional audit log of broadcast chat messages
+    chat_log: Option<std::sync::mpsc::Sender<ChatLogEntry>>,
+    message_seq: MessageSeq, // Sequence numbers given to chat messages as they are broadcast
+    // The latest chat messages of each account, least recently seen first, as
+    // (client_msg_id, message_id, seq), so a resend of one can be acknowledged without repeating it
+    recent_messages: HashMap<String, VecDeque<(String, String, u64)>>,
+    admins: HashSet<String>, // Accounts allowed to run admin commands
+    mutes: HashMap<String, SystemTime>, // When each muted account may talk again, across reconnects
+    auth_tokens: HashMap<String, String>, // Pre-shared login tokens and the name each signs in as
+    messages_processed: u64, // Chat messages accepted since the server started
+    start_time: Instant,     // When the server started, for uptime
+    broadcast_tx: broadcast::Sender<Broadcast>, // Shared channel every connection subscribes to
+    broadcasts_sent: AtomicU64, // Number given to the next broadcast
+    presence_tx: broadcast::Sender<PresenceEvent>, // Users joining and leaving
 }
 
 pub struct UserInfo {
-    pub username: String,
+    pub account: String,  // Account the user authenticated as; never changes
+    pub username: String, // Display name, can be changed with /name
     pub connection_time: SystemTime,
     pub message_count: usize,
+    pub away_message: Option<String>, // Set while the user is marked away
+    pub channel: String,              // Chat channel the user is talking in
+    pub joined_at: u64,               // First broadcast sent after the user joined `channel`
+    pub last_active: SystemTime,      // When the user last chatted or sent a DM, or connected
+    pub last_received: SystemTime,    // When the user last sent anything at all, or connected
+    pub afk_replied: HashSet<String>, // Accounts already auto-replied to while away
+    pub blocked: HashSet<String>,     // Accounts whose messages aren't relayed to this user
+    pub history_requested: Option<SystemTime>, // When the user last asked for the history
+    pub idle: Arc<Notify>,            // Signalled when the idle sweep disconnects the user
 }
 
 pub struct UserCredentials {
-    pub username: String,
     pub password: String, // Ideally store hashed passwords
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum MessageType {
-    ChatMessage { sender: String, content: String },
-    Command { name: String, args: Vec<String> },
+    ChatMessage {
+        sender: String,
+        content: String,
+        #[serde(default)]
+        sender_id: String, // Stable account id of the sender, unaffected by renames
+        #[serde(default)]
+        client_msg_id: Option<String>, // Id chosen by the sending client, echoed in its Ack
+        #[serde(default)]
+        message_id: Option<String>, // Id the server gives the message when storing it
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        reply_to: Option<u64>, // seq of the message this one answers
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        seq: Option<u64>, // Order the server broadcast it in; only ever goes up, even across restarts
+    },
+    Command {
+        name: String,
+        args: Vec<String>,
+    },
     SystemMessage(String),
+    PrivateMessage {
+        sender: String,
+        recipient: String,
+        content: String,
+    },
+    AwayList(Vec<(String, String)>), // (username, away message) for users currently away
+    Spectate,                        // Sent instead of credentials to join as a read-only spectator
+    Ack {
+        id: String, // client_msg_id of the chat message that was accepted
+        #[serde(default)]
+        message_id: Option<String>, // Id the server stored it under, so it can be reacted to
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        seq: Option<u64>, // Sequence number the server gave it
+    },
+    UserCount(usize), // Number of users online, broadcast whenever someone joins or leaves
+    Roster(Vec<String>), // Display names of the users online, sorted, broadcast with UserCount
+    UserList(Vec<String>), // Display names of the users online, sorted, in reply to /list
+    UserDetails(Vec<UserSummary>), // The users online, sorted, in reply to `list details`
+    History(Vec<MessageType>), // A channel's history and reactions, in reply to /history
+    Topic(String),    // Channel topic, broadcast in it when it changes and sent to those joining
+    Join(String),     // Switch to a chat channel; echoed back once the switch is made
+    Leave(String),    // Leave a chat channel, going back to the default one
+    Redirect(String), // ws:// or wss:// URL of another server the client should move to
+    Notice {
+        level: Severity, // How the client should present it; plain SystemMessages count as Info
+        text: String,
+    },
+    Auth {
+        username: String, // Login credentials, only accepted before the user is authenticated
+        password: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        token: Option<String>, // Pre-shared token to log in with instead, e.g. for bots
+    },
+    FileChunk {
+        id: String,   // Transfer id shared by all chunks of one file
+        name: String, // File name chosen by the sender
+        seq: u32,     // Position of this chunk, starting at 0
+        total: u32,   // Number of chunks in the transfer
+        data: String, // Base64-encoded chunk bytes
+        #[serde(default)]
+        sender: String, // Display name of the sender, filled in when relaying
+    },
+    Welcome {
+        your_name: String,  // Name of this connection; a guest name until it logs in
+        your_color: String, // Color the client should draw this user's messages in
+    },
+    Reaction {
+        message_id: String, // Chat message being reacted to
+        #[serde(default)]
+        user: String, // Account reacting, filled in by the server
+        emoji: String,      // Toggles: the same user sending the same emoji again removes it
+    },
+    CommandResult {
+        command: String, // Name of the command this answers, e.g. "name"
+        ok: bool,        // Whether it took effect
+        detail: String,  // The outcome (for "name", the new name) or why it failed
+    },
+    Renamed {
+        account: String, // Account whose display name changed
+        from: String,    // Name they had before
+        to: String,      // Name they have now
+    },
+}
+
+// One row of the user list sent in reply to `list details`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UserSummary {
+    pub username: String,
+    pub status: String,   // "online", "away" or "muted"
+    pub online_secs: u64, // How long the user has been connected
+    pub messages: usize,  // Chat messages sent since connecting
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub enum Severity {
+    Info,
+    Success,
+    Warn,
+    Error,
+}
+
+impl MessageType {
+    // A system notice with a severity level
+    pub fn notice(level: Severity, text: impl Into<String>) -> MessageType {
+        MessageType::Notice {
+            level,
+            text: text.into(),
+        }
+    }
 }
 
 impl App {
-    pub fn new() -> App {
+    pub fn new(history_size: usize) -> App {
         let mut user_credentials = HashMap::new();
 
         // For simplicity, let's add a couple of users (these should be hashed passwords)
         user_credentials.insert(
             "user1".to_string(),
             UserCredentials {
-                username: "user1".to_string(),
                 password: "password1".to_string(),
             },
         );
         user_credentials.insert(
             "user2".to_string(),
             UserCredentials {
-                username: "user2".to_string(),
                 password: "password2".to_string(),
             },
         );
         user_credentials.insert(
             "William".to_string(),
             UserCredentials {
-                username: "William".to_string(),
                 password: "password".to_string(),
             },
         );
         user_credentials.insert(
             "PickleRick".to_string(),
             UserCredentials {
-                username: "PickleRick".to_string(),
                 password: "password".to_string(),
             },
         );
 
+        let (broadcast_tx, _) = broadcast::channel(BROADCAST_CAPACITY);
+        let (presence_tx, _) = broadcast::channel(BROADCAST_CAPACITY);
+
         App {
             connected_users: HashMap::new(),
-            message_history: VecDeque::with_capacity(100), // Store up to 100 messages
-            user_credentials,                              // finitialize the credentials
+            message_history: HashMap::new(),
+            history_size,
+            reactions: HashMap::new(),
+            topics: HashMap::new(),
+            topic_saver: None,
+            user_credentials, // finitialize the credentials
+            word_filter: None,
+            chat_log: None,
+            message_seq: MessageSeq::new(),
+            recent_messages: HashMap::new(),
+            admins: HashSet::new(),
+            mutes: HashMap::new(),
+            auth_tokens: HashMap::new(),
+            messages_processed: 0,
+            start_time: Instant::now(),
+            broadcast_tx,
+            broadcasts_sent: AtomicU64::new(0),
+            presence_tx,
         }
     }
 
@@ -82,27 +268,240 @@ impl App {
         false
     }
 
+    // The name a login token signs in as, if it is one of the configured tokens
+    pub fn authenticate_token(&self, token: &str) -> Option<String> {
+        self.auth_tokens.get(token).cloned()
+    }
+
+    // Install the blocked-word filter applied to chat messages
+    pub fn set_word_filter(&mut self, word_filter: WordFilter) {
+        self.word_filter = Some(word_filter);
+    }
+
+    // Number chat messages with a counter that carries on across restarts
+    pub fn set_message_seq(&mut self, message_seq: MessageSeq) {
+        self.message_seq = message_seq;
+    }
+
+    // Sequence number for a chat message about to be broadcast, or why there is none to give
+    pub fn next_message_seq(&mut self) -> io::Result<u64> {
+        self.message_seq.next()
+    }
+
+    // The message_id and seq an account's chat message was broadcast with, if one with this
+    // client_msg_id already was. Kept by account rather than connection, as resends usually
+    // follow a reconnect.
+    pub fn seen_message(&mut self, account: &str, client_msg_id: &str) -> Option<(String, u64)> {
+        let recent = self.recent_messages.get_mut(account)?;
+        let index = recent.iter().position(|(id, _, _)| id == client_msg_id)?;
+        // Move it to the back, so ids being retried are the last to be forgotten
+        let entry = recent.remove(index)?;
+        let seen = (entry.1.clone(), entry.2);
+        recent.push_back(entry);
+        Some(seen)
+    }
+
+    // Remember a chat message that was broadcast, forgetting the account's least recently seen
+    // one if there are too many
+    pub fn remember_message(
+        &mut self,
+        account: &str,
+        client_msg_id: String,
+        message_id: String,
+        seq: u64,
+    ) {
+        let recent = self.recent_messages.entry(account.to_string()).or_default();
+        if recent.len() >= RECENT_MESSAGE_IDS {
+            recent.pop_front();
+        }
+        recent.push_back((client_msg_id, message_id, seq));
+    }
+
+    // Start writing broadcast chat messages to the audit log
+    pub fn set_chat_log(&mut self, chat_log: ChatLog) {
+        self.chat_log = Some(chat_log.spawn());
+    }
+
+    // Queue a chat message for the audit log, if there is one. It is written on the log's own
+    // thread, where a failed write is only logged, so a slow or full disk never holds up delivery.
+    pub fn log_chat(&self, channel: &str, account: &str, name: &str, content: &str) {
+        if let Some(chat_log) = &self.chat_log {
+            let _ = chat_log.send(ChatLogEntry {
+                channel: channel.to_string(),
+                account: account.to_string(),
+                name: name.to_string(),
+                content: content.to_string(),
+            });
+        }
+    }
+
+    // Run a chat message through the word filter, passing it through untouched if none is set
+    pub fn apply_word_filter(&self, content: &str) -> FilterResult {
+        match &self.word_filter {
+            Some(word_filter) => word_filter.apply(content),
+            None => FilterResult::Pass(content.to_string()),
+        }
+    }
+
+    // Send a message to every subscribed connection except `origin`. The message is queued once
+    // and shared, no matter how many clients are connected.
+    pub fn broadcast(&self, message: MessageType, origin: Option<&str>) {
+        self.send_broadcast(None, message, origin);
+    }
+
+    // Like `broadcast`, but only delivered to connections that were in `channel` when it was sent
+    pub fn broadcast_to_channel(&self, channel: &str, message: MessageType, origin: Option<&str>) {
+        self.send_broadcast(Some(channel.to_string()), message, origin);
+    }
+
+    fn send_broadcast(&self, channel: Option<String>, message: MessageType, origin: Option<&str>) {
+        // An error only means nobody is subscribed right now
+        let _ = self.broadcast_tx.send(Broadcast {
+            origin: origin.map(str::to_string),
+            channel,
+            number: self.broadcasts_sent.fetch_add(1, Ordering::Relaxed),
+            message,
+        });
+    }
+
+    // Number the next broadcast will get. Read under the App lock, everything numbered below it
+    // has already been sent.
+    pub fn next_broadcast(&self) -> u64 {
+        self.broadcasts_sent.load(Ordering::Relaxed)
+    }
+
+    // Broadcasts still waiting for at least one connection to take them
+    #[cfg(test)]
+    pub fn queued_broadcasts(&self) -> usize {
+        self.broadcast_tx.len()
+    }
+
+    // Start receiving broadcasts, e.g. once a connection has joined
+    pub fn subscribe(&self) -> broadcast::Receiver<Broadcast> {
+        self.broadcast_tx.subscribe()
+    }
+
+    // Start receiving presence events for users added and removed from now on
+    pub fn subscribe_presence(&self) -> broadcast::Receiver<PresenceEvent> {
+        self.presence_tx.subscribe()
+    }
+
+    // Set the accounts allowed to run admin commands
+    pub fn set_admins(&mut self, admins: HashSet<String>) {
+        self.admins = admins;
+    }
+
+    // Set the login tokens, each mapped to the name it signs in as. Tokens whose name couldn't
+    // be chosen with /name are left out, as are ones naming a password account, which would
+    // otherwise sign in as that account with its admin rights.
+    pub fn set_auth_tokens(&mut self, auth_tokens: HashMap<String, String>) {
+        let accounts: Vec<&String> = self.user_credentials.keys().collect();
+        self.auth_tokens = auth_tokens
+            .into_iter()
+            .filter_map(|(token, name)| match validate_username(&name) {
+                Ok(name) if accounts.iter().any(|a| a.eq_ignore_ascii_case(&name)) => {
+                    log::warn!(
+                        "Ignoring login token for '{}': it is the name of an account",
+                        name
+                    );
+                    None
+                }
+                Ok(name) => Some((token, name)),
+                Err(reason) => {
+                    log::warn!("Ignoring login token for '{}': {}", name, reason);
+                    None
+                }
+            })
+            .collect();
+    }
+
+    // Pick a stable color for an account. The client has the same palette, so it draws
+    // everyone else's messages in the colors they were welcomed with.
+    pub fn user_color(account: &str) -> &'static str {
+        const PALETTE: [&str; 6] = [
+            "green",
+            "lightgreen",
+            "blue",
+            "lightblue",
+            "magenta",
+            "lightred",
+        ];
+        let hash = account.bytes().fold(0usize, |hash, byte| {
+            hash.wrapping_mul(31).wrapping_add(byte as usize)
+        });
+        PALETTE[hash % PALETTE.len()]
+    }
+
+    // Check whether an account may run admin commands
+    pub fn is_admin(&self, account: &str) -> bool {
+        self.admins.contains(account)
+    }
+
+    // Stop an account's messages from being relayed for a while, however often it reconnects.
+    // Returns how long the mute lasts.
+    pub fn mute(&mut self, account: &str, duration: Duration) -> Duration {
+        let now = SystemTime::now();
+        let duration = duration.min(MAX_MUTE);
+        self.mutes.retain(|_, until| *until > now);
+        self.mutes.insert(account.to_string(), now + duration);
+        duration
+    }
+
+    // Lift a mute early; returns whether the account was muted
+    pub fn unmute(&mut self, account: &str) -> bool {
+        self.mutes
+            .remove(account)
+            .is_some_and(|until| until > SystemTime::now())
+    }
+
+    // Time left on an account's mute
+    pub fn mute_remaining(&self, account: &str) -> Option<Duration> {
+        self.mutes
+            .get(account)?
+            .duration_since(SystemTime::now())
+            .ok()
+            .filter(|remaining| !remaining.is_zero())
+    }
+
+    // Count a chat message that was accepted and broadcast
+    pub fn record_message_processed(&mut self) {
+        self.messages_processed += 1;
+    }
+
+    pub fn messages_processed(&self) -> u64 {
+        self.messages_processed
+    }
+
+    // Messages kept across all channels
+    pub fn history_len(&self) -> usize {
+        self.message_history.values().map(VecDeque::len).sum()
+    }
+
+    pub fn uptime(&self) -> Duration {
+        self.start_time.elapsed()
+    }
+
     // Add a connected user by UUID
     pub async fn add_connected_user(
         &mut self,
         user_id: String,
         username: String,
     ) -> Result<(), String> {
-        // Check if user with same username is already connected
+        // Check if the account is already connected, under whatever name it goes by now
         for value in self.connected_users.values() {
             let data = value.lock().await;
-            if data.username == username {
+            if data.account.eq_ignore_ascii_case(&username) {
                 return Err(format!("The User {} is already connected", username));
             }
         }
 
-        let user_info = Arc::new(Mutex::new(UserInfo {
-            username,
-            connection_time: SystemTime::now(),
-            message_count: 0,
-        }));
+        let user_info = Arc::new(Mutex::new(UserInfo::new(username.clone())));
 
         self.connected_users.insert(user_id.clone(), user_info);
+        // An error only means nobody is listening for presence
+        let _ = self
+            .presence_tx
+            .send(PresenceEvent::Joined { user_id, username });
 
         Ok(())
     }
@@ -114,41 +513,689 @@ impl App {
 
     // Remove a connected user by UUID
     pub async fn remove_connected_user(&mut self, user_id: &str) -> Option<Arc<Mutex<UserInfo>>> {
-        self.connected_users.remove(user_id)
+        let user_info = self.connected_users.remove(user_id)?;
+        let username = user_info.lock().await.username.clone();
+        let _ = self.presence_tx.send(PresenceEvent::Left {
+            user_id: user_id.to_string(),
+            username,
+        });
+        Some(user_info)
+    }
+
+    // Connections of users who haven't sent anything for at least `idle_timeout`
+    pub async fn idle_users(&self, idle_timeout: Duration) -> Vec<String> {
+        let mut idle = Vec::new();
+        for (user_id, user_info) in self.connected_users.iter() {
+            let last_received = user_info.lock().await.last_received;
+            if last_received.elapsed().unwrap_or_default() >= idle_timeout {
+                idle.push(user_id.clone());
+            }
+        }
+        idle
+    }
+
+    // Find the UUID of a connected user by their current username
+    pub async fn find_user_id_by_name(&self, username: &str) -> Option<String> {
+        for (user_id, user_info) in self.connected_users.iter() {
+            if user_info.lock().await.username == username {
+                return Some(user_id.clone());
+            }
+        }
+        None
+    }
+
+    // Number of authenticated users online; spectators are not counted
+    pub fn connected_user_count(&self) -> usize {
+        self.connected_users.len()
     }
 
     pub async fn get_connected_users(&self) -> Vec<Arc<Mutex<UserInfo>>> {
         self.connected_users.values().cloned().collect()
     }
 
-    // Update username for a user
-    pub async fn update_username(&mut self, user_id: String, username: String) {
-        if let Some(user_info) = self.connected_users.get_mut(&user_id) {
-            user_info.lock().await.username = username;
+    // Tell every connection who is online, after someone joins, leaves or renames
+    pub async fn broadcast_presence(&self) {
+        let names = self.roster().await;
+        self.broadcast(MessageType::UserCount(names.len()), None);
+        self.broadcast(MessageType::Roster(names), None);
+    }
+
+    // Sorted names of the users online
+    pub async fn roster(&self) -> Vec<String> {
+        let mut names = Vec::with_capacity(self.connected_users.len());
+        for user in self.connected_users.values() {
+            names.push(user.lock().await.username.clone());
+        }
+        names.sort();
+        names
+    }
+
+    // The users online with their status, time online and message count, sorted by name
+    pub async fn user_summaries(&self) -> Vec<UserSummary> {
+        let mut users = Vec::with_capacity(self.connected_users.len());
+        for user in self.connected_users.values() {
+            let user_lock = user.lock().await;
+            let status = if user_lock.away_message.is_some() {
+                "away"
+            } else if self.mute_remaining(&user_lock.account).is_some() {
+                "muted"
+            } else {
+                "online"
+            };
+            users.push(UserSummary {
+                username: user_lock.username.clone(),
+                status: status.to_string(),
+                online_secs: user_lock
+                    .connection_time
+                    .elapsed()
+                    .unwrap_or_default()
+                    .as_secs(),
+                messages: user_lock.message_count,
+            });
+        }
+        users.sort_by(|a, b| a.username.cmp(&b.username));
+        users
+    }
+
+    // Whether a name belongs to someone other than the user connected as `user_id`: another
+    // connection's name or account, or a password or token account. Names differing only in case
+    // count as the same, so nobody can pass for someone else by renaming.
+    pub async fn name_taken(&self, user_id: &str, name: &str) -> bool {
+        let own_account = match self.connected_users.get(user_id) {
+            Some(user) => user.lock().await.account.clone(),
+            None => String::new(),
+        };
+        let is_other = |taken: &str| taken.eq_ignore_ascii_case(name) && taken != own_account;
+        for (id, user) in self.connected_users.iter() {
+            if id == user_id {
+                continue;
+            }
+            let user_lock = user.lock().await;
+            if user_lock.username.eq_ignore_ascii_case(name) || is_other(&user_lock.account) {
+                return true;
+            }
         }
+        self.user_credentials
+            .keys()
+            .any(|account| is_other(account))
+            || self.auth_tokens.values().any(|account| is_other(account))
     }
 
-    // Add a message to the message history (limit to 100 messages)
-    pub async fn add_message_to_history(&mut self, message: MessageType) {
-        if self.message_history.len() == 100 {
-            self.message_history.pop_front(); // Remove oldest message if full
+    // Update username for a user, returning their account and the name they had before
+    pub async fn update_username(
+        &mut self,
+        user_id: String,
+        username: String,
+    ) -> Option<(String, String)> {
+        let user_info = self.connected_users.get_mut(&user_id)?;
+        let mut user_lock = user_info.lock().await;
+        let previous = std::mem::replace(&mut user_lock.username, username);
+        Some((user_lock.account.clone(), previous))
+    }
+
+    // Add a message to a channel's history (limited to `history_size` messages, so none at 0)
+    pub async fn add_message_to_history(&mut self, channel: &str, message: MessageType) {
+        if self.history_size == 0 {
+            return;
+        }
+        let history = self.message_history.entry(channel.to_string()).or_default();
+        if history.len() >= self.history_size {
+            // Remove oldest message if full, along with its reactions
+            if let Some(MessageType::ChatMessage {
+                message_id: Some(id),
+                ..
+            }) = history.pop_front()
+            {
+                self.reactions.remove(&id);
+            }
         }
-        self.message_history.push_back(message);
+        history.push_back(message);
     }
 
-    // Retrieve the message history
-    pub async fn get_message_history(&self) -> Vec<MessageType> {
-        self.message_history.iter().cloned().collect()
+    // Forget a channel's history and the reactions to it, so newcomers start with a blank slate.
+    // Returns how many messages were dropped.
+    pub fn clear_history(&mut self, channel: &str) -> usize {
+        let Some(history) = self.message_history.remove(channel) else {
+            return 0;
+        };
+        for message in &history {
+            if let MessageType::ChatMessage {
+                message_id: Some(id),
+                ..
+            } = message
+            {
+                self.reactions.remove(id);
+            }
+        }
+        history.len()
+    }
+
+    // Add or remove a user's reaction to a message in a channel's history. Returns false when
+    // the message isn't there, e.g. because it has aged out.
+    pub fn toggle_reaction(
+        &mut self,
+        channel: &str,
+        message_id: &str,
+        user: &str,
+        emoji: &str,
+    ) -> bool {
+        let in_history = self.message_history.get(channel).is_some_and(|history| {
+            history.iter().any(|message| {
+                matches!(message, MessageType::ChatMessage { message_id: Some(id), .. } if id == message_id)
+            })
+        });
+        if !in_history {
+            return false;
+        }
+        let reactions = self.reactions.entry(message_id.to_string()).or_default();
+        match reactions
+            .iter()
+            .position(|(account, existing)| account == user && existing == emoji)
+        {
+            Some(index) => {
+                reactions.remove(index);
+            }
+            None => reactions.push((user.to_string(), emoji.to_string())),
+        }
+        if reactions.is_empty() {
+            self.reactions.remove(message_id);
+        }
+        true
+    }
+
+    // Keep every channel's topic in `file`, restoring the ones saved there. Changes are written
+    // by a task of their own, so a slow disk never holds up the App lock.
+    pub fn load_topics(&mut self, file: PathBuf) -> io::Result<()> {
+        let text = match fs::read_to_string(&file) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e),
+        };
+        self.topics = parse_topics(&text);
+        let (saver, changes) = mpsc::unbounded_channel();
+        tokio::spawn(save_topics_task(file, changes));
+        self.topic_saver = Some(saver);
+        Ok(())
+    }
+
+    // Change a channel's topic. If it can't be saved the new topic still applies until the
+    // server stops, and the failure is logged.
+    pub fn set_topic(&mut self, channel: &str, topic: String) {
+        self.topics.insert(channel.to_string(), topic);
+        if let Some(saver) = &self.topic_saver {
+            let _ = saver.send(self.topics.clone());
+        }
+    }
+
+    pub fn topic(&self, channel: &str) -> Option<String> {
+        self.topics.get(channel).cloned()
+    }
+
+    // Whether an account may change the topic: anyone when no admins are configured
+    pub fn can_set_topic(&self, account: &str) -> bool {
+        self.admins.is_empty() || self.is_admin(account)
+    }
+
+    // Retrieve a channel's message history, followed by the reactions to its messages
+    pub async fn get_message_history(&self, channel: &str) -> Vec<MessageType> {
+        let Some(history) = self.message_history.get(channel) else {
+            return Vec::new();
+        };
+        let mut replay: Vec<MessageType> = history.iter().cloned().collect();
+        for message in history {
+            if let MessageType::ChatMessage {
+                message_id: Some(id),
+                ..
+            } = message
+            {
+                for (user, emoji) in self.reactions.get(id).into_iter().flatten() {
+                    replay.push(MessageType::Reaction {
+                        message_id: id.clone(),
+                        user: user.clone(),
+                        emoji: emoji.clone(),
+                    });
+                }
+            }
+        }
+        replay
     }
 }
 
 impl UserInfo {
-    pub fn new() -> UserInfo {
-        // Initalize and return a new isntance of 'UserInfo'
+    // A user who just logged in to `account`, going by its name in the default channel
+    pub fn new(account: String) -> UserInfo {
         UserInfo {
-            username: "username".to_string(),
+            username: account.clone(),
+            account,
             connection_time: SystemTime::now(),
             message_count: 0,
+            away_message: None,
+            afk_replied: HashSet::new(),
+            channel: DEFAULT_CHANNEL.to_string(),
+            joined_at: 0,
+            last_active: SystemTime::now(),
+            last_received: SystemTime::now(),
+            blocked: HashSet::new(),
+            history_requested: None,
+            idle: Arc::new(Notify::new()),
+        }
+    }
+
+    // Note a /history request. Returns how long to wait instead if the last one was too recent.
+    pub fn request_history(&mut self) -> Option<Duration> {
+        let now = SystemTime::now();
+        if let Some(last) = self.history_requested {
+            let elapsed = now.duration_since(last).unwrap_or_default();
+            if elapsed < HISTORY_COOLDOWN {
+                return Some(HISTORY_COOLDOWN - elapsed);
+            }
+        }
+        self.history_requested = Some(now);
+        None
+    }
+
+    // Whether a message comes from an account this user has blocked
+    pub fn blocks(&self, message: &MessageType) -> bool {
+        match message {
+            MessageType::ChatMessage { sender_id, .. } => self.blocked.contains(sender_id),
+            MessageType::Reaction { user, .. } => self.blocked.contains(user),
+            _ => false,
+        }
+    }
+
+    // A history about to be replayed to this user, without what they have blocked
+    pub fn unblocked(&self, history: Vec<MessageType>) -> Vec<MessageType> {
+        history
+            .into_iter()
+            .filter(|message| !self.blocks(message))
+            .collect()
+    }
+
+    // Mark the user away, starting a fresh AFK session
+    pub fn set_away(&mut self, message: String) {
+        self.away_message = Some(message);
+        self.afk_replied.clear();
+    }
+
+    // Record that the user chatted or sent a DM, bringing them back from AFK. Returns whether
+    // they were away.
+    pub fn mark_active(&mut self) -> bool {
+        self.last_active = SystemTime::now();
+        self.clear_away()
+    }
+
+    // Come back from AFK; returns whether the user was away
+    pub fn clear_away(&mut self) -> bool {
+        self.afk_replied.clear();
+        self.away_message.take().is_some()
+    }
+
+    // The AFK auto-reply owed to a sender, given only once per sender per AFK session
+    pub fn afk_auto_reply(&mut self, sender_account: &str) -> Option<String> {
+        let away_message = self.away_message.as_ref()?;
+        if self.afk_replied.insert(sender_account.to_string()) {
+            Some(away_message.clone())
+        } else {
+            None
+        }
+    }
+}
+
+// Topics as saved by `save_topics_task`: a JSON object of channel to topic. A file holding just
+// some text is from before every channel's topic was kept, and is the default channel's topic.
+fn parse_topics(text: &str) -> HashMap<String, String> {
+    if text.trim().is_empty() {
+        return HashMap::new();
+    }
+    serde_json::from_str(text)
+        .unwrap_or_else(|_| HashMap::from([(DEFAULT_CHANNEL.to_string(), text.trim().to_string())]))
+}
+
+// Write each set of topics sent by `set_topic` to `file`, skipping to the latest when several
+// changes arrive while one is being written
+async fn save_topics_task(
+    file: PathBuf,
+    mut changes: mpsc::UnboundedReceiver<HashMap<String, String>>,
+) {
+    while let Some(mut topics) = changes.recv().await {
+        while let Ok(newer) = changes.try_recv() {
+            topics = newer;
+        }
+        // Written aside and renamed over the old file, so a crash never leaves half of it
+        let json = serde_json::to_string(&topics).expect("topics serialize");
+        let mut partial = file.clone().into_os_string();
+        partial.push(".tmp");
+        let saved = match tokio::fs::write(&partial, json).await {
+            Ok(()) => tokio::fs::rename(&partial, &file).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = saved {
+            log::warn!("Failed to save topics to {}: {}", file.display(), e);
+        }
+    }
+}
+
+// Normalise a channel name given to Join: an optional leading '#' is dropped and the rest follows
+// the same rules as usernames, lowercased so #Rust and #rust are the same channel.
+pub fn validate_channel_name(name: &str) -> Result<String, String> {
+    let name = name.trim();
+    let name = name.strip_prefix('#').unwrap_or(name);
+    validate_username(name)
+        .map(|name| name.to_lowercase())
+        .map_err(|reason| reason.replace("Username", "Channel name"))
+}
+
+// Longest display name accepted, in characters; the client applies the same rules
+pub const MAX_USERNAME_LEN: usize = 32;
+
+// Trim a requested display name and check it is usable: non-empty, not too long, and made only of
+// letters, digits, '_', '-' and '.', so it can't carry control characters or break the
+// "name: message" layout. Returns the trimmed name or the reason it was rejected.
+// The client has a copy of these rules (validate_username in its app.rs) to turn names down
+// before sending them; the two crates share no code, so change both together.
+pub fn validate_username(name: &str) -> Result<String, String> {
+    let name = name.trim();
+    if name.is_empty() {
+        return Err("Username cannot be empty.".to_string());
+    }
+    if name.chars().count() > MAX_USERNAME_LEN {
+        return Err(format!(
+            "Username is too long (at most {} characters).",
+            MAX_USERNAME_LEN
+        ));
+    }
+    if let Some(c) = name
+        .chars()
+        .find(|c| !(c.is_alphanumeric() || matches!(c, '_' | '-' | '.')))
+    {
+        return Err(format!(
+            "Username cannot contain {:?}; use letters, digits, '_', '-' or '.'.",
+            c
+        ));
+    }
+    Ok(name.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chat(content: &str) -> MessageType {
+        MessageType::ChatMessage {
+            sender: "alice".to_string(),
+            content: content.to_string(),
+            sender_id: "alice".to_string(),
+            client_msg_id: None,
+            message_id: None,
+            reply_to: None,
+            seq: None,
         }
     }
+
+    fn contents(history: &[MessageType]) -> Vec<String> {
+        history
+            .iter()
+            .filter_map(|message| match message {
+                MessageType::ChatMessage { content, .. } => Some(content.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn adding_and_removing_users_publishes_presence() {
+        let mut app = App::new(10);
+        let mut presence = app.subscribe_presence();
+        app.add_connected_user("id-1".to_string(), "alice".to_string())
+            .await
+            .unwrap();
+        app.remove_connected_user("id-1").await;
+        // Removing someone who isn't connected publishes nothing
+        app.remove_connected_user("id-1").await;
+
+        assert_eq!(
+            presence.try_recv().unwrap(),
+            PresenceEvent::Joined {
+                user_id: "id-1".to_string(),
+                username: "alice".to_string(),
+            }
+        );
+        assert_eq!(
+            presence.try_recv().unwrap(),
+            PresenceEvent::Left {
+                user_id: "id-1".to_string(),
+                username: "alice".to_string(),
+            }
+        );
+        assert!(presence.try_recv().is_err());
+    }
+
+    #[test]
+    fn mutes_run_out_and_are_capped() {
+        let mut app = App::new(10);
+        // However long a mute is asked for, its end can be represented
+        assert_eq!(app.mute("bob", Duration::MAX), MAX_MUTE);
+        assert!(app
+            .mute_remaining("bob")
+            .is_some_and(|remaining| remaining <= MAX_MUTE));
+
+        // A new mute replaces the old one, and is gone once it runs out
+        app.mute("bob", Duration::from_millis(20));
+        assert!(app.mute_remaining("bob").is_some());
+        std::thread::sleep(Duration::from_millis(30));
+        assert_eq!(app.mute_remaining("bob"), None);
+        assert!(!app.unmute("bob"));
+    }
+
+    #[tokio::test]
+    async fn topics_of_every_channel_are_restored_after_a_restart() {
+        let file = std::env::temp_dir().join(format!("topic-{}", std::process::id()));
+        let _ = fs::remove_file(&file);
+
+        let mut app = App::new(10);
+        app.load_topics(file.clone()).unwrap();
+        assert_eq!(app.topic(DEFAULT_CHANNEL), None);
+        app.set_topic(DEFAULT_CHANNEL, "Release on Friday".to_string());
+        app.set_topic("games", "Chess at noon".to_string());
+
+        // Saving happens in the background, so wait for the restarted server to see both
+        let restored = tokio::time::timeout(Duration::from_secs(2), async {
+            loop {
+                let mut restarted = App::new(10);
+                restarted.load_topics(file.clone()).unwrap();
+                if restarted.topic("games").is_some() {
+                    return restarted;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("topics were not saved");
+        assert_eq!(
+            restored.topic(DEFAULT_CHANNEL).as_deref(),
+            Some("Release on Friday")
+        );
+        assert_eq!(restored.topic("games").as_deref(), Some("Chess at noon"));
+        let _ = fs::remove_file(&file);
+    }
+
+    #[test]
+    fn a_topic_saved_as_plain_text_belongs_to_the_default_channel() {
+        assert_eq!(
+            parse_topics("Release on Friday\n"),
+            HashMap::from([(DEFAULT_CHANNEL.to_string(), "Release on Friday".to_string())])
+        );
+        assert!(parse_topics("").is_empty());
+    }
+
+    #[test]
+    fn unreadable_topic_file_is_reported() {
+        // A directory can't be read as a file
+        let mut app = App::new(10);
+        assert!(app.load_topics(std::env::temp_dir()).is_err());
+        assert_eq!(app.topic(DEFAULT_CHANNEL), None);
+    }
+
+    #[test]
+    fn afk_auto_reply_is_sent_once_per_sender_per_absence() {
+        let mut user = UserInfo::new("alice".to_string());
+        assert_eq!(user.afk_auto_reply("bob"), None);
+
+        user.set_away("at lunch".to_string());
+        assert_eq!(user.afk_auto_reply("bob").as_deref(), Some("at lunch"));
+        assert_eq!(user.afk_auto_reply("bob"), None);
+        assert_eq!(user.afk_auto_reply("carol").as_deref(), Some("at lunch"));
+
+        // Coming back and going away again starts over
+        assert!(user.mark_active());
+        assert_eq!(user.afk_auto_reply("bob"), None);
+        user.set_away("in a meeting".to_string());
+        assert_eq!(user.afk_auto_reply("bob").as_deref(), Some("in a meeting"));
+    }
+
+    #[test]
+    fn validate_username_rejects_empty_long_and_odd_names() {
+        assert_eq!(
+            validate_username("  al.ice_2-b "),
+            Ok("al.ice_2-b".to_string())
+        );
+        assert_eq!(
+            validate_username(""),
+            Err("Username cannot be empty.".to_string())
+        );
+        let long = "a".repeat(MAX_USERNAME_LEN + 1);
+        assert!(validate_username(&long).unwrap_err().contains("too long"));
+        assert!(validate_username(&long[1..]).is_ok());
+        for name in ["al ice", "alice:", "a\u{1b}[31m", "<script>"] {
+            assert!(validate_username(name)
+                .unwrap_err()
+                .contains("cannot contain"));
+        }
+        // Channel names follow the same rules, with an optional '#', lowercased
+        assert_eq!(validate_channel_name("#Rust"), Ok("rust".to_string()));
+        assert!(validate_channel_name("#")
+            .unwrap_err()
+            .starts_with("Channel name"));
+    }
+
+    #[test]
+    fn passwords_may_contain_colons() {
+        let mut app = App::new(10);
+        app.user_credentials.insert(
+            "carol".to_string(),
+            UserCredentials {
+                password: "pa:ss:word".to_string(),
+            },
+        );
+        let sent = MessageType::Auth {
+            username: "carol".to_string(),
+            password: "pa:ss:word".to_string(),
+            token: None,
+        };
+        let received = serde_json::from_str(&serde_json::to_string(&sent).unwrap()).unwrap();
+        let MessageType::Auth {
+            username, password, ..
+        } = received
+        else {
+            panic!("expected Auth, got {:?}", received);
+        };
+        assert!(app.authenticate_user(&username, &password));
+        assert!(!app.authenticate_user("carol", "pa"));
+    }
+
+    #[test]
+    fn login_tokens_sign_in_as_their_own_name_only() {
+        let mut app = App::new(10);
+        app.set_auth_tokens(HashMap::from([
+            ("t-bot".to_string(), " deploy-bot ".to_string()),
+            ("t-odd".to_string(), "not ok!".to_string()),
+            // Would take over user1's account
+            ("t-user".to_string(), "User1".to_string()),
+        ]));
+        assert_eq!(
+            app.authenticate_token("t-bot").as_deref(),
+            Some("deploy-bot")
+        );
+        assert_eq!(app.authenticate_token("t-odd"), None);
+        assert_eq!(app.authenticate_token("t-user"), None);
+        assert_eq!(app.authenticate_token("guess"), None);
+    }
+
+    #[test]
+    fn resent_message_ids_are_recognised_per_account() {
+        let mut app = App::new(10);
+        app.remember_message("alice", "c-1".to_string(), "m-1".to_string(), 7);
+        assert_eq!(
+            app.seen_message("alice", "c-1"),
+            Some(("m-1".to_string(), 7))
+        );
+        assert_eq!(app.seen_message("alice", "c-2"), None);
+        assert_eq!(app.seen_message("bob", "c-1"), None);
+
+        // The oldest id is forgotten first, unless it is being retried
+        for n in 2..=RECENT_MESSAGE_IDS {
+            app.remember_message("alice", format!("c-{}", n), format!("m-{}", n), n as u64);
+        }
+        app.seen_message("alice", "c-1");
+        app.remember_message("alice", "c-new".to_string(), "m-new".to_string(), 100);
+        assert!(app.seen_message("alice", "c-1").is_some());
+        assert_eq!(app.seen_message("alice", "c-2"), None);
+    }
+
+    #[test]
+    fn reply_to_is_optional_on_the_wire() {
+        // Left out when a message isn't a reply, so older clients see what they always did
+        let json = serde_json::to_string(&chat("hi")).unwrap();
+        assert!(!json.contains("reply_to"), "{}", json);
+
+        // Messages from clients that don't know about replies still parse
+        let received: MessageType =
+            serde_json::from_str(r#"{"ChatMessage":{"sender":"bob","content":"hi"}}"#).unwrap();
+        assert!(matches!(
+            received,
+            MessageType::ChatMessage { reply_to: None, .. }
+        ));
+
+        let reply = MessageType::ChatMessage {
+            sender: "alice".to_string(),
+            content: "me too".to_string(),
+            sender_id: "alice".to_string(),
+            client_msg_id: None,
+            message_id: None,
+            reply_to: Some(7),
+            seq: None,
+        };
+        let received = serde_json::from_str(&serde_json::to_string(&reply).unwrap()).unwrap();
+        assert!(matches!(
+            received,
+            MessageType::ChatMessage {
+                reply_to: Some(7),
+                ..
+            }
+        ));
+    }
+
+    #[tokio::test]
+    async fn history_keeps_only_the_newest_messages_per_channel() {
+        let mut app = App::new(3);
+        for content in ["one", "two", "three", "four", "five"] {
+            app.add_message_to_history(DEFAULT_CHANNEL, chat(content))
+                .await;
+        }
+        app.add_message_to_history("rust", chat("elsewhere")).await;
+        assert_eq!(
+            contents(&app.get_message_history(DEFAULT_CHANNEL).await),
+            ["three", "four", "five"]
+        );
+        assert_eq!(app.history_len(), 4);
+    }
+
+    #[tokio::test]
+    async fn a_history_size_of_zero_keeps_nothing() {
+        let mut app = App::new(0);
+        app.add_message_to_history(DEFAULT_CHANNEL, chat("one"))
+            .await;
+        assert!(app.get_message_history(DEFAULT_CHANNEL).await.is_empty());
+        assert_eq!(app.history_len(), 0);
+    }
 }
@@ -0,0 +1,309 @@
+//  This file contains the optional chat log, an append-only audit trail of every chat message the
+//  server broadcasts. Each line has a UTC timestamp, the channel, the sender's display name and
+//  account, and the content. The file is rotated once it grows past a size or a day has passed,
+//  the old one being renamed with the time it was rotated at. Writing happens on a thread of its
+//  own, so a slow disk never holds up delivery.
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Rotation {
+    Size(u64), // Start a new file once the current one reaches this many bytes
+    Daily,     // Start a new file at midnight UTC
+}
+
+// A chat message waiting to be written to the log
+pub struct ChatLogEntry {
+    pub channel: String,
+    pub account: String,
+    pub name: String,
+    pub content: String,
+}
+
+pub struct ChatLog {
+    path: PathBuf,
+    rotation: Rotation,
+    file: Option<File>, // Opened on the first write, and again after rotating
+    size: u64,          // Bytes in the current file
+    day: u64,           // Days since the epoch when the current file was started
+}
+
+impl Rotation {
+    // `daily`, or a size in bytes
+    pub fn parse(value: &str) -> Option<Rotation> {
+        match value.trim().to_lowercase().as_str() {
+            "daily" => Some(Rotation::Daily),
+            size => size
+                .parse()
+                .ok()
+                .filter(|size| *size > 0)
+                .map(Rotation::Size),
+        }
+    }
+}
+
+impl ChatLog {
+    pub fn new(path: PathBuf, rotation: Rotation) -> ChatLog {
+        ChatLog {
+            path,
+            rotation,
+            file: None,
+            size: 0,
+            day: 0,
+        }
+    }
+
+    // Hand the log to a thread that writes each entry sent to the returned sender, logging any
+    // failure. The thread ends once the sender is dropped.
+    pub fn spawn(mut self) -> mpsc::Sender<ChatLogEntry> {
+        let (entries_tx, entries) = mpsc::channel::<ChatLogEntry>();
+        thread::Builder::new()
+            .name("chat-log".to_string())
+            .spawn(move || {
+                for entry in entries {
+                    let written =
+                        self.write(&entry.channel, &entry.account, &entry.name, &entry.content);
+                    if let Err(e) = written {
+                        log::warn!("Failed to write to the chat log: {}", e);
+                    }
+                }
+            })
+            .expect("failed to start the chat log thread");
+        entries_tx
+    }
+
+    // Append one chat message. Errors are for the caller to report; the message has already been
+    // delivered either way.
+    pub fn write(
+        &mut self,
+        channel: &str,
+        account: &str,
+        name: &str,
+        content: &str,
+    ) -> io::Result<()> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let line = format!(
+            "{} #{} {} ({}): {}\n",
+            format_timestamp(now),
+            channel,
+            name,
+            account,
+            escape_content(content)
+        );
+
+        // Before opening, size up a file left by an earlier run, so one that is already too big
+        // or was started on an earlier day is rotated rather than added to
+        if self.file.is_none() {
+            (self.size, self.day) = match fs::metadata(&self.path) {
+                Ok(metadata) => {
+                    let modified = metadata
+                        .modified()
+                        .ok()
+                        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                        .map_or(now, |modified| modified.as_secs());
+                    (metadata.len(), modified / SECS_PER_DAY)
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => (0, now / SECS_PER_DAY),
+                Err(e) => return Err(e),
+            };
+        }
+        if self.should_rotate(now, line.len() as u64) {
+            self.file = None;
+            fs::rename(&self.path, rotated_path(&self.path, now))?;
+            self.size = 0;
+            self.day = now / SECS_PER_DAY;
+        }
+        let file = match &mut self.file {
+            Some(file) => file,
+            None => self.file.insert(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&self.path)?,
+            ),
+        };
+        file.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    fn should_rotate(&self, now: u64, next_line: u64) -> bool {
+        match self.rotation {
+            Rotation::Size(limit) => self.size > 0 && self.size + next_line > limit,
+            Rotation::Daily => now / SECS_PER_DAY != self.day,
+        }
+    }
+}
+
+// Escape backslashes and control characters, e.g. a newline as `\n` and ESC as `\u{1b}`, so each
+// message stays on one greppable line and can't pass for, or garble, another one
+fn escape_content(content: &str) -> String {
+    let mut escaped = String::with_capacity(content.len());
+    for c in content.chars() {
+        if c == '\\' || c.is_control() {
+            escaped.extend(c.escape_default());
+        } else {
+            escaped.push(c);
+        }
+    }
+    escaped
+}
+
+// Where a rotated file is moved to: the log's own name with the rotation time appended, and a
+// counter too if it rotated more than once that second
+fn rotated_path(path: &Path, now: u64) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", now));
+    let mut rotated = PathBuf::from(&name);
+    let mut n = 1;
+    while rotated.exists() {
+        let mut numbered = name.clone();
+        numbered.push(format!(".{}", n));
+        rotated = PathBuf::from(numbered);
+        n += 1;
+    }
+    rotated
+}
+
+// Seconds since the epoch as an RFC 3339 UTC timestamp, e.g. 2024-05-01T12:30:00Z
+fn format_timestamp(secs: u64) -> String {
+    let (year, month, day) = civil_from_days((secs / SECS_PER_DAY) as i64);
+    let time = secs % SECS_PER_DAY;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        time / 3600,
+        time % 3600 / 60,
+        time % 60
+    )
+}
+
+// Days since 1970-01-01 to a (year, month, day) date, after Howard Hinnant's algorithm
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // An empty directory of its own for each test
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("chat-log-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn each_message_is_one_timestamped_line() {
+        assert_eq!(format_timestamp(0), "1970-01-01T00:00:00Z");
+        assert_eq!(format_timestamp(1_714_566_600), "2024-05-01T12:30:00Z");
+        assert_eq!(format_timestamp(951_782_400), "2000-02-29T00:00:00Z");
+
+        let dir = scratch_dir("format");
+        let path = dir.join("chat.log");
+        let mut log = ChatLog::new(path.clone(), Rotation::Daily);
+        log.write("general", "alice", "Ally", "two\nlines").unwrap();
+        let text = fs::read_to_string(&path).unwrap();
+        let (timestamp, rest) = text.split_once(' ').unwrap();
+        assert_eq!(timestamp.len(), "1970-01-01T00:00:00Z".len());
+        assert!(timestamp.ends_with('Z'));
+        assert_eq!(rest, "#general Ally (alice): two\\nlines\n");
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn control_characters_cannot_forge_a_line() {
+        assert_eq!(
+            escape_content("ok\r2024-05-01T12:30:00Z #general admin (admin): hi"),
+            "ok\\r2024-05-01T12:30:00Z #general admin (admin): hi"
+        );
+        assert_eq!(
+            escape_content("\u{1b}[31mred\t\\n"),
+            "\\u{1b}[31mred\\t\\\\n"
+        );
+        assert_eq!(escape_content("plain text ✓"), "plain text ✓");
+    }
+
+    #[test]
+    fn log_is_rotated_once_it_reaches_its_size() {
+        let dir = scratch_dir("rotate");
+        let path = dir.join("chat.log");
+        let mut log = ChatLog::new(path.clone(), Rotation::Size(100));
+        for content in ["first", "second", "third"] {
+            log.write("general", "alice", "alice", content).unwrap();
+        }
+
+        // Each line is over half the limit, so every message after the first started a new file
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 3);
+        assert!(fs::read_to_string(&path)
+            .unwrap()
+            .ends_with("alice (alice): third\n"));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn a_file_left_too_big_by_an_earlier_run_is_rotated() {
+        let dir = scratch_dir("oversized");
+        let path = dir.join("chat.log");
+        fs::write(&path, "x".repeat(200)).unwrap();
+        let mut log = ChatLog::new(path.clone(), Rotation::Size(100));
+        log.write("general", "alice", "alice", "fresh").unwrap();
+
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+        assert!(fs::read_to_string(&path)
+            .unwrap()
+            .ends_with("alice (alice): fresh\n"));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn a_file_from_an_earlier_day_is_rotated() {
+        let dir = scratch_dir("yesterday");
+        let path = dir.join("chat.log");
+        fs::write(&path, "old\n").unwrap();
+        let yesterday = SystemTime::now() - std::time::Duration::from_secs(SECS_PER_DAY);
+        File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(yesterday)
+            .unwrap();
+        let mut log = ChatLog::new(path.clone(), Rotation::Daily);
+        log.write("general", "alice", "alice", "today").unwrap();
+
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+        assert!(!fs::read_to_string(&path).unwrap().contains("old"));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn write_failures_are_returned() {
+        // A directory can't be opened as the log file
+        let dir = scratch_dir("unwritable");
+        let mut log = ChatLog::new(dir.clone(), Rotation::Daily);
+        assert!(log.write("general", "alice", "alice", "lost").is_err());
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
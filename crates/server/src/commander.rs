@@ -1,9 +1,10 @@
 //  This file contains functions related to handling commands from clients. It includes a function
 //  for handling commands and sending messages to clients.
 pub mod command_handler {
-    use crate::app::{App, MessageType};
+    use crate::app::{validate_username, App, MessageType, Severity, DEFAULT_MUTE};
     use std::collections::HashMap;
     use std::sync::Arc;
+    use std::time::Duration;
     use tokio::sync::{mpsc, Mutex};
 
     pub async fn handle_command(
@@ -13,62 +14,955 @@ pub mod command_handler {
         clients: &Arc<Mutex<HashMap<String, mpsc::UnboundedSender<MessageType>>>>,
         app: Arc<Mutex<App>>,
     ) {
-        println!(
+        log::debug!(
             "Handling command '{}' with arguments {:?}",
-            command_name, args
+            command_name,
+            args
         );
 
         match command_name.as_str() {
             "name" => {
-                if let Some(new_name) = args.get(0) {
-                    // Update client name in the App (UserInfo)
-                    app.lock()
-                        .await
-                        .update_username(client_id.to_string(), new_name.clone())
-                        .await;
+                if let Some(new_name) = args.first() {
+                    // Reject names that are empty, too long or contain unsafe characters
+                    let new_name = match validate_username(new_name) {
+                        Ok(new_name) => new_name,
+                        Err(reason) => {
+                            if let Some(sender) = clients.lock().await.get(client_id) {
+                                let _ = sender.send(MessageType::CommandResult {
+                                    command: "name".to_string(),
+                                    ok: false,
+                                    detail: reason,
+                                });
+                            }
+                            return;
+                        }
+                    };
+
+                    // Update client name in the App (UserInfo) and let everyone see it. Everyone
+                    // else is told who was renamed, so they can relabel the old name's messages.
+                    // Names someone else goes by, or logs in as, are refused, as they would
+                    // receive that person's DMs.
+                    {
+                        let mut app_lock = app.lock().await;
+                        if app_lock.name_taken(client_id, &new_name).await {
+                            drop(app_lock);
+                            if let Some(sender) = clients.lock().await.get(client_id) {
+                                let _ = sender.send(MessageType::CommandResult {
+                                    command: "name".to_string(),
+                                    ok: false,
+                                    detail: "Name is taken".to_string(),
+                                });
+                            }
+                            return;
+                        }
+                        let renamed = app_lock
+                            .update_username(client_id.to_string(), new_name.clone())
+                            .await;
+                        app_lock.broadcast_presence().await;
+                        if let Some((account, from)) = renamed.filter(|(_, from)| *from != new_name)
+                        {
+                            app_lock.broadcast(
+                                MessageType::Renamed {
+                                    account,
+                                    from,
+                                    to: new_name.clone(),
+                                },
+                                Some(client_id),
+                            );
+                        }
+                    }
+
+                    // Confirm the new name, which is when the client starts using it
+                    if let Some(sender) = clients.lock().await.get(client_id) {
+                        let _ = sender.send(MessageType::CommandResult {
+                            command: "name".to_string(),
+                            ok: true,
+                            detail: new_name,
+                        });
+                    }
+                }
+            }
+            "list" | "names" => {
+                // Send the names as a list so the client can lay them out to fit its screen, or
+                // a row per user with their status and activity if the client asked for details
+                let reply = if args.first().map(String::as_str) == Some("details") {
+                    MessageType::UserDetails(app.lock().await.user_summaries().await)
+                } else {
+                    MessageType::UserList(app.lock().await.roster().await)
+                };
+                if let Some(sender) = clients.lock().await.get(client_id) {
+                    let _ = sender.send(reply);
+                }
+            }
+            "DirectMessage" => {
+                if let [recipient, content] = args.as_slice() {
+                    let app_lock = app.lock().await;
+                    let Some(sender_user) = app_lock.get_connected_user(client_id).await else {
+                        return;
+                    };
+                    let sender_account = sender_user.lock().await.account.clone();
+                    // Muted users can't send direct messages either
+                    if let Some(remaining) = app_lock.mute_remaining(&sender_account) {
+                        drop(app_lock);
+                        if let Some(sender) = clients.lock().await.get(client_id) {
+                            let _ = sender.send(MessageType::notice(
+                                Severity::Warn,
+                                format!(
+                                    "You are muted for another {}s; your message was not sent.",
+                                    remaining.as_secs().max(1)
+                                ),
+                            ));
+                        }
+                        return;
+                    }
+                    // Sending a message brings the sender back from AFK
+                    let (sender_name, back_from_afk) = {
+                        let mut user_lock = sender_user.lock().await;
+                        (user_lock.username.clone(), user_lock.mark_active())
+                    };
+                    let recipient_id = app_lock.find_user_id_by_name(recipient).await;
+                    // An away recipient answers each sender once with their AFK message, and a
+                    // recipient who blocked the sender doesn't get the message at all
+                    let (auto_reply, blocked) = match &recipient_id {
+                        Some(id) => match app_lock.get_connected_user(id).await {
+                            Some(user) => {
+                                let mut user_lock = user.lock().await;
+                                let blocked = user_lock.blocked.contains(&sender_account);
+                                let auto_reply = if blocked {
+                                    None
+                                } else {
+                                    user_lock.afk_auto_reply(&sender_account)
+                                };
+                                (auto_reply, blocked)
+                            }
+                            None => (None, false),
+                        },
+                        None => (None, false),
+                    };
+                    drop(app_lock);
+
+                    // A message to ourselves is already delivered by the echo below
+                    let to_self = recipient_id.as_deref() == Some(client_id);
+                    let clients_lock = clients.lock().await;
+                    match recipient_id.and_then(|id| clients_lock.get(&id)) {
+                        Some(recipient_tx) => {
+                            let private_message = MessageType::PrivateMessage {
+                                sender: sender_name.clone(),
+                                recipient: recipient.clone(),
+                                content: content.clone(),
+                            };
+                            if !blocked && !to_self {
+                                let _ = recipient_tx.send(private_message.clone());
+                            }
+
+                            // Echo back to the sender so their conversation buffer stays complete
+                            if let Some(sender) = clients_lock.get(client_id) {
+                                let _ = sender.send(private_message);
+                                if let Some(away_message) = auto_reply {
+                                    let _ = sender.send(MessageType::PrivateMessage {
+                                        sender: recipient.clone(),
+                                        recipient: sender_name.clone(),
+                                        content: format!("[auto-reply] {}", away_message),
+                                    });
+                                }
+                            }
+                        }
+                        None => {
+                            if let Some(sender) = clients_lock.get(client_id) {
+                                let _ = sender.send(MessageType::notice(
+                                    Severity::Warn,
+                                    format!("User {} is not connected.", recipient),
+                                ));
+                            }
+                        }
+                    }
+                    if back_from_afk {
+                        if let Some(sender) = clients_lock.get(client_id) {
+                            let _ = sender.send(MessageType::SystemMessage(
+                                "You are no longer AFK.".to_string(),
+                            ));
+                        }
+                    }
+                }
+            }
+            "seen" => {
+                let Some(name) = args.first() else {
+                    if let Some(sender) = clients.lock().await.get(client_id) {
+                        let _ = sender.send(MessageType::SystemMessage(
+                            "Usage: /seen <user>".to_string(),
+                        ));
+                    }
+                    return;
+                };
+                let last_active = {
+                    let app_lock = app.lock().await;
+                    match app_lock.find_user_id_by_name(name).await {
+                        Some(id) => match app_lock.get_connected_user(&id).await {
+                            Some(user) => Some(user.lock().await.last_active),
+                            None => None,
+                        },
+                        None => None,
+                    }
+                };
+                let reply = match last_active {
+                    Some(last_active) => format!(
+                        "{} was last active {} ago",
+                        name,
+                        format_duration(last_active.elapsed().unwrap_or_default())
+                    ),
+                    None => format!("{} is not connected", name),
+                };
+                if let Some(sender) = clients.lock().await.get(client_id) {
+                    let _ = sender.send(MessageType::SystemMessage(reply));
+                }
+            }
+            "afk" => {
+                let away_message = if args.is_empty() {
+                    "Away".to_string()
+                } else {
+                    args.join(" ")
+                };
+                let user = match app.lock().await.get_connected_user(client_id).await {
+                    Some(user) => user,
+                    None => return,
+                };
+                user.lock().await.set_away(away_message.clone());
 
-                    // Notify client of the name change
-                    let system_message = MessageType::SystemMessage(format!(
-                        "Your name is now set to '{}'",
-                        new_name
-                    ));
-                    clients
-                        .lock()
-                        .await
-                        .get(client_id)
-                        .unwrap()
-                        .send(system_message)
-                        .unwrap();
-                }
-            }
-            "list" => {
-                let app_clone = Arc::clone(&app);
-                let app_lock = app_clone.lock().await;
-                let connected_users = app_lock.get_connected_users().await;
-
-                // Collect usernames from App's connected_users
-                let mut names = Vec::new();
+                if let Some(sender) = clients.lock().await.get(client_id) {
+                    let _ = sender.send(MessageType::SystemMessage(format!(
+                        "You are now AFK: {}. DMs get an auto-reply until you next send a message.",
+                        away_message
+                    )));
+                }
+            }
+            "afk-list" => {
+                let connected_users = app.lock().await.get_connected_users().await;
+
+                // Only report users that currently have an away message set
+                let mut away_users = Vec::new();
                 for user in connected_users.iter() {
                     let user_lock = user.lock().await;
-                    names.push(user_lock.username.clone());
+                    if let Some(away_message) = &user_lock.away_message {
+                        away_users.push((user_lock.username.clone(), away_message.clone()));
+                    }
+                }
+
+                if let Some(sender) = clients.lock().await.get(client_id) {
+                    let _ = sender.send(MessageType::AwayList(away_users));
                 }
+            }
+            "topic" => {
+                let topic = args.join(" ");
+                let mut app_lock = app.lock().await;
+                let (account, username, channel) =
+                    match app_lock.get_connected_user(client_id).await {
+                        Some(user) => {
+                            let user_lock = user.lock().await;
+                            (
+                                user_lock.account.clone(),
+                                user_lock.username.clone(),
+                                user_lock.channel.clone(),
+                            )
+                        }
+                        None => return,
+                    };
+
+                let reply = if topic.trim().is_empty() {
+                    MessageType::SystemMessage("Usage: /topic <text>".to_string())
+                } else if !app_lock.can_set_topic(&account) {
+                    MessageType::notice(
+                        Severity::Error,
+                        "Permission denied: only admins can change the topic.",
+                    )
+                } else {
+                    // The topic belongs to the channel it was set in
+                    app_lock.set_topic(&channel, topic.clone());
+                    app_lock.broadcast_to_channel(
+                        &channel,
+                        MessageType::Topic(topic.clone()),
+                        None,
+                    );
+                    // A notice rather than a system message, as the text is the user's own and
+                    // clients act on some system messages
+                    app_lock.broadcast_to_channel(
+                        &channel,
+                        MessageType::notice(
+                            Severity::Info,
+                            format!("{} changed the topic to: {}", username, topic),
+                        ),
+                        None,
+                    );
+                    return;
+                };
+                drop(app_lock);
+
+                if let Some(sender) = clients.lock().await.get(client_id) {
+                    let _ = sender.send(reply);
+                }
+            }
+            "mute" | "unmute" => {
+                let muting = command_name == "mute";
+                let usage = if muting {
+                    "Usage: /mute <user> [seconds]"
+                } else {
+                    "Usage: /unmute <user>"
+                };
+                let duration = match args.get(1).map(|secs| secs.parse::<u64>()) {
+                    None => Some(DEFAULT_MUTE),
+                    Some(Ok(secs)) if muting && secs > 0 => Some(Duration::from_secs(secs)),
+                    Some(_) => None,
+                };
+                let (reply, target_notice) = {
+                    let mut app_lock = app.lock().await;
+                    let (account, moderator) = match app_lock.get_connected_user(client_id).await {
+                        Some(user) => {
+                            let user_lock = user.lock().await;
+                            (user_lock.account.clone(), user_lock.username.clone())
+                        }
+                        None => return,
+                    };
+                    let target_id = match args.first() {
+                        Some(name) => app_lock.find_user_id_by_name(name).await,
+                        None => None,
+                    };
+                    let target = match &target_id {
+                        Some(id) => app_lock.get_connected_user(id).await,
+                        None => None,
+                    };
 
-                let names_string = names.join(", ");
-                let system_message =
-                    MessageType::SystemMessage(format!("Connected users: {}", names_string));
+                    // Only admins may moderate
+                    if !app_lock.is_admin(&account) {
+                        let reply = MessageType::notice(
+                            Severity::Error,
+                            format!(
+                                "Permission denied: /{} is only available to admins.",
+                                command_name
+                            ),
+                        );
+                        (reply, None)
+                    } else if args.is_empty() || args.len() > 2 || duration.is_none() {
+                        (MessageType::SystemMessage(usage.to_string()), None)
+                    } else if let (Some(target), Some(target_id), Some(duration)) =
+                        (target, target_id, duration)
+                    {
+                        let name = &args[0];
+                        // Mutes are kept by account, so reconnecting doesn't lift them
+                        let target_account = target.lock().await.account.clone();
+                        if muting {
+                            let duration = app_lock.mute(&target_account, duration);
+                            let reply = MessageType::notice(
+                                Severity::Success,
+                                format!("Muted {} for {}.", name, format_duration(duration)),
+                            );
+                            let notice = MessageType::notice(
+                                Severity::Warn,
+                                format!(
+                                    "You have been muted by {} for {}.",
+                                    moderator,
+                                    format_duration(duration)
+                                ),
+                            );
+                            (reply, Some((target_id, notice)))
+                        } else if app_lock.unmute(&target_account) {
+                            let reply = MessageType::notice(
+                                Severity::Success,
+                                format!("Unmuted {}.", name),
+                            );
+                            let notice = MessageType::notice(
+                                Severity::Info,
+                                format!("You have been unmuted by {}.", moderator),
+                            );
+                            (reply, Some((target_id, notice)))
+                        } else {
+                            let reply =
+                                MessageType::SystemMessage(format!("{} is not muted.", name));
+                            (reply, None)
+                        }
+                    } else {
+                        let reply = MessageType::notice(
+                            Severity::Warn,
+                            format!("User {} is not connected.", args[0]),
+                        );
+                        (reply, None)
+                    }
+                };
 
+                let clients_lock = clients.lock().await;
+                if let Some(sender) = clients_lock.get(client_id) {
+                    let _ = sender.send(reply);
+                }
+                if let Some((target_id, notice)) = target_notice {
+                    if let Some(target) = clients_lock.get(&target_id) {
+                        let _ = target.send(notice);
+                    }
+                }
+            }
+            "history" => {
+                // Only the asking client gets the history, at most once per cooldown
+                let reply = {
+                    let app_lock = app.lock().await;
+                    let Some(user) = app_lock.get_connected_user(client_id).await else {
+                        return;
+                    };
+                    let mut user_lock = user.lock().await;
+                    match user_lock.request_history() {
+                        Some(wait) => MessageType::notice(
+                            Severity::Warn,
+                            format!(
+                                "You just asked for the history; try again in {}.",
+                                format_duration(wait + Duration::from_millis(999))
+                            ),
+                        ),
+                        None => {
+                            let history = app_lock.get_message_history(&user_lock.channel).await;
+                            MessageType::History(user_lock.unblocked(history))
+                        }
+                    }
+                };
+                if let Some(sender) = clients.lock().await.get(client_id) {
+                    let _ = sender.send(reply);
+                }
+            }
+            "block" | "unblock" => {
+                let blocking = command_name == "block";
+                let reply = {
+                    let app_lock = app.lock().await;
+                    let Some(user) = app_lock.get_connected_user(client_id).await else {
+                        return;
+                    };
+                    let target = match args.as_slice() {
+                        [name] => match app_lock.find_user_id_by_name(name).await {
+                            Some(id) => match app_lock.get_connected_user(&id).await {
+                                Some(target) => Some(target.lock().await.account.clone()),
+                                None => None,
+                            },
+                            None => None,
+                        },
+                        _ => {
+                            let usage = format!("Usage: /{} <user>", command_name);
+                            if let Some(sender) = clients.lock().await.get(client_id) {
+                                let _ = sender.send(MessageType::SystemMessage(usage));
+                            }
+                            return;
+                        }
+                    };
+                    let name = &args[0];
+                    let mut user_lock = user.lock().await;
+                    match target {
+                        Some(account) if account == user_lock.account => MessageType::notice(
+                            Severity::Warn,
+                            format!("You can't {} yourself.", command_name),
+                        ),
+                        // Blocks are by account, so they hold when the user changes their name
+                        Some(account) if blocking => {
+                            user_lock.blocked.insert(account);
+                            MessageType::notice(
+                                Severity::Success,
+                                format!(
+                                    "Blocked {}. Their messages won't reach you until you /unblock them or reconnect.",
+                                    name
+                                ),
+                            )
+                        }
+                        Some(account) if user_lock.blocked.remove(&account) => {
+                            MessageType::notice(Severity::Success, format!("Unblocked {}.", name))
+                        }
+                        Some(_) => MessageType::SystemMessage(format!("{} is not blocked.", name)),
+                        None => MessageType::notice(
+                            Severity::Warn,
+                            format!("User {} is not connected.", name),
+                        ),
+                    }
+                };
+                if let Some(sender) = clients.lock().await.get(client_id) {
+                    let _ = sender.send(reply);
+                }
+            }
+            "status" => {
+                // What the server knows about the user asking, e.g. to check a rename took
+                let user = match app.lock().await.get_connected_user(client_id).await {
+                    Some(user) => user,
+                    None => return,
+                };
+                let status = {
+                    let user_lock = user.lock().await;
+                    format!(
+                        "You are {} (account {}, color {}), connected for {}, {} messages sent.",
+                        user_lock.username,
+                        user_lock.account,
+                        App::user_color(&user_lock.account),
+                        format_duration(user_lock.connection_time.elapsed().unwrap_or_default()),
+                        user_lock.message_count
+                    )
+                };
                 if let Some(sender) = clients.lock().await.get(client_id) {
-                    sender.send(system_message).unwrap();
+                    let _ = sender.send(MessageType::SystemMessage(status));
+                }
+            }
+            "stats" => {
+                // Snapshot the figures and let go of the App lock before taking the clients lock
+                let stats = {
+                    let app_lock = app.lock().await;
+                    let account = match app_lock.get_connected_user(client_id).await {
+                        Some(user) => user.lock().await.account.clone(),
+                        None => return,
+                    };
+                    // Only admins get to see server internals
+                    app_lock.is_admin(&account).then(|| {
+                        (
+                            app_lock.connected_user_count(),
+                            app_lock.history_len(),
+                            app_lock.messages_processed(),
+                            app_lock.uptime().as_secs(),
+                        )
+                    })
+                };
+
+                let clients_lock = clients.lock().await;
+                let reply = match stats {
+                    Some((users, history, processed, uptime)) => {
+                        MessageType::SystemMessage(format!(
+                            "Server stats: {} clients connected ({} users), {} messages in history, {} messages processed, up {}h {}m {}s",
+                            clients_lock.len(),
+                            users,
+                            history,
+                            processed,
+                            uptime / 3600,
+                            uptime % 3600 / 60,
+                            uptime % 60
+                        ))
+                    }
+                    None => MessageType::notice(
+                        Severity::Error,
+                        "Permission denied: /stats is only available to admins.",
+                    ),
+                };
+                if let Some(sender) = clients_lock.get(client_id) {
+                    let _ = sender.send(reply);
+                }
+            }
+            "clearhistory" => {
+                let mut app_lock = app.lock().await;
+                let (account, username, channel) =
+                    match app_lock.get_connected_user(client_id).await {
+                        Some(user) => {
+                            let user_lock = user.lock().await;
+                            (
+                                user_lock.account.clone(),
+                                user_lock.username.clone(),
+                                user_lock.channel.clone(),
+                            )
+                        }
+                        None => return,
+                    };
+
+                // Only admins may wipe what everyone else sees on joining
+                if !app_lock.is_admin(&account) {
+                    drop(app_lock);
+                    if let Some(sender) = clients.lock().await.get(client_id) {
+                        let _ = sender.send(MessageType::notice(
+                            Severity::Error,
+                            "Permission denied: /clearhistory is only available to admins.",
+                        ));
+                    }
+                    return;
                 }
+                let cleared = app_lock.clear_history(&channel);
+                log::info!(
+                    "{} cleared {} messages of history in {}",
+                    account,
+                    cleared,
+                    channel
+                );
+                app_lock.broadcast_to_channel(
+                    &channel,
+                    MessageType::SystemMessage(format!(
+                        "{} cleared the message history of {}.",
+                        username, channel
+                    )),
+                    None,
+                );
             }
             _ => {
                 let system_message = MessageType::SystemMessage(
                     "Unknown command. Type /help for a list of commands.".to_string(),
                 );
                 if let Some(sender) = clients.lock().await.get(client_id) {
-                    sender.send(system_message).unwrap();
+                    let _ = sender.send(system_message);
                 }
             }
         }
     }
+
+    // Format a duration for people: "42s", "3m 12s" or "2h 5m"
+    fn format_duration(duration: Duration) -> String {
+        let secs = duration.as_secs();
+        match secs {
+            0..=59 => format!("{}s", secs),
+            60..=3599 => format!("{}m {}s", secs / 60, secs % 60),
+            _ => format!("{}h {}m", secs / 3600, secs % 3600 / 60),
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::app::DEFAULT_CHANNEL;
+        use std::collections::HashSet;
+        use std::time::SystemTime;
+
+        type Clients = Arc<Mutex<HashMap<String, mpsc::UnboundedSender<MessageType>>>>;
+
+        // An App with each of `names` connected under the same client id, and their inboxes
+        async fn connected(
+            names: &[&str],
+        ) -> (
+            Arc<Mutex<App>>,
+            Clients,
+            Vec<mpsc::UnboundedReceiver<MessageType>>,
+        ) {
+            let app = Arc::new(Mutex::new(App::new(10)));
+            let clients: Clients = Arc::new(Mutex::new(HashMap::new()));
+            let mut inboxes = Vec::new();
+            for name in names {
+                app.lock()
+                    .await
+                    .add_connected_user(name.to_string(), name.to_string())
+                    .await
+                    .unwrap();
+                let (tx, rx) = mpsc::unbounded_channel();
+                clients.lock().await.insert(name.to_string(), tx);
+                inboxes.push(rx);
+            }
+            (app, clients, inboxes)
+        }
+
+        async fn run(
+            command: &str,
+            args: &[&str],
+            client_id: &str,
+            clients: &Clients,
+            app: &Arc<Mutex<App>>,
+        ) {
+            let args = args.iter().map(|arg| arg.to_string()).collect();
+            handle_command(command.to_string(), args, client_id, clients, app.clone()).await;
+        }
+
+        #[tokio::test]
+        async fn afk_list_reports_only_users_who_are_away() {
+            let (app, clients, mut inboxes) = connected(&["alice", "bob"]).await;
+            run("afk", &["lunch"], "bob", &clients, &app).await;
+            run("afk-list", &[], "alice", &clients, &app).await;
+            match inboxes[0].try_recv() {
+                Ok(MessageType::AwayList(away)) => {
+                    assert_eq!(away, vec![("bob".to_string(), "lunch".to_string())])
+                }
+                other => panic!("expected the away list, got {:?}", other),
+            }
+
+            // A connection that went away before the reply doesn't bring the server down
+            drop(inboxes);
+            run("afk-list", &[], "alice", &clients, &app).await;
+            run("nonsense", &[], "alice", &clients, &app).await;
+        }
+
+        #[tokio::test]
+        async fn renames_keep_the_account_and_say_who_was_renamed() {
+            let (app, clients, _inboxes) = connected(&["alice", "bob"]).await;
+            let mut broadcasts = app.lock().await.subscribe();
+            run("name", &["ally"], "alice", &clients, &app).await;
+
+            let user = app.lock().await.get_connected_user("alice").await.unwrap();
+            let user = user.lock().await;
+            assert_eq!(
+                (user.account.as_str(), user.username.as_str()),
+                ("alice", "ally")
+            );
+            let renamed = std::iter::from_fn(|| broadcasts.try_recv().ok()).find_map(|broadcast| {
+                match broadcast.message {
+                    MessageType::Renamed { account, from, to } => Some((account, from, to)),
+                    _ => None,
+                }
+            });
+            assert_eq!(
+                renamed,
+                Some(("alice".to_string(), "alice".to_string(), "ally".to_string()))
+            );
+        }
+
+        #[tokio::test]
+        async fn topics_are_broadcast_to_the_channel_and_must_not_be_empty() {
+            let (app, clients, mut inboxes) = connected(&["alice", "bob"]).await;
+            let mut broadcasts = app.lock().await.subscribe();
+
+            run("topic", &["  "], "alice", &clients, &app).await;
+            assert!(matches!(
+                &drain(&mut inboxes[0])[..],
+                [MessageType::SystemMessage(text)] if text == "Usage: /topic <text>"
+            ));
+            assert!(broadcasts.try_recv().is_err());
+            assert_eq!(app.lock().await.topic(DEFAULT_CHANNEL), None);
+
+            run(
+                "topic",
+                &["Release", "on", "Friday"],
+                "alice",
+                &clients,
+                &app,
+            )
+            .await;
+            let topic = std::iter::from_fn(|| broadcasts.try_recv().ok()).find_map(|broadcast| {
+                match broadcast.message {
+                    MessageType::Topic(topic) => Some((broadcast.channel, topic)),
+                    _ => None,
+                }
+            });
+            assert_eq!(
+                topic,
+                Some((
+                    Some(DEFAULT_CHANNEL.to_string()),
+                    "Release on Friday".to_string()
+                ))
+            );
+            assert_eq!(
+                app.lock().await.topic(DEFAULT_CHANNEL).as_deref(),
+                Some("Release on Friday")
+            );
+        }
+
+        #[tokio::test]
+        async fn names_of_other_users_and_accounts_are_refused() {
+            let (app, clients, mut inboxes) = connected(&["alice", "bob"]).await;
+            let refused = |inbox: &mut mpsc::UnboundedReceiver<MessageType>| {
+                matches!(
+                    &drain(inbox)[..],
+                    [MessageType::CommandResult { ok: false, detail, .. }] if detail == "Name is taken"
+                )
+            };
+
+            // Someone connected, in any case, and an account that isn't online
+            run("name", &["alice"], "bob", &clients, &app).await;
+            assert!(refused(&mut inboxes[1]));
+            run("name", &["ALICE"], "bob", &clients, &app).await;
+            assert!(refused(&mut inboxes[1]));
+            run("name", &["William"], "bob", &clients, &app).await;
+            assert!(refused(&mut inboxes[1]));
+            assert_eq!(
+                app.lock()
+                    .await
+                    .find_user_id_by_name("alice")
+                    .await
+                    .as_deref(),
+                Some("alice")
+            );
+
+            // An account's own name stays free to its owner, even after a rename
+            run("name", &["ally"], "alice", &clients, &app).await;
+            drain(&mut inboxes[0]);
+            run("name", &["alice"], "bob", &clients, &app).await;
+            assert!(refused(&mut inboxes[1]));
+            run("name", &["Alice"], "alice", &clients, &app).await;
+            assert!(matches!(
+                &drain(&mut inboxes[0])[..],
+                [.., MessageType::CommandResult { ok: true, detail, .. }] if detail == "Alice"
+            ));
+        }
+
+        #[tokio::test]
+        async fn stats_are_only_for_admins() {
+            let (app, clients, mut inboxes) = connected(&["alice", "bob"]).await;
+            app.lock()
+                .await
+                .set_admins(HashSet::from(["alice".to_string()]));
+
+            run("stats", &[], "alice", &clients, &app).await;
+            assert!(matches!(
+                &drain(&mut inboxes[0])[..],
+                [MessageType::SystemMessage(text)]
+                    if text.starts_with("Server stats: 2 clients connected (2 users), 0 messages in history")
+            ));
+            run("stats", &[], "bob", &clients, &app).await;
+            assert!(matches!(
+                &drain(&mut inboxes[1])[..],
+                [MessageType::Notice { text, .. }] if text.starts_with("Permission denied")
+            ));
+        }
+
+        #[tokio::test]
+        async fn seen_reports_how_long_a_user_has_been_idle() {
+            let (app, clients, mut inboxes) = connected(&["alice", "bob"]).await;
+            let bob = app.lock().await.get_connected_user("bob").await.unwrap();
+            bob.lock().await.last_active = SystemTime::now() - Duration::from_secs(90);
+
+            run("seen", &["bob"], "alice", &clients, &app).await;
+            assert!(matches!(
+                &drain(&mut inboxes[0])[..],
+                [MessageType::SystemMessage(text)] if text == "bob was last active 1m 30s ago"
+            ));
+            run("seen", &["carol"], "alice", &clients, &app).await;
+            assert!(matches!(
+                &drain(&mut inboxes[0])[..],
+                [MessageType::SystemMessage(text)] if text == "carol is not connected"
+            ));
+        }
+
+        #[test]
+        fn format_duration_uses_the_two_largest_units() {
+            assert_eq!(format_duration(Duration::from_secs(0)), "0s");
+            assert_eq!(format_duration(Duration::from_secs(59)), "59s");
+            assert_eq!(format_duration(Duration::from_secs(60)), "1m 0s");
+            assert_eq!(format_duration(Duration::from_secs(3599)), "59m 59s");
+            assert_eq!(format_duration(Duration::from_secs(3600)), "1h 0m");
+            assert_eq!(format_duration(Duration::from_secs(90_061)), "25h 1m");
+        }
+
+        #[tokio::test]
+        async fn only_admins_mute_and_mutes_outlast_a_reconnect() {
+            let (app, clients, mut inboxes) = connected(&["alice", "bob"]).await;
+            app.lock()
+                .await
+                .set_admins(HashSet::from(["alice".to_string()]));
+
+            run("mute", &["alice"], "bob", &clients, &app).await;
+            assert!(matches!(
+                &drain(&mut inboxes[1])[..],
+                [MessageType::Notice { text, .. }] if text.starts_with("Permission denied")
+            ));
+            assert_eq!(app.lock().await.mute_remaining("alice"), None);
+
+            run("mute", &["bob", "60"], "alice", &clients, &app).await;
+            assert!(matches!(
+                &drain(&mut inboxes[0])[..],
+                [MessageType::Notice { text, .. }] if text == "Muted bob for 1m 0s."
+            ));
+            assert!(matches!(
+                &drain(&mut inboxes[1])[..],
+                [MessageType::Notice { text, .. }] if text == "You have been muted by alice for 1m 0s."
+            ));
+
+            // Direct messages are held back too, and don't bring the sender back from AFK
+            run("afk", &["lunch"], "bob", &clients, &app).await;
+            drain(&mut inboxes[1]);
+            run("DirectMessage", &["alice", "psst"], "bob", &clients, &app).await;
+            assert!(matches!(
+                &drain(&mut inboxes[1])[..],
+                [MessageType::Notice { text, .. }] if text.starts_with("You are muted")
+            ));
+            assert!(drain(&mut inboxes[0]).is_empty());
+            let bob = app.lock().await.get_connected_user("bob").await.unwrap();
+            assert_eq!(bob.lock().await.away_message.as_deref(), Some("lunch"));
+
+            // Reconnecting doesn't lift the mute
+            {
+                let mut app_lock = app.lock().await;
+                app_lock.remove_connected_user("bob").await;
+                app_lock
+                    .add_connected_user("bob".to_string(), "bob".to_string())
+                    .await
+                    .unwrap();
+            }
+            assert!(app.lock().await.mute_remaining("bob").is_some());
+
+            run("unmute", &["bob"], "alice", &clients, &app).await;
+            assert!(matches!(
+                &drain(&mut inboxes[0])[..],
+                [MessageType::Notice { text, .. }] if text == "Unmuted bob."
+            ));
+            drain(&mut inboxes[1]);
+            run("DirectMessage", &["alice", "psst"], "bob", &clients, &app).await;
+            assert!(matches!(
+                &drain(&mut inboxes[0])[..],
+                [MessageType::PrivateMessage { content, .. }] if content == "psst"
+            ));
+        }
+
+        #[tokio::test]
+        async fn only_admins_clear_the_history() {
+            let (app, clients, mut inboxes) = connected(&["alice", "bob"]).await;
+            {
+                let mut app_lock = app.lock().await;
+                app_lock.set_admins(HashSet::from(["alice".to_string()]));
+                for content in ["one", "two"] {
+                    let message = MessageType::SystemMessage(content.to_string());
+                    app_lock
+                        .add_message_to_history(DEFAULT_CHANNEL, message)
+                        .await;
+                }
+            }
+
+            run("clearhistory", &[], "bob", &clients, &app).await;
+            assert!(matches!(
+                &drain(&mut inboxes[1])[..],
+                [MessageType::Notice { text, .. }] if text.starts_with("Permission denied")
+            ));
+            let history = app.lock().await.get_message_history(DEFAULT_CHANNEL).await;
+            assert_eq!(history.len(), 2);
+
+            run("clearhistory", &[], "alice", &clients, &app).await;
+            let history = app.lock().await.get_message_history(DEFAULT_CHANNEL).await;
+            assert!(history.is_empty());
+        }
+
+        #[tokio::test]
+        async fn history_goes_only_to_the_user_who_asked() {
+            let (app, clients, mut inboxes) = connected(&["alice", "bob"]).await;
+            for content in ["one", "two"] {
+                let message = MessageType::SystemMessage(content.to_string());
+                app.lock()
+                    .await
+                    .add_message_to_history(DEFAULT_CHANNEL, message)
+                    .await;
+            }
+
+            run("history", &[], "alice", &clients, &app).await;
+            assert!(matches!(
+                &drain(&mut inboxes[0])[..],
+                [MessageType::History(history)] if history.len() == 2
+            ));
+            assert!(drain(&mut inboxes[1]).is_empty());
+        }
+
+        #[tokio::test]
+        async fn history_is_refused_again_within_the_cooldown() {
+            let (app, clients, mut inboxes) = connected(&["alice", "bob"]).await;
+            run("history", &[], "alice", &clients, &app).await;
+            drain(&mut inboxes[0]);
+
+            run("history", &[], "alice", &clients, &app).await;
+            assert!(matches!(
+                &drain(&mut inboxes[0])[..],
+                [MessageType::Notice { text, .. }]
+                    if text == "You just asked for the history; try again in 10s."
+            ));
+            // The cooldown is each user's own
+            run("history", &[], "bob", &clients, &app).await;
+            assert!(matches!(
+                &drain(&mut inboxes[1])[..],
+                [MessageType::History(_)]
+            ));
+        }
+
+        // Everything waiting in an inbox
+        fn drain(inbox: &mut mpsc::UnboundedReceiver<MessageType>) -> Vec<MessageType> {
+            std::iter::from_fn(|| inbox.try_recv().ok()).collect()
+        }
+
+        #[tokio::test]
+        async fn direct_messages_reach_the_recipient_and_echo_once() {
+            let (app, clients, mut inboxes) = connected(&["alice", "bob"]).await;
+            run("DirectMessage", &["bob", "psst"], "alice", &clients, &app).await;
+            for inbox in inboxes.iter_mut() {
+                assert!(matches!(
+                    &drain(inbox)[..],
+                    [MessageType::PrivateMessage { sender, content, .. }]
+                        if sender == "alice" && content == "psst"
+                ));
+            }
+
+            // A note to ourselves arrives once, not as both recipient and echo
+            run("DirectMessage", &["alice", "memo"], "alice", &clients, &app).await;
+            assert_eq!(drain(&mut inboxes[0]).len(), 1);
+            assert!(drain(&mut inboxes[1]).is_empty());
+        }
+    }
 }
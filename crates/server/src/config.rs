@@ -0,0 +1,298 @@
+//  This file contains the `ServerConfig` struct, which collects the server settings that can be
+//  tuned through environment variables at startup.
+use crate::chat_log::Rotation;
+use crate::filter::FilterMode;
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::time::Duration;
+
+// Upper bound on HISTORY_SIZE so a typo can't make every join replay millions of messages
+const MAX_HISTORY_SIZE: usize = 10_000;
+// Upper bound on MAX_CLIENTS
+const MAX_CLIENTS_LIMIT: usize = 100_000;
+// Upper bound on MAX_MESSAGE_BYTES
+const MAX_MESSAGE_BYTES_LIMIT: usize = 16 * 1024 * 1024;
+// Size the chat log is rotated at unless CHAT_LOG_ROTATE says otherwise
+const DEFAULT_CHAT_LOG_BYTES: u64 = 10 * 1024 * 1024;
+
+pub struct ServerConfig {
+    // Path to a file of blocked words, one per line (FILTER_FILE)
+    pub filter_file: Option<PathBuf>,
+    // Whether blocked words are masked or the message is dropped (FILTER_MODE=mask|drop)
+    pub filter_mode: FilterMode,
+    // How often each client is pinged (PING_INTERVAL_SECS)
+    pub ping_interval: Duration,
+    // How long to wait for the matching pong before disconnecting (PONG_TIMEOUT_SECS)
+    pub pong_timeout: Duration,
+    // How long a connection has to finish the handshake and log in or spectate
+    // (LOGIN_TIMEOUT_SECS)
+    pub login_timeout: Duration,
+    // How many chat messages are kept and replayed to new clients (HISTORY_SIZE)
+    pub history_size: usize,
+    // Accounts allowed to use admin commands such as /stats (ADMINS=alice,bob)
+    pub admins: HashSet<String>,
+    // Pre-shared login tokens for bots, each with the name it signs in as (AUTH_TOKENS=token:name)
+    pub auth_tokens: HashMap<String, String>,
+    // How many connections (users, spectators and those still logging in) may be open at once
+    // (MAX_CLIENTS)
+    pub max_clients: usize,
+    // Server that connections are sent to when this one is full (REDIRECT_URL=ws://host:port)
+    pub redirect_url: Option<String>,
+    // How long a user may send nothing before being disconnected (IDLE_TIMEOUT_SECS, off if unset)
+    pub idle_timeout: Option<Duration>,
+    // Largest websocket message or frame a client may send, in bytes (MAX_MESSAGE_BYTES)
+    pub max_message_size: usize,
+    // Whether large messages are deflated for clients that support it (COMPRESSION=1)
+    pub compression: bool,
+    // File every broadcast chat message is appended to (CHAT_LOG, off if unset)
+    pub chat_log: Option<PathBuf>,
+    // When the chat log starts a new file (CHAT_LOG_ROTATE=daily or a size in bytes)
+    pub chat_log_rotation: Rotation,
+    // File keeping chat message sequence numbers increasing across restarts (MESSAGE_SEQ_FILE,
+    // numbering starts again at 1 on each run if unset)
+    pub seq_file: Option<PathBuf>,
+    // File every channel's /topic is kept in so they survive a restart (TOPIC_FILE)
+    pub topic_file: Option<PathBuf>,
+}
+
+impl ServerConfig {
+    pub fn from_env() -> ServerConfig {
+        let filter_file = std::env::var("FILTER_FILE").ok().map(PathBuf::from);
+
+        let filter_mode = match std::env::var("FILTER_MODE") {
+            Ok(value) => FilterMode::parse(&value).unwrap_or_else(|| {
+                log::warn!("Unknown FILTER_MODE '{}', defaulting to mask", value);
+                FilterMode::Mask
+            }),
+            Err(_) => FilterMode::Mask,
+        };
+
+        let chat_log_rotation = match std::env::var("CHAT_LOG_ROTATE") {
+            Ok(value) => Rotation::parse(&value).unwrap_or_else(|| {
+                log::warn!(
+                    "Invalid CHAT_LOG_ROTATE '{}', rotating at {} bytes",
+                    value,
+                    DEFAULT_CHAT_LOG_BYTES
+                );
+                Rotation::Size(DEFAULT_CHAT_LOG_BYTES)
+            }),
+            Err(_) => Rotation::Size(DEFAULT_CHAT_LOG_BYTES),
+        };
+
+        let config = ServerConfig {
+            filter_file,
+            filter_mode,
+            ping_interval: env_secs("PING_INTERVAL_SECS", 30),
+            pong_timeout: env_secs("PONG_TIMEOUT_SECS", 10),
+            login_timeout: env_secs("LOGIN_TIMEOUT_SECS", 30),
+            history_size: env_count("HISTORY_SIZE", 100, MAX_HISTORY_SIZE),
+            admins: env_list("ADMINS"),
+            auth_tokens: env_pairs("AUTH_TOKENS"),
+            max_clients: env_count("MAX_CLIENTS", 100, MAX_CLIENTS_LIMIT),
+            redirect_url: env_ws_url("REDIRECT_URL"),
+            idle_timeout: env_optional_secs("IDLE_TIMEOUT_SECS"),
+            max_message_size: env_count("MAX_MESSAGE_BYTES", 64 * 1024, MAX_MESSAGE_BYTES_LIMIT),
+            compression: env_flag("COMPRESSION"),
+            chat_log: std::env::var("CHAT_LOG").ok().map(PathBuf::from),
+            chat_log_rotation,
+            seq_file: std::env::var("MESSAGE_SEQ_FILE").ok().map(PathBuf::from),
+            topic_file: std::env::var("TOPIC_FILE").ok().map(PathBuf::from),
+        };
+        config.validate();
+        config
+    }
+
+    // Warn about settings that are accepted but unlikely to behave as intended
+    fn validate(&self) {
+        if self.pong_timeout >= self.ping_interval {
+            log::warn!(
+                "Pong timeout ({}s) should be shorter than the ping interval ({}s)",
+                self.pong_timeout.as_secs(),
+                self.ping_interval.as_secs()
+            );
+        }
+    }
+}
+
+// Read a comma-separated list of names from the environment, ignoring blanks
+fn env_list(name: &str) -> HashSet<String> {
+    std::env::var(name)
+        .map(|value| {
+            value
+                .split(',')
+                .map(str::trim)
+                .filter(|entry| !entry.is_empty())
+                .map(str::to_string)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+// Read comma-separated `key:value` pairs from the environment, skipping malformed entries
+fn env_pairs(name: &str) -> HashMap<String, String> {
+    let mut pairs = HashMap::new();
+    for entry in env_list(name) {
+        match entry.split_once(':') {
+            Some((key, value)) if !key.trim().is_empty() && !value.trim().is_empty() => {
+                pairs.insert(key.trim().to_string(), value.trim().to_string());
+            }
+            _ => log::warn!("Ignoring malformed {} entry, expected key:value", name),
+        }
+    }
+    pairs
+}
+
+// Read a websocket URL from the environment, ignoring anything that isn't ws:// or wss://
+fn env_ws_url(name: &str) -> Option<String> {
+    let value = std::env::var(name).ok()?;
+    let value = value.trim();
+    let host = value
+        .strip_prefix("ws://")
+        .or_else(|| value.strip_prefix("wss://"));
+    match host {
+        Some(host) if !host.is_empty() => Some(value.to_string()),
+        _ => {
+            log::warn!("Invalid {} '{}', expected ws:// or wss://", name, value);
+            None
+        }
+    }
+}
+
+// Read a count from the environment, rejecting zero and clamping absurd values
+fn env_count(name: &str, default: usize, max: usize) -> usize {
+    match std::env::var(name) {
+        Ok(value) => match value.parse::<usize>() {
+            Ok(0) | Err(_) => {
+                log::warn!("Invalid {} '{}', using {}", name, value, default);
+                default
+            }
+            Ok(count) if count > max => {
+                log::warn!("{} {} is too large, using {}", name, count, max);
+                max
+            }
+            Ok(count) => count,
+        },
+        Err(_) => default,
+    }
+}
+
+// Read an on/off switch from the environment; off unless set to 1, true or on
+fn env_flag(name: &str) -> bool {
+    match std::env::var(name) {
+        Ok(value) => match value.to_lowercase().as_str() {
+            "1" | "true" | "on" => true,
+            "0" | "false" | "off" | "" => false,
+            _ => {
+                log::warn!("Invalid {} '{}', leaving it off", name, value);
+                false
+            }
+        },
+        Err(_) => false,
+    }
+}
+
+// Read an optional positive number of seconds from the environment; unset or 0 turns it off
+fn env_optional_secs(name: &str) -> Option<Duration> {
+    let value = std::env::var(name).ok()?;
+    match value.parse::<u64>() {
+        Ok(0) => None,
+        Ok(secs) => Some(Duration::from_secs(secs)),
+        Err(_) => {
+            log::warn!("Invalid {} '{}', leaving it off", name, value);
+            None
+        }
+    }
+}
+
+// Read a positive number of seconds from the environment, falling back to the default
+fn env_secs(name: &str, default: u64) -> Duration {
+    let secs = match std::env::var(name) {
+        Ok(value) => match value.parse::<u64>() {
+            Ok(secs) if secs > 0 => secs,
+            _ => {
+                log::warn!("Invalid {} '{}', using {}s", name, value, default);
+                default
+            }
+        },
+        Err(_) => default,
+    };
+    Duration::from_secs(secs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Each test sets its own variables, as tests share the process environment
+
+    #[test]
+    fn env_secs_falls_back_on_missing_zero_or_garbage() {
+        assert_eq!(env_secs("TEST_SECS_UNSET", 30), Duration::from_secs(30));
+        std::env::set_var("TEST_SECS_SET", "5");
+        assert_eq!(env_secs("TEST_SECS_SET", 30), Duration::from_secs(5));
+        for value in ["0", "-1", "soon"] {
+            std::env::set_var("TEST_SECS_BAD", value);
+            assert_eq!(env_secs("TEST_SECS_BAD", 30), Duration::from_secs(30));
+        }
+    }
+
+    #[test]
+    fn env_optional_secs_is_off_unless_positive() {
+        assert_eq!(env_optional_secs("TEST_OPTIONAL_UNSET"), None);
+        std::env::set_var("TEST_OPTIONAL_SECS", "90");
+        assert_eq!(
+            env_optional_secs("TEST_OPTIONAL_SECS"),
+            Some(Duration::from_secs(90))
+        );
+        std::env::set_var("TEST_OPTIONAL_ZERO", "0");
+        assert_eq!(env_optional_secs("TEST_OPTIONAL_ZERO"), None);
+    }
+
+    #[test]
+    fn env_count_rejects_zero_and_clamps() {
+        std::env::set_var("TEST_COUNT_ZERO", "0");
+        assert_eq!(env_count("TEST_COUNT_ZERO", 100, 1000), 100);
+        std::env::set_var("TEST_COUNT_BIG", "5000");
+        assert_eq!(env_count("TEST_COUNT_BIG", 100, 1000), 1000);
+        std::env::set_var("TEST_COUNT_OK", "7");
+        assert_eq!(env_count("TEST_COUNT_OK", 100, 1000), 7);
+    }
+
+    #[test]
+    fn a_history_size_of_zero_falls_back_to_the_default() {
+        std::env::set_var("TEST_HISTORY_SIZE", "0");
+        assert_eq!(env_count("TEST_HISTORY_SIZE", 100, MAX_HISTORY_SIZE), 100);
+    }
+
+    #[test]
+    fn env_lists_and_pairs_skip_blanks_and_malformed_entries() {
+        std::env::set_var("TEST_LIST", " alice, ,bob ");
+        assert_eq!(
+            env_list("TEST_LIST"),
+            HashSet::from(["alice".to_string(), "bob".to_string()])
+        );
+        std::env::set_var("TEST_PAIRS", "t1:bot,broken,:nobody,t2:other");
+        assert_eq!(
+            env_pairs("TEST_PAIRS"),
+            HashMap::from([
+                ("t1".to_string(), "bot".to_string()),
+                ("t2".to_string(), "other".to_string()),
+            ])
+        );
+    }
+
+    #[test]
+    fn env_flag_and_ws_url_accept_only_known_forms() {
+        for (value, on) in [("1", true), ("ON", true), ("off", false), ("maybe", false)] {
+            std::env::set_var("TEST_FLAG", value);
+            assert_eq!(env_flag("TEST_FLAG"), on, "{}", value);
+        }
+        std::env::set_var("TEST_WS_URL", "wss://chat.example.com");
+        assert_eq!(
+            env_ws_url("TEST_WS_URL").as_deref(),
+            Some("wss://chat.example.com")
+        );
+        std::env::set_var("TEST_WS_BAD", "http://chat.example.com");
+        assert_eq!(env_ws_url("TEST_WS_BAD"), None);
+    }
+}
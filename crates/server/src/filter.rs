@@ -0,0 +1,131 @@
+//  This file contains the optional keyword filter applied to chat messages before they are
+//  broadcast. Blocked words are matched case-insensitively on word boundaries and are either
+//  masked with asterisks or cause the whole message to be dropped, depending on the mode.
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FilterMode {
+    Mask, // Replace blocked words with asterisks and deliver the message
+    Drop, // Drop the whole message and warn the sender
+}
+
+#[derive(Debug, PartialEq)]
+pub enum FilterResult {
+    Pass(String), // Message may be delivered (possibly masked)
+    Blocked,      // Message must not be delivered
+}
+
+pub struct WordFilter {
+    blocked_words: HashSet<String>, // stored lowercase
+    mode: FilterMode,
+}
+
+impl FilterMode {
+    pub fn parse(value: &str) -> Option<FilterMode> {
+        match value.trim().to_lowercase().as_str() {
+            "mask" => Some(FilterMode::Mask),
+            "drop" => Some(FilterMode::Drop),
+            _ => None,
+        }
+    }
+}
+
+impl WordFilter {
+    pub fn new(words: Vec<String>, mode: FilterMode) -> WordFilter {
+        WordFilter {
+            blocked_words: words
+                .into_iter()
+                .map(|word| word.trim().to_lowercase())
+                .filter(|word| !word.is_empty())
+                .collect(),
+            mode,
+        }
+    }
+
+    // Load blocked words from a file with one word per line; blank lines and `#` comments are skipped
+    pub fn load(path: &Path, mode: FilterMode) -> io::Result<WordFilter> {
+        let contents = fs::read_to_string(path)?;
+        let words = contents
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(str::to_string)
+            .collect();
+        Ok(WordFilter::new(words, mode))
+    }
+
+    // Check a message against the blocked words, masking or blocking it depending on the mode
+    pub fn apply(&self, content: &str) -> FilterResult {
+        let mut filtered = String::with_capacity(content.len());
+        let mut word = String::new();
+        let mut matched = false;
+
+        // Walk the message, flushing each alphanumeric run as a single word
+        for c in content.chars() {
+            if c.is_alphanumeric() {
+                word.push(c);
+                continue;
+            }
+            matched |= self.flush_word(&mut word, &mut filtered);
+            filtered.push(c);
+        }
+        matched |= self.flush_word(&mut word, &mut filtered);
+
+        match (matched, self.mode) {
+            (true, FilterMode::Drop) => FilterResult::Blocked,
+            _ => FilterResult::Pass(filtered),
+        }
+    }
+
+    // Append the pending word to the output, masked if blocked. Returns whether it was blocked.
+    fn flush_word(&self, word: &mut String, output: &mut String) -> bool {
+        if word.is_empty() {
+            return false;
+        }
+        let blocked = self.blocked_words.contains(&word.to_lowercase());
+        if blocked {
+            output.push_str(&"*".repeat(word.chars().count()));
+        } else {
+            output.push_str(word);
+        }
+        word.clear();
+        blocked
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(mode: FilterMode) -> WordFilter {
+        WordFilter::new(vec!["Darn".to_string(), " heck ".to_string()], mode)
+    }
+
+    #[test]
+    fn mask_hides_whole_blocked_words_in_any_case() {
+        assert_eq!(
+            filter(FilterMode::Mask).apply("Oh DARN it, what the heck!"),
+            FilterResult::Pass("Oh **** it, what the ****!".to_string())
+        );
+        // Only whole words match, so longer words containing one pass untouched
+        assert_eq!(
+            filter(FilterMode::Mask).apply("darned hecks"),
+            FilterResult::Pass("darned hecks".to_string())
+        );
+    }
+
+    #[test]
+    fn drop_blocks_messages_with_a_blocked_word() {
+        assert_eq!(
+            filter(FilterMode::Drop).apply("darn."),
+            FilterResult::Blocked
+        );
+        assert_eq!(
+            filter(FilterMode::Drop).apply("all fine"),
+            FilterResult::Pass("all fine".to_string())
+        );
+    }
+}
@@ -0,0 +1,85 @@
+//  This file sets up logging. Log lines always go to stdout, and are also appended to the file
+//  named by LOG_FILE when it is set, so operators can keep logs without redirecting output.
+//  RUST_LOG picks the level, info by default.
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+
+pub fn init() {
+    let log_file = std::env::var_os("LOG_FILE");
+    builder(log_file.as_deref().map(Path::new)).init();
+}
+
+// Logger configuration writing to the console, and to `log_file` as well if one is given
+pub fn builder(log_file: Option<&Path>) -> env_logger::Builder {
+    let mut builder =
+        env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info"));
+    builder.target(env_logger::Target::Stdout);
+    if let Some(path) = log_file {
+        match OpenOptions::new().create(true).append(true).open(path) {
+            Ok(file) => {
+                builder.target(env_logger::Target::Pipe(Box::new(ConsoleAndFile { file })));
+            }
+            // Logging isn't set up yet, so this can only go to the console
+            Err(e) => eprintln!("Failed to open LOG_FILE {}: {}", path.display(), e),
+        }
+    }
+    builder
+}
+
+// Copies each log line to stdout and the log file
+struct ConsoleAndFile {
+    file: File,
+}
+
+impl Write for ConsoleAndFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // Losing the console copy shouldn't stop the file being written
+        let _ = io::stdout().write_all(buf);
+        self.file.write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let _ = io::stdout().flush();
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::Log;
+
+    #[test]
+    fn log_lines_are_appended_to_the_log_file() {
+        let path = std::env::temp_dir().join(format!("server-log-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let logger = builder(Some(&path)).build();
+        logger.log(
+            &log::Record::builder()
+                .args(format_args!("written to the file"))
+                .level(log::Level::Error)
+                .target("server")
+                .build(),
+        );
+        logger.flush();
+
+        let text = std::fs::read_to_string(&path).unwrap();
+        assert!(text.contains("written to the file"), "{:?}", text);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn unopenable_log_file_falls_back_to_the_console() {
+        // A directory can't be opened for appending
+        let logger = builder(Some(&std::env::temp_dir())).build();
+        assert!(logger.enabled(
+            &log::Metadata::builder()
+                .level(log::Level::Error)
+                .target("server")
+                .build()
+        ));
+    }
+}
@@ -7,22 +7,82 @@ use tokio::sync::broadcast;
 use tokio::sync::Mutex;
 
 mod app;
+mod chat_log;
 mod commander;
+mod config;
+mod filter;
+mod logging;
+mod sequence;
 mod websocket;
 use crate::app::App;
+use crate::chat_log::ChatLog;
+use crate::config::ServerConfig;
+use crate::filter::WordFilter;
+use crate::sequence::MessageSeq;
 use crate::websocket::websocket_task;
 #[tokio::main]
 async fn main() {
+    logging::init();
+
     // Load port from ENV or default to 8080
-    let port:u16 = std::env::var("PORT")
+    let port: u16 = std::env::var("PORT")
         .unwrap_or("8080".into())
         .parse()
         .expect("PORT must be a number");
 
     let addr = SocketAddr::from(([0, 0, 0, 0], port));
 
+    let config = Arc::new(ServerConfig::from_env());
+
     // Initialize server state
-    let app = Arc::new(Mutex::new(App::new()));
+    let mut app = App::new(config.history_size);
+    app.set_admins(config.admins.clone());
+    app.set_auth_tokens(config.auth_tokens.clone());
+
+    // Load the blocked-word filter if one is configured
+    if let Some(filter_file) = &config.filter_file {
+        match WordFilter::load(filter_file, config.filter_mode) {
+            Ok(word_filter) => app.set_word_filter(word_filter),
+            Err(e) => log::error!(
+                "Failed to load word filter from {}: {}",
+                filter_file.display(),
+                e
+            ),
+        }
+    }
+
+    // Carry message numbering on from the last run, if it is kept. Starting again from 1 would
+    // reuse numbers clients have already seen, so a file that can't be read stops the server.
+    if let Some(seq_file) = &config.seq_file {
+        match MessageSeq::load(seq_file.clone()) {
+            Ok(message_seq) => app.set_message_seq(message_seq),
+            Err(e) => {
+                log::error!(
+                    "Failed to read message numbers from {}: {}",
+                    seq_file.display(),
+                    e
+                );
+                std::process::exit(1);
+            }
+        }
+    }
+
+    // Restore the topics set before the last restart
+    if let Some(topic_file) = &config.topic_file {
+        if let Err(e) = app.load_topics(topic_file.clone()) {
+            log::error!(
+                "Failed to read the topics from {}: {}",
+                topic_file.display(),
+                e
+            );
+        }
+    }
+
+    if let Some(chat_log) = &config.chat_log {
+        app.set_chat_log(ChatLog::new(chat_log.clone(), config.chat_log_rotation));
+    }
+
+    let app = Arc::new(Mutex::new(app));
 
     // Channel to broadcast shutdown signal
     let (shutdown_tx, _) = broadcast::channel(1);
@@ -31,27 +91,34 @@ async fn main() {
     let shutdown_tx_websocket = shutdown_tx.clone();
 
     // Start the WebSocket task
-    let websocket_handle = tokio::spawn(websocket_task(addr, app.clone(), shutdown_tx_websocket));
+    let mut websocket_handle = tokio::spawn(websocket_task(
+        addr,
+        app.clone(),
+        config.clone(),
+        shutdown_tx_websocket,
+    ));
 
     // Listen for shutdown signal (Ctrl+C)
     tokio::select! {
         _ = shutdown_signal() => {
-            println!("Shutdown signal received");
+            log::info!("Shutdown signal received");
             // Notify the websocket task to shut down
             shutdown_tx.send(()).unwrap();
+            // Wait for connected clients to be told and disconnected cleanly
+            let _ = (&mut websocket_handle).await;
         }
-        _ = websocket_handle => {
+        _ = &mut websocket_handle => {
             // Handle if the WebSocket task completes first (in case of error, etc.)
-            println!("Websocket task completed");
+            log::info!("Websocket task completed");
         }
     }
 
-    println!("Server shutdown complete.");
+    log::info!("Server shutdown complete.");
 }
 
 async fn shutdown_signal() {
     tokio::signal::ctrl_c()
         .await
         .expect("Failed to listen for shutdown signal");
-    println!("Ctrl+C received, shutting down...");
+    log::info!("Ctrl+C received, shutting down...");
 }
@@ -0,0 +1,135 @@
+//  This file contains `MessageSeq`, the counter behind the sequence number every chat message is
+//  given when it is broadcast. Numbers only ever go up, so clients can order and de-duplicate
+//  messages by them. With a file to keep them in they keep going up across restarts: the counter
+//  reserves numbers a block at a time and records the end of the block before handing any out,
+//  so a restart carries on after the last reservation, skipping at most one block's unused numbers.
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+// Numbers reserved per write to the file
+const SEQ_BLOCK: u64 = 1000;
+
+pub struct MessageSeq {
+    next: u64,             // Number the next message gets
+    reserved: u64,         // End of the reserved block; the file is updated before reaching it
+    file: Option<PathBuf>, // Where the reservation is kept, if the numbers should outlive a restart
+}
+
+impl MessageSeq {
+    // A counter starting at 1 that is forgotten when the server stops
+    pub fn new() -> MessageSeq {
+        MessageSeq {
+            next: 1,
+            reserved: u64::MAX,
+            file: None,
+        }
+    }
+
+    // A counter carrying on from the reservation recorded in `file`, or starting at 1 if there
+    // isn't one yet
+    pub fn load(file: PathBuf) -> io::Result<MessageSeq> {
+        let next = match fs::read_to_string(&file) {
+            Ok(text) => text
+                .trim()
+                .parse()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => 1,
+            Err(e) => return Err(e),
+        };
+        Ok(MessageSeq {
+            next,
+            reserved: next,
+            file: Some(file),
+        })
+    }
+
+    // Hand out the next number. Numbers past the recorded block are never handed out: if the
+    // next block can't be recorded the error is returned and nothing is used up, so the next call
+    // tries again. Otherwise a restart would hand out the same numbers a second time.
+    pub fn next(&mut self) -> io::Result<u64> {
+        if self.next >= self.reserved {
+            if let Some(file) = &self.file {
+                let reserved = self.next + SEQ_BLOCK;
+                fs::write(file, reserved.to_string())
+                    .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", file.display(), e)))?;
+                self.reserved = reserved;
+            }
+        }
+        let seq = self.next;
+        self.next += 1;
+        Ok(seq)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A file of its own for each test, not there yet
+    fn scratch_file(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("message-seq-{}-{}", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn numbers_keep_going_up_across_a_restart() {
+        let path = scratch_file("restart");
+        let mut seq = MessageSeq::load(path.clone()).unwrap();
+        let before: Vec<u64> = (0..5).map(|_| seq.next().unwrap()).collect();
+        assert_eq!(before, [1, 2, 3, 4, 5]);
+        drop(seq);
+
+        let mut seq = MessageSeq::load(path.clone()).unwrap();
+        for _ in 0..5 {
+            let number = seq.next().unwrap();
+            assert!(before.iter().all(|&earlier| number > earlier));
+        }
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn a_missing_file_starts_at_one() {
+        let path = scratch_file("missing");
+        assert_eq!(MessageSeq::load(path.clone()).unwrap().next().unwrap(), 1);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn numbers_stop_when_they_cannot_be_recorded() {
+        // A file in a directory that doesn't exist can't be written
+        let dir = scratch_file("unwritable");
+        let mut seq = MessageSeq::load(dir.join("message_seq")).unwrap();
+        assert!(seq.next().is_err());
+        assert!(seq.next().is_err());
+
+        // Once it can be written, numbering starts where it would have
+        fs::create_dir(&dir).unwrap();
+        assert_eq!(seq.next().unwrap(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn a_corrupt_file_never_starts_numbering_over() {
+        let path = scratch_file("corrupt");
+        fs::write(&path, "not a number").unwrap();
+        let error = MessageSeq::load(path.clone()).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+        // Nothing is written over it either, so every later start is refused too
+        assert_eq!(fs::read_to_string(&path).unwrap(), "not a number");
+        assert!(MessageSeq::load(path.clone()).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn an_unreadable_file_is_refused() {
+        // A directory where the file should be can't be read as one
+        let path = scratch_file("unreadable");
+        fs::create_dir(&path).unwrap();
+        assert!(MessageSeq::load(path.clone()).is_err());
+        fs::remove_dir(&path).unwrap();
+    }
+}
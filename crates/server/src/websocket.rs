@@ -1,37 +1,106 @@
 //  This file contains functions related to handling WebSocket connections.
 //  It includes a function for starting the WebSocket task,
 //  handling individual connections, and processing incoming and outgoing messages.
+use flate2::{write::DeflateEncoder, Compression};
+use futures_util::stream::SplitSink;
 use futures_util::{SinkExt, StreamExt};
 use std::collections::HashMap;
+use std::io::Write;
 use std::net::SocketAddr;
 use std::sync::Arc;
-use tokio::net::TcpListener;
-use tokio::sync::{broadcast, mpsc, Mutex};
-use tokio::time::{timeout, Duration};
-use tokio_tungstenite::{accept_async, tungstenite::protocol::Message};
+use std::time::SystemTime;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{broadcast, mpsc, Mutex, OwnedSemaphorePermit, Semaphore};
+use tokio::task::JoinSet;
+use tokio::time::{timeout, timeout_at, Duration, Instant};
+use tokio_tungstenite::{
+    accept_hdr_async_with_config,
+    tungstenite::handshake::server::{Request, Response},
+    tungstenite::protocol::{frame::coding::CloseCode, CloseFrame, Message, WebSocketConfig},
+    tungstenite::Error as WsError,
+    WebSocketStream,
+};
 use uuid::Uuid; //  unique IDs for users
 
-use crate::app::{App, MessageType};
+use crate::app::{
+    validate_channel_name, App, Broadcast, MessageType, PresenceEvent, Severity, DEFAULT_CHANNEL,
+};
 use crate::commander::command_handler::handle_command;
+use crate::config::ServerConfig;
+use crate::filter::FilterResult;
+
+// How long in-flight messages get to reach a client before its socket is closed on shutdown
+const SHUTDOWN_GRACE: Duration = Duration::from_millis(500);
+// How long the server waits for all connections to wind down before giving up on them
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+// File transfers are relayed in chunks of at most 16 KiB, up to 1 MiB per file. The client splits
+// files with the same numbers (CHUNK_SIZE and MAX_FILE_SIZE in its file_transfer.rs); the two
+// crates share no code, so change both together.
+const FILE_CHUNK_SIZE: usize = 16 * 1024;
+const MAX_FILE_SIZE: usize = 1024 * 1024;
+// Base64-encoded data allowed in one chunk, and chunks allowed in one file
+const MAX_FILE_CHUNK_DATA: usize = FILE_CHUNK_SIZE.div_ceil(3) * 4;
+const MAX_FILE_CHUNKS: u32 = (MAX_FILE_SIZE / FILE_CHUNK_SIZE) as u32;
+
+// Malformed messages a client may send before it is disconnected
+const MAX_MALFORMED_MESSAGES: usize = 5;
+
+// Characters allowed in one reaction; emoji built from several code points need a few
+const MAX_REACTION_CHARS: usize = 8;
+
+// Handshake header a client sends, and we echo, to agree that messages may arrive deflated in
+// binary frames. Tungstenite has no permessage-deflate, so compression is done a message at a
+// time instead.
+const COMPRESSION_HEADER: &str = "x-message-compression";
+const COMPRESSION_DEFLATE: &str = "deflate";
+// Messages shorter than this are sent as they are; compressing them gains next to nothing
+const COMPRESSION_MIN_BYTES: usize = 1024;
+
+// Write half of a client's websocket
+type WsSink = SplitSink<WebSocketStream<TcpStream>, Message>;
 
 pub async fn websocket_task(
     addr: SocketAddr,
     app: Arc<Mutex<App>>,
+    config: Arc<ServerConfig>,
     shutdown: broadcast::Sender<()>,
 ) {
     let listener = TcpListener::bind(addr).await.expect("Failed to bind");
-    println!("Server listening on {}", addr.to_string());
+    log::info!("Server listening on {}", addr);
+    serve(listener, app, config, shutdown).await;
+}
 
+// Accept connections on `listener` until shutdown, then wait for them to close
+async fn serve(
+    listener: TcpListener,
+    app: Arc<Mutex<App>>,
+    config: Arc<ServerConfig>,
+    shutdown: broadcast::Sender<()>,
+) {
+    // Senders of the connections that have logged in or chosen to spectate. A connection is only
+    // added, and only subscribes to broadcasts, once it gets past the login step, so nobody still
+    // logging in is sent chat.
     let clients = Arc::new(Mutex::new(HashMap::<
         String,
         mpsc::UnboundedSender<MessageType>,
     >::new()));
 
-    // Channel for sending messages to the batch processor
-    let (batch_tx, batch_rx) = mpsc::channel(100);
+    // Announce users as they come and go, starting before anyone can connect
+    let presence_rx = app.lock().await.subscribe_presence();
+    tokio::spawn(presence_task(presence_rx, app.clone()));
 
-    // Spawn the batch processing task
-    tokio::spawn(batch_send_task(clients.clone(), batch_rx));
+    // Sweep out idle users when a timeout is configured; each user's connection is told directly
+    if let Some(idle_timeout) = config.idle_timeout {
+        tokio::spawn(idle_sweep_task(app.clone(), idle_timeout));
+    }
+
+    // Track connection tasks so shutdown can wait for them to say goodbye
+    let mut connections = JoinSet::new();
+    // One slot per connection allowed at once, taken as it is accepted, so connections still
+    // logging in count towards MAX_CLIENTS too
+    let slots = Arc::new(Semaphore::new(config.max_clients));
 
     loop {
         let mut shutdown_subscriber = shutdown.subscribe();
@@ -39,27 +108,82 @@ pub async fn websocket_task(
             Ok((stream, _)) = listener.accept() => {
                 let clients = clients.clone();
                 let app = app.clone();
+                let config = config.clone();
                 let shutdown_subscriber = shutdown.subscribe();
+                let slot = slots.clone().try_acquire_owned().ok();
 
-                tokio::spawn(handle_connection(stream, clients, app, shutdown_subscriber, batch_tx.clone())); // Pass the batch_tx to handle_connection
+                connections.spawn(handle_connection(stream, slot, clients, app, config, shutdown_subscriber));
             }
 
+            // Reap finished connections so the set doesn't grow forever
+            Some(_) = connections.join_next() => {}
+
             _ = shutdown_subscriber.recv() => {
-                println!("Shutting down WebSocket task.");
+                log::info!("Shutting down WebSocket task.");
                 break;
             }
         }
     }
+
+    // Give every connection a chance to notify its client and close cleanly
+    let drained = timeout(SHUTDOWN_TIMEOUT, async {
+        while connections.join_next().await.is_some() {}
+    })
+    .await;
+    if drained.is_err() {
+        log::warn!("Timed out waiting for connections to close.");
+    }
 }
 
+// `slot` is the connection's place under MAX_CLIENTS, held until it ends, or None if the server
+// was already full when it was accepted. A connection that hasn't logged in or chosen to spectate
+// by the login deadline is closed, so sockets left idle can't keep holding slots.
 async fn handle_connection(
     stream: tokio::net::TcpStream,
+    slot: Option<OwnedSemaphorePermit>,
     clients: Arc<Mutex<HashMap<String, mpsc::UnboundedSender<MessageType>>>>,
     app: Arc<Mutex<App>>,
+    config: Arc<ServerConfig>,
     mut shutdown: broadcast::Receiver<()>,
-    batch_tx: mpsc::Sender<MessageType>,
 ) {
-    let ws_stream = accept_async(stream).await.expect("Error during handshake");
+    let login_deadline = Instant::now() + config.login_timeout;
+    // Refuse oversized messages while reading them, before they are buffered in full
+    let ws_config = WebSocketConfig {
+        max_message_size: Some(config.max_message_size),
+        max_frame_size: Some(config.max_message_size),
+        ..Default::default()
+    };
+    // Compress for clients that offer to take deflated messages, if it is turned on
+    let mut compress = false;
+    // The error type is tungstenite's, which we never return
+    #[allow(clippy::result_large_err)]
+    let negotiate = |request: &Request, mut response: Response| {
+        let offered = request
+            .headers()
+            .get(COMPRESSION_HEADER)
+            .is_some_and(|value| value == COMPRESSION_DEFLATE);
+        if offered && config.compression {
+            compress = true;
+            response.headers_mut().insert(
+                COMPRESSION_HEADER,
+                COMPRESSION_DEFLATE.parse().expect("valid header value"),
+            );
+        }
+        Ok(response)
+    };
+    // Anything that isn't a WebSocket client, such as a port scanner, is simply dropped
+    let handshake = accept_hdr_async_with_config(stream, negotiate, Some(ws_config));
+    let ws_stream = match timeout_at(login_deadline, handshake).await {
+        Ok(Ok(ws_stream)) => ws_stream,
+        Ok(Err(e)) => {
+            log::warn!("WebSocket handshake failed: {}", e);
+            return;
+        }
+        Err(_) => {
+            log::warn!("WebSocket handshake timed out");
+            return;
+        }
+    };
 
     let client_id = Uuid::new_v4().to_string();
     let (tx_original, mut rx) = mpsc::unbounded_channel();
@@ -67,94 +191,211 @@ async fn handle_connection(
     let (outgoing, mut incoming) = ws_stream.split();
     let outgoing = Arc::new(Mutex::new(outgoing));
 
-    // Step 1: Authenticate the user before proceeding
+    // Turn away new connections politely once the server is full, pointing them at another
+    // server if one is configured
+    if slot.is_none() {
+        let reply = match &config.redirect_url {
+            Some(url) => {
+                log::warn!(
+                    "Server full, redirecting connection {} to {}",
+                    client_id,
+                    url
+                );
+                MessageType::Redirect(url.clone())
+            }
+            None => {
+                log::warn!("Server full, rejecting connection {}", client_id);
+                MessageType::notice(Severity::Error, "Server full, try later")
+            }
+        };
+        send_now(&outgoing, &reply).await;
+        let close_frame = CloseFrame {
+            code: CloseCode::Again,
+            reason: "Server full".into(),
+        };
+        let _ = outgoing
+            .lock()
+            .await
+            .send(Message::Close(Some(close_frame)))
+            .await;
+        return;
+    }
+
+    // Greet the connection straight away with the name it goes by until it logs in, which is
+    // the only one a spectator gets; logging in sends another Welcome with the account's name
+    let guest = guest_name(&client_id);
+    let greeting = MessageType::Welcome {
+        your_color: App::user_color(&guest).to_string(),
+        your_name: guest,
+    };
+    send_now(&outgoing, &greeting).await;
+
+    // Step 1: Authenticate the user (or accept a read-only spectator) before proceeding
     let mut authenticated = false;
+    let mut spectator = false;
     let mut login_attempts = 0; // Add counter for failed login attempts
     let max_attempts = 5;
 
-    while let Some(result) = incoming.next().await {
+    loop {
+        let result = match timeout_at(login_deadline, incoming.next()).await {
+            Ok(Some(result)) => result,
+            Ok(None) => break,
+            Err(_) => {
+                log::warn!("Closing connection {}: not logged in in time", client_id);
+                let notice = MessageType::notice(Severity::Error, "Login timed out");
+                send_now(&outgoing, &notice).await;
+                let close_frame = CloseFrame {
+                    code: CloseCode::Policy,
+                    reason: "Login timed out".into(),
+                };
+                let _ = outgoing
+                    .lock()
+                    .await
+                    .send(Message::Close(Some(close_frame)))
+                    .await;
+                return;
+            }
+        };
+        if let Err(WsError::Capacity(err)) = &result {
+            close_too_big(&outgoing, &client_id, err, config.max_message_size).await;
+            return;
+        }
         if let Ok(Message::Text(text)) = result {
-            if let Ok(MessageType::SystemMessage(auth_msg)) =
-                serde_json::from_str::<MessageType>(&text)
+            let parsed = serde_json::from_str::<MessageType>(&text);
+
+            // Spectators skip authentication and only ever receive broadcasts
+            if let Ok(MessageType::Spectate) = parsed {
+                spectator = true;
+                let spectate_message = MessageType::SystemMessage(
+                    "Spectating: this connection is read-only".to_string(),
+                );
+                tx_original.send(spectate_message).unwrap();
+                clients
+                    .lock()
+                    .await
+                    .insert(client_id.clone(), tx_original.clone());
+                log::info!("Spectator connected: {}", client_id);
+                break;
+            }
+
+            if let Ok(MessageType::Auth {
+                username,
+                password,
+                token,
+            }) = parsed
             {
-                // Expecting a username and password in the form "username:password"
-                let creds: Vec<&str> = auth_msg.split(':').collect();
-                if creds.len() == 2 {
-                    let username = creds[0];
-                    let password = creds[1];
-
-                    // Authenticate user
-                    let is_authenticated = app.lock().await.authenticate_user(username, password);
-                    if is_authenticated {
-                        authenticated = true;
-
-                        let result = app
-                            .lock()
-                            .await
-                            .add_connected_user(client_id.clone(), creds[0].to_string())
-                            .await;
-                        match result {
-                            Ok(_) => {
-                                // User added successfully
-                            }
-                            Err(err_msg) => {
-                                // Send error message to client and close connection.
-                                println!("{}", err_msg);
-                                return;
-                            }
-                        }
-                        let success_message =
-                            MessageType::SystemMessage("Authentication successful".to_string());
-                        tx_original.send(success_message).unwrap();
-                        clients
-                            .lock()
-                            .await
-                            .insert(client_id.clone(), tx_original.clone());
-
-                        break; // User is authenticated, proceed
-                    } else {
-                        login_attempts += 1; // Increment failed attempts
-                        let remaining_attempts = max_attempts - login_attempts;
-                        let fail_message = MessageType::SystemMessage(format!(
-                            "Authentication failed. {} attempts remaining.",
-                            remaining_attempts
-                        ));
-                        tx_original.send(fail_message).unwrap();
-                        println!("Authentication Failed.");
+                // Authenticate user, by password or else by a token that gives its own name
+                let authenticated_as = {
+                    let app_lock = app.lock().await;
+                    match token {
+                        Some(token) => app_lock.authenticate_token(&token),
+                        None => app_lock
+                            .authenticate_user(&username, &password)
+                            .then_some(username),
+                    }
+                };
+                if let Some(username) = authenticated_as {
+                    let result = app
+                        .lock()
+                        .await
+                        .add_connected_user(client_id.clone(), username.clone())
+                        .await;
+                    if let Err(err_msg) = result {
+                        // Tell the client why before closing the connection
+                        log::warn!("{}", err_msg);
+                        send_now(&outgoing, &MessageType::SystemMessage(err_msg)).await;
+                        return;
+                    }
+                    authenticated = true;
 
-                        // If the user exceeds max attempts, close the connection
-                        if login_attempts >= max_attempts {
-                            let max_attempt_message = MessageType::SystemMessage(
-                                "Max login attempts reached. Closing connection.".to_string(),
-                            );
-                            tx_original.send(max_attempt_message).unwrap();
-                            println!(
-                                "Max login attempts reached, closing connection for {}",
-                                client_id
-                            );
-                            return; // Exit function, closing the connection
-                        }
+                    let success_message =
+                        MessageType::SystemMessage("Authentication successful".to_string());
+                    tx_original.send(success_message).unwrap();
+                    // Tell the client who it is, so it doesn't have to guess its own name
+                    let welcome_message = MessageType::Welcome {
+                        your_color: App::user_color(&username).to_string(),
+                        your_name: username,
+                    };
+                    tx_original.send(welcome_message).unwrap();
+                    clients
+                        .lock()
+                        .await
+                        .insert(client_id.clone(), tx_original.clone());
+
+                    break; // User is authenticated, proceed
+                } else {
+                    login_attempts += 1; // Increment failed attempts
+                    let remaining_attempts = max_attempts - login_attempts;
+                    // The send task doesn't exist yet, so reply on the socket directly
+                    let fail_message = MessageType::SystemMessage(format!(
+                        "Authentication failed. {} attempts remaining.",
+                        remaining_attempts
+                    ));
+                    send_now(&outgoing, &fail_message).await;
+                    log::info!("Authentication Failed.");
+
+                    // If the user exceeds max attempts, close the connection
+                    if login_attempts >= max_attempts {
+                        let max_attempt_message = MessageType::SystemMessage(
+                            "Max login attempts reached. Closing connection.".to_string(),
+                        );
+                        send_now(&outgoing, &max_attempt_message).await;
+                        log::warn!(
+                            "Max login attempts reached, closing connection for {}",
+                            client_id
+                        );
+                        return; // Exit function, closing the connection
                     }
                 }
             }
         }
     }
 
-    if !authenticated {
-        println!("Authentication failed, closing connection");
+    // Nothing below runs for a connection that never logged in, so it never subscribes to
+    // broadcasts; until here it has only been sent replies about its own login
+    if !authenticated && !spectator {
+        log::info!("Authentication failed, closing connection");
         return;
     }
 
-    // Send message history to the new client from the App
-    let history = app.lock().await.get_message_history().await;
+    // Snapshot the history and join the shared broadcast channel under one App lock. Messages
+    // are broadcast while holding that lock, so each one is either in the snapshot or waiting
+    // in `broadcast_rx`, never both or neither.
+    // The user list is snapshotted too, as the presence task may have announced this user
+    // before we subscribed.
+    let (mut broadcast_rx, history, topic, roster) = {
+        let app_lock = app.lock().await;
+        let history = app_lock.get_message_history(DEFAULT_CHANNEL).await;
+        let history = match app_lock.get_connected_user(&client_id).await {
+            Some(user) => user.lock().await.unblocked(history),
+            None => history,
+        };
+        (
+            app_lock.subscribe(),
+            history,
+            app_lock.topic(DEFAULT_CHANNEL),
+            app_lock.roster().await,
+        )
+    };
+
+    // Queue who is online, the history and the current topic; live messages wait in
+    // `broadcast_rx` until the send task has flushed them
+    tx_original
+        .send(MessageType::UserCount(roster.len()))
+        .unwrap();
+    tx_original.send(MessageType::Roster(roster)).unwrap();
     for message in history {
         tx_original.send(message.clone()).unwrap();
     }
+    if let Some(topic) = topic {
+        tx_original.send(MessageType::Topic(topic)).unwrap();
+    }
 
     let disconnect_handled = Arc::new(Mutex::new(false));
 
-    // Create a channel for ping task to detect pong responses
-    let (pong_tx, mut pong_rx) = mpsc::channel(1); // Use bounded channel to ensure order
+    // When the client last answered a ping; the receive task updates it and the ping task
+    // checks it, so a pong that comes early or twice can't be lost or block anything
+    let last_pong = Arc::new(Mutex::new(Instant::now()));
 
     // Ping task
     let ping_task = {
@@ -163,10 +404,12 @@ async fn handle_connection(
         let clients_clone = Arc::clone(&clients);
         let app_clone = Arc::clone(&app);
         let disconnect_handled_clone = Arc::clone(&disconnect_handled);
+        let last_pong = Arc::clone(&last_pong);
+        let ping_interval = config.ping_interval;
+        let pong_timeout = config.pong_timeout; // How long to wait for Pong
 
         tokio::spawn(async move {
-            let mut ping_interval = tokio::time::interval(Duration::from_secs(30)); // Ping every 30 seconds
-            let pong_timeout = Duration::from_secs(10); // Wait 10 seconds for Pong
+            let mut ping_interval = tokio::time::interval(ping_interval);
 
             loop {
                 ping_interval.tick().await;
@@ -175,37 +418,45 @@ async fn handle_connection(
 
                 // Send Ping message to the client
                 if outgoing_lock.send(Message::Ping(vec![])).await.is_err() {
-                    println!("Error sending Ping to client: {}", client_id_clone);
+                    log::warn!("Error sending Ping to client: {}", client_id_clone);
                     break;
                 }
                 drop(outgoing_lock); // Release the lock before waiting for Pong
+                let ping_sent = Instant::now();
 
-                // Wait for Pong within the timeout period
-                match timeout(pong_timeout, pong_rx.recv()).await {
-                    Ok(Some(())) => {
-                        println!("Pong received from client: {}", client_id_clone);
-                    }
-                    _ => {
-                        println!(
-                            "Client {} is unresponsive. Disconnecting...",
-                            client_id_clone
-                        );
-                        handle_disconnection(
-                            disconnect_handled_clone,
-                            &client_id_clone,
-                            &clients_clone,
-                            Arc::clone(&app_clone),
-                        )
-                        .await;
-                        break;
-                    }
+                // The client is alive if a pong came in since this ping went out
+                tokio::time::sleep(pong_timeout).await;
+                if *last_pong.lock().await >= ping_sent {
+                    log::debug!("Pong received from client: {}", client_id_clone);
+                } else {
+                    log::warn!(
+                        "Client {} is unresponsive. Disconnecting...",
+                        client_id_clone
+                    );
+                    handle_disconnection(
+                        disconnect_handled_clone,
+                        &client_id_clone,
+                        &clients_clone,
+                        Arc::clone(&app_clone),
+                    )
+                    .await;
+                    break;
                 }
             }
         })
     };
 
     // Task for sending messages
+    // Users can switch channels; spectators always follow the default one
+    let user_info = app.lock().await.get_connected_user(&client_id).await;
+    // Spectators aren't users, so they are never swept as idle
+    let idle = match &user_info {
+        Some(user) => Some(user.lock().await.idle.clone()),
+        None => None,
+    };
+
     let send_task = {
+        let user_info = user_info.clone();
         let outgoing_clone = Arc::clone(&outgoing);
         let client_id_clone = client_id.clone();
         let clients_clone = Arc::clone(&clients);
@@ -213,14 +464,65 @@ async fn handle_connection(
         let disconnect_handled_clone = Arc::clone(&disconnect_handled);
 
         tokio::spawn(async move {
-            while let Some(message) = rx.recv().await {
+            loop {
+                // Targeted messages arrive on this client's own channel, everything else
+                // on the shared broadcast channel. Queued targeted messages go first, so the
+                // history replay is finished before any live message is sent.
+                let message = tokio::select! {
+                    biased;
+                    message = rx.recv() => match message {
+                        Some(message) => message,
+                        None => break,
+                    },
+                    received = broadcast_rx.recv() => match received {
+                        Ok(Broadcast { origin, .. })
+                            if origin.as_deref() == Some(client_id_clone.as_str()) =>
+                        {
+                            continue; // Our own message, already shown by the sender
+                        }
+                        Ok(Broadcast {
+                            message,
+                            channel,
+                            number,
+                            ..
+                        }) => {
+                            // Channel messages only reach connections that were in that channel
+                            // when they were sent. Ones sent before joining it came with the
+                            // history replay instead.
+                            if let Some(channel) = channel {
+                                let (current, joined_at) = match &user_info {
+                                    Some(user) => {
+                                        let user_lock = user.lock().await;
+                                        (user_lock.channel.clone(), user_lock.joined_at)
+                                    }
+                                    None => (DEFAULT_CHANNEL.to_string(), 0),
+                                };
+                                if current != channel || number < joined_at {
+                                    continue;
+                                }
+                            }
+                            // Nothing from users we blocked is relayed to us
+                            if let Some(user) = &user_info {
+                                if user.lock().await.blocks(&message) {
+                                    continue;
+                                }
+                            }
+                            message
+                        }
+                        Err(RecvError::Lagged(skipped)) => {
+                            log::warn!("Client {} lagged by {} messages", client_id_clone, skipped);
+                            MessageType::SystemMessage(format!(
+                                "You missed {} messages because your connection fell behind.",
+                                skipped
+                            ))
+                        }
+                        Err(RecvError::Closed) => break,
+                    },
+                };
                 let serialized_message = serde_json::to_string(&message).unwrap();
+                let frame = outgoing_frame(serialized_message, compress);
                 let mut outgoing_lock = outgoing_clone.lock().await;
-                if outgoing_lock
-                    .send(Message::Text(serialized_message))
-                    .await
-                    .is_err()
-                {
+                if outgoing_lock.send(frame).await.is_err() {
                     break;
                 }
             }
@@ -236,46 +538,96 @@ async fn handle_connection(
 
     // Task for receiving messages and detecting Pong responses
     let recv_task = {
+        let outgoing_clone = Arc::clone(&outgoing);
+        let max_message_size = config.max_message_size;
         let client_id_clone = client_id.clone();
         let clients_clone = Arc::clone(&clients);
         let app_clone = Arc::clone(&app);
         let disconnect_handled_clone = Arc::clone(&disconnect_handled);
-        let pong_tx_clone = pong_tx.clone(); // Clone pong sender for use in task
+        let last_pong = Arc::clone(&last_pong);
 
         tokio::spawn(async move {
+            let mut malformed_messages = 0;
             while let Some(result) = incoming.next().await {
                 match result {
                     Ok(Message::Text(text)) => match serde_json::from_str::<MessageType>(&text) {
+                        Ok(
+                            MessageType::ChatMessage { .. }
+                            | MessageType::Command { .. }
+                            | MessageType::FileChunk { .. }
+                            | MessageType::Reaction { .. }
+                            | MessageType::Join(_)
+                            | MessageType::Leave(_),
+                        ) if spectator => {
+                            // Spectators are read-only
+                            let rejection = MessageType::notice(
+                                Severity::Error,
+                                "Spectators cannot send messages or commands.",
+                            );
+                            if let Some(sender) = clients_clone.lock().await.get(&client_id_clone) {
+                                let _ = sender.send(rejection);
+                            }
+                        }
                         Ok(message) => {
+                            // Anything the user sends keeps them from being swept as idle, though
+                            // only chat and DMs count as activity for /seen
+                            if let Some(user) = &user_info {
+                                user.lock().await.last_received = SystemTime::now();
+                            }
                             handle_incoming_message(
                                 message,
                                 &client_id_clone,
                                 &clients_clone,
-                                &app_clone, // Pass batch_tx to handle_incoming_message
+                                &app_clone,
                             )
                             .await;
                         }
                         Err(_) => {
-                            println!("Invalid message format from client: {}", client_id_clone);
+                            log::warn!("Invalid message format from client: {}", client_id_clone);
+                            malformed_messages += 1;
+
+                            // Tell the client, and cut off clients that keep sending garbage
+                            let too_many = malformed_messages >= MAX_MALFORMED_MESSAGES;
+                            let reply = if too_many {
+                                "Too many malformed messages. Closing connection."
+                            } else {
+                                "Malformed message rejected"
+                            };
+                            if let Some(sender) = clients_clone.lock().await.get(&client_id_clone) {
+                                let _ = sender.send(MessageType::notice(Severity::Error, reply));
+                            }
+                            if too_many {
+                                log::warn!(
+                                    "Disconnecting client {} after {} malformed messages",
+                                    client_id_clone,
+                                    malformed_messages
+                                );
+                                break;
+                            }
                         }
                     },
                     Ok(Message::Ping(_)) => {
-                        println!("Received Ping from client {}", client_id_clone);
+                        log::debug!("Received Ping from client {}", client_id_clone);
                     }
                     Ok(Message::Pong(_)) => {
-                        // Notify ping task that Pong was received
-                        let _ = pong_tx_clone.send(()).await;
+                        *last_pong.lock().await = Instant::now();
                     }
                     Ok(_) => {
-                        println!(
+                        log::debug!(
                             "Received other type of message from client {}",
                             client_id_clone
                         );
                     }
+                    Err(WsError::Capacity(err)) => {
+                        close_too_big(&outgoing_clone, &client_id_clone, &err, max_message_size)
+                            .await;
+                        break;
+                    }
                     Err(e) => {
-                        println!(
+                        log::warn!(
                             "Error receiving message from client {}: {}",
-                            client_id_clone, e
+                            client_id_clone,
+                            e
                         );
                         break;
                     }
@@ -291,69 +643,224 @@ async fn handle_connection(
         })
     };
 
+    let (mut send_task, mut recv_task, mut ping_task) = (send_task, recv_task, ping_task);
     tokio::select! {
-        _ = send_task => {},
-        _ = recv_task => {},
-        _ = ping_task => {},
+        _ = &mut send_task => {},
+        _ = &mut recv_task => {},
+        _ = &mut ping_task => {},
         _ = shutdown.recv() => {
-            println!("Shutdown received for client: {}", client_id);
+            log::info!("Shutdown received for client: {}", client_id);
+            let notice = MessageType::notice(Severity::Warn, "Server shutting down");
+            let close_frame = CloseFrame {
+                code: CloseCode::Away,
+                reason: "Server shutting down".into(),
+            };
+            say_goodbye(&tx_original, &outgoing, notice, close_frame).await;
+        }
+        _ = async {
+            match &idle {
+                Some(idle) => idle.notified().await,
+                None => std::future::pending().await,
+            }
+        } => {
+            log::info!("Disconnecting idle client {}", client_id);
+            let notice = MessageType::SystemMessage(
+                "You were disconnected for being idle too long.".to_string(),
+            );
+            let close_frame = CloseFrame {
+                code: CloseCode::Policy,
+                reason: "Idle timeout".into(),
+            };
+            say_goodbye(&tx_original, &outgoing, notice, close_frame).await;
         }
     }
 
+    // Stop whatever is still running for this connection, so the socket is dropped and closed
+    // rather than kept open by the ping task. The send task gets to flush what is already
+    // queued, such as the reason for disconnecting, once nothing else can be queued.
+    recv_task.abort();
+    ping_task.abort();
     handle_disconnection(disconnect_handled, &client_id, &clients, app).await;
+    drop(tx_original);
+    // A send task that already ended was polled to completion above and can't be awaited again
+    if !send_task.is_finished() && timeout(SHUTDOWN_GRACE, &mut send_task).await.is_err() {
+        send_task.abort();
+    }
+}
+
+// Name a connection goes by before it logs in, e.g. "Guest-1a2b3c4d"
+fn guest_name(client_id: &str) -> String {
+    format!("Guest-{}", &client_id[..8])
+}
+
+// Frame a serialized message for the socket: deflated in a binary frame when the client agreed
+// to compression and the message is big enough to benefit, as text otherwise
+fn outgoing_frame(text: String, compress: bool) -> Message {
+    if !compress || text.len() < COMPRESSION_MIN_BYTES {
+        return Message::Text(text);
+    }
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::fast());
+    // Writing into a Vec can't fail
+    encoder
+        .write_all(text.as_bytes())
+        .expect("compressing into memory");
+    Message::Binary(encoder.finish().expect("compressing into memory"))
+}
+
+// Write a message straight to the socket, for replies sent before the send task is running
+async fn send_now(outgoing: &Arc<Mutex<WsSink>>, message: &MessageType) {
+    let serialized_message = serde_json::to_string(message).unwrap();
+    let _ = outgoing
+        .lock()
+        .await
+        .send(Message::Text(serialized_message))
+        .await;
 }
 
 async fn handle_incoming_message(
     message: MessageType,
     client_id: &str,
     clients: &Arc<Mutex<HashMap<String, mpsc::UnboundedSender<MessageType>>>>,
-    app: &Arc<Mutex<App>>, // Batch processing sender
+    app: &Arc<Mutex<App>>,
 ) {
     match message {
-        MessageType::ChatMessage { sender: _, content } => {
-            // Fetch username from App
-            let client_name = app
-                .lock()
-                .await
-                .get_connected_user(client_id)
-                .await
-                .unwrap()
-                .lock()
-                .await
-                .username
-                .clone();
+        MessageType::ChatMessage {
+            content,
+            client_msg_id,
+            reply_to,
+            ..
+        } => {
+            // Take the App lock once for filtering, storing and broadcasting, and release it
+            // before touching the clients map
+            let mut back_from_afk = false;
+            let reply = {
+                let mut app_lock = app.lock().await;
 
-            let broadcast_message = MessageType::ChatMessage {
-                sender: client_name.clone(),
-                content: content.clone(),
-            };
+                // Apply the blocked-word filter before anything is stored or broadcast
+                match app_lock.apply_word_filter(&content) {
+                    FilterResult::Blocked => Some(MessageType::notice(
+                        Severity::Warn,
+                        "Your message was blocked by the server's word filter.",
+                    )),
+                    FilterResult::Pass(content) => {
+                        // The user may have disconnected while this message was in flight
+                        let user_info = match app_lock.get_connected_user(client_id).await {
+                            Some(user_info) => user_info,
+                            None => return,
+                        };
+                        let (client_name, account, channel, seq) = {
+                            let mut user_lock = user_info.lock().await;
+                            // Muted users' messages go nowhere until the mute runs out
+                            if let Some(remaining) = app_lock.mute_remaining(&user_lock.account) {
+                                drop(user_lock);
+                                drop(app_lock);
+                                if let Some(sender) = clients.lock().await.get(client_id) {
+                                    let _ = sender.send(MessageType::notice(
+                                        Severity::Warn,
+                                        format!(
+                                            "You are muted for another {}s; your message was not sent.",
+                                            remaining.as_secs().max(1)
+                                        ),
+                                    ));
+                                }
+                                return;
+                            }
+                            // A resend of a message that was already broadcast, e.g. retried
+                            // because the connection dropped before the Ack came, is only
+                            // acknowledged again so nobody sees it twice
+                            let seen = client_msg_id
+                                .as_deref()
+                                .and_then(|id| app_lock.seen_message(&user_lock.account, id));
+                            if let (Some((message_id, seq)), Some(id)) =
+                                (seen, client_msg_id.clone())
+                            {
+                                drop(user_lock);
+                                drop(app_lock);
+                                log::debug!("Dropping resent message {} from {}", id, client_id);
+                                if let Some(sender) = clients.lock().await.get(client_id) {
+                                    let _ = sender.send(MessageType::Ack {
+                                        id,
+                                        message_id: Some(message_id),
+                                        seq: Some(seq),
+                                    });
+                                }
+                                return;
+                            }
+                            // A number that couldn't be recorded could be handed out again after a
+                            // restart, so the message is refused rather than sent without one
+                            let seq = match app_lock.next_message_seq() {
+                                Ok(seq) => seq,
+                                Err(e) => {
+                                    drop(user_lock);
+                                    drop(app_lock);
+                                    log::error!("Failed to record message numbers: {}", e);
+                                    if let Some(sender) = clients.lock().await.get(client_id) {
+                                        let _ = sender.send(MessageType::notice(
+                                            Severity::Error,
+                                            "The server could not number your message; it was not sent.",
+                                        ));
+                                    }
+                                    return;
+                                }
+                            };
+                            // Sending a message brings the user back from AFK
+                            back_from_afk = user_lock.mark_active();
+                            user_lock.message_count += 1;
+                            (
+                                user_lock.username.clone(),
+                                user_lock.account.clone(),
+                                user_lock.channel.clone(),
+                                seq,
+                            )
+                        };
 
-            // Add message to history in App
-            app.lock()
-                .await
-                .add_message_to_history(broadcast_message.clone())
-                .await;
-
-            // Broadcast to all clients
-            let mut clients_lock = clients.lock().await;
-            let disconnected_clients: Vec<String> = clients_lock
-                .iter()
-                .filter_map(|(id, tx)| {
-                    if id == client_id {
-                        None // prevent sending back to yourself
-                    } else if tx.send(broadcast_message.clone()).is_err() {
-                        // If sending fails, mark this client as disconnected
-                        Some(id.clone())
-                    } else {
-                        None
+                        app_lock.log_chat(&channel, &account, &client_name, &content);
+
+                        let message_id = Uuid::new_v4().to_string();
+                        if let Some(id) = &client_msg_id {
+                            app_lock.remember_message(
+                                &account,
+                                id.clone(),
+                                message_id.clone(),
+                                seq,
+                            );
+                        }
+                        let broadcast_message = MessageType::ChatMessage {
+                            sender: client_name,
+                            content,
+                            sender_id: account,
+                            client_msg_id: None,
+                            message_id: Some(message_id.clone()),
+                            reply_to,
+                            seq: Some(seq),
+                        };
+
+                        // Add message to the channel's history and broadcast it to everyone else there
+                        app_lock
+                            .add_message_to_history(&channel, broadcast_message.clone())
+                            .await;
+                        app_lock.record_message_processed();
+                        app_lock.broadcast_to_channel(&channel, broadcast_message, Some(client_id));
+
+                        // Acknowledge the message now that it is stored and queued for everyone
+                        client_msg_id.map(|id| MessageType::Ack {
+                            id,
+                            message_id: Some(message_id),
+                            seq: Some(seq),
+                        })
                     }
-                })
-                .collect();
+                }
+            };
 
-            // Remove disconnected clients
-            for id in disconnected_clients {
-                clients_lock.remove(&id);
-                println!("Removed disconnected client: {}", id);
+            if let Some(sender) = clients.lock().await.get(client_id) {
+                if let Some(reply) = reply {
+                    let _ = sender.send(reply);
+                }
+                if back_from_afk {
+                    let _ = sender.send(MessageType::SystemMessage(
+                        "You are no longer AFK.".to_string(),
+                    ));
+                }
             }
         }
 
@@ -362,38 +869,222 @@ async fn handle_incoming_message(
         }
 
         MessageType::SystemMessage(system_message) => {
-            println!("System message: {}", system_message);
+            log::info!("System message: {}", system_message);
         }
-    }
-}
 
-async fn batch_send_task(
-    clients: Arc<Mutex<HashMap<String, mpsc::UnboundedSender<MessageType>>>>,
-    mut rx: mpsc::Receiver<MessageType>, // Receives messages for broadcasting
-) {
-    let mut message_batch = Vec::new(); // Buffer to store batched messages
-    let batch_interval = Duration::from_millis(100); // Define batch interval (100ms)
+        MessageType::FileChunk {
+            id,
+            name,
+            seq,
+            total,
+            data,
+            ..
+        } => {
+            // Refuse chunks that break the size limits rather than relaying them
+            if total == 0
+                || total > MAX_FILE_CHUNKS
+                || seq >= total
+                || data.len() > MAX_FILE_CHUNK_DATA
+            {
+                if seq == 0 {
+                    if let Some(sender) = clients.lock().await.get(client_id) {
+                        let _ = sender.send(MessageType::notice(
+                            Severity::Error,
+                            format!("File {} was rejected: files are limited to 1 MiB.", name),
+                        ));
+                    }
+                }
+                return;
+            }
 
-    loop {
-        tokio::select! {
-            // Collect messages to batch
-            Some(message) = rx.recv() => {
-                message_batch.push(message);
-            }
-            _ = tokio::time::sleep(batch_interval) => {
-                if !message_batch.is_empty() {
-                    // If there are messages, broadcast them to all clients
-                    let clients = clients.lock().await;
-                    for (_, tx) in clients.iter() {
-                        for message in &message_batch {
-                            if tx.send(message.clone()).is_err() {
-                                println!("Failed to send message to a client.");
-                            }
-                        }
+            let app_lock = app.lock().await;
+            let (sender_name, account, channel) = match app_lock.get_connected_user(client_id).await
+            {
+                Some(user) => {
+                    let user_lock = user.lock().await;
+                    (
+                        user_lock.username.clone(),
+                        user_lock.account.clone(),
+                        user_lock.channel.clone(),
+                    )
+                }
+                None => return,
+            };
+            // Muted users can't share files either; they're told once per file
+            if app_lock.mute_remaining(&account).is_some() {
+                drop(app_lock);
+                if seq == 0 {
+                    if let Some(sender) = clients.lock().await.get(client_id) {
+                        let _ = sender.send(MessageType::notice(
+                            Severity::Warn,
+                            format!("You are muted; file {} was not sent.", name),
+                        ));
+                    }
+                }
+                return;
+            }
+            let relayed = MessageType::FileChunk {
+                id,
+                name,
+                seq,
+                total,
+                data,
+                sender: sender_name,
+            };
+
+            // Files are relayed to everyone else in the channel but not kept in history
+            app_lock.broadcast_to_channel(&channel, relayed, Some(client_id));
+        }
+
+        MessageType::Reaction {
+            message_id, emoji, ..
+        } => {
+            let reply = |text: &str| {
+                let notice = MessageType::notice(Severity::Warn, text);
+                async move {
+                    if let Some(sender) = clients.lock().await.get(client_id) {
+                        let _ = sender.send(notice);
                     }
-                    message_batch.clear(); // Clear the batch after sending
                 }
+            };
+            if !valid_reaction(&emoji) {
+                return reply("Reactions must be a single emoji.").await;
+            }
+            let mut app_lock = app.lock().await;
+            let (account, channel) = match app_lock.get_connected_user(client_id).await {
+                Some(user) => {
+                    let user_lock = user.lock().await;
+                    (user_lock.account.clone(), user_lock.channel.clone())
+                }
+                None => return,
+            };
+            if app_lock.mute_remaining(&account).is_some() {
+                drop(app_lock);
+                return reply("You are muted; your reaction was not sent.").await;
+            }
+            if !app_lock.toggle_reaction(&channel, &message_id, &account, &emoji) {
+                drop(app_lock);
+                return reply("That message is too old to react to.").await;
             }
+            // Everyone in the channel applies the toggle, the reacting user included
+            let reaction = MessageType::Reaction {
+                message_id,
+                user: account,
+                emoji,
+            };
+            app_lock.broadcast_to_channel(&channel, reaction, None);
+        }
+
+        MessageType::Join(channel) => {
+            join_channel(&channel, client_id, clients, app).await;
+        }
+
+        MessageType::Leave(channel) => {
+            // Leaving the channel you're in takes you back to the default one
+            let current = match app.lock().await.get_connected_user(client_id).await {
+                Some(user) => user.lock().await.channel.clone(),
+                None => return,
+            };
+            let channel = channel.trim_start_matches('#').to_lowercase();
+            if channel == current && current != DEFAULT_CHANNEL {
+                join_channel(DEFAULT_CHANNEL, client_id, clients, app).await;
+            } else if let Some(sender) = clients.lock().await.get(client_id) {
+                let _ = sender.send(MessageType::SystemMessage(format!(
+                    "You can't leave #{} (you are in #{}).",
+                    channel, current
+                )));
+            }
+        }
+
+        MessageType::Auth { .. } => {
+            // Credentials are only accepted during the login handshake
+            if let Some(sender) = clients.lock().await.get(client_id) {
+                let _ = sender.send(MessageType::SystemMessage(
+                    "You are already authenticated.".to_string(),
+                ));
+            }
+        }
+
+        _ => {
+            // Server-to-client message types are never valid from a client
+            log::warn!(
+                "Ignoring unexpected message type from client: {}",
+                client_id
+            );
+        }
+    }
+}
+
+// A reaction is one short run of non-alphanumeric, printable characters, e.g. "👍" or "❤️"
+fn valid_reaction(emoji: &str) -> bool {
+    let count = emoji.chars().count();
+    (1..=MAX_REACTION_CHARS).contains(&count)
+        && !emoji
+            .chars()
+            .any(|c| c.is_alphanumeric() || c.is_whitespace() || c.is_control())
+}
+
+// Move a user into a channel, announcing it in the old and new channels and replaying the new
+// channel's history to them
+async fn join_channel(
+    channel: &str,
+    client_id: &str,
+    clients: &Arc<Mutex<HashMap<String, mpsc::UnboundedSender<MessageType>>>>,
+    app: &Arc<Mutex<App>>,
+) {
+    let reply = |message: MessageType| async {
+        if let Some(sender) = clients.lock().await.get(client_id) {
+            let _ = sender.send(message);
+        }
+    };
+    let channel = match validate_channel_name(channel) {
+        Ok(channel) => channel,
+        Err(reason) => return reply(MessageType::SystemMessage(reason)).await,
+    };
+
+    let app_lock = app.lock().await;
+    let user = match app_lock.get_connected_user(client_id).await {
+        Some(user) => user,
+        None => return,
+    };
+    let (name, previous) = {
+        let mut user_lock = user.lock().await;
+        let previous = std::mem::replace(&mut user_lock.channel, channel.clone());
+        if previous != channel {
+            user_lock.joined_at = app_lock.next_broadcast();
+        }
+        (user_lock.username.clone(), previous)
+    };
+    if previous == channel {
+        drop(app_lock);
+        return reply(MessageType::SystemMessage(format!(
+            "You are already in #{}.",
+            channel
+        )))
+        .await;
+    }
+    app_lock.broadcast_to_channel(
+        &previous,
+        MessageType::SystemMessage(format!("{} left #{}", name, previous)),
+        Some(client_id),
+    );
+    app_lock.broadcast_to_channel(
+        &channel,
+        MessageType::SystemMessage(format!("{} joined #{}", name, channel)),
+        Some(client_id),
+    );
+    // Queue the replay and the channel's topic before releasing the App lock, so no live
+    // message in the new channel can overtake them
+    let history = app_lock.get_message_history(&channel).await;
+    let history = user.lock().await.unblocked(history);
+    let topic = app_lock.topic(&channel);
+    if let Some(sender) = clients.lock().await.get(client_id) {
+        let _ = sender.send(MessageType::Join(channel));
+        for message in history {
+            let _ = sender.send(message);
+        }
+        if let Some(topic) = topic {
+            let _ = sender.send(MessageType::Topic(topic));
         }
     }
 }
@@ -410,30 +1101,1254 @@ async fn handle_disconnection(
     }
     *handled = true;
 
-    // Log and remove the user from the app
-    let client_name = app
+    // Remove the client from the list of connected clients
+    clients.lock().await.remove(client_id);
+
+    // Remove the user from the app, which lets the presence task tell everyone; spectators
+    // were never added as users
+    match app.lock().await.remove_connected_user(client_id).await {
+        Some(user_info) => log::info!("{} has disconnected", user_info.lock().await.username),
+        None => log::info!("Spectator {} has disconnected", client_id),
+    }
+}
+
+// Send a last message and then close the socket, giving the send task time to flush first
+async fn say_goodbye(
+    tx: &mpsc::UnboundedSender<MessageType>,
+    outgoing: &Arc<Mutex<WsSink>>,
+    notice: MessageType,
+    close_frame: CloseFrame<'static>,
+) {
+    let _ = tx.send(notice);
+    tokio::time::sleep(SHUTDOWN_GRACE).await;
+    let _ = outgoing
         .lock()
         .await
-        .get_connected_user(client_id)
-        .await
-        .unwrap()
+        .send(Message::Close(Some(close_frame)))
+        .await;
+}
+
+// Tell a client its message was over the size limit and close the connection, which can't
+// carry on once a message has been cut off part way
+async fn close_too_big(
+    outgoing: &Arc<Mutex<WsSink>>,
+    client_id: &str,
+    err: &impl std::fmt::Display,
+    max_message_size: usize,
+) {
+    log::warn!(
+        "Disconnecting client {} for an oversized message: {}",
+        client_id,
+        err
+    );
+    let notice = MessageType::notice(
+        Severity::Error,
+        format!(
+            "Message too big: the limit is {} bytes. Closing connection.",
+            max_message_size
+        ),
+    );
+    send_now(outgoing, &notice).await;
+    let close_frame = CloseFrame {
+        code: CloseCode::Size,
+        reason: "Message too big".into(),
+    };
+    let _ = outgoing
         .lock()
         .await
-        .username
-        .clone();
+        .send(Message::Close(Some(close_frame)))
+        .await;
+}
 
-    app.lock().await.remove_connected_user(client_id).await;
+// Flag users who haven't sent anything for `idle_timeout`, checking a few times per timeout
+async fn idle_sweep_task(app: Arc<Mutex<App>>, idle_timeout: Duration) {
+    let mut sweep = tokio::time::interval((idle_timeout / 4).max(Duration::from_secs(1)));
+    loop {
+        sweep.tick().await;
+        let app_lock = app.lock().await;
+        for client_id in app_lock.idle_users(idle_timeout).await {
+            if let Some(user) = app_lock.get_connected_user(&client_id).await {
+                user.lock().await.idle.notify_one();
+            }
+        }
+    }
+}
 
-    // Remove the client from the list of connected clients
-    clients.lock().await.remove(client_id);
+// Tell everyone else when a user leaves, and keep everyone's user count and list current as users
+// come and go
+async fn presence_task(mut presence_rx: broadcast::Receiver<PresenceEvent>, app: Arc<Mutex<App>>) {
+    loop {
+        let event = match presence_rx.recv().await {
+            Ok(event) => event,
+            // Missed events only lose their notices; the user list is rebuilt in full each time
+            Err(RecvError::Lagged(skipped)) => {
+                log::warn!("Presence task lagged by {} events", skipped);
+                continue;
+            }
+            Err(RecvError::Closed) => break,
+        };
+        let app_lock = app.lock().await;
+        if let PresenceEvent::Left { user_id, username } = event {
+            app_lock.broadcast(
+                MessageType::notice(Severity::Warn, format!("{} has disconnected.", username)),
+                Some(&user_id),
+            );
+        }
+        app_lock.broadcast_presence().await;
+    }
+}
 
-    // Broadcast that the user has disconnected
-    let disconnect_message =
-        MessageType::SystemMessage(format!("{} has disconnected.", client_name));
-    for (_, tx) in clients.lock().await.iter() {
-        // Send the message to all connected clients
-        let _ = tx.send(disconnect_message.clone());
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::BROADCAST_CAPACITY;
+    use crate::chat_log::ChatLog;
+    use crate::filter::{FilterMode, WordFilter};
+    use crate::sequence::MessageSeq;
+    use tokio_tungstenite::{connect_async, MaybeTlsStream};
+
+    type Client = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+    // How long a test waits for a message it expects
+    const RECV_TIMEOUT: Duration = Duration::from_secs(2);
+
+    // Run a server on a free local port with the default settings changed by `configure`
+    async fn start(
+        configure: impl FnOnce(&mut ServerConfig),
+    ) -> (SocketAddr, Arc<Mutex<App>>, broadcast::Sender<()>) {
+        let mut config = ServerConfig::from_env();
+        configure(&mut config);
+        let mut app = App::new(config.history_size);
+        app.set_admins(config.admins.clone());
+        app.set_auth_tokens(config.auth_tokens.clone());
+        if let Some(chat_log) = &config.chat_log {
+            app.set_chat_log(ChatLog::new(chat_log.clone(), config.chat_log_rotation));
+        }
+        if let Some(filter_file) = &config.filter_file {
+            app.set_word_filter(WordFilter::load(filter_file, config.filter_mode).unwrap());
+        }
+        if let Some(seq_file) = &config.seq_file {
+            app.set_message_seq(MessageSeq::load(seq_file.clone()).unwrap());
+        }
+        let app = Arc::new(Mutex::new(app));
+        let (shutdown, _) = broadcast::channel(1);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(
+            listener,
+            app.clone(),
+            Arc::new(config),
+            shutdown.clone(),
+        ));
+        (addr, app, shutdown)
+    }
+
+    async fn connect(addr: SocketAddr) -> Client {
+        connect_async(format!("ws://{}", addr)).await.unwrap().0
+    }
+
+    async fn send(client: &mut Client, message: MessageType) {
+        let text = serde_json::to_string(&message).unwrap();
+        client.send(Message::Text(text)).await.unwrap();
     }
 
-    println!("{} has disconnected", client_name);
+    // The next message from the server, or None once it closes or goes quiet
+    async fn recv(client: &mut Client) -> Option<MessageType> {
+        loop {
+            match timeout(RECV_TIMEOUT, client.next()).await {
+                Ok(Some(Ok(Message::Text(text)))) => return serde_json::from_str(&text).ok(),
+                Ok(Some(Ok(Message::Close(_)))) | Ok(Some(Err(_))) | Ok(None) | Err(_) => {
+                    return None
+                }
+                Ok(Some(Ok(_))) => continue,
+            }
+        }
+    }
+
+    // Read messages until one matches, returning it
+    async fn recv_until(
+        client: &mut Client,
+        mut wanted: impl FnMut(&MessageType) -> bool,
+    ) -> Option<MessageType> {
+        while let Some(message) = recv(client).await {
+            if wanted(&message) {
+                return Some(message);
+            }
+        }
+        None
+    }
+
+    // Connect and log in, reading up to the welcome
+    async fn login(addr: SocketAddr, username: &str, password: &str) -> Client {
+        let mut client = connect(addr).await;
+        send(
+            &mut client,
+            MessageType::Auth {
+                username: username.to_string(),
+                password: password.to_string(),
+                token: None,
+            },
+        )
+        .await;
+        recv_until(&mut client, |m| welcomed_as(m, username))
+            .await
+            .expect("no welcome");
+        client
+    }
+
+    fn welcomed_as(message: &MessageType, name: &str) -> bool {
+        matches!(message, MessageType::Welcome { your_name, .. } if your_name == name)
+    }
+
+    fn chat(content: &str) -> MessageType {
+        MessageType::ChatMessage {
+            sender: String::new(),
+            content: content.to_string(),
+            sender_id: String::new(),
+            client_msg_id: None,
+            message_id: None,
+            reply_to: None,
+            seq: None,
+        }
+    }
+
+    fn is_chat(message: &MessageType, text: &str) -> bool {
+        matches!(message, MessageType::ChatMessage { content, .. } if content == text)
+    }
+
+    #[tokio::test]
+    async fn an_account_cannot_log_in_twice_after_a_rename() {
+        let (addr, app, _shutdown) = start(|_| {}).await;
+        let mut first = login(addr, "user1", "password1").await;
+        send(
+            &mut first,
+            MessageType::Command {
+                name: "name".to_string(),
+                args: vec!["someone".to_string()],
+            },
+        )
+        .await;
+        assert!(recv_until(&mut first, |m| matches!(
+            m,
+            MessageType::CommandResult { ok: true, .. }
+        ))
+        .await
+        .is_some());
+
+        let mut second = connect(addr).await;
+        send(
+            &mut second,
+            MessageType::Auth {
+                username: "user1".to_string(),
+                password: "password1".to_string(),
+                token: None,
+            },
+        )
+        .await;
+        assert!(matches!(
+            recv_until(&mut second, |m| matches!(m, MessageType::SystemMessage(_))).await,
+            Some(MessageType::SystemMessage(text)) if text.contains("already connected")
+        ));
+        assert!(recv(&mut second).await.is_none());
+        assert_eq!(app.lock().await.get_connected_users().await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn shutdown_warns_clients_and_closes_their_sockets() {
+        let (addr, app, shutdown) = start(|_| {}).await;
+        let mut client = login(addr, "user1", "password1").await;
+        shutdown.send(()).unwrap();
+
+        assert!(recv_until(&mut client, |m| matches!(
+            m,
+            MessageType::Notice { text, .. } if text == "Server shutting down"
+        ))
+        .await
+        .is_some());
+        let close = loop {
+            match timeout(RECV_TIMEOUT, client.next()).await {
+                Ok(Some(Ok(Message::Close(frame)))) => break frame,
+                Ok(Some(Ok(_))) => continue,
+                other => panic!("expected a close frame, got {:?}", other),
+            }
+        };
+        assert_eq!(close.map(|frame| frame.code), Some(CloseCode::Away));
+        // The user is removed as the connection winds down
+        timeout(RECV_TIMEOUT, async {
+            while app.lock().await.connected_user_count() > 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("user still connected");
+    }
+
+    #[tokio::test]
+    async fn connections_are_welcomed_before_and_after_logging_in() {
+        let (addr, _app, _shutdown) = start(|_| {}).await;
+        let mut client = connect(addr).await;
+        let guest = match recv(&mut client).await {
+            Some(MessageType::Welcome { your_name, .. }) => your_name,
+            other => panic!("expected a welcome, got {:?}", other),
+        };
+        assert!(guest.starts_with("Guest-"));
+
+        // Logging in renames the connection after its account
+        send(
+            &mut client,
+            MessageType::Auth {
+                username: "user1".to_string(),
+                password: "password1".to_string(),
+                token: None,
+            },
+        )
+        .await;
+        assert!(matches!(
+            recv_until(&mut client, |m| matches!(m, MessageType::Welcome { .. })).await,
+            Some(MessageType::Welcome { your_name, your_color })
+                if your_name == "user1" && your_color == App::user_color("user1")
+        ));
+    }
+
+    #[tokio::test]
+    async fn user_count_follows_users_joining_and_leaving() {
+        let (addr, _app, _shutdown) = start(|_| {}).await;
+        let mut alice = login(addr, "user1", "password1").await;
+        let bob = login(addr, "user2", "password2").await;
+        assert!(
+            recv_until(&mut alice, |m| matches!(m, MessageType::UserCount(2)))
+                .await
+                .is_some()
+        );
+        drop(bob);
+        assert!(
+            recv_until(&mut alice, |m| matches!(m, MessageType::UserCount(1)))
+                .await
+                .is_some()
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_joins_leaves_and_messages_do_not_deadlock() {
+        const WORKERS: usize = 8;
+        const ROUNDS: usize = 5;
+        const MESSAGES: usize = 3;
+        // A name may only be connected once, so every session signs in with its own token
+        let token = |worker: usize, round: usize| format!("token-{}-{}", worker, round);
+        let (addr, app, _shutdown) = start(|config| {
+            for worker in 0..WORKERS {
+                for round in 0..ROUNDS {
+                    config
+                        .auth_tokens
+                        .insert(token(worker, round), format!("bot{}x{}", worker, round));
+                }
+            }
+        })
+        .await;
+        let _watcher = login(addr, "user1", "password1").await;
+
+        let workers: Vec<_> = (0..WORKERS)
+            .map(|worker| {
+                tokio::spawn(async move {
+                    for round in 0..ROUNDS {
+                        let mut client = connect(addr).await;
+                        send(
+                            &mut client,
+                            MessageType::Auth {
+                                username: String::new(),
+                                password: String::new(),
+                                token: Some(token(worker, round)),
+                            },
+                        )
+                        .await;
+                        let name = format!("bot{}x{}", worker, round);
+                        recv_until(&mut client, |m| welcomed_as(m, &name))
+                            .await
+                            .expect("no welcome");
+                        for i in 0..MESSAGES {
+                            send(&mut client, chat(&format!("{} {} {}", worker, round, i))).await;
+                        }
+                        // Wait for the server to close too, which it does after handling
+                        // everything sent before the close
+                        client.close(None).await.unwrap();
+                        while recv(&mut client).await.is_some() {}
+                    }
+                })
+            })
+            .collect();
+
+        let settled = timeout(Duration::from_secs(20), async {
+            for worker in workers {
+                worker.await.unwrap();
+            }
+            // Everyone but the watcher has gone and every message was handled
+            loop {
+                {
+                    let app_lock = app.lock().await;
+                    if app_lock.connected_user_count() == 1
+                        && app_lock.messages_processed() == (WORKERS * ROUNDS * MESSAGES) as u64
+                    {
+                        break;
+                    }
+                }
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await;
+        assert!(settled.is_ok(), "server stalled under concurrent activity");
+    }
+
+    #[tokio::test]
+    async fn connections_past_max_clients_are_refused_even_before_login() {
+        let (addr, _app, _shutdown) = start(|config| config.max_clients = 2).await;
+        let first = connect(addr).await;
+        let _second = connect(addr).await;
+
+        // Neither has logged in, yet both hold a slot
+        let mut third = connect(addr).await;
+        assert!(matches!(
+            recv(&mut third).await,
+            Some(MessageType::Notice { text, .. }) if text == "Server full, try later"
+        ));
+        assert!(recv(&mut third).await.is_none());
+
+        // A slot is free again once a connection ends
+        drop(first);
+        let mut retry = None;
+        for _ in 0..50 {
+            let mut client = connect(addr).await;
+            send(
+                &mut client,
+                MessageType::Auth {
+                    username: "user1".to_string(),
+                    password: "password1".to_string(),
+                    token: None,
+                },
+            )
+            .await;
+            if recv_until(&mut client, |m| welcomed_as(m, "user1"))
+                .await
+                .is_some()
+            {
+                retry = Some(client);
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert!(retry.is_some());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn a_hundred_clients_get_every_broadcast_with_bounded_queueing() {
+        const CLIENTS: usize = 100;
+        const MESSAGES: usize = 300;
+        let (addr, app, _shutdown) = start(|config| config.max_clients = CLIENTS + 2).await;
+
+        // Spectators that read everything, and one that never reads at all
+        async fn spectate(addr: SocketAddr) -> Client {
+            let mut client = connect(addr).await;
+            send(&mut client, MessageType::Spectate).await;
+            recv_until(
+                &mut client,
+                |m| matches!(m, MessageType::SystemMessage(text) if text.starts_with("Spectating")),
+            )
+            .await
+            .expect("not spectating");
+            client
+        }
+        let mut readers = Vec::new();
+        for _ in 0..CLIENTS {
+            let mut reader = spectate(addr).await;
+            readers.push(tokio::spawn(async move {
+                let mut received = 0;
+                while received < MESSAGES {
+                    match recv(&mut reader).await {
+                        Some(MessageType::ChatMessage { .. }) => received += 1,
+                        Some(_) => {}
+                        None => break,
+                    }
+                }
+                received
+            }));
+        }
+        let _stalled = spectate(addr).await;
+
+        let mut sender = login(addr, "user1", "password1").await;
+        for i in 0..MESSAGES {
+            send(&mut sender, chat(&format!("message {}", i))).await;
+        }
+
+        // Everyone gets everything without the server stalling, while the messages are queued
+        // once for all of them, up to the channel's capacity however far behind anyone falls
+        let delivered = timeout(Duration::from_secs(20), async {
+            let mut counts = Vec::new();
+            for reader in readers {
+                counts.push(reader.await.unwrap());
+            }
+            counts
+        })
+        .await
+        .expect("broadcast stalled");
+        assert!(delivered.iter().all(|&count| count == MESSAGES));
+        assert!(app.lock().await.queued_broadcasts() <= BROADCAST_CAPACITY);
+    }
+
+    #[tokio::test]
+    async fn connections_that_never_log_in_give_their_slot_back() {
+        let (addr, _app, _shutdown) = start(|config| {
+            config.max_clients = 1;
+            config.login_timeout = Duration::from_secs(1);
+        })
+        .await;
+        let mut idle = connect(addr).await;
+        assert!(recv_until(&mut idle, |m| matches!(
+            m,
+            MessageType::Notice { text, .. } if text == "Login timed out"
+        ))
+        .await
+        .is_some());
+        assert!(recv(&mut idle).await.is_none());
+
+        // The slot it held is free for someone who does log in
+        let mut client = connect(addr).await;
+        send(
+            &mut client,
+            MessageType::Auth {
+                username: "user1".to_string(),
+                password: "password1".to_string(),
+                token: None,
+            },
+        )
+        .await;
+        assert!(recv_until(&mut client, |m| welcomed_as(m, "user1"))
+            .await
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn malformed_messages_are_rejected_until_the_limit() {
+        let (addr, _app, _shutdown) = start(|_| {}).await;
+        let mut client = login(addr, "user1", "password1").await;
+        let is_notice = |wanted: &'static str| move |m: &MessageType| matches!(m, MessageType::Notice { text, .. } if text == wanted);
+        for _ in 1..MAX_MALFORMED_MESSAGES {
+            client
+                .send(Message::Text("{not json".to_string()))
+                .await
+                .unwrap();
+            assert!(
+                recv_until(&mut client, is_notice("Malformed message rejected"))
+                    .await
+                    .is_some()
+            );
+        }
+        client
+            .send(Message::Text("{not json".to_string()))
+            .await
+            .unwrap();
+        assert!(recv_until(
+            &mut client,
+            is_notice("Too many malformed messages. Closing connection.")
+        )
+        .await
+        .is_some());
+        // The connection is closed after that
+        assert!(recv(&mut client).await.is_none());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn history_is_replayed_before_live_messages_without_gaps() {
+        let (addr, _app, _shutdown) = start(|config| config.history_size = 100).await;
+        let mut alice = login(addr, "user1", "password1").await;
+        for i in 0..5 {
+            send(&mut alice, chat(&format!("old {}", i))).await;
+        }
+        // Keep chatting while several others join at once, so live messages race each replay
+        let chatter = tokio::spawn(async move {
+            for i in 0..50 {
+                send(&mut alice, chat(&format!("live {}", i))).await;
+            }
+            alice
+        });
+        let joiners: Vec<_> = [
+            ("user2", "password2"),
+            ("William", "password"),
+            ("PickleRick", "password"),
+        ]
+        .into_iter()
+        .map(|(name, password)| {
+            tokio::spawn(async move {
+                let mut client = login(addr, name, password).await;
+                let mut seqs = Vec::new();
+                while let Some(message) = recv(&mut client).await {
+                    if let MessageType::ChatMessage {
+                        seq: Some(seq),
+                        content,
+                        ..
+                    } = message
+                    {
+                        seqs.push(seq);
+                        if content == "live 49" {
+                            break;
+                        }
+                    }
+                }
+                seqs
+            })
+        })
+        .collect();
+        for joiner in joiners {
+            // Every message arrives once, in the order it was sent, wherever the replay ended
+            assert_eq!(joiner.await.unwrap(), (1..=55).collect::<Vec<u64>>());
+        }
+        let _alice = chatter.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn newly_connected_clients_are_sent_the_current_topic() {
+        let (addr, _app, _shutdown) = start(|_| {}).await;
+        let mut alice = login(addr, "user1", "password1").await;
+        send(
+            &mut alice,
+            MessageType::Command {
+                name: "topic".to_string(),
+                args: vec!["Release on Friday".to_string()],
+            },
+        )
+        .await;
+        assert!(
+            recv_until(&mut alice, |m| matches!(m, MessageType::Topic(_)))
+                .await
+                .is_some()
+        );
+
+        let mut bob = login(addr, "user2", "password2").await;
+        assert!(matches!(
+            recv_until(&mut bob, |m| matches!(m, MessageType::Topic(_))).await,
+            Some(MessageType::Topic(topic)) if topic == "Release on Friday"
+        ));
+    }
+
+    #[tokio::test]
+    async fn spectators_receive_chat_but_cannot_send() {
+        let (addr, _app, _shutdown) = start(|_| {}).await;
+        let mut spectator = connect(addr).await;
+        send(&mut spectator, MessageType::Spectate).await;
+        assert!(matches!(
+            recv(&mut spectator).await,
+            Some(MessageType::Welcome { your_name, .. }) if your_name.starts_with("Guest-")
+        ));
+        assert!(matches!(
+            recv(&mut spectator).await,
+            Some(MessageType::SystemMessage(text)) if text.starts_with("Spectating")
+        ));
+
+        send(&mut spectator, chat("let me in")).await;
+        assert!(recv_until(&mut spectator, |m| matches!(
+            m,
+            MessageType::Notice { text, .. } if text.starts_with("Spectators cannot")
+        ))
+        .await
+        .is_some());
+
+        let mut user = login(addr, "user1", "password1").await;
+        send(&mut user, chat("hello")).await;
+        assert!(recv_until(&mut spectator, |m| is_chat(m, "hello"))
+            .await
+            .is_some());
+        // Spectators aren't users, so they don't count as online
+        assert!(
+            recv_until(&mut user, |m| matches!(m, MessageType::UserCount(1)))
+                .await
+                .is_some()
+        );
+    }
+
+    #[tokio::test]
+    async fn channels_keep_their_messages_apart_and_replay_each_once_on_join() {
+        let (addr, _app, _shutdown) = start(|_| {}).await;
+        let mut gamer = login(addr, "William", "password").await;
+        let mut observer = login(addr, "PickleRick", "password").await;
+        let mut general = login(addr, "user1", "password1").await;
+        let mut joiner = login(addr, "user2", "password2").await;
+        for client in [&mut gamer, &mut observer] {
+            send(client, MessageType::Join("games".to_string())).await;
+            assert!(recv_until(
+                client,
+                |m| matches!(m, MessageType::Join(c) if c == "games")
+            )
+            .await
+            .is_some());
+        }
+
+        send(&mut gamer, chat("early")).await;
+        assert!(recv_until(&mut observer, |m| is_chat(m, "early"))
+            .await
+            .is_some());
+        send(&mut general, chat("general only")).await;
+        assert!(recv_until(&mut joiner, |m| is_chat(m, "general only"))
+            .await
+            .is_some());
+
+        // The message sent before joining comes once, with the history, and not again live
+        send(&mut joiner, MessageType::Join("games".to_string())).await;
+        assert!(recv_until(
+            &mut joiner,
+            |m| matches!(m, MessageType::Join(c) if c == "games")
+        )
+        .await
+        .is_some());
+        send(&mut gamer, chat("late")).await;
+        let mut seen = Vec::new();
+        while let Some(message) = recv(&mut joiner).await {
+            let late = is_chat(&message, "late");
+            seen.push(message);
+            if late {
+                break;
+            }
+        }
+        assert_eq!(seen.iter().filter(|m| is_chat(m, "early")).count(), 1);
+        assert!(seen.iter().any(|m| is_chat(m, "late")));
+
+        // Nothing said in the default channel reached the other one
+        send(&mut general, chat("still general")).await;
+        send(&mut gamer, chat("last")).await;
+        let mut seen = Vec::new();
+        while let Some(message) = recv(&mut observer).await {
+            let last = is_chat(&message, "last");
+            seen.push(message);
+            if last {
+                break;
+            }
+        }
+        assert!(seen.iter().any(|m| is_chat(m, "last")));
+        assert!(!seen
+            .iter()
+            .any(|m| is_chat(m, "general only") || is_chat(m, "still general")));
+    }
+
+    #[tokio::test]
+    async fn idle_users_are_each_disconnected() {
+        let (addr, app, _shutdown) = start(|config| {
+            config.idle_timeout = Some(Duration::from_secs(1));
+        })
+        .await;
+        let mut clients = [
+            login(addr, "user1", "password1").await,
+            login(addr, "user2", "password2").await,
+            login(addr, "William", "password").await,
+        ];
+        for client in clients.iter_mut() {
+            assert!(recv_until(client, |m| matches!(
+                m,
+                MessageType::SystemMessage(text) if text.contains("idle too long")
+            ))
+            .await
+            .is_some());
+            // The server closes the connection after saying why
+            while recv(client).await.is_some() {}
+        }
+        assert_eq!(app.lock().await.connected_user_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn answering_pings_keeps_a_connection_open() {
+        let (addr, app, _shutdown) = start(|config| {
+            config.ping_interval = Duration::from_millis(200);
+            config.pong_timeout = Duration::from_millis(100);
+        })
+        .await;
+        let mut reader = login(addr, "user1", "password1").await;
+        let silent = login(addr, "user2", "password2").await;
+
+        // Reading lets the client answer each ping; the silent one never does
+        let _ = timeout(Duration::from_millis(1500), async {
+            while recv(&mut reader).await.is_some() {}
+        })
+        .await;
+        let app_lock = app.lock().await;
+        assert_eq!(app_lock.connected_user_count(), 1);
+        assert!(app_lock.find_user_id_by_name("user1").await.is_some());
+        drop((app_lock, silent));
+    }
+
+    #[tokio::test]
+    async fn clients_that_ignore_pings_are_dropped_after_the_configured_timeout() {
+        let (addr, app, _shutdown) = start(|config| {
+            config.ping_interval = Duration::from_secs(1);
+            config.pong_timeout = Duration::from_secs(1);
+        })
+        .await;
+        // Never reading means never answering a ping
+        let silent = login(addr, "user1", "password1").await;
+
+        // Kept while the first ping's timeout runs, then dropped once it is up
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert_eq!(app.lock().await.connected_user_count(), 1);
+        timeout(Duration::from_secs(2), async {
+            while app.lock().await.connected_user_count() > 0 {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await
+        .expect("unresponsive client still connected");
+        drop(silent);
+    }
+
+    #[tokio::test]
+    async fn failed_handshakes_end_the_connection_quietly() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let stranger = tokio::spawn(async move {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            stream
+                .write_all(b"GET / HTTP/1.1\r\nHost: nowhere\r\n\r\n")
+                .await
+                .unwrap();
+            let mut reply = Vec::new();
+            let _ = stream.read_to_end(&mut reply).await;
+        });
+        let (stream, _) = listener.accept().await.unwrap();
+
+        // Returns rather than panicking, and the stranger is hung up on
+        let (shutdown, _) = broadcast::channel(1);
+        let connection = handle_connection(
+            stream,
+            None,
+            Arc::new(Mutex::new(HashMap::new())),
+            Arc::new(Mutex::new(App::new(10))),
+            Arc::new(ServerConfig::from_env()),
+            shutdown.subscribe(),
+        );
+        timeout(RECV_TIMEOUT, connection).await.unwrap();
+        timeout(RECV_TIMEOUT, stranger).await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn messages_over_the_size_limit_close_the_connection() {
+        let (addr, _app, _shutdown) = start(|config| config.max_message_size = 1024).await;
+        let mut client = login(addr, "user1", "password1").await;
+        send(&mut client, chat(&"x".repeat(2048))).await;
+        assert!(recv_until(&mut client, |m| matches!(
+            m,
+            MessageType::Notice { text, .. } if text.starts_with("Message too big")
+        ))
+        .await
+        .is_some());
+        assert!(recv(&mut client).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn large_messages_are_deflated_only_for_clients_that_asked() {
+        use flate2::read::DeflateDecoder;
+        use std::io::Read;
+        use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+
+        let (addr, _app, _shutdown) = start(|config| config.compression = true).await;
+        let mut request = format!("ws://{}", addr).into_client_request().unwrap();
+        request
+            .headers_mut()
+            .insert(COMPRESSION_HEADER, COMPRESSION_DEFLATE.parse().unwrap());
+        let (mut compressed, response) = connect_async(request).await.unwrap();
+        assert_eq!(
+            response.headers().get(COMPRESSION_HEADER).unwrap(),
+            COMPRESSION_DEFLATE
+        );
+        send(
+            &mut compressed,
+            MessageType::Auth {
+                username: "user1".to_string(),
+                password: "password1".to_string(),
+                token: None,
+            },
+        )
+        .await;
+        // Small messages such as the welcome still come as text
+        assert!(recv_until(&mut compressed, |m| welcomed_as(m, "user1"))
+            .await
+            .is_some());
+        let mut plain = login(addr, "user2", "password2").await;
+        let mut sender = login(addr, "William", "password").await;
+
+        let big = "a fairly long message ".repeat(COMPRESSION_MIN_BYTES / 10);
+        send(&mut sender, chat(&big)).await;
+
+        // The chat message is the first big one, so the first binary frame
+        let deflated = loop {
+            match timeout(RECV_TIMEOUT, compressed.next()).await {
+                Ok(Some(Ok(Message::Binary(data)))) => break data,
+                Ok(Some(Ok(_))) => continue,
+                other => panic!("expected a binary frame, got {:?}", other),
+            }
+        };
+        let mut json = String::new();
+        DeflateDecoder::new(&deflated[..])
+            .read_to_string(&mut json)
+            .unwrap();
+        assert!(deflated.len() < json.len());
+        assert!(is_chat(&serde_json::from_str(&json).unwrap(), &big));
+
+        // A client that didn't ask gets the same message as text
+        let text = loop {
+            match timeout(RECV_TIMEOUT, plain.next()).await {
+                Ok(Some(Ok(Message::Text(text)))) if text.contains("a fairly long") => break text,
+                Ok(Some(Ok(Message::Binary(_)))) => panic!("plain client was sent a binary frame"),
+                Ok(Some(Ok(_))) => continue,
+                other => panic!("expected the message as text, got {:?}", other),
+            }
+        };
+        assert!(is_chat(&serde_json::from_str(&text).unwrap(), &big));
+    }
+
+    #[tokio::test]
+    async fn blocked_senders_reach_everyone_but_the_blocker() {
+        let (addr, _app, _shutdown) = start(|_| {}).await;
+        let mut sender = login(addr, "user1", "password1").await;
+        let mut blocker = login(addr, "user2", "password2").await;
+        let mut bystander = login(addr, "William", "password").await;
+        let command = |name: &str, args: &[&str]| MessageType::Command {
+            name: name.to_string(),
+            args: args.iter().map(|arg| arg.to_string()).collect(),
+        };
+        // Messages up to `last`, which a bystander sends once everything before it is out
+        async fn read_until(client: &mut Client, last: &str) -> Vec<MessageType> {
+            let mut seen = Vec::new();
+            while let Some(message) = recv(client).await {
+                let done = is_chat(&message, last);
+                seen.push(message);
+                if done {
+                    break;
+                }
+            }
+            seen
+        }
+
+        send(&mut blocker, command("block", &["user1"])).await;
+        assert!(recv_until(&mut blocker, |m| matches!(
+            m,
+            MessageType::Notice { text, .. } if text.starts_with("Blocked user1")
+        ))
+        .await
+        .is_some());
+
+        send(&mut sender, chat("hello")).await;
+        assert!(recv_until(&mut bystander, |m| is_chat(m, "hello"))
+            .await
+            .is_some());
+        send(&mut bystander, chat("first marker")).await;
+        let seen = read_until(&mut blocker, "first marker").await;
+        assert!(seen.iter().any(|m| is_chat(m, "first marker")));
+        assert!(!seen.iter().any(|m| is_chat(m, "hello")));
+
+        // Nor does it come back with the history, asked for or replayed on joining
+        send(&mut blocker, command("history", &[])).await;
+        match recv_until(&mut blocker, |m| matches!(m, MessageType::History(_))).await {
+            Some(MessageType::History(history)) => {
+                assert!(history.iter().any(|m| is_chat(m, "first marker")));
+                assert!(!history.iter().any(|m| is_chat(m, "hello")));
+            }
+            other => panic!("expected the history, got {:?}", other),
+        }
+        send(&mut blocker, MessageType::Join("games".to_string())).await;
+        send(&mut blocker, MessageType::Join("general".to_string())).await;
+        assert!(recv_until(&mut blocker, |m| matches!(
+            m,
+            MessageType::Join(channel) if channel == "general"
+        ))
+        .await
+        .is_some());
+        send(&mut bystander, chat("second marker")).await;
+        let seen = read_until(&mut blocker, "second marker").await;
+        assert!(seen.iter().any(|m| is_chat(m, "first marker")));
+        assert!(!seen.iter().any(|m| is_chat(m, "hello")));
+    }
+
+    #[tokio::test]
+    async fn broadcast_chat_is_written_to_the_chat_log() {
+        let dir = std::env::temp_dir().join(format!("chat-log-server-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("chat.log");
+        let (addr, _app, _shutdown) = start(|config| config.chat_log = Some(path.clone())).await;
+        let mut sender = login(addr, "user1", "password1").await;
+        let mut receiver = login(addr, "user2", "password2").await;
+        send(&mut sender, chat("for the record")).await;
+        assert!(recv_until(&mut receiver, |m| is_chat(m, "for the record"))
+            .await
+            .is_some());
+
+        // Written on the log's own thread, so wait for it
+        let text = timeout(RECV_TIMEOUT, async {
+            loop {
+                let text = std::fs::read_to_string(&path).unwrap_or_default();
+                if text.ends_with('\n') {
+                    return text;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("nothing was logged");
+        let (timestamp, rest) = text.split_once(' ').unwrap();
+        assert_eq!(timestamp.len(), "1970-01-01T00:00:00Z".len());
+        assert!(timestamp.ends_with('Z'));
+        assert_eq!(rest, "#general user1 (user1): for the record\n");
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn chat_is_delivered_when_the_chat_log_cannot_be_written() {
+        // A directory can't be opened as the log file
+        let (addr, _app, _shutdown) = start(|config| {
+            config.chat_log = Some(std::env::temp_dir());
+        })
+        .await;
+        let mut sender = login(addr, "user1", "password1").await;
+        let mut receiver = login(addr, "user2", "password2").await;
+        send(&mut sender, chat("logged or not")).await;
+        assert!(recv_until(&mut receiver, |m| is_chat(m, "logged or not"))
+            .await
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn connections_still_logging_in_are_sent_no_chat() {
+        let (addr, _app, _shutdown) = start(|_| {}).await;
+        let mut guest = connect(addr).await;
+        let bad_login = MessageType::Auth {
+            username: "user1".to_string(),
+            password: "wrong".to_string(),
+            token: None,
+        };
+        let failed = |m: &MessageType| matches!(m, MessageType::SystemMessage(text) if text.starts_with("Authentication failed"));
+        send(&mut guest, bad_login.clone()).await;
+        assert!(recv_until(&mut guest, failed).await.is_some());
+
+        let mut sender = login(addr, "user1", "password1").await;
+        let mut receiver = login(addr, "user2", "password2").await;
+        send(&mut sender, chat("members only")).await;
+        assert!(recv_until(&mut receiver, |m| is_chat(m, "members only"))
+            .await
+            .is_some());
+
+        // Everything the guest was sent since then, up to the reply to another attempt
+        send(&mut guest, bad_login).await;
+        let mut seen = Vec::new();
+        while let Some(message) = recv(&mut guest).await {
+            let done = failed(&message);
+            seen.push(message);
+            if done {
+                break;
+            }
+        }
+        assert!(seen.iter().any(failed));
+        assert!(!seen
+            .iter()
+            .any(|m| matches!(m, MessageType::ChatMessage { .. })));
+    }
+
+    #[tokio::test]
+    async fn status_counts_the_messages_sent_so_far() {
+        let (addr, _app, _shutdown) = start(|_| {}).await;
+        let mut client = login(addr, "user1", "password1").await;
+        let status = MessageType::Command {
+            name: "status".to_string(),
+            args: Vec::new(),
+        };
+        let reply = |count: usize| {
+            let ending = format!("{} messages sent.", count);
+            move |m: &MessageType| {
+                matches!(m, MessageType::SystemMessage(text)
+                    if text.starts_with("You are user1") && text.ends_with(&ending))
+            }
+        };
+
+        send(&mut client, status.clone()).await;
+        assert!(recv_until(&mut client, reply(0)).await.is_some());
+        send(&mut client, chat("one")).await;
+        send(&mut client, chat("two")).await;
+        send(&mut client, status).await;
+        assert!(recv_until(&mut client, reply(2)).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn seen_counts_chat_but_not_polling_as_activity() {
+        let (addr, app, _shutdown) = start(|_| {}).await;
+        let mut alice = login(addr, "user1", "password1").await;
+        let mut bob = login(addr, "user2", "password2").await;
+        let command = |name: &str, args: &[&str]| MessageType::Command {
+            name: name.to_string(),
+            args: args.iter().map(|arg| arg.to_string()).collect(),
+        };
+        let bob_info = {
+            let app_lock = app.lock().await;
+            let id = app_lock.find_user_id_by_name("user2").await.unwrap();
+            app_lock.get_connected_user(&id).await.unwrap()
+        };
+        bob_info.lock().await.last_active = SystemTime::now() - Duration::from_secs(90);
+        let seen = |wanted: &'static str| move |m: &MessageType| matches!(m, MessageType::SystemMessage(text) if text == wanted);
+
+        // Asking for the user list is not being active
+        send(&mut bob, command("list", &[])).await;
+        assert!(
+            recv_until(&mut bob, |m| matches!(m, MessageType::UserList(_)))
+                .await
+                .is_some()
+        );
+        send(&mut alice, command("seen", &["user2"])).await;
+        assert!(
+            recv_until(&mut alice, seen("user2 was last active 1m 30s ago"))
+                .await
+                .is_some()
+        );
+
+        send(&mut bob, chat("back")).await;
+        assert!(recv_until(&mut alice, |m| is_chat(m, "back"))
+            .await
+            .is_some());
+        send(&mut alice, command("seen", &["user2"])).await;
+        assert!(recv_until(&mut alice, seen("user2 was last active 0s ago"))
+            .await
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn chat_is_refused_while_message_numbers_cannot_be_recorded() {
+        // A file in a directory that doesn't exist can't be written
+        let missing = std::env::temp_dir().join(format!("no-such-dir-{}", std::process::id()));
+        let (addr, _app, _shutdown) = start(|config| {
+            config.seq_file = Some(missing.join("message_seq"));
+        })
+        .await;
+        let mut sender = login(addr, "user1", "password1").await;
+        let mut receiver = login(addr, "user2", "password2").await;
+
+        send(&mut sender, chat("unnumbered")).await;
+        assert!(recv_until(&mut sender, |m| matches!(
+            m,
+            MessageType::Notice { level: Severity::Error, text }
+                if text.starts_with("The server could not number your message")
+        ))
+        .await
+        .is_some());
+        assert!(recv_until(&mut receiver, |m| is_chat(m, "unnumbered"))
+            .await
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn login_tokens_welcome_the_bot_under_its_name() {
+        let (addr, _app, _shutdown) = start(|config| {
+            config.auth_tokens = HashMap::from([("s3cret".to_string(), "deploy-bot".to_string())]);
+        })
+        .await;
+        let token_login = |token: &str| MessageType::Auth {
+            username: "ignored".to_string(),
+            password: String::new(),
+            token: Some(token.to_string()),
+        };
+
+        let mut stranger = connect(addr).await;
+        send(&mut stranger, token_login("guess")).await;
+        assert!(recv_until(&mut stranger, |m| matches!(
+            m,
+            MessageType::SystemMessage(text) if text.starts_with("Authentication failed")
+        ))
+        .await
+        .is_some());
+
+        let mut bot = connect(addr).await;
+        send(&mut bot, token_login("s3cret")).await;
+        assert!(recv_until(&mut bot, |m| welcomed_as(m, "deploy-bot"))
+            .await
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn resent_messages_are_acknowledged_but_delivered_once() {
+        let (addr, _app, _shutdown) = start(|_| {}).await;
+        let mut sender = login(addr, "user1", "password1").await;
+        let mut receiver = login(addr, "user2", "password2").await;
+        let with_id = |content: &str, id: &str| MessageType::ChatMessage {
+            sender: String::new(),
+            content: content.to_string(),
+            sender_id: String::new(),
+            client_msg_id: Some(id.to_string()),
+            message_id: None,
+            reply_to: None,
+            seq: None,
+        };
+        let acked = |wanted: &'static str| move |m: &MessageType| matches!(m, MessageType::Ack { id, .. } if id == wanted);
+
+        send(&mut sender, with_id("once", "c-1")).await;
+        let first = recv_until(&mut sender, acked("c-1")).await;
+        send(&mut sender, with_id("once", "c-1")).await;
+        let again = recv_until(&mut sender, acked("c-1")).await;
+        // The resend is acknowledged as the same message
+        match (first, again) {
+            (
+                Some(MessageType::Ack {
+                    message_id: a,
+                    seq: x,
+                    ..
+                }),
+                Some(MessageType::Ack {
+                    message_id: b,
+                    seq: y,
+                    ..
+                }),
+            ) => assert_eq!((a, x), (b, y)),
+            other => panic!("expected two acks, got {:?}", other),
+        }
+        send(&mut sender, with_id("twice", "c-2")).await;
+        assert!(recv_until(&mut sender, acked("c-2")).await.is_some());
+
+        let mut seen = Vec::new();
+        while let Some(message) = recv(&mut receiver).await {
+            let done = is_chat(&message, "twice");
+            seen.push(message);
+            if done {
+                break;
+            }
+        }
+        assert_eq!(seen.iter().filter(|m| is_chat(m, "once")).count(), 1);
+        assert!(seen.iter().any(|m| is_chat(m, "twice")));
+    }
+
+    #[tokio::test]
+    async fn dropped_messages_only_warn_their_sender() {
+        let filter_file = std::env::temp_dir().join(format!("filter-{}", std::process::id()));
+        std::fs::write(&filter_file, "darn\n").unwrap();
+        let (addr, _app, _shutdown) = start(|config| {
+            config.filter_file = Some(filter_file.clone());
+            config.filter_mode = FilterMode::Drop;
+        })
+        .await;
+        let mut sender = login(addr, "user1", "password1").await;
+        let mut receiver = login(addr, "user2", "password2").await;
+
+        send(&mut sender, chat("oh darn")).await;
+        assert!(recv_until(&mut sender, |m| matches!(
+            m,
+            MessageType::Notice { level: Severity::Warn, text }
+                if text == "Your message was blocked by the server's word filter."
+        ))
+        .await
+        .is_some());
+
+        // Everyone else only ever sees the message that passed
+        send(&mut sender, chat("all fine")).await;
+        let mut seen = Vec::new();
+        while let Some(message) = recv(&mut receiver).await {
+            let done = is_chat(&message, "all fine");
+            seen.push(message);
+            if done {
+                break;
+            }
+        }
+        assert!(seen.iter().any(|m| is_chat(m, "all fine")));
+        assert!(!seen.iter().any(|m| is_chat(m, "oh darn")));
+        let _ = std::fs::remove_file(&filter_file);
+    }
 }